@@ -0,0 +1,79 @@
+// Copyright 2023  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Task context switch.
+//!
+//! A task that is not running is described by its `Context`: the
+//! stack pointer at which its callee-saved registers were pushed
+//! when it last switched away, and its extended processor state,
+//! which nothing else saves (see `arch::xsave`).
+
+use arch::xsave::{self, Area};
+use core::arch::naked_asm;
+
+/// The saved state of a task that is not running.
+// XXX: Nothing schedules tasks yet.
+#[allow(dead_code)]
+#[repr(C)]
+pub(crate) struct Context {
+    xsave: Area,
+    rsp: usize,
+}
+
+#[allow(dead_code)]
+impl Context {
+    /// Returns the context of a task that will start running on
+    /// the given stack, which must hold the address at which it
+    /// is to start, beneath space for the callee-saved registers.
+    pub(crate) const fn new(rsp: usize) -> Context {
+        Context { xsave: Area::new(), rsp }
+    }
+}
+
+/// Switches from the current task, whose state is saved in
+/// `from`, to the task whose state is in `to`.  Returns when
+/// some other task switches back to `from`.
+///
+/// # Safety
+/// XSAVE must be enabled, and `to` must describe a task that is
+/// not running.
+#[allow(dead_code)]
+pub(crate) unsafe fn switch(from: &mut Context, to: &Context) {
+    let components = xsave::host();
+    unsafe {
+        from.xsave.save(components);
+        to.xsave.restore(components);
+        swtch(&mut from.rsp, to.rsp);
+    }
+}
+
+// Pushes the callee-saved registers, saves the stack pointer in
+// `*from`, loads `to` into it, and pops the registers of the task
+// that switched away on that stack.
+#[unsafe(naked)]
+unsafe extern "C" fn swtch(from: *mut usize, to: usize) {
+    naked_asm!(
+        r#"
+        pushq %rbp
+        pushq %rbx
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+        movq %rsp, (%rdi)
+        movq %rsi, %rsp
+        popq %r15
+        popq %r14
+        popq %r13
+        popq %r12
+        popq %rbx
+        popq %rbp
+        retq
+        "#,
+        options(att_syntax)
+    );
+}
@@ -16,6 +16,8 @@ pub fn print_panic(info: &PanicInfo<'_>) {
     uart::panic_println!("\nPANIC: ");
     uart::panic_println!("*************** [ Cut Here ] *************");
     uart::panic_println!("{:#?}", info);
+    let fp = arch::backtrace::frame_pointer();
+    uart::panic_println!("{}", arch::backtrace::Backtrace::new(fp));
     uart::panic_println!("******************************************");
     uart::panic_println!("System halted.");
 }
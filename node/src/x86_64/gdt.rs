@@ -5,12 +5,12 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+use arch::sync::TicketLock;
 use arch::{Page, V4KA, VPageAddr, gdt};
-use core::sync::atomic::{AtomicBool, Ordering};
 
 #[unsafe(link_section = ".gdt")]
 static mut GDT: gdt::GDT = gdt::GDT::empty();
-static INITED: AtomicBool = AtomicBool::new(false);
+static LOCK: TicketLock<()> = TicketLock::new(());
 
 pub(crate) fn map() {
     let zeros = crate::zero_page();
@@ -18,19 +18,24 @@ pub(crate) fn map() {
     const R: bool = true;
     const NW: bool = false;
     const NX: bool = false;
-    for k in 1..16 {
-        let zva = V4KA::new(va.addr() + k * 4096);
-        arch::vm::map_leaf(zeros.frame(), zva, R, NW, NX).expect("mapped zero page in GDT");
-    }
+    let start = V4KA::new(va.addr() + 4096);
+    let end = V4KA::new(va.addr() + 16 * 4096);
+    arch::vm::map_leaf_range(zeros.frame(), start..end, R, NW, NX)
+        .expect("mapped zero page in GDT");
 }
 
+/// Loads the GDT on the current CPU, with the task register
+/// referring to the given TSS, which must be the CPU's own.
+/// Every CPU shares the GDT, and so its one TSS descriptor, but
+/// `ltr` caches the TSS in the task register, and only marks the
+/// descriptor busy in memory, so CPUs take turns installing their
+/// own descriptor and loading it.
 pub(crate) fn init(task_state: &arch::tss::TSS) {
-    if !INITED.swap(true, Ordering::AcqRel) {
-        let gdtp = &raw mut GDT;
-        let gdt = unsafe { &mut *gdtp };
-        gdt.init(task_state);
-        unsafe {
-            arch::gdt::load(gdt);
-        }
+    let _guard = LOCK.lock();
+    let gdtp = &raw mut GDT;
+    let gdt = unsafe { &mut *gdtp };
+    gdt.init(task_state);
+    unsafe {
+        arch::gdt::load(gdt);
     }
 }
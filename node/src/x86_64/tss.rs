@@ -5,10 +5,11 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-use core::cell::SyncUnsafeCell;
-
-static TSS: SyncUnsafeCell<arch::tss::TSS> = SyncUnsafeCell::new(arch::tss::TSS::empty());
-
+/// Returns the current CPU's TSS, whose interrupt stack entries
+/// point at its own stacks, for the traps that may arrive on a
+/// bad stack.  Theon places it, and maps the stacks with their
+/// guard pages, in the CPU's window of the per-CPU area when it
+/// starts the CPU.
 pub(crate) fn init() -> &'static arch::tss::TSS {
-    unsafe { &*TSS.get() }
+    arch::cpu_local!(tss)
 }
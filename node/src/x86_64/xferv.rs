@@ -7,16 +7,25 @@
 
 use core::arch::naked_asm;
 
+// The node segment is privileged, so each entry in its transfer
+// vector goes through the taint trampoline, which taints the CPU
+// until the call returns.
 #[unsafe(export_name = "xferv")]
 #[unsafe(link_section = ".xferv")]
 #[unsafe(naked)]
 pub unsafe extern "C" fn xferv() {
     naked_asm!(r#"
-        .balign 8; jmp {hi};
-        .balign 8; jmp {bye};
+        .balign 8; jmp 1f;
+        .balign 8; jmp 2f;
+
+        .pushsection .text.xferv_entries, "ax", @progbits
+        1: leaq {hi}(%rip), %r11; jmp {trampoline};
+        2: leaq {bye}(%rip), %r11; jmp {trampoline};
+        .popsection
         "#,
         hi = sym hi,
         bye = sym bye,
+        trampoline = sym arch::taint::trampoline,
         options(att_syntax));
 }
 
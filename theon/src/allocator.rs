@@ -473,8 +473,8 @@ impl QuickFit {
 mod global {
     use super::{Block, BumpAlloc, QuickFit};
     use alloc::alloc::{GlobalAlloc, Layout};
+    use arch::sync::TicketLock;
     use core::mem;
-    use core::ptr;
     use core::sync::atomic::{AtomicPtr, Ordering};
 
     const GLOBAL_HEAP_SIZE: usize = 4 * 1024 * 1024;
@@ -491,18 +491,18 @@ mod global {
 
     /// GlobalQuickAlloc is a wrapper around a QuickFit over a
     /// GlobalHeap that uses interior mutability to implement
-    /// the GlobalAlloc trait.
-    struct GlobalQuickAlloc(AtomicPtr<QuickFit>);
+    /// the GlobalAlloc trait.  Concurrent callers are
+    /// serialized by the lock.
+    struct GlobalQuickAlloc(TicketLock<()>, AtomicPtr<QuickFit>);
     impl GlobalQuickAlloc {
         fn with_allocator<F, R>(&self, thunk: F) -> R
         where
             F: FnOnce(&mut QuickFit) -> R,
         {
-            let a = self.0.swap(ptr::null_mut(), Ordering::Relaxed);
+            let _guard = self.0.lock();
+            let a = self.1.load(Ordering::Relaxed);
             assert!(!a.is_null(), "global allocator is nil");
-            let r = thunk(unsafe { &mut *a });
-            self.0.swap(a, Ordering::Relaxed);
-            r
+            thunk(unsafe { &mut *a })
         }
     }
 
@@ -519,11 +519,14 @@ mod global {
     }
 
     #[global_allocator]
-    static GLOBAL_ALLOCATOR: GlobalQuickAlloc = GlobalQuickAlloc(AtomicPtr::new({
-        static mut HEAP: GlobalHeap = GlobalHeap::new();
-        static mut ALLOC: QuickFit = QuickFit::new(BumpAlloc::new(unsafe {
-            Block::new_from_raw_parts((&raw mut HEAP).cast(), mem::size_of::<GlobalHeap>())
-        }));
-        &raw mut ALLOC
-    }));
+    static GLOBAL_ALLOCATOR: GlobalQuickAlloc = GlobalQuickAlloc(
+        TicketLock::new(()),
+        AtomicPtr::new({
+            static mut HEAP: GlobalHeap = GlobalHeap::new();
+            static mut ALLOC: QuickFit = QuickFit::new(BumpAlloc::new(unsafe {
+                Block::new_from_raw_parts((&raw mut HEAP).cast(), mem::size_of::<GlobalHeap>())
+            }));
+            &raw mut ALLOC
+        }),
+    );
 }
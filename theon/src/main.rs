@@ -139,11 +139,30 @@ const BINARY_LOAD_REGION_END: HPA = load_addr(BINARY_TABLE.len());
 /// so we can address them via pointers.
 #[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn main(mbinfo_phys: u64) -> ! {
-    arch::lapic::enable_x2apic();
+    if let Err(missing) = arch::cpuid::require(arch::cpuid::Features::REQUIRED) {
+        panic!("CPU lacks required features: {missing:?}");
+    }
+    // Should the APIC be in xAPIC mode, its register window is
+    // mapped from the MMIO arena, and shared into each binary's
+    // address space as it is loaded.
+    unsafe {
+        arch::lapic::enable(|hpa| {
+            use arch::Page;
+            let regs = arch::mmio::map(&MMIO, hpa, arch::Page4K::SIZE, &mut alloc_frame)
+                .expect("mapped local APIC");
+            regs.as_ptr().cast()
+        });
+        arch::lapic::software_enable(arch::lapic::SPURIOUS_VECTOR);
+    }
+    arch::pcid::init();
+    arch::pat::init();
+    arch::xsave::init();
     let multiboot = x86_64::platform::init::start(mbinfo_phys);
+    arch::shootdown::online();
     let crate::x86_64::pc::multiboot1::InitInfo { memory_regions, regions, modules } =
         multiboot.info();
     assert!(theon_fits(&regions));
+    check_mtrrs(&memory_regions);
     core::mem::drop(memory_regions);
     uart::panic_println!("end = {:016x?}", theon::end_addr());
     uart::panic_println!("regions: {:#x?}", regions);
@@ -153,23 +172,35 @@ pub extern "C" fn main(mbinfo_phys: u64) -> ! {
         unsafe { bins.bytes.as_ptr().add(bins.bytes.len()) }.addr()
             < theon::vaddr(BINARY_LOAD_REGION_START).addr()
     );
+    let mca = arch::mca::init();
+    match mca {
+        Ok(cap) => uart::panic_println!("Machine checks enabled, {} banks", cap.count()),
+        Err(err) => uart::panic_println!("Machine checks not enabled: {err}"),
+    }
+    arch::taint::map(&mut alloc_frame).expect("mapped shared taint state");
+    uart::panic_println!("Privileged segment mitigations: {:?}", arch::taint::init());
+    let tables = x86_64::platform::acpi::init().expect("found ACPI tables");
+    x86_64::platform::tsc::init(tables);
+    if mca.is_ok() {
+        arch::mca::start_polling();
+    }
     let archive = goblin::archive::Archive::parse(bins.bytes).expect("cannot parse bin.a");
     uart::panic_println!("Binary archive: {:#x?}", archive);
     clear_binary_load_region();
+    arch::backtrace::set_symbolizer(symbolize);
     let mut roots = alloc::vec::Vec::with_capacity(BINARY_TABLE.len());
-    for &(name, addr, typ) in BINARY_TABLE {
+    for (&(name, addr, typ), symbols) in BINARY_TABLE.iter().zip(&SYMBOLS) {
         let bytes = archive.extract(name, bins.bytes).expect("cannot extract elf");
         let region_end = addr.offset(BINARY_IMAGE_MEMORY_SIZE);
-        let root = load(name, typ, bytes, addr..region_end).expect("loaded binary");
+        let root = load(name, typ, bytes, addr..region_end, symbols).expect("loaded binary");
         roots.push((name, root));
     }
     unsafe { core::arch::asm!("int3") };
     // Start other CPUs.
     uart::panic_println!("starting APs");
     unsafe {
-        let rsdp = crate::x86_64::platform::acpi::init();
-        uart::panic_println!("rsdp = {:#x?}", rsdp);
-        crate::x86_64::platform::acpi::parse(rsdp.unwrap());
+        uart::panic_println!("ACPI tables = {:#x?}", tables);
+        crate::x86_64::platform::acpi::parse(tables);
         mp::start_aps(cpus());
     }
     panic!("main: trapstubs = {:#x?}", arch::trap::stubs as usize);
@@ -204,6 +235,18 @@ fn cpus() -> &'static [mp::EntryCPU] {
     cs.leak()
 }
 
+/// The arena from which device register windows are mapped.
+pub(crate) static MMIO: arch::mmio::Arena = arch::mmio::Arena::new(arch::mmio::REGION);
+
+/// Allocates a zeroed page frame from the heap.  It is never
+/// freed.
+pub(crate) fn alloc_frame() -> Result<PF4K> {
+    use alloc::boxed::Box;
+    use arch::Page;
+    let page = Box::leak(Box::new(arch::Page4K::new()));
+    Ok(page.frame())
+}
+
 fn theon_fits(regions: &[Region]) -> bool {
     assert!(theon::end_addr().addr() < theon::vaddr(BINARY_LOAD_REGION_START).addr());
     for region in regions.iter().filter(|&r| r.typ == Type::RAM) {
@@ -216,6 +259,19 @@ fn theon_fits(regions: &[Region]) -> bool {
     false
 }
 
+/// Reports any RAM that the firmware's MTRRs would keep from
+/// being mapped write back.
+fn check_mtrrs(regions: &[Region]) {
+    let Some(mtrrs) = arch::mtrr::Mtrrs::read() else {
+        return;
+    };
+    for region in regions.iter().filter(|&r| r.typ == Type::RAM) {
+        if let Err(conflict) = mtrrs.check(region.start..region.end, arch::MemoryType::WriteBack) {
+            uart::panic_println!("MTRR conflict in RAM at {conflict}");
+        }
+    }
+}
+
 /// Zeroes the memory region that binaries are loaded into.
 fn clear_binary_load_region() {
     let start = theon::vaddr(BINARY_LOAD_REGION_START);
@@ -223,8 +279,15 @@ fn clear_binary_load_region() {
     unsafe { core::ptr::write_bytes(start.cast_mut(), 0, end.offset_from_unsigned(start)) };
 }
 
-/// Loads the named binary of the given type into given physical region.
-fn load(name: &str, typ: BinaryType, bytes: &[u8], region: Range<HPA>) -> Result<PF4K> {
+/// Loads the named binary of the given type into given physical
+/// region, recording its functions for symbolizing backtraces.
+fn load(
+    name: &str,
+    typ: BinaryType,
+    bytes: &[u8],
+    region: Range<HPA>,
+    symbols: &arch::sync::Once<Vec<Symbol>>,
+) -> Result<PF4K> {
     use arch::{Page, Page4K};
     let elf = goblin::elf::Elf::parse(bytes).expect("cannot parse elf");
     uart::panic_println!(
@@ -264,6 +327,16 @@ fn load(name: &str, typ: BinaryType, bytes: &[u8], region: Range<HPA>) -> Result
         Ok(page.frame())
     })
     .expect("mapped mem regions");
+    // The xAPIC register window lies outside the binary's own
+    // ranges, so it is shared in where the binary will look.
+    let xapic = arch::lapic::XAPIC_REGS.load(core::sync::atomic::Ordering::Relaxed);
+    let root = if xapic.is_null() {
+        root
+    } else {
+        let window = V4KA::new(xapic.addr())..V4KA::new(xapic.addr() + Page4K::SIZE);
+        arch::vm::share_range(window, root, &mut || Ok(allocate()?.frame()))
+            .expect("shared xAPIC window")
+    };
     for (&header, region) in headers.iter().zip(&regions) {
         let mut src = &bytes[header.file_range()];
         let r = header.is_read();
@@ -280,9 +353,19 @@ fn load(name: &str, typ: BinaryType, bytes: &[u8], region: Range<HPA>) -> Result
                 }
                 src = &src[len..];
             }
-            arch::vm::map_leaf(page.frame(), addr, r, w, x).expect("mapped a page");
+            // Segments are mapped identically in every address
+            // space, so their pages may be global.
+            match typ {
+                BinaryType::Segment => arch::vm::map_global_leaf(page.frame(), addr, r, w, x),
+                BinaryType::Task => arch::vm::map_leaf(page.frame(), addr, r, w, x),
+            }
+            .expect("mapped a page");
         }
     }
+    publish(&elf);
+    if let BinaryType::Segment = typ {
+        symbols.call_once(|| functions(&elf));
+    }
     if let BinaryType::Task = typ {
         arch::vm::unmap_root_ranges(&regions);
     } else {
@@ -293,21 +376,102 @@ fn load(name: &str, typ: BinaryType, bytes: &[u8], region: Range<HPA>) -> Result
     Ok(root)
 }
 
+/// Writes the values theon has discovered into a loaded
+/// binary's copies of them, so that it need not rediscover them:
+/// the TSC frequency, the address of the local APIC's register
+/// window, which `load` has shared into the binary's address
+/// space, and the host's extended state configuration.
+fn publish(elf: &goblin::elf::Elf<'_>) {
+    use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+    let symbol = |name| elf.syms.iter().find(|sym| elf.strtab.get_at(sym.st_name) == Some(name));
+    let publish_u64 = |name, value| {
+        if let Some(sym) = symbol(name) {
+            let copy = unsafe { &*(sym.st_value as *const AtomicU64) };
+            copy.store(value, Ordering::Relaxed);
+        }
+    };
+    publish_u64(arch::tsc::FREQUENCY_SYMBOL, arch::tsc::frequency());
+    publish_u64(arch::xsave::HOST_SYMBOL, arch::xsave::HOST.load(Ordering::Relaxed));
+    if let Some(sym) = symbol(arch::xsave::OPTIMIZED_SYMBOL) {
+        let optimized = unsafe { &*(sym.st_value as *const AtomicBool) };
+        optimized.store(arch::xsave::OPTIMIZED.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+    if let Some(sym) = symbol(arch::lapic::XAPIC_REGS_SYMBOL) {
+        let regs = unsafe { &*(sym.st_value as *const AtomicPtr<u32>) };
+        regs.store(arch::lapic::XAPIC_REGS.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// A function in a loaded segment.
+struct Symbol {
+    addrs: Range<usize>,
+    name: &'static str,
+}
+
+/// The functions of each loaded binary, sorted by address.  The
+/// tasks all link at the same address, so theirs are omitted.
+static SYMBOLS: [arch::sync::Once<Vec<Symbol>>; BINARY_TABLE.len()] =
+    [const { arch::sync::Once::new() }; BINARY_TABLE.len()];
+
+/// Returns the function symbols in the given ELF's symbol table,
+/// sorted by address.  Names are copied, as the image they come
+/// from is not retained.
+fn functions(elf: &goblin::elf::Elf<'_>) -> Vec<Symbol> {
+    use alloc::string::String;
+    let mut symbols = elf
+        .syms
+        .iter()
+        .filter(|sym| sym.is_function() && sym.st_size != 0)
+        .filter_map(|sym| {
+            let name = elf.strtab.get_at(sym.st_name)?;
+            let start = sym.st_value as usize;
+            let addrs = start..start + sym.st_size as usize;
+            Some(Symbol { addrs, name: String::from(name).leak() })
+        })
+        .collect::<Vec<_>>();
+    symbols.sort_unstable_by_key(|sym| sym.addrs.start);
+    symbols
+}
+
+/// Resolves an address in a loaded segment to the function
+/// containing it, for backtraces.
+fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    SYMBOLS.iter().filter_map(arch::sync::Once::get).find_map(|symbols| {
+        let k = symbols.partition_point(|sym| sym.addrs.start <= addr);
+        let sym = &symbols[k.checked_sub(1)?];
+        sym.addrs.contains(&addr).then(|| (sym.name, addr - sym.addrs.start))
+    })
+}
+
 #[cfg_attr(test, allow(dead_code))]
 #[unsafe(no_mangle)]
 pub extern "C" fn apmain(cpu: arch::ProcessorID) -> ! {
-    use core::sync::atomic::{AtomicBool, Ordering};
-    static S: AtomicBool = AtomicBool::new(false);
-    while S.compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire).is_err() {
-        arch::cpu::relax();
+    static S: arch::sync::TicketLock<()> = arch::sync::TicketLock::new(());
+    let guard = S.lock();
+    arch::pcid::init();
+    arch::pat::init();
+    arch::xsave::init();
+    unsafe {
+        x86_64::platform::init::load_idt();
+        mp::init_ap(cpu);
+        arch::lapic::software_enable(arch::lapic::SPURIOUS_VECTOR);
+    }
+    arch::shootdown::online();
+    // The BSP has already reported whether machine checks work.
+    if arch::mca::init().is_ok() {
+        arch::mca::start_polling();
     }
-    uart::panic_println!("Hello from {}", u32::from(cpu));
-    S.store(false, Ordering::Release);
+    arch::taint::init();
+    uart::panic_println!("Hello from {}", u32::from(arch::cpu_local!(id)));
+    drop(guard);
     mp::signal_ap(cpu);
-    unsafe {
-        core::arch::asm!("hlt");
+    // Other CPUs may shoot down translations cached here, so
+    // park with interrupts enabled.
+    loop {
+        unsafe {
+            core::arch::asm!("sti; hlt; cli");
+        }
     }
-    panic!("apmain");
 }
 
 hypatia::runtime!();
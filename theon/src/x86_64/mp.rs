@@ -40,6 +40,7 @@
 //! mode with paging enabled and then jump into theon.
 
 use crate::theon;
+use alloc::boxed::Box;
 use core::sync::atomic::{AtomicU32, Ordering};
 use core::time::Duration;
 
@@ -185,6 +186,32 @@ unsafe fn init_sipi_sipi(cpus: &'static [EntryCPU]) {
     }
 }
 
+/// Gives the calling AP its own GDT, allocated from the heap and
+/// never freed, and its per-CPU data, interrupt stacks and TSS,
+/// in its window of the per-CPU area.
+///
+/// # Safety
+/// Must be called once, on the AP with the given ID, with
+/// interrupts disabled.
+pub unsafe fn init_ap(cpu: arch::ProcessorID) {
+    arch::percpu::map_interrupt_stacks(cpu, &mut crate::alloc_frame)
+        .expect("mapped interrupt stacks");
+    let tss: &'static arch::tss::TSS =
+        arch::percpu::place_tss(cpu, &mut crate::alloc_frame).expect("mapped TSS");
+    let gdt = Box::leak(Box::new(arch::gdt::GDT::empty()));
+    gdt.init(tss);
+    let ptr: *const arch::gdt::GDT = gdt;
+    unsafe {
+        arch::gdt::load(gdt);
+    }
+    let gdt = unsafe { &*ptr };
+    let percpu = arch::percpu::PerCpu::new(cpu, gdt, tss);
+    let percpu = arch::percpu::place(percpu, &mut crate::alloc_frame).expect("mapped per-CPU data");
+    unsafe {
+        arch::percpu::install(percpu);
+    }
+}
+
 static COUNT: AtomicU32 = AtomicU32::new(1);
 
 // Wait up to 500 ms for all APs to mark themselves up from high
@@ -0,0 +1,56 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Header;
+use crate::Result;
+
+use core::ptr;
+
+/// The location of the ACPI PM timer, as given by the FADT:
+/// its I/O port, and whether it is 32 bits wide rather than 24.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PmTimer {
+    pub port: u16,
+    pub extended: bool,
+}
+
+const PM_TMR_BLK: usize = 76;
+const FLAGS: usize = 112;
+const X_PM_TMR_BLK: usize = 208;
+const GAS_LEN: usize = 12;
+
+const TMR_VAL_EXT: u32 = 1 << 8;
+const SYSTEM_IO_SPACE: u8 = 1;
+
+/// Returns the PM timer described by the FADT, if it has one.
+/// Ref: ACPI v6.4 sec 5.2.9
+pub(crate) fn parse(header: &Header, dp: *const u8) -> Result<Option<PmTimer>> {
+    if header.checksum(dp) != 0 {
+        return Err("fadt bad checksum");
+    }
+    if header.len() < FLAGS + 4 {
+        return Err("fadt too short");
+    }
+    let read_u32 = |offset| unsafe { ptr::read_unaligned(dp.wrapping_add(offset).cast::<u32>()) };
+    let extended = read_u32(FLAGS) & TMR_VAL_EXT != 0;
+    let port = match read_u32(PM_TMR_BLK) {
+        0 if header.len() >= X_PM_TMR_BLK + GAS_LEN => {
+            let gas = unsafe { ptr::read(dp.wrapping_add(X_PM_TMR_BLK).cast::<[u8; GAS_LEN]>()) };
+            let addr = u64::from_le_bytes(gas[4..12].try_into().unwrap());
+            if gas[0] != SYSTEM_IO_SPACE {
+                return Ok(None);
+            }
+            addr
+        }
+        port => u64::from(port),
+    };
+    match u16::try_from(port) {
+        Ok(0) => Ok(None),
+        Ok(port) => Ok(Some(PmTimer { port, extended })),
+        Err(_) => Err("fadt PM timer port out of range"),
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::Header;
+use crate::Result;
+
+use arch::HPA;
+use core::{mem, ptr};
+
+const BASE_ADDRESS: usize = mem::size_of::<Header>() + 4;
+const GAS_LEN: usize = 12;
+
+const SYSTEM_MEMORY_SPACE: u8 = 0;
+
+/// Returns the physical address of the HPET register block.
+/// Ref: IA-PC HPET Specification 1.0a sec 3.2.4
+pub(crate) fn parse(header: &Header, dp: *const u8) -> Result<HPA> {
+    if header.checksum(dp) != 0 {
+        return Err("hpet bad checksum");
+    }
+    if header.len() < BASE_ADDRESS + GAS_LEN {
+        return Err("hpet too short");
+    }
+    let gas = unsafe { ptr::read(dp.wrapping_add(BASE_ADDRESS).cast::<[u8; GAS_LEN]>()) };
+    if gas[0] != SYSTEM_MEMORY_SPACE {
+        return Err("hpet not in memory space");
+    }
+    Ok(HPA::new(u64::from_le_bytes(gas[4..12].try_into().unwrap())))
+}
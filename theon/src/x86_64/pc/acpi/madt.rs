@@ -9,6 +9,7 @@ use super::Header;
 use crate::Result;
 
 use alloc::vec::Vec;
+use arch::ioapic::SourceOverride;
 use bitstruct::bitstruct;
 use core::{mem, ptr};
 
@@ -27,14 +28,18 @@ mod ty {
     pub const IOAPIC: u8 = 1;
     pub const IOAPIC_LEN: usize = 12;
 
+    pub const SOURCE_OVERRIDE: u8 = 2;
+    pub const SOURCE_OVERRIDE_LEN: usize = 10;
+
     pub const X2LAPIC: u8 = 9;
     pub const X2LAPIC_LEN: usize = 16;
 }
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct CPUInventory {
-    cpus: &'static [arch::ProcessorID],
-    ioapics: &'static [arch::IOAPIC],
+    pub cpus: &'static [arch::ProcessorID],
+    pub ioapics: &'static [arch::IOAPIC],
+    pub overrides: &'static [SourceOverride],
 }
 
 pub(crate) fn parse(header: &Header, dp: *const u8) -> Result<CPUInventory> {
@@ -46,6 +51,7 @@ pub(crate) fn parse(header: &Header, dp: *const u8) -> Result<CPUInventory> {
 
     let mut cpus = Vec::new();
     let mut ioapics = Vec::new();
+    let mut overrides = Vec::new();
 
     let mut k = 8;
     while k < datalen {
@@ -63,11 +69,12 @@ pub(crate) fn parse(header: &Header, dp: *const u8) -> Result<CPUInventory> {
             ty::LAPIC if let Some(id) = parse_lapic(p) => cpus.push(id),
             ty::X2LAPIC if let Some(id) = parse_x2lapic(p) => cpus.push(id),
             ty::IOAPIC => ioapics.push(parse_ioapic(p)),
+            ty::SOURCE_OVERRIDE => overrides.push(parse_source_override(p)),
             _ => uart::panic_println!("ignoring {typ}"),
         }
         k += len;
     }
-    Ok(CPUInventory { cpus: cpus.leak(), ioapics: ioapics.leak() })
+    Ok(CPUInventory { cpus: cpus.leak(), ioapics: ioapics.leak(), overrides: overrides.leak() })
 }
 
 fn parse_lapic(p: *const u8) -> Option<arch::ProcessorID> {
@@ -98,3 +105,13 @@ fn parse_ioapic(p: *const u8) -> arch::IOAPIC {
     let gsib = u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]);
     arch::IOAPIC::new(id, hpa, gsib)
 }
+
+fn parse_source_override(p: *const u8) -> SourceOverride {
+    let raw = unsafe { ptr::read(p.cast::<[u8; ty::SOURCE_OVERRIDE_LEN]>()) };
+    assert_eq!(raw[0], ty::SOURCE_OVERRIDE);
+    assert_eq!(raw[1], ty::SOURCE_OVERRIDE_LEN as u8);
+    let irq = raw[3];
+    let gsi = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+    let flags = u16::from_le_bytes([raw[8], raw[9]]);
+    SourceOverride { irq, gsi, flags }
+}
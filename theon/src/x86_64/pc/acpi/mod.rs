@@ -11,9 +11,13 @@ use crate::theon;
 use arch::HPA;
 use core::{mem, ptr, slice};
 
+mod fadt;
+mod hpet;
 mod madt;
 mod rsdp;
 
+use fadt::PmTimer;
+
 /// The ACPI Table Header.
 ///
 /// This is a common header that all ACPI tables other than the
@@ -84,10 +88,64 @@ pub(crate) fn parse(addrs: &[*const Header]) {
         if sig == "APIC" {
             let cpus = madt::parse(&header, addr.cast());
             uart::panic_println!("cpus = {cpus:#x?}");
+            if let Ok(inventory) = cpus {
+                init_ioapics(&inventory);
+            }
         }
     }
 }
 
+/// Masks every input of every I/O APIC, until devices claim
+/// them, and sets the trigger mode and polarity of those wired
+/// to ISA IRQs, as the interrupt source overrides describe, so
+/// that claiming one need not consult them.
+fn init_ioapics(inventory: &madt::CPUInventory) {
+    for ioapic in inventory.ioapics {
+        let mut driver =
+            unsafe { arch::ioapic::IoApic::map(ioapic, &crate::MMIO, &mut crate::alloc_frame) }
+                .expect("mapped I/O APIC");
+        uart::panic_println!(
+            "IOAPIC {} version {:#x} routes GSIs {:?}",
+            driver.id(),
+            driver.version(),
+            driver.gsis()
+        );
+        driver.mask_all();
+        for irq in 0..arch::ioapic::ISA_IRQS {
+            let (gsi, trigger_mode, polarity) = arch::ioapic::isa_irq(irq, inventory.overrides);
+            if !driver.gsis().contains(&gsi) {
+                continue;
+            }
+            uart::panic_println!("ISA IRQ {irq} is GSI {gsi}, {trigger_mode:?}, {polarity:?}");
+            let entry = arch::ioapic::RedirectionEntry::new()
+                .with_trigger_mode(trigger_mode)
+                .with_polarity(polarity);
+            unsafe { driver.set_entry(gsi, entry) }.expect("set ISA IRQ entry");
+        }
+    }
+}
+
+/// Returns the table with the given signature, if there is one.
+fn find(addrs: &[*const Header], signature: &[u8; 4]) -> Option<(Header, *const u8)> {
+    addrs.iter().find_map(|&addr| {
+        let header = unsafe { ptr::read_unaligned(addr) };
+        (header.signature == *signature).then_some((header, addr.cast()))
+    })
+}
+
+/// Returns the ACPI PM timer, if the FADT describes one.
+pub(crate) fn pm_timer(addrs: &[*const Header]) -> Result<Option<PmTimer>> {
+    match find(addrs, b"FACP") {
+        Some((header, dp)) => fadt::parse(&header, dp),
+        None => Ok(None),
+    }
+}
+
+/// Returns the address of the HPET registers, if there is one.
+pub(crate) fn hpet(addrs: &[*const Header]) -> Result<Option<HPA>> {
+    find(addrs, b"HPET").map(|(header, dp)| hpet::parse(&header, dp)).transpose()
+}
+
 fn acpi_region() -> (*const u8, usize) {
     const ACPI_REGION_RAW: u64 = 0x000E_0000;
     const ACPI_REGION_LIMIT: u64 = 0x000F_FFFF;
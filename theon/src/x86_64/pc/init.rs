@@ -9,26 +9,59 @@ use crate::x86_64::pc::multiboot1;
 use core::cell::SyncUnsafeCell;
 use core::sync::atomic::{AtomicBool, Ordering};
 
+static IDT: SyncUnsafeCell<arch::idt::IDT> = SyncUnsafeCell::new(arch::idt::IDT::empty());
+
 pub(crate) fn start(mbinfo_phys: u64) -> multiboot1::Multiboot1 {
     static INITED: AtomicBool = AtomicBool::new(false);
     if INITED.swap(false, Ordering::SeqCst) {
         panic!("double init");
     }
-    static IDT: SyncUnsafeCell<arch::idt::IDT> = SyncUnsafeCell::new(arch::idt::IDT::empty());
     static GDT: SyncUnsafeCell<arch::gdt::GDT> = SyncUnsafeCell::new(arch::gdt::GDT::empty());
-    static TSS: SyncUnsafeCell<arch::tss::TSS> = SyncUnsafeCell::new(arch::tss::TSS::empty());
 
     uart::panic_println!("\nBooting Hypatia...");
+    unsafe {
+        arch::pic::disable();
+    }
     let idt = unsafe { &mut *IDT.get() };
     idt.init(arch::trap::stubs());
     unsafe {
         arch::idt::load(idt);
     }
-    let tss = unsafe { &*TSS.get() };
+    let cpu = arch::lapic::id();
+    arch::percpu::map_interrupt_stacks(cpu, &mut crate::alloc_frame)
+        .expect("mapped interrupt stacks");
+    let tss: &'static arch::tss::TSS =
+        arch::percpu::place_tss(cpu, &mut crate::alloc_frame).expect("mapped TSS");
+    arch::mca::map_log(&mut crate::alloc_frame).expect("mapped machine check log");
+    arch::trap::register(arch::mca::MACHINE_CHECK, arch::mca::handle).expect("registered #MC");
+    arch::trap::register(arch::mca::CMCI_VECTOR as u8, arch::mca::handle_cmci)
+        .expect("registered CMCI");
+    arch::trap::register(arch::mca::POLL_VECTOR as u8, arch::mca::handle_poll)
+        .expect("registered machine check poll");
+    arch::trap::register(arch::taint::VECTOR as u8, arch::taint::handle).expect("registered stun");
+    arch::trap::register(arch::shootdown::VECTOR as u8, arch::shootdown::handle)
+        .expect("registered shootdown");
     let gdt = unsafe { &mut *GDT.get() };
     gdt.init(tss);
     unsafe {
         arch::gdt::load(gdt);
     }
+    let gdt = unsafe { &*GDT.get() };
+    let percpu = arch::percpu::PerCpu::new(cpu, gdt, tss);
+    let percpu = arch::percpu::place(percpu, &mut crate::alloc_frame).expect("mapped per-CPU data");
+    unsafe {
+        arch::percpu::install(percpu);
+        arch::sync::order::enable();
+    }
     multiboot1::init(mbinfo_phys)
 }
+
+/// Loads the IDT built by `start`, which every CPU shares.
+///
+/// # Safety
+/// Must be called after `start`, with interrupts disabled.
+pub(crate) unsafe fn load_idt() {
+    unsafe {
+        arch::idt::load(&mut *IDT.get());
+    }
+}
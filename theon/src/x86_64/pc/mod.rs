@@ -9,3 +9,4 @@ pub mod acpi;
 pub mod asm;
 pub mod init;
 pub mod multiboot1;
+pub mod tsc;
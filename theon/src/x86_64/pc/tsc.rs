@@ -0,0 +1,49 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::x86_64::pc::acpi::{self, Header};
+
+use arch::tsc::{self, Clock};
+use core::time::Duration;
+
+/// How long to measure the TSC against a reference clock.
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+/// Determines the TSC frequency, from CPUID if the CPU reports
+/// it and otherwise by calibration, and publishes it.
+pub(crate) fn init(tables: &[*const Header]) -> u64 {
+    if !tsc::invariant() {
+        uart::panic_println!("warning: TSC is not invariant");
+    }
+    let hz = tsc::cpuid_frequency().unwrap_or_else(|| calibrate(tables));
+    uart::panic_println!("TSC frequency: {hz} Hz");
+    tsc::publish(hz);
+    hz
+}
+
+/// Calibrates the TSC against the best available clock.
+fn calibrate(tables: &[*const Header]) -> u64 {
+    if let Some(hpa) = acpi::hpet(tables).expect("parsed HPET table") {
+        let regs = unsafe {
+            arch::mmio::map(&crate::MMIO, hpa, tsc::Hpet::WINDOW_SIZE, &mut crate::alloc_frame)
+        }
+        .expect("mapped HPET");
+        let mut hpet = unsafe { tsc::Hpet::new(regs.as_ptr().cast()) };
+        return measure("HPET", &mut hpet);
+    }
+    if let Some(timer) = acpi::pm_timer(tables).expect("parsed FADT") {
+        let mut pm_timer = unsafe { tsc::PmTimer::new(timer.port, timer.extended) };
+        return measure("ACPI PM timer", &mut pm_timer);
+    }
+    let mut pit = unsafe { tsc::Pit::new() };
+    measure("PIT", &mut pit)
+}
+
+fn measure<C: Clock>(name: &str, clock: &mut C) -> u64 {
+    uart::panic_println!("calibrating TSC against the {name}");
+    tsc::calibrate(clock, CALIBRATION_PERIOD)
+}
@@ -7,3 +7,62 @@
 
 pub(crate) mod asm;
 pub(crate) mod vmcs;
+
+use arch::xsave::{self, Area};
+
+// The state that `vmenter` saves and restores around VM entry
+// and exit: the guest's general purpose registers, the host stack
+// pointer, and whether the current VMCS has been launched, at the
+// offsets `vmenter.S` expects.
+#[allow(dead_code)]
+#[repr(C)]
+struct Registers {
+    gprs: [u64; 15],
+    host_rsp: u64,
+    launched: u64,
+}
+
+/// The state of a guest: the registers `vmenter` saves and
+/// restores, and its extended processor state, which `vmenter`
+/// leaves alone.
+// XXX: Nothing runs guests yet.
+#[allow(dead_code)]
+#[repr(C)]
+pub(crate) struct Context {
+    regs: Registers,
+    xsave: xsave::Guest,
+}
+
+unsafe extern "C" {
+    fn vmenter(regs: *mut Registers);
+}
+
+#[allow(dead_code)]
+impl Context {
+    /// Returns the context of a guest at reset.
+    pub(crate) const fn new() -> Context {
+        let regs = Registers { gprs: [0; 15], host_rsp: 0, launched: 0 };
+        Context { regs, xsave: xsave::Guest::new() }
+    }
+
+    /// Returns the guest's extended state, so that exits for
+    /// XSETBV may set its XCR0.
+    pub(crate) fn xsave(&mut self) -> &mut xsave::Guest {
+        &mut self.xsave
+    }
+
+    /// Enters the guest, returning on VM exit.  The host's
+    /// extended state is saved in `host` while the guest's is
+    /// loaded.
+    ///
+    /// # Safety
+    /// The guest's VMCS must be current on this CPU, with its
+    /// host %rsp pointing to this context.
+    pub(crate) unsafe fn enter(&mut self, host: &mut Area) {
+        unsafe {
+            self.xsave.enter(host);
+            vmenter(&mut self.regs);
+            self.xsave.exit(host);
+        }
+    }
+}
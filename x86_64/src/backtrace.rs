@@ -0,0 +1,240 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Stack backtraces
+//!
+//! We build with frame pointers, so every function's frame begins
+//! with the caller's %rbp, followed by the return address.  We
+//! walk that chain, checking that each word is mapped before we
+//! read it, and stop at the first frame that looks wrong.
+//!
+//! Each address is tagged with the segment or task whose link
+//! range contains it, so that it can be resolved against the
+//! right binary offline, and symbolized on the spot if some
+//! symbolizer has been registered.
+
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The deepest backtrace we walk.
+pub const MAX_DEPTH: usize = 64;
+
+/// The link ranges of the segments and tasks, from the base
+/// addresses in their linker scripts.  The tasks all link at the
+/// same address, so cannot be told apart.
+pub const REGIONS: &[(&str, Range<usize>)] = &[
+    ("task", 0xFFFF_F000_0000_0000..0xFFFF_F400_0000_0000),
+    ("scheduler", 0xFFFF_F400_0000_0000..0xFFFF_F500_0000_0000),
+    ("devices", 0xFFFF_F500_0000_0000..0xFFFF_F600_0000_0000),
+    ("memory", 0xFFFF_F600_0000_0000..0xFFFF_F700_0000_0000),
+    ("supervisor", 0xFFFF_F700_0000_0000..0xFFFF_F800_0000_0000),
+    ("trace", 0xFFFF_F900_0000_0000..0xFFFF_FA00_0000_0000),
+    ("monitor", 0xFFFF_FA00_0000_0000..0xFFFF_FB00_0000_0000),
+    ("node", 0xFFFF_FB40_0000_0000..0xFFFF_FC00_0000_0000),
+    ("global", 0xFFFF_FC00_0000_0000..0xFFFF_FD00_0000_0000),
+];
+
+/// Returns the name of the segment or task containing the given
+/// address, and its offset from the link base.
+pub fn region(addr: usize) -> Option<(&'static str, usize)> {
+    REGIONS
+        .iter()
+        .find(|(_, range)| range.contains(&addr))
+        .map(|(name, range)| (*name, addr - range.start))
+}
+
+/// Returns the frame pointer of the caller.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        core::arch::asm!("movq %rbp, {}", out(reg) fp, options(att_syntax, nomem, nostack));
+    }
+    fp
+}
+
+/// An iterator over the return addresses in a chain of frames,
+/// reading memory through a function that returns the word at the
+/// given address, or `None` if it cannot be read.
+pub struct Frames<R> {
+    fp: usize,
+    depth: usize,
+    read: R,
+}
+
+impl<R: Fn(usize) -> Option<usize>> Frames<R> {
+    /// Returns an iterator over the frames starting with that at
+    /// the given frame pointer.
+    pub fn new(fp: usize, read: R) -> Frames<R> {
+        Frames { fp, depth: 0, read }
+    }
+}
+
+impl<R: Fn(usize) -> Option<usize>> Iterator for Frames<R> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        const WORD: usize = core::mem::size_of::<usize>();
+        if self.fp == 0 || !self.fp.is_multiple_of(WORD) || self.depth == MAX_DEPTH {
+            return None;
+        }
+        let next = (self.read)(self.fp)?;
+        let ret = (self.read)(self.fp.checked_add(WORD)?)?;
+        if ret == 0 {
+            return None;
+        }
+        // Stacks grow down, so callers' frames lie above.
+        self.fp = if next > self.fp { next } else { 0 };
+        self.depth += 1;
+        Some(ret)
+    }
+}
+
+// Reads the word at the given address, if it is mapped.
+fn read_mapped(va: usize) -> Option<usize> {
+    crate::vm::translate(va)?;
+    Some(unsafe { core::ptr::read(va as *const usize) })
+}
+
+/// Returns an iterator over the frames of the current stack,
+/// starting with that at the given frame pointer.
+pub fn frames(fp: usize) -> Frames<fn(usize) -> Option<usize>> {
+    Frames::new(fp, read_mapped)
+}
+
+/// Resolves an address to a symbol name and offset.
+pub type Symbolizer = fn(usize) -> Option<(&'static str, usize)>;
+
+static SYMBOLIZER: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers a function used to symbolize backtraces.
+pub fn set_symbolizer(symbolizer: Symbolizer) {
+    SYMBOLIZER.store(symbolizer as usize, Ordering::Release);
+}
+
+fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    match SYMBOLIZER.load(Ordering::Acquire) {
+        0 => None,
+        f => {
+            let symbolizer: Symbolizer = unsafe { core::mem::transmute(f) };
+            symbolizer(addr)
+        }
+    }
+}
+
+/// An address in a backtrace, displayed with its region and, if
+/// possible, its symbol.
+pub struct Location(pub usize);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = region(self.0) {
+            write!(f, " {name}+{offset:#x}")?;
+        }
+        if let Some((symbol, offset)) = symbolize(self.0) {
+            write!(f, " <{symbol}+{offset:#x}>")?;
+        }
+        Ok(())
+    }
+}
+
+/// A backtrace through a chain of frames, walked when displayed.
+pub struct Backtrace {
+    pc: Option<usize>,
+    fp: usize,
+}
+
+impl Backtrace {
+    /// Returns a backtrace through the frames starting at the
+    /// given frame pointer.
+    pub fn new(fp: usize) -> Backtrace {
+        Backtrace { pc: None, fp }
+    }
+
+    /// Returns a backtrace starting at the given instruction, in
+    /// the function whose frame pointer is given, as saved in a
+    /// trap frame.
+    pub fn with_pc(pc: usize, fp: usize) -> Backtrace {
+        Backtrace { pc: Some(pc), fp }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        let frames = self.pc.into_iter().chain(frames(self.fp));
+        for (k, addr) in frames.enumerate() {
+            writeln!(f, "{k:>4}: {}", Location(addr))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn regions_match_link_scripts() {
+        let scripts = [
+            ("devices", include_str!("../../devices/src/link.ld")),
+            ("global", include_str!("../../global/src/link.ld")),
+            ("memory", include_str!("../../memory/src/link.ld")),
+            ("monitor", include_str!("../../monitor/src/link.ld")),
+            ("node", include_str!("../../node/src/link.ld")),
+            ("scheduler", include_str!("../../scheduler/src/link.ld")),
+            ("supervisor", include_str!("../../supervisor/src/link.ld")),
+            ("task", include_str!("../../system/src/link.ld")),
+            ("trace", include_str!("../../trace/src/link.ld")),
+            ("task", include_str!("../../vcpu/src/link.ld")),
+            ("task", include_str!("../../vm/src/link.ld")),
+        ];
+        for (name, script) in scripts {
+            let line = script.lines().find(|l| l.trim().starts_with(". = 0x")).unwrap();
+            let hex = line.trim().trim_start_matches(". = 0x").trim_end_matches(';');
+            let base = usize::from_str_radix(hex, 16).unwrap();
+            assert_eq!(region(base), Some((name, 0)), "{name} links at {base:#x}");
+        }
+        assert_eq!(region(0x1000), None);
+    }
+
+    #[test]
+    fn walk_frames() {
+        // Three frames, the outermost of which has a null frame
+        // pointer, as set up at entry.
+        let memory = BTreeMap::from([
+            (0x1000, 0x1040),
+            (0x1008, 0xFFFF_FB40_0000_1234),
+            (0x1040, 0x1100),
+            (0x1048, 0xFFFF_FC00_0000_0042),
+            (0x1100, 0),
+            (0x1108, 0xFFFF_F000_0000_0010),
+        ]);
+        let read = |va| memory.get(&va).copied();
+        let frames: Vec<usize> = Frames::new(0x1000, read).collect();
+        assert_eq!(frames, [0xFFFF_FB40_0000_1234, 0xFFFF_FC00_0000_0042, 0xFFFF_F000_0000_0010]);
+        assert_eq!(Frames::new(0x1004, read).count(), 0);
+        assert_eq!(Frames::new(0x2000, read).count(), 0);
+    }
+
+    #[test]
+    fn walk_stops_on_loops() {
+        let memory = BTreeMap::from([(0x1000, 0x1000), (0x1008, 0x5000)]);
+        assert_eq!(Frames::new(0x1000, |va| memory.get(&va).copied()).count(), 1);
+        let deep = |va: usize| Some(if va % 16 == 0 { va + 16 } else { 0x5000 });
+        assert_eq!(Frames::new(0x1000, deep).count(), MAX_DEPTH);
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(Location(0xFFFF_FB40_0000_1234).to_string(), "0xfffffb4000001234 node+0x1234");
+        assert_eq!(Location(0x1000).to_string(), "0x0000000000001000");
+    }
+}
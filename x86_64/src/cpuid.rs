@@ -0,0 +1,389 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # CPU feature discovery
+//!
+//! Decodes the CPUID leaves we care about into a typed set of
+//! features, and the topology leaves into the layout of the
+//! x2APIC ID.  The decoders take the CPUID instruction as a
+//! function, so that they may be exercised with canned register
+//! values.
+//!
+//! Hypatia cannot run at all without some features, and theon
+//! checks for those with `require` before going further.
+
+use crate::vmx;
+use bitflags::bitflags;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The registers returned by CPUID for some leaf and subleaf.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Executes CPUID for the given leaf and subleaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> Registers {
+    let r = core::arch::x86_64::__cpuid_count(leaf, subleaf);
+    Registers { eax: r.eax, ebx: r.ebx, ecx: r.ecx, edx: r.edx }
+}
+
+bitflags! {
+    /// CPU features relevant to Hypatia.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Features: u64 {
+        const TSC = 1 << 0;
+        const MSR = 1 << 1;
+        const APIC = 1 << 2;
+        const MTRR = 1 << 3;
+        const PGE = 1 << 4;
+        const MCE = 1 << 5;
+        const MCA = 1 << 6;
+        const PAT = 1 << 7;
+        const VMX = 1 << 8;
+        const PCID = 1 << 9;
+        const X2APIC = 1 << 10;
+        const TSC_DEADLINE = 1 << 11;
+        const XSAVE = 1 << 12;
+        const OSXSAVE = 1 << 13;
+        const HYPERVISOR = 1 << 14;
+        const FSGSBASE = 1 << 15;
+        const SMEP = 1 << 16;
+        const INVPCID = 1 << 17;
+        const SMAP = 1 << 18;
+        const LA57 = 1 << 19;
+        const MD_CLEAR = 1 << 20;
+        const L1D_FLUSH = 1 << 21;
+        const ARCH_CAPABILITIES = 1 << 22;
+        const NX = 1 << 23;
+        const PAGE_1G = 1 << 24;
+        const RDTSCP = 1 << 25;
+        const LONG_MODE = 1 << 26;
+        const INVARIANT_TSC = 1 << 27;
+        const EPT = 1 << 28;
+    }
+}
+
+// Each feature, with the leaf, register and bit that reports it.
+#[derive(Clone, Copy)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+const FEATURE_BITS: &[(Features, u32, Reg, u32)] = &[
+    (Features::TSC, 0x1, Reg::Edx, 4),
+    (Features::MSR, 0x1, Reg::Edx, 5),
+    (Features::MCE, 0x1, Reg::Edx, 7),
+    (Features::APIC, 0x1, Reg::Edx, 9),
+    (Features::MTRR, 0x1, Reg::Edx, 12),
+    (Features::PGE, 0x1, Reg::Edx, 13),
+    (Features::MCA, 0x1, Reg::Edx, 14),
+    (Features::PAT, 0x1, Reg::Edx, 16),
+    (Features::VMX, 0x1, Reg::Ecx, 5),
+    (Features::PCID, 0x1, Reg::Ecx, 17),
+    (Features::X2APIC, 0x1, Reg::Ecx, 21),
+    (Features::TSC_DEADLINE, 0x1, Reg::Ecx, 24),
+    (Features::XSAVE, 0x1, Reg::Ecx, 26),
+    (Features::OSXSAVE, 0x1, Reg::Ecx, 27),
+    (Features::HYPERVISOR, 0x1, Reg::Ecx, 31),
+    (Features::FSGSBASE, 0x7, Reg::Ebx, 0),
+    (Features::SMEP, 0x7, Reg::Ebx, 7),
+    (Features::INVPCID, 0x7, Reg::Ebx, 10),
+    (Features::SMAP, 0x7, Reg::Ebx, 20),
+    (Features::LA57, 0x7, Reg::Ecx, 16),
+    (Features::MD_CLEAR, 0x7, Reg::Edx, 10),
+    (Features::L1D_FLUSH, 0x7, Reg::Edx, 28),
+    (Features::ARCH_CAPABILITIES, 0x7, Reg::Edx, 29),
+    (Features::NX, 0x8000_0001, Reg::Edx, 20),
+    (Features::PAGE_1G, 0x8000_0001, Reg::Edx, 26),
+    (Features::RDTSCP, 0x8000_0001, Reg::Edx, 27),
+    (Features::LONG_MODE, 0x8000_0001, Reg::Edx, 29),
+    (Features::INVARIANT_TSC, 0x8000_0007, Reg::Edx, 8),
+];
+
+impl Features {
+    /// The features without which Hypatia cannot run.
+    pub const REQUIRED: Features = Features::TSC
+        .union(Features::MSR)
+        .union(Features::APIC)
+        .union(Features::VMX)
+        .union(Features::EPT)
+        .union(Features::NX)
+        .union(Features::PAGE_1G)
+        .union(Features::RDTSCP)
+        .union(Features::LONG_MODE);
+
+    /// Decodes the features reported through the given CPUID
+    /// function.  EPT support is reported through the VMX
+    /// capability MSRs rather than CPUID, so is never set here.
+    pub fn decode<F: Fn(u32, u32) -> Registers>(cpuid: F) -> Features {
+        let max_basic = cpuid(0, 0).eax;
+        let max_extended = cpuid(0x8000_0000, 0).eax;
+        let mut features = Features::empty();
+        for &(feature, leaf, reg, bit) in FEATURE_BITS {
+            let max = if leaf & 0x8000_0000 != 0 { max_extended } else { max_basic };
+            if leaf > max {
+                continue;
+            }
+            let regs = cpuid(leaf, 0);
+            let value = match reg {
+                Reg::Ebx => regs.ebx,
+                Reg::Ecx => regs.ecx,
+                Reg::Edx => regs.edx,
+            };
+            if value & (1 << bit) != 0 {
+                features |= feature;
+            }
+        }
+        features
+    }
+}
+
+// The features of this CPU, or zero if not yet decoded.  The
+// top bit is set once they are, since the set may be empty.
+static FEATURES: AtomicU64 = AtomicU64::new(0);
+const DECODED: u64 = 1 << 63;
+
+/// Returns the features of the current CPU.  We assume that
+/// every CPU in the system has the same features.
+pub fn features() -> Features {
+    let bits = FEATURES.load(Ordering::Relaxed);
+    if bits & DECODED != 0 {
+        return Features::from_bits_truncate(bits);
+    }
+    let mut features = Features::decode(cpuid);
+    if features.contains(Features::VMX) && vmx::Capabilities::read().has_ept() {
+        features |= Features::EPT;
+    }
+    FEATURES.store(features.bits() | DECODED, Ordering::Relaxed);
+    features
+}
+
+/// Returns true iff the current CPU has the given features.
+pub fn has(features: Features) -> bool {
+    self::features().contains(features)
+}
+
+/// Checks that the current CPU has every given feature,
+/// returning those it lacks if not.
+pub fn require(required: Features) -> Result<(), Features> {
+    let missing = required.difference(features());
+    if missing.is_empty() { Ok(()) } else { Err(missing) }
+}
+
+/// The kinds of level in the processor topology.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LevelType {
+    Thread,
+    Core,
+    Module,
+    Tile,
+    Die,
+    Other(u8),
+}
+
+impl From<u8> for LevelType {
+    fn from(raw: u8) -> LevelType {
+        match raw {
+            1 => LevelType::Thread,
+            2 => LevelType::Core,
+            3 => LevelType::Module,
+            4 => LevelType::Tile,
+            5 => LevelType::Die,
+            o => LevelType::Other(o),
+        }
+    }
+}
+
+/// A level of the processor topology: the number of low-order
+/// bits of the x2APIC ID that identify a logical processor within
+/// the next level up, and the number of logical processors at
+/// this level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level {
+    pub kind: LevelType,
+    pub shift: u8,
+    pub count: u16,
+}
+
+/// The most topology levels we record.
+pub const MAX_LEVELS: usize = 6;
+
+/// The processor topology, as reported by the extended topology
+/// leaf 0x1F, or else 0xB, from the innermost level outward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Topology {
+    levels: [Option<Level>; MAX_LEVELS],
+    x2apic_id: u32,
+}
+
+impl Topology {
+    /// Decodes the topology reported through the given CPUID
+    /// function, if the CPU reports one.
+    pub fn decode<F: Fn(u32, u32) -> Registers>(cpuid: F) -> Option<Topology> {
+        let max_basic = cpuid(0, 0).eax;
+        let leaf =
+            [0x1F, 0xB].into_iter().find(|&leaf| leaf <= max_basic && cpuid(leaf, 0).ebx != 0)?;
+        let mut levels = [None; MAX_LEVELS];
+        let mut x2apic_id = 0;
+        for (subleaf, level) in levels.iter_mut().enumerate() {
+            let regs = cpuid(leaf, subleaf as u32);
+            let kind = ((regs.ecx >> 8) & 0xFF) as u8;
+            if kind == 0 {
+                break;
+            }
+            x2apic_id = regs.edx;
+            *level = Some(Level {
+                kind: LevelType::from(kind),
+                shift: (regs.eax & 0x1F) as u8,
+                count: regs.ebx as u16,
+            });
+        }
+        Some(Topology { levels, x2apic_id })
+    }
+
+    /// Returns the levels of the topology, innermost first.
+    pub fn levels(&self) -> impl Iterator<Item = Level> + '_ {
+        self.levels.iter().map_while(|&level| level)
+    }
+
+    /// Returns the x2APIC ID of the CPU that was queried.
+    pub fn x2apic_id(&self) -> u32 {
+        self.x2apic_id
+    }
+
+    /// Returns the number of x2APIC ID bits that identify a
+    /// logical processor within a package.
+    pub fn package_shift(&self) -> u8 {
+        self.levels().last().map_or(0, |level| level.shift)
+    }
+
+    /// Returns the number of x2APIC ID bits that identify a
+    /// thread within a core.
+    pub fn thread_shift(&self) -> u8 {
+        self.levels().find(|level| level.kind == LevelType::Thread).map_or(0, |level| level.shift)
+    }
+
+    /// Splits an x2APIC ID into its package, core and thread
+    /// numbers, where the core is numbered within the package.
+    pub fn split(&self, x2apic_id: u32) -> (u32, u32, u32) {
+        let (thread_shift, package_shift) = (self.thread_shift(), self.package_shift());
+        let thread = x2apic_id & ((1 << thread_shift) - 1);
+        let core = (x2apic_id & ((1 << package_shift) - 1)) >> thread_shift;
+        (x2apic_id >> package_shift, core, thread)
+    }
+}
+
+/// Returns the topology of the current CPU, if it reports one.
+pub fn topology() -> Option<Topology> {
+    Topology::decode(cpuid)
+}
+
+/// Returns a CPU responding with canned values, and zeros for
+/// any leaf or subleaf it was not given, for testing decoders.
+#[cfg(test)]
+pub(crate) fn canned(leaves: &[((u32, u32), [u32; 4])]) -> impl Fn(u32, u32) -> Registers {
+    use std::collections::BTreeMap;
+    let leaves: BTreeMap<(u32, u32), Registers> = leaves
+        .iter()
+        .map(|&(k, [eax, ebx, ecx, edx])| (k, Registers { eax, ebx, ecx, edx }))
+        .collect();
+    move |leaf, subleaf| leaves.get(&(leaf, subleaf)).copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features() {
+        let cpu = canned(&[
+            ((0, 0), [0x1F, 0, 0, 0]),
+            ((0x1, 0), [0, 0, 0x8420_0020, 0x0000_2230]),
+            ((0x7, 0), [0, 0x0000_0401, 0x0001_0000, 0x3000_0400]),
+            ((0x8000_0000, 0), [0x8000_0008, 0, 0, 0]),
+            ((0x8000_0001, 0), [0, 0, 0, 0x2c10_0000]),
+            ((0x8000_0007, 0), [0, 0, 0, 0x0000_0100]),
+        ]);
+        let features = Features::decode(cpu);
+        let expected = Features::TSC
+            | Features::MSR
+            | Features::APIC
+            | Features::PGE
+            | Features::VMX
+            | Features::X2APIC
+            | Features::XSAVE
+            | Features::HYPERVISOR
+            | Features::FSGSBASE
+            | Features::INVPCID
+            | Features::LA57
+            | Features::MD_CLEAR
+            | Features::L1D_FLUSH
+            | Features::ARCH_CAPABILITIES
+            | Features::NX
+            | Features::PAGE_1G
+            | Features::RDTSCP
+            | Features::LONG_MODE
+            | Features::INVARIANT_TSC;
+        assert_eq!(features, expected);
+        let missing = Features::REQUIRED.difference(features);
+        assert_eq!(missing, Features::EPT);
+    }
+
+    #[test]
+    fn unsupported_leaves() {
+        // Leaves beyond the maximum may return junk, which must
+        // be ignored.
+        let cpu = canned(&[
+            ((0, 0), [0x1, 0, 0, 0]),
+            ((0x1, 0), [0, 0, 0, 0x0000_0010]),
+            ((0x7, 0), [0, !0, !0, !0]),
+            ((0x8000_0000, 0), [0x8000_0001, 0, 0, 0]),
+            ((0x8000_0007, 0), [0, 0, 0, !0]),
+        ]);
+        assert_eq!(Features::decode(cpu), Features::TSC);
+    }
+
+    #[test]
+    fn topology() {
+        // Two threads per core, eight cores per die, and two dies
+        // per package, as seen from x2APIC ID 0x25.
+        let cpu = canned(&[
+            ((0, 0), [0x1F, 0, 0, 0]),
+            ((0x1F, 0), [1, 2, 0x0100, 0x25]),
+            ((0x1F, 1), [4, 16, 0x0201, 0x25]),
+            ((0x1F, 2), [5, 32, 0x0502, 0x25]),
+            ((0x1F, 3), [0, 0, 0x0003, 0x25]),
+        ]);
+        let topology = Topology::decode(cpu).unwrap();
+        let kinds: Vec<LevelType> = topology.levels().map(|level| level.kind).collect();
+        assert_eq!(kinds, [LevelType::Thread, LevelType::Core, LevelType::Die]);
+        assert_eq!(topology.x2apic_id(), 0x25);
+        assert_eq!(topology.thread_shift(), 1);
+        assert_eq!(topology.package_shift(), 5);
+        assert_eq!(topology.split(0x25), (1, 2, 1));
+    }
+
+    #[test]
+    fn legacy_topology() {
+        // Leaf 0x1F is in range but empty, so 0xB is used.
+        let cpu = canned(&[
+            ((0, 0), [0x1F, 0, 0, 0]),
+            ((0xB, 0), [1, 2, 0x0100, 0x3]),
+            ((0xB, 1), [3, 8, 0x0201, 0x3]),
+        ]);
+        let topology = Topology::decode(cpu).unwrap();
+        assert_eq!(topology.levels().count(), 2);
+        assert_eq!(topology.split(0x3), (0, 1, 1));
+        assert_eq!(Topology::decode(canned(&[((0, 0), [0xA, 0, 0, 0])])), None);
+    }
+}
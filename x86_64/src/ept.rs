@@ -0,0 +1,1044 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Intel Extended Page Tables
+//!
+//! The EPT is the second-level page table (L2PT) that describes
+//! a VMX guest's physical address space in terms of the host's.
+//!
+//! The MMU cannot walk the EPT format, so we cannot reach it by
+//! recursion as we do the host tables.  Instead, as described in
+//! HDP 0015, the EPT paging structures are mapped as ordinary
+//! data into the "Nested Page Table" slot of the host root table,
+//! at exactly the addresses where they would appear if that slot
+//! were a recursive entry pointing to the EPT root.  The tables
+//! at any given level are thus adjacent, and indexed by GPA, just
+//! as in the recursive region.
+//!
+//! The invariant maintained by this module is that every table
+//! referred to by a present, non-leaf EPT entry is mapped at its
+//! mirror address.  Tables may remain mapped after the entries
+//! referring to them have been replaced by big pages; this is
+//! what lets us splinter those pages again later.
+//!
+//! Mutators that invalidate cached guest-physical translations
+//! do so with single-context INVEPT on the local CPU only.  An
+//! EPT may be in use on several CPUs at once, and the caller is
+//! responsible for invalidating it on every other CPU where its
+//! EPTP is active before relying on the change.
+
+use crate::vm::{self, Mmu, PTE, PTEFlags, Result};
+use crate::{GPA, HPA, MemoryType, PF4K, Page, V1GA, V2MA, V4KA, V512GA, VPageAddr};
+use bitflags::bitflags;
+use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+bitflags! {
+    #[derive(Clone, Copy, Debug)]
+    pub struct EPTEFlags: u64 {
+        const READ        = 1;
+        const WRITE       = 1 << 1;
+        const EXEC        = 1 << 2;
+        const IGNORE_PAT  = 1 << 6;
+        const HUGE        = 1 << 7;
+        const ACCESS      = 1 << 8;
+        const DIRTY       = 1 << 9;
+        const UEXEC       = 1 << 10;
+        const SUPPRESS_VE = 1 << 63;
+    }
+}
+
+/// EPT entries are 64-bit integers that may be accessed
+/// concurrently by the processor, so like host PTEs, we
+/// define them in terms of atomics.
+#[repr(transparent)]
+pub struct EPTE(AtomicU64);
+
+impl EPTE {
+    const PFA_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+    const MEMORY_TYPE_SHIFT: u64 = 3;
+    const MEMORY_TYPE_MASK: u64 = 0b111 << Self::MEMORY_TYPE_SHIFT;
+    const PERM_MASK: u64 =
+        EPTEFlags::READ.bits() | EPTEFlags::WRITE.bits() | EPTEFlags::EXEC.bits();
+
+    /// Creates a new EPTE referring to a paging structure at the
+    /// given HPA.
+    pub fn new(hpa: HPA, flags: EPTEFlags) -> EPTE {
+        let address = hpa.addr() & Self::PFA_MASK;
+        assert_eq!(hpa.addr(), address);
+        EPTE(AtomicU64::new(address | flags.bits()))
+    }
+
+    /// Creates a new EPTE mapping a page at the given HPA with
+    /// the given flags and memory type.
+    pub fn new_page(hpa: HPA, flags: EPTEFlags, memory_type: MemoryType) -> EPTE {
+        let epte = EPTE::new(hpa, flags);
+        let memory_type = u64::from(memory_type as u8) << Self::MEMORY_TYPE_SHIFT;
+        epte.0.fetch_or(memory_type, Ordering::Relaxed);
+        epte
+    }
+
+    /// Creates an empty (zero) EPTE.
+    pub const fn empty() -> EPTE {
+        EPTE(AtomicU64::new(0))
+    }
+
+    /// Zeroes out the EPTE.
+    pub fn clear(&self) {
+        self.0.store(0, Ordering::Relaxed)
+    }
+
+    /// Atomically clears the given flags in the EPTE, returning
+    /// true iff any of them were set.
+    pub fn test_and_clear(&self, flags: EPTEFlags) -> bool {
+        self.0.fetch_and(!flags.bits(), Ordering::AcqRel) & flags.bits() != 0
+    }
+
+    /// Assign self the value of the given EPTE.
+    pub fn assign(&self, epte: EPTE) {
+        self.0.store(epte.0.into_inner(), Ordering::Relaxed);
+    }
+
+    /// Returns the physical frame address associated with the EPTE.
+    pub fn pfa(&self) -> HPA {
+        HPA::new(self.0.load(Ordering::Relaxed) & Self::PFA_MASK)
+    }
+
+    /// Extracts and returns the flags attached to this EPTE.
+    pub fn flags(&self) -> EPTEFlags {
+        EPTEFlags::from_bits_truncate(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Returns the memory type of a leaf EPTE.  The field is
+    /// ignored in entries referring to paging structures.
+    pub fn memory_type(&self) -> Result<MemoryType> {
+        let raw =
+            (self.0.load(Ordering::Relaxed) & Self::MEMORY_TYPE_MASK) >> Self::MEMORY_TYPE_SHIFT;
+        MemoryType::try_from(raw as u8)
+    }
+
+    /// Returns true iff the EPTE grants any access.  Unlike the
+    /// host's page tables, the EPT has no "present" bit: an entry
+    /// is present if any of its permission bits are set.
+    pub fn is_present(&self) -> bool {
+        self.0.load(Ordering::Relaxed) & Self::PERM_MASK != 0
+    }
+
+    /// Returns true iff the bit marking this either a huge or large page is set.
+    pub fn is_big(&self) -> bool {
+        self.flags().contains(EPTEFlags::HUGE)
+    }
+
+    /// Returns true iff the entry is zero.
+    pub fn is_zero(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl Clone for EPTE {
+    fn clone(&self) -> EPTE {
+        EPTE(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl core::fmt::Debug for EPTE {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flags = self.flags();
+        let flag_or = |f: EPTEFlags, a, b| {
+            if flags.contains(f) { a } else { b }
+        };
+        f.write_fmt(format_args!("{:#x?}:", self.pfa().addr()))?;
+        f.write_str(flag_or(EPTEFlags::HUGE, "H", "-"))?;
+        f.write_str(flag_or(EPTEFlags::IGNORE_PAT, "I", "-"))?;
+        f.write_str(flag_or(EPTEFlags::DIRTY, "D", "-"))?;
+        f.write_str(flag_or(EPTEFlags::ACCESS, "A", "-"))?;
+        f.write_str(flag_or(EPTEFlags::EXEC, "X", "-"))?;
+        f.write_str(flag_or(EPTEFlags::WRITE, "W", "-"))?;
+        f.write_str(flag_or(EPTEFlags::READ, "R", "-"))
+    }
+}
+
+/// The index of the host root table slot where the EPT is mirrored.
+pub const MIRROR_INDEX: usize = 510;
+
+/// The bound on guest physical addresses: those must be
+/// representable as host virtual addresses in the lower half of
+/// the address space.  This also keeps the mirrored tables from
+/// colliding with one another, as they would in a recursive table
+/// for addresses in the mirror slot itself.
+pub const MAX_GPA: u64 = 1 << 47;
+
+/// The levels of the EPT, each of which has an array of entries
+/// in the mirror region.  The base addresses are those that each
+/// level would have if the mirror slot were recursive.
+trait Level {
+    const BASE_ADDRESS: usize;
+    const PAGE_SHIFT: usize;
+
+    fn index(gpa: GPA) -> usize {
+        gpa.addr() as usize >> Self::PAGE_SHIFT
+    }
+
+    fn entry_address(gpa: GPA) -> usize {
+        Self::BASE_ADDRESS + Self::index(gpa) * core::mem::size_of::<EPTE>()
+    }
+
+    /// Returns the address of the page in the mirror region
+    /// holding the table that contains the entry for the GPA.
+    fn table_address(gpa: GPA) -> V4KA {
+        V4KA::new_round_down(Self::entry_address(gpa))
+    }
+
+    fn epte_ref<M: Mmu>(mmu: &M, gpa: GPA) -> &EPTE {
+        epte(mmu, Self::entry_address(gpa))
+    }
+}
+
+enum Level4 {}
+enum Level3 {}
+enum Level2 {}
+enum Level1 {}
+
+impl Level for Level4 {
+    const BASE_ADDRESS: usize = 0xFFFF_FF7F_BFDF_E000;
+    const PAGE_SHIFT: usize = 39;
+}
+
+impl Level for Level3 {
+    const BASE_ADDRESS: usize = 0xFFFF_FF7F_BFC0_0000;
+    const PAGE_SHIFT: usize = 30;
+}
+
+impl Level for Level2 {
+    const BASE_ADDRESS: usize = 0xFFFF_FF7F_8000_0000;
+    const PAGE_SHIFT: usize = 21;
+}
+
+impl Level for Level1 {
+    const BASE_ADDRESS: usize = 0xFFFF_FF00_0000_0000;
+    const PAGE_SHIFT: usize = 12;
+}
+
+// Returns a reference to the EPT entry mirrored at the given
+// host virtual address.
+fn epte<M: Mmu>(mmu: &M, va: usize) -> &EPTE {
+    let pte: *const PTE = mmu.pte(va);
+    // PTE and EPTE are both transparent wrappers around AtomicU64,
+    // and the mirror maps EPT tables as data, so this reference is
+    // valid for as long as the one returned by the MMU.
+    unsafe { &*pte.cast::<EPTE>() }
+}
+
+// Host page flags for mapping EPT tables into the mirror.
+fn mirror_flags() -> PTEFlags {
+    PTEFlags::PRESENT | PTEFlags::WRITE | PTEFlags::NX
+}
+
+// Maps a newly allocated table for the next level down at its
+// mirror address, and refers to it from the entry at this level,
+// unless there is already such a table.  Tables are allocated
+// from `tables`, which is given the allocator for host tables.
+fn make_level<L, N, M, T, A>(mmu: &M, gpa: GPA, tables: &mut T, allocator: &mut A) -> Result<()>
+where
+    L: Level,
+    N: Level,
+    M: Mmu,
+    T: FnMut(&mut A) -> Result<PF4K>,
+    A: FnMut() -> Result<PF4K>,
+{
+    let entry = L::epte_ref(mmu, gpa);
+    if entry.is_present() {
+        return if entry.is_big() { Err("mapped by a big page") } else { Ok(()) };
+    }
+    let table = tables(allocator)?;
+    vm::map_in(mmu, table, mirror_flags(), N::table_address(gpa), allocator)?;
+    let inner = EPTEFlags::READ | EPTEFlags::WRITE | EPTEFlags::EXEC;
+    entry.assign(EPTE::new(table.pfa(), inner));
+    Ok(())
+}
+
+/// Maps the root table of an EPT at its mirror address in the
+/// current address space.
+pub fn mount<A>(root: PF4K, allocator: &mut A) -> Result<()>
+where
+    A: FnMut() -> Result<PF4K>,
+{
+    mount_in(&vm::HardMmu, root, allocator)
+}
+
+/// Maps the root table of an EPT at its mirror address in the
+/// address space viewed through the given MMU.  It is assumed
+/// that the allocator returns zeroed pages.
+pub fn mount_in<M, A>(mmu: &M, root: PF4K, allocator: &mut A) -> Result<()>
+where
+    M: Mmu,
+    A: FnMut() -> Result<PF4K>,
+{
+    vm::map_in(mmu, root, mirror_flags(), Level4::table_address(GPA::new(0)), allocator)
+}
+
+/// Returns the EPT pointer, suitable for the VMCS, for the EPT
+/// with the given root.  The paging structures themselves are
+/// always write-back cacheable, and are walked in four levels.
+pub fn eptp(root: PF4K, accessed_dirty: bool) -> u64 {
+    const WALK_LENGTH_SHIFT: u64 = 3;
+    const ACCESSED_DIRTY: u64 = 1 << 6;
+    let walk_length = (4 - 1) << WALK_LENGTH_SHIFT;
+    let ad = if accessed_dirty { ACCESSED_DIRTY } else { 0 };
+    root.pfa().addr() | walk_length | ad | MemoryType::WriteBack as u64
+}
+
+/// Translates the given GPA to a host physical address via the
+/// EPT mounted in the current address space.
+pub fn translate(gpa: GPA) -> Option<HPA> {
+    translate_in(&vm::HardMmu, gpa)
+}
+
+/// Translates the given GPA to a host physical address via the
+/// EPT mounted in the address space viewed through the MMU.
+pub fn translate_in<M: Mmu>(mmu: &M, gpa: GPA) -> Option<HPA> {
+    const MASK_1G: usize = <V1GA as VPageAddr>::PageType::MASK;
+    const MASK_2M: usize = <V2MA as VPageAddr>::PageType::MASK;
+    const MASK_4K: usize = <V4KA as VPageAddr>::PageType::MASK;
+    if gpa.addr() >= MAX_GPA || !Level4::epte_ref(mmu, gpa).is_present() {
+        return None;
+    }
+    let offset = gpa.addr() as usize;
+    let e3 = Level3::epte_ref(mmu, gpa);
+    if !e3.is_present() {
+        return None;
+    }
+    if e3.is_big() {
+        return Some(e3.pfa().offset(offset & MASK_1G));
+    }
+    let e2 = Level2::epte_ref(mmu, gpa);
+    if !e2.is_present() {
+        return None;
+    }
+    if e2.is_big() {
+        return Some(e2.pfa().offset(offset & MASK_2M));
+    }
+    let e1 = Level1::epte_ref(mmu, gpa);
+    e1.is_present().then(|| e1.pfa().offset(offset & MASK_4K))
+}
+
+/// Maps the given PF4K at the given GPA in the EPT mounted in the
+/// current address space.
+pub fn map<A>(
+    hpf: PF4K,
+    gpa: GPA,
+    flags: EPTEFlags,
+    memory_type: MemoryType,
+    allocator: &mut A,
+) -> Result<()>
+where
+    A: FnMut() -> Result<PF4K>,
+{
+    map_in(&vm::HardMmu, hpf, gpa, flags, memory_type, allocator)
+}
+
+/// Maps the given PF4K at the given GPA in the EPT mounted in the
+/// address space viewed through the given MMU, creating and
+/// mirroring any intermediate tables.  The allocator is used for
+/// both EPT tables and the host tables mapping the mirror, and it
+/// is assumed that it returns zeroed pages.
+pub fn map_in<M, A>(
+    mmu: &M,
+    hpf: PF4K,
+    gpa: GPA,
+    flags: EPTEFlags,
+    memory_type: MemoryType,
+    allocator: &mut A,
+) -> Result<()>
+where
+    M: Mmu,
+    A: FnMut() -> Result<PF4K>,
+{
+    map_tables_in(
+        mmu,
+        hpf,
+        gpa,
+        flags,
+        memory_type,
+        &mut |allocator: &mut A| allocator(),
+        allocator,
+    )
+}
+
+/// Maps the given PF4K at the given GPA as `map_in` does, but
+/// allocates EPT tables from `tables` rather than the allocator
+/// used for host tables.
+pub(crate) fn map_tables_in<M, T, A>(
+    mmu: &M,
+    hpf: PF4K,
+    gpa: GPA,
+    flags: EPTEFlags,
+    memory_type: MemoryType,
+    tables: &mut T,
+    allocator: &mut A,
+) -> Result<()>
+where
+    M: Mmu,
+    T: FnMut(&mut A) -> Result<PF4K>,
+    A: FnMut() -> Result<PF4K>,
+{
+    const MASK_4K: usize = <V4KA as VPageAddr>::PageType::MASK;
+    assert_eq!(gpa.addr() as usize & MASK_4K, 0, "unaligned GPA");
+    assert!(gpa.addr() < MAX_GPA, "GPA out of range");
+    assert!(!flags.contains(EPTEFlags::HUGE), "use splinter and recombine for big pages");
+    make_level::<Level4, Level3, _, _, _>(mmu, gpa, tables, allocator)?;
+    make_level::<Level3, Level2, _, _, _>(mmu, gpa, tables, allocator)?;
+    make_level::<Level2, Level1, _, _, _>(mmu, gpa, tables, allocator)?;
+    let entry = Level1::epte_ref(mmu, gpa);
+    if entry.is_present() {
+        return Err("Already mapped");
+    }
+    entry.assign(EPTE::new_page(hpf.pfa(), flags, memory_type));
+    Ok(())
+}
+
+// Returns the number of tables that mapping a 4KiB page at the
+// given GPA would create.
+pub(crate) fn missing_tables<M: Mmu>(mmu: &M, gpa: GPA) -> Result<usize> {
+    let levels = [Level4::epte_ref as fn(&M, GPA) -> &EPTE, Level3::epte_ref, Level2::epte_ref];
+    for (k, epte_ref) in levels.iter().enumerate() {
+        let entry = epte_ref(mmu, gpa);
+        if !entry.is_present() {
+            return Ok(levels.len() - k);
+        }
+        if entry.is_big() {
+            return Err("mapped by a big page");
+        }
+    }
+    Ok(0)
+}
+
+// Removes the last `count` tables on the walk to the given GPA,
+// as made by a `map_tables_in` that then failed, unmapping them
+// from the mirror.  Tables it did not get as far as making are
+// skipped.  The tables must map nothing, and are not freed.
+pub(crate) fn unmake_tables_in<M: Mmu>(mmu: &M, gpa: GPA, count: usize) {
+    let entries = [Level4::epte_ref as fn(&M, GPA) -> &EPTE, Level3::epte_ref, Level2::epte_ref];
+    let tables =
+        [Level3::table_address as fn(GPA) -> V4KA, Level2::table_address, Level1::table_address];
+    let first = entries.len() - count;
+    let made = (first..entries.len()).take_while(|&k| entries[k](mmu, gpa).is_present()).count();
+    for k in (first..first + made).rev() {
+        vm::unmap_in(mmu, tables[k](gpa));
+        entries[k](mmu, gpa).clear();
+    }
+}
+
+/// Unmaps the 4KiB page at the given GPA in the EPT mounted in
+/// the current address space.
+pub fn unmap(gpa: GPA) -> Result<()> {
+    unmap_in(&vm::HardMmu, gpa)
+}
+
+/// Unmaps the 4KiB page at the given GPA in the EPT mounted in
+/// the address space viewed through the given MMU.  Only the leaf
+/// entry is cleared; tables are retained.  Note that the caller
+/// is responsible for invalidating any cached guest-physical
+/// translations, with `invept` on every CPU where the EPT is
+/// active.
+pub fn unmap_in<M: Mmu>(mmu: &M, gpa: GPA) -> Result<()> {
+    if gpa.addr() >= MAX_GPA || !Level4::epte_ref(mmu, gpa).is_present() {
+        return Ok(());
+    }
+    for entry in [Level3::epte_ref(mmu, gpa), Level2::epte_ref(mmu, gpa)] {
+        if !entry.is_present() {
+            return Ok(());
+        }
+        if entry.is_big() {
+            return Err("mapped by a big page");
+        }
+    }
+    Level1::epte_ref(mmu, gpa).clear();
+    Ok(())
+}
+
+/// Collects and clears the dirty bits for the given range of
+/// guest physical memory, in the EPT with the given pointer
+/// mounted in the current address space.
+pub fn harvest_dirty(eptp: u64, range: Range<GPA>, bitmap: &mut [u64]) -> Result<usize> {
+    harvest_in(&vm::HardMmu, eptp, range, EPTEFlags::DIRTY, bitmap)
+}
+
+/// Collects and clears the accessed bits for the given range of
+/// guest physical memory, in the EPT with the given pointer
+/// mounted in the current address space.
+pub fn harvest_accessed(eptp: u64, range: Range<GPA>, bitmap: &mut [u64]) -> Result<usize> {
+    harvest_in(&vm::HardMmu, eptp, range, EPTEFlags::ACCESS, bitmap)
+}
+
+/// Collects and clears the dirty bits for the given range of
+/// guest physical memory, in the EPT mounted in the address space
+/// viewed through the given MMU.  Bit `k` of the bitmap is set iff
+/// the `k`th page of the range was dirty, and the number of dirty
+/// pages is returned.  As with the host, leaf entries are scanned
+/// linearly through the mirror, and big pages are treated as a
+/// unit.  Accessed and dirty flags must be enabled in the EPTP.
+/// If anything was harvested, the EPT is invalidated on the local
+/// CPU; the caller must invalidate it on any other CPU where it is
+/// active, or bits set there may be lost.
+pub fn harvest_dirty_in<M: Mmu>(
+    mmu: &M,
+    eptp: u64,
+    range: Range<GPA>,
+    bitmap: &mut [u64],
+) -> Result<usize> {
+    harvest_in(mmu, eptp, range, EPTEFlags::DIRTY, bitmap)
+}
+
+/// Collects and clears the accessed bits for the given range of
+/// guest physical memory, as `harvest_dirty_in` does for dirty
+/// bits.  The same caveat about other CPUs applies.
+pub fn harvest_accessed_in<M: Mmu>(
+    mmu: &M,
+    eptp: u64,
+    range: Range<GPA>,
+    bitmap: &mut [u64],
+) -> Result<usize> {
+    harvest_in(mmu, eptp, range, EPTEFlags::ACCESS, bitmap)
+}
+
+// Tests and clears the given flag in the leaf entries for every
+// page in the range, recording those where it was set in the
+// bitmap.
+fn harvest_in<M: Mmu>(
+    mmu: &M,
+    eptp: u64,
+    range: Range<GPA>,
+    flag: EPTEFlags,
+    bitmap: &mut [u64],
+) -> Result<usize> {
+    const SIZE_4K: u64 = <V4KA as VPageAddr>::PageType::SIZE as u64;
+    const MASK_2M: u64 = <V2MA as VPageAddr>::PageType::MASK as u64;
+    const MASK_1G: u64 = <V1GA as VPageAddr>::PageType::MASK as u64;
+    let (start, end) = (range.start.addr(), range.end.addr());
+    assert_eq!(start % SIZE_4K, 0, "unaligned GPA");
+    assert!(end <= MAX_GPA, "GPA out of range");
+    let npages = (end.saturating_sub(start) / SIZE_4K) as usize;
+    let bitmap = bitmap.get_mut(..npages.div_ceil(64)).ok_or("bitmap too small")?;
+    bitmap.fill(0);
+    let mut mark = |gpa: u64| {
+        let k = ((gpa - start) / SIZE_4K) as usize;
+        bitmap[k / 64] |= 1 << (k % 64);
+    };
+    let mut count = 0;
+    let mut gpa = start;
+    while gpa < end {
+        let at = GPA::new(gpa);
+        let (big, mask) = if reaches::<Level1, _>(mmu, at) {
+            (None, MASK_2M)
+        } else if reaches::<Level2, _>(mmu, at) && Level2::epte_ref(mmu, at).is_present() {
+            (Some(Level2::epte_ref(mmu, at)), MASK_2M)
+        } else if reaches::<Level3, _>(mmu, at) && Level3::epte_ref(mmu, at).is_present() {
+            (Some(Level3::epte_ref(mmu, at)), MASK_1G)
+        } else {
+            gpa = u64::min((gpa | MASK_2M) + 1, end);
+            continue;
+        };
+        let next = u64::min((gpa | mask) + 1, end);
+        match big {
+            Some(entry) if entry.test_and_clear(flag) => {
+                for page in (gpa..next).step_by(SIZE_4K as usize) {
+                    mark(page);
+                }
+                count += ((next - gpa) / SIZE_4K) as usize;
+            }
+            Some(_) => {}
+            None => {
+                for page in (gpa..next).step_by(SIZE_4K as usize) {
+                    if Level1::epte_ref(mmu, GPA::new(page)).test_and_clear(flag) {
+                        mark(page);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        gpa = next;
+    }
+    if count > 0 {
+        mmu.invept(eptp);
+    }
+    Ok(count)
+}
+
+/// Returns the range of host virtual addresses reserved for
+/// the EPT mirror.
+pub fn mirror_range() -> Range<V4KA> {
+    const SIZE_512G: usize = <V512GA as VPageAddr>::PageType::SIZE;
+    V4KA::new(Level1::BASE_ADDRESS)..V4KA::new(Level1::BASE_ADDRESS + SIZE_512G)
+}
+
+/// Invalidates the guest-physical mappings cached for the EPT
+/// with the given EPT pointer, on the local CPU.  Other CPUs on
+/// which the EPTP is active are unaffected, and must execute this
+/// themselves.
+///
+/// # Safety
+///
+/// The CPU must be in VMX operation.
+pub unsafe fn invept(eptp: u64) {
+    const SINGLE_CONTEXT: u64 = 1;
+    let descriptor: [u64; 2] = [eptp, 0];
+    unsafe {
+        asm!("invept {}, [{}];", in(reg) SINGLE_CONTEXT, in(reg) &descriptor, options(nostack));
+    }
+}
+
+// Returns true iff a walk for the GPA reaches the table at level L,
+// rather than stopping early at a non-present entry or a big page.
+// Tables below a big page remain mirrored, but are stale.
+fn reaches<L: Level, M: Mmu>(mmu: &M, gpa: GPA) -> bool {
+    if gpa.addr() >= MAX_GPA {
+        return false;
+    }
+    let above = [
+        (Level4::PAGE_SHIFT, Level4::epte_ref as fn(&M, GPA) -> &EPTE),
+        (Level3::PAGE_SHIFT, Level3::epte_ref),
+        (Level2::PAGE_SHIFT, Level2::epte_ref),
+    ];
+    above.iter().take_while(|&&(shift, _)| shift > L::PAGE_SHIFT).all(|(_, epte_ref)| {
+        let entry = epte_ref(mmu, gpa);
+        entry.is_present() && !entry.is_big()
+    })
+}
+
+// Replaces the big page mapped by the entry for the GPA at level L
+// with the table for the level below, whose entries are rewritten to
+// map the same frames with the same permissions and memory type.
+// The table is found through its mirror mapping, which we retain
+// for exactly this purpose, so no memory is allocated.
+fn splinter<L, N, M>(mmu: &M, eptp: u64, gpa: GPA, lower_big: bool) -> Result<()>
+where
+    L: Level,
+    N: Level,
+    M: Mmu,
+{
+    const NENTRIES: usize = 512;
+    if !reaches::<L, _>(mmu, gpa) {
+        return Err("not a big page");
+    }
+    let entry = L::epte_ref(mmu, gpa);
+    if !entry.is_present() || !entry.is_big() {
+        return Err("not a big page");
+    }
+    let table =
+        vm::translate_in(mmu, N::table_address(gpa).addr()).ok_or("no table to splinter")?;
+    let memory_type = entry.memory_type()?;
+    let mut flags = entry.flags();
+    flags.set(EPTEFlags::HUGE, lower_big);
+    let step = 1 << N::PAGE_SHIFT;
+    let base = GPA::new(gpa.addr() & !((1 << L::PAGE_SHIFT) - 1));
+    let pfa = entry.pfa();
+    for k in 0..NENTRIES {
+        let lower = N::epte_ref(mmu, base.offset(k * step));
+        lower.assign(EPTE::new_page(pfa.offset(k * step), flags, memory_type));
+    }
+    let inner = EPTEFlags::READ | EPTEFlags::WRITE | EPTEFlags::EXEC;
+    entry.assign(EPTE::new(table, inner));
+    mmu.invept(eptp);
+    Ok(())
+}
+
+// Replaces the entry for the GPA at level L, which must refer to a
+// table, with a big page, provided that the entries in that table
+// map physically contiguous, suitably aligned frames with uniform
+// permissions and memory type.  The table remains mapped in the
+// mirror so that the page may later be splintered.  Accessed and
+// dirty bits are accumulated into the big page.
+fn recombine<L, N, M>(mmu: &M, gpa: GPA, lower_big: bool) -> Result<()>
+where
+    L: Level,
+    N: Level,
+    M: Mmu,
+{
+    const NENTRIES: usize = 512;
+    if !reaches::<L, _>(mmu, gpa) {
+        return Err("not mapped");
+    }
+    let entry = L::epte_ref(mmu, gpa);
+    if !entry.is_present() {
+        return Err("not mapped");
+    }
+    if entry.is_big() {
+        return Ok(());
+    }
+    let size = 1 << L::PAGE_SHIFT;
+    let step = 1 << N::PAGE_SHIFT;
+    let base = GPA::new(gpa.addr() & !(size as u64 - 1));
+    let history = EPTEFlags::ACCESS | EPTEFlags::DIRTY;
+    let first = N::epte_ref(mmu, base);
+    let pfa = first.pfa();
+    let flags = first.flags().difference(history);
+    let memory_type = first.memory_type()?;
+    if pfa.addr() % size as u64 != 0 {
+        return Err("misaligned frames");
+    }
+    let mut accumulated = EPTEFlags::empty();
+    for k in 0..NENTRIES {
+        let lower = N::epte_ref(mmu, base.offset(k * step));
+        let uniform = lower.is_present()
+            && lower.is_big() == lower_big
+            && lower.flags().difference(history).bits() == flags.bits()
+            && lower.memory_type() == Ok(memory_type);
+        if !uniform {
+            return Err("non-uniform mappings");
+        }
+        if lower.pfa().addr() != pfa.offset(k * step).addr() {
+            return Err("discontiguous frames");
+        }
+        accumulated |= lower.flags().intersection(history);
+    }
+    entry.assign(EPTE::new_page(pfa, flags | accumulated | EPTEFlags::HUGE, memory_type));
+    Ok(())
+}
+
+/// Splinters the 2MiB page containing the given GPA into 4KiB
+/// pages, in the EPT with the given pointer mounted in the current
+/// address space.
+pub fn splinter_2m(eptp: u64, gpa: GPA) -> Result<()> {
+    splinter_2m_in(&vm::HardMmu, eptp, gpa)
+}
+
+/// Splinters the 2MiB page containing the given GPA into 4KiB
+/// pages, in the EPT mounted in the address space viewed through
+/// the given MMU.  Never allocates.  The EPT is invalidated on
+/// the local CPU only; the caller must invalidate it on any other
+/// CPU where it is active.
+pub fn splinter_2m_in<M: Mmu>(mmu: &M, eptp: u64, gpa: GPA) -> Result<()> {
+    splinter::<Level2, Level1, _>(mmu, eptp, gpa, false)
+}
+
+/// Splinters the 1GiB page containing the given GPA into 2MiB
+/// pages, in the EPT with the given pointer mounted in the current
+/// address space.
+pub fn splinter_1g(eptp: u64, gpa: GPA) -> Result<()> {
+    splinter_1g_in(&vm::HardMmu, eptp, gpa)
+}
+
+/// Splinters the 1GiB page containing the given GPA into 2MiB
+/// pages, in the EPT mounted in the address space viewed through
+/// the given MMU.  Those may be splintered further with
+/// `splinter_2m_in`.  Never allocates.  As with `splinter_2m_in`,
+/// other CPUs where the EPT is active must be invalidated by the
+/// caller.
+pub fn splinter_1g_in<M: Mmu>(mmu: &M, eptp: u64, gpa: GPA) -> Result<()> {
+    splinter::<Level3, Level2, _>(mmu, eptp, gpa, true)
+}
+
+/// Recombines the 4KiB pages in the 2MiB region containing the
+/// given GPA into a single large page, in the EPT with the given
+/// pointer mounted in the current address space.
+pub fn recombine_2m(eptp: u64, gpa: GPA) -> Result<()> {
+    recombine_2m_in(&vm::HardMmu, eptp, gpa)
+}
+
+/// Recombines the 4KiB pages in the 2MiB region containing the
+/// given GPA into a single large page, in the EPT mounted in the
+/// address space viewed through the given MMU.  The EPT is
+/// invalidated on the local CPU only; the caller must invalidate it
+/// on any other CPU where it is active.
+pub fn recombine_2m_in<M: Mmu>(mmu: &M, eptp: u64, gpa: GPA) -> Result<()> {
+    recombine::<Level2, Level1, _>(mmu, gpa, false)?;
+    mmu.invept(eptp);
+    Ok(())
+}
+
+/// Recombines the 1GiB region containing the given GPA into a
+/// single huge page, in the EPT with the given pointer mounted in
+/// the current address space.
+pub fn recombine_1g(eptp: u64, gpa: GPA) -> Result<()> {
+    recombine_1g_in(&vm::HardMmu, eptp, gpa)
+}
+
+/// Recombines the 1GiB region containing the given GPA into a
+/// single huge page, in the EPT mounted in the address space
+/// viewed through the given MMU.  Any 2MiB regions still mapped
+/// with 4KiB pages are recombined first; if the whole cannot be
+/// recombined, those remain as large pages.  As with
+/// `recombine_2m_in`, other CPUs where the EPT is active must be
+/// invalidated by the caller.
+pub fn recombine_1g_in<M: Mmu>(mmu: &M, eptp: u64, gpa: GPA) -> Result<()> {
+    const NENTRIES: usize = 512;
+    const SIZE_2M: usize = <V2MA as VPageAddr>::PageType::SIZE;
+    const MASK_1G: u64 = <V1GA as VPageAddr>::PageType::MASK as u64;
+    let base = GPA::new(gpa.addr() & !MASK_1G);
+    if !reaches::<Level3, _>(mmu, base) || !Level3::epte_ref(mmu, base).is_present() {
+        return Err("not mapped");
+    }
+    if Level3::epte_ref(mmu, base).is_big() {
+        return Ok(());
+    }
+    let result = (0..NENTRIES)
+        .try_for_each(|k| recombine::<Level2, Level1, _>(mmu, base.offset(k * SIZE_2M), false))
+        .and_then(|()| recombine::<Level3, Level2, _>(mmu, base, true));
+    mmu.invept(eptp);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PageFrame;
+    use crate::vm::soft::SoftMmu;
+
+    fn pf(pa: u64) -> PF4K {
+        PF4K::new(HPA::new(pa))
+    }
+
+    fn pa(hpa: Option<HPA>) -> Option<u64> {
+        hpa.map(HPA::addr)
+    }
+
+    fn rwx() -> EPTEFlags {
+        EPTEFlags::READ | EPTEFlags::WRITE | EPTEFlags::EXEC
+    }
+
+    #[test]
+    fn mirror_bases() {
+        let base = !0usize << 48 | MIRROR_INDEX << 39;
+        assert_eq!(base, Level1::BASE_ADDRESS);
+        let base = base | MIRROR_INDEX << 30;
+        assert_eq!(base, Level2::BASE_ADDRESS);
+        let base = base | MIRROR_INDEX << 21;
+        assert_eq!(base, Level3::BASE_ADDRESS);
+        let base = base | MIRROR_INDEX << 12;
+        assert_eq!(base, Level4::BASE_ADDRESS);
+    }
+
+    #[test]
+    fn epte_bits() {
+        let flags = rwx() | EPTEFlags::IGNORE_PAT;
+        let epte = EPTE::new_page(HPA::new(0xabc000), flags, MemoryType::WriteBack);
+        assert_eq!(epte.0.load(Ordering::Relaxed), 0xabc000 | 6 << 3 | 1 << 6 | 0b111);
+        assert_eq!(epte.memory_type(), Ok(MemoryType::WriteBack));
+        assert!(epte.is_present());
+        assert_eq!(format!("{:?}", epte), "0xabc000:-I--XWR");
+        let epte = EPTE::new(HPA::new(0xabc000), EPTEFlags::EXEC);
+        assert!(epte.is_present());
+        assert!(!EPTE::new(HPA::new(0xabc000), EPTEFlags::ACCESS).is_present());
+    }
+
+    #[test]
+    fn eptp_format() {
+        assert_eq!(eptp(pf(0x1234_5000), false), 0x1234_501e);
+        assert_eq!(eptp(pf(0x1234_5000), true), 0x1234_505e);
+    }
+
+    #[test]
+    fn map_translate_unmap() {
+        let mmu = SoftMmu::new(64);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let gpa = GPA::new(0x1_4020_3000);
+        assert_eq!(pa(translate_in(&mmu, gpa)), None);
+        map_in(&mmu, pf(0xabc000), gpa, rwx(), MemoryType::WriteBack, &mut || mmu.alloc()).unwrap();
+        assert_eq!(pa(translate_in(&mmu, gpa)), Some(0xabc000));
+        assert_eq!(pa(translate_in(&mmu, gpa.offset(0x123))), Some(0xabc123));
+        assert_eq!(pa(translate_in(&mmu, gpa.offset(0x1000))), None);
+        assert_eq!(pa(translate_in(&mmu, GPA::new(0))), None);
+        let r = map_in(&mmu, pf(0xdef000), gpa, rwx(), MemoryType::WriteBack, &mut || mmu.alloc());
+        assert!(r.is_err());
+        unmap_in(&mmu, gpa).unwrap();
+        assert_eq!(pa(translate_in(&mmu, gpa)), None);
+    }
+
+    #[test]
+    fn tables_are_mirrored() {
+        let mmu = SoftMmu::new(64);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let gpa = GPA::new(0x80_0000_0000);
+        map_in(&mmu, pf(0x7000), gpa, rwx(), MemoryType::Uncacheable, &mut || mmu.alloc()).unwrap();
+        // Each table referred to from the level above is mapped
+        // in the host at its mirror address.
+        let e4 = Level4::epte_ref(&mmu, gpa);
+        let e3 = Level3::epte_ref(&mmu, gpa);
+        let e2 = Level2::epte_ref(&mmu, gpa);
+        let mirror = |a: V4KA| pa(vm::translate_in(&mmu, a.addr()));
+        assert_eq!(mirror(Level4::table_address(gpa)), Some(root.pfa().addr()));
+        assert_eq!(mirror(Level3::table_address(gpa)), Some(e4.pfa().addr()));
+        assert_eq!(mirror(Level2::table_address(gpa)), Some(e3.pfa().addr()));
+        assert_eq!(mirror(Level1::table_address(gpa)), Some(e2.pfa().addr()));
+        let e1 = Level1::epte_ref(&mmu, gpa);
+        assert_eq!(e1.memory_type(), Ok(MemoryType::Uncacheable));
+        // Adjacent pages share tables.
+        let allocated = mmu.allocated();
+        let next = gpa.offset(0x1000);
+        map_in(&mmu, pf(0x8000), next, rwx(), MemoryType::WriteBack, &mut || mmu.alloc()).unwrap();
+        assert_eq!(mmu.allocated(), allocated);
+        let range = mirror_range();
+        assert!(range.contains(&Level4::table_address(gpa)));
+        assert!(range.contains(&Level1::table_address(next)));
+    }
+
+    // Maps npages 4KiB pages at the given GPA to contiguous frames
+    // starting at the given HPA.
+    fn map_contiguous(mmu: &SoftMmu, gpa: GPA, hpa: u64, npages: usize) {
+        for k in 0..npages {
+            let offset = k * 4096;
+            let pf = pf(hpa + offset as u64);
+            let flags = rwx();
+            let r = map_in(mmu, pf, gpa.offset(offset), flags, MemoryType::WriteBack, &mut || {
+                mmu.alloc()
+            });
+            r.unwrap();
+        }
+    }
+
+    #[test]
+    fn splinter_recombine_2m() {
+        let mmu = SoftMmu::new(64);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let eptp = eptp(root, true);
+        let gpa = GPA::new(0x20_0000);
+        map_contiguous(&mmu, gpa, 0x4000_0000, 512);
+        Level1::epte_ref(&mmu, gpa.offset(0x5000)).0.fetch_or(1 << 9, Ordering::Relaxed);
+        let allocated = mmu.allocated();
+        assert!(splinter_2m_in(&mmu, eptp, gpa).is_err());
+        recombine_2m_in(&mmu, eptp, gpa.offset(0x1234)).unwrap();
+        assert_eq!(mmu.invepts(), 1);
+        let e2 = Level2::epte_ref(&mmu, gpa);
+        assert!(e2.is_big());
+        assert!(e2.flags().contains(EPTEFlags::DIRTY));
+        assert_eq!(pa(translate_in(&mmu, gpa.offset(0x12_3456))), Some(0x4012_3456));
+        assert!(
+            map_in(&mmu, pf(0x1000), gpa, rwx(), MemoryType::WriteBack, &mut || mmu.alloc())
+                .is_err()
+        );
+
+        // Splinter and write-protect a single page.
+        splinter_2m_in(&mmu, eptp, gpa).unwrap();
+        assert_eq!(mmu.invepts(), 2);
+        assert_eq!(mmu.allocated(), allocated);
+        assert!(!Level2::epte_ref(&mmu, gpa).is_big());
+        let ro = EPTEFlags::READ | EPTEFlags::EXEC;
+        let page = gpa.offset(0x3000);
+        Level1::epte_ref(&mmu, page).assign(EPTE::new_page(
+            HPA::new(0x4000_3000),
+            ro,
+            MemoryType::WriteBack,
+        ));
+        assert!(Level1::epte_ref(&mmu, gpa.offset(0x6000)).flags().contains(EPTEFlags::DIRTY));
+        assert_eq!(recombine_2m_in(&mmu, eptp, gpa), Err("non-uniform mappings"));
+        assert!(!Level2::epte_ref(&mmu, gpa).is_big());
+        for offset in [0, 0x3000, 0x1F_F000] {
+            let expected = 0x4000_0000 + offset as u64;
+            assert_eq!(pa(translate_in(&mmu, gpa.offset(offset))), Some(expected));
+        }
+    }
+
+    #[test]
+    fn recombine_requires_contiguity() {
+        let mmu = SoftMmu::new(64);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let eptp = eptp(root, false);
+        let gpa = GPA::new(0x40_0000);
+        map_contiguous(&mmu, gpa, 0x4000_1000, 512);
+        assert_eq!(recombine_2m_in(&mmu, eptp, gpa), Err("misaligned frames"));
+        let gpa = GPA::new(0x60_0000);
+        map_contiguous(&mmu, gpa, 0x4020_0000, 511);
+        assert_eq!(recombine_2m_in(&mmu, eptp, gpa), Err("non-uniform mappings"));
+        let last = gpa.offset(511 * 4096);
+        map_in(&mmu, pf(0x5000_0000), last, rwx(), MemoryType::WriteBack, &mut || mmu.alloc())
+            .unwrap();
+        assert_eq!(recombine_2m_in(&mmu, eptp, gpa), Err("discontiguous frames"));
+        unmap_in(&mmu, last).unwrap();
+        let r = map_in(&mmu, pf(0x403F_F000), last, rwx(), MemoryType::Uncacheable, &mut || {
+            mmu.alloc()
+        });
+        r.unwrap();
+        assert_eq!(recombine_2m_in(&mmu, eptp, gpa), Err("non-uniform mappings"));
+        assert_eq!(recombine_2m_in(&mmu, eptp, GPA::new(0x8000_0000)), Err("not mapped"));
+    }
+
+    #[test]
+    fn splinter_recombine_1g() {
+        const NPAGES: usize = 1 << 18;
+        let mmu = SoftMmu::new(1024);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let eptp = eptp(root, true);
+        let gpa = GPA::new(0x4000_0000);
+        map_contiguous(&mmu, gpa, 0x1_0000_0000, NPAGES);
+        let allocated = mmu.allocated();
+        recombine_1g_in(&mmu, eptp, gpa).unwrap();
+        assert_eq!(mmu.invepts(), 1);
+        assert!(Level3::epte_ref(&mmu, gpa).is_big());
+        assert_eq!(pa(translate_in(&mmu, gpa.offset(0x1234_5678))), Some(0x1_1234_5678));
+
+        splinter_1g_in(&mmu, eptp, gpa).unwrap();
+        assert!(!Level3::epte_ref(&mmu, gpa).is_big());
+        assert!(Level2::epte_ref(&mmu, gpa.offset(0x1234_5678)).is_big());
+        splinter_2m_in(&mmu, eptp, gpa.offset(0x1234_5678)).unwrap();
+        assert!(!Level2::epte_ref(&mmu, gpa.offset(0x1234_5678)).is_big());
+        assert_eq!(mmu.allocated(), allocated);
+        for offset in [0, 0x1234_5678, 0x3FFF_FFFF] {
+            let expected = 0x1_0000_0000 + offset as u64;
+            assert_eq!(pa(translate_in(&mmu, gpa.offset(offset))), Some(expected));
+        }
+
+        // A 2MiB region within a huge page cannot be splintered
+        // until the huge page is.
+        recombine_1g_in(&mmu, eptp, gpa).unwrap();
+        assert_eq!(splinter_2m_in(&mmu, eptp, gpa), Err("not a big page"));
+        assert_eq!(mmu.allocated(), allocated);
+    }
+
+    #[test]
+    fn big_pages_translate() {
+        let mmu = SoftMmu::new(64);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let gpa = GPA::new(0x4000_0000);
+        map_in(&mmu, pf(0x7000), gpa, rwx(), MemoryType::WriteBack, &mut || mmu.alloc()).unwrap();
+        let big = EPTEFlags::HUGE | rwx();
+        Level3::epte_ref(&mmu, gpa).assign(EPTE::new_page(
+            HPA::new(0x8000_0000),
+            big,
+            MemoryType::WriteBack,
+        ));
+        assert_eq!(pa(translate_in(&mmu, gpa.offset(0x1234_5678))), Some(0x9234_5678));
+        assert!(unmap_in(&mmu, gpa).is_err());
+        let r = map_in(&mmu, pf(0x9000), gpa, rwx(), MemoryType::WriteBack, &mut || mmu.alloc());
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn harvest_bits() {
+        let mmu = SoftMmu::new(64);
+        let root = mmu.alloc().unwrap();
+        mount_in(&mmu, root, &mut || mmu.alloc()).unwrap();
+        let eptp = eptp(root, true);
+        let gpa = GPA::new(0x20_0000);
+        map_contiguous(&mmu, gpa, 0x4000_0000, 512);
+        map_contiguous(&mmu, gpa.offset(0x20_0000), 0x4020_0000, 4);
+        recombine_2m_in(&mmu, eptp, gpa).unwrap();
+        let dirty = EPTEFlags::DIRTY.bits();
+        Level2::epte_ref(&mmu, gpa).0.fetch_or(dirty, Ordering::Relaxed);
+        Level1::epte_ref(&mmu, gpa.offset(0x20_2000)).0.fetch_or(dirty, Ordering::Relaxed);
+        let end = gpa.offset(0x40_0000);
+        let mut bitmap = [0u64; 16];
+        assert_eq!(harvest_dirty_in(&mmu, eptp, gpa..end, &mut bitmap), Ok(513));
+        assert_eq!(mmu.invepts(), 2);
+        assert!(bitmap[..8].iter().all(|&w| w == !0));
+        assert_eq!(bitmap[8..], [0b100, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(!Level2::epte_ref(&mmu, gpa).flags().contains(EPTEFlags::DIRTY));
+        assert_eq!(harvest_dirty_in(&mmu, eptp, gpa..end, &mut bitmap), Ok(0));
+        assert_eq!(mmu.invepts(), 2);
+        assert_eq!(
+            harvest_accessed_in(&mmu, eptp, gpa..end, &mut bitmap[..15]),
+            Err("bitmap too small")
+        );
+    }
+}
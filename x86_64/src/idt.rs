@@ -13,14 +13,19 @@ pub struct IDT {
     entries: [segment::InterruptGateDescriptor; 256],
 }
 
+/// The interrupt stacks for traps that may arrive when the
+/// current stack cannot be trusted.  The TSS must provide each.
+pub const NMI_STACK: StackIndex = StackIndex::Ist1;
+pub const DEBUG_STACK: StackIndex = StackIndex::Ist2;
+pub const DOUBLE_FAULT_STACK: StackIndex = StackIndex::Ist3;
+pub const MACHINE_CHECK_STACK: StackIndex = StackIndex::Ist4;
+
 fn make_gate(thunk: &trap::Stub, vecnum: u8) -> segment::InterruptGateDescriptor {
-    const NMI_STACK: StackIndex = StackIndex::Ist1;
-    const DEBUG_STACK: StackIndex = StackIndex::Ist2;
-    const DOUBLE_FAULT_STACK: StackIndex = StackIndex::Ist3;
     match vecnum {
         1 => segment::InterruptGateDescriptor::new(thunk, DEBUG_STACK),
         2 => segment::InterruptGateDescriptor::new(thunk, NMI_STACK),
         8 => segment::InterruptGateDescriptor::new(thunk, DOUBLE_FAULT_STACK),
+        18 => segment::InterruptGateDescriptor::new(thunk, MACHINE_CHECK_STACK),
         _ => segment::InterruptGateDescriptor::new(thunk, StackIndex::Rsp0),
     }
 }
@@ -0,0 +1,344 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # I/O APIC
+//!
+//! Each I/O APIC routes a contiguous range of global system
+//! interrupts (GSIs), starting at its GSI base, to local APICs
+//! according to its redirection table.  Its registers are
+//! accessed indirectly through a small memory-mapped window:
+//! software writes a register index into IOREGSEL, and then
+//! reads or writes the register through IOWIN.
+//!
+//! The legacy ISA IRQs are identity mapped onto the first GSIs,
+//! edge-triggered and active high, except where the firmware
+//! describes an interrupt source override in the MADT.
+
+use crate::lapic::{DeliveryMode, InterruptVector, TriggerMode};
+use crate::mmio::{self, Arena, Block, ReadWrite, Register};
+use crate::{IOAPIC, PF4K, ProcessorID};
+use bitstruct::bitstruct;
+use core::ops::Range;
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+/// The polarity of an interrupt input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+bitstruct! {
+    /// An entry in the redirection table.  Destinations are
+    /// physical APIC IDs; without interrupt remapping, only
+    /// the first 255 CPUs may be targeted.
+    #[derive(Clone, Copy, Default)]
+    pub struct RedirectionEntry(pub u64) {
+        pub vector: u8 = 0..8;
+        raw_delivery_mode: u8 = 8..11;
+        pub pending: bool = 12;
+        pub polarity: Polarity = 13;
+        pub remote_irr: bool = 14;
+        pub trigger_mode: TriggerMode = 15;
+        pub masked: bool = 16;
+        pub destination: u8 = 56..64;
+    }
+}
+
+impl RedirectionEntry {
+    /// Returns a masked entry, as the table holds after reset.
+    #[must_use]
+    pub fn new() -> RedirectionEntry {
+        RedirectionEntry(0).with_masked(true)
+    }
+
+    #[must_use]
+    pub fn with_delivery_mode(self, mode: DeliveryMode) -> RedirectionEntry {
+        self.with_raw_delivery_mode(mode as u8)
+    }
+}
+
+impl bitstruct::FromRaw<bool, Polarity> for RedirectionEntry {
+    fn from_raw(raw: bool) -> Polarity {
+        match raw {
+            false => Polarity::ActiveHigh,
+            true => Polarity::ActiveLow,
+        }
+    }
+}
+
+impl bitstruct::IntoRaw<bool, Polarity> for RedirectionEntry {
+    fn into_raw(polarity: Polarity) -> bool {
+        match polarity {
+            Polarity::ActiveHigh => false,
+            Polarity::ActiveLow => true,
+        }
+    }
+}
+
+impl bitstruct::FromRaw<bool, TriggerMode> for RedirectionEntry {
+    fn from_raw(raw: bool) -> TriggerMode {
+        match raw {
+            false => TriggerMode::Edge,
+            true => TriggerMode::Level,
+        }
+    }
+}
+
+impl bitstruct::IntoRaw<bool, TriggerMode> for RedirectionEntry {
+    fn into_raw(mode: TriggerMode) -> bool {
+        match mode {
+            TriggerMode::Edge => false,
+            TriggerMode::Level => true,
+        }
+    }
+}
+
+/// An interrupt source override from the MADT, describing how
+/// an ISA IRQ is wired to a GSI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl SourceOverride {
+    /// Returns the polarity of the input.  Inputs that conform
+    /// to the bus specification are active high, as on ISA.
+    pub fn polarity(&self) -> Polarity {
+        match self.flags & 0b11 {
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        }
+    }
+
+    /// Returns the trigger mode of the input.  Inputs that
+    /// conform to the bus specification are edge triggered, as
+    /// on ISA.
+    pub fn trigger_mode(&self) -> TriggerMode {
+        match (self.flags >> 2) & 0b11 {
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        }
+    }
+}
+
+/// The number of ISA IRQs.
+pub const ISA_IRQS: u8 = 16;
+
+/// Returns the GSI, trigger mode and polarity of the given ISA
+/// IRQ, given the interrupt source overrides.
+pub fn isa_irq(irq: u8, overrides: &[SourceOverride]) -> (u32, TriggerMode, Polarity) {
+    match overrides.iter().find(|o| o.irq == irq) {
+        Some(o) => (o.gsi, o.trigger_mode(), o.polarity()),
+        None => (u32::from(irq), TriggerMode::Edge, Polarity::ActiveHigh),
+    }
+}
+
+/// The size of the register window.
+pub const WINDOW_SIZE: usize = 0x20;
+
+// The register window.
+#[repr(C)]
+struct Window {
+    select: Register<u32, ReadWrite>,
+    _reserved: [u32; 3],
+    data: Register<u32, ReadWrite>,
+}
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+/// A driver for an I/O APIC whose registers are mapped.
+pub struct IoApic {
+    regs: &'static Window,
+    gsib: u32,
+    version: u8,
+    entries: u32,
+}
+
+impl IoApic {
+    /// Returns a driver for the given I/O APIC, whose register
+    /// window is the given block.
+    ///
+    /// # Safety
+    /// The block must map the I/O APIC's registers, and no
+    /// other driver may be using them.
+    pub unsafe fn new(ioapic: &IOAPIC, regs: Block<'static>) -> IoApic {
+        let regs = unsafe { regs.view::<Window>() };
+        let mut ioapic = IoApic { regs, gsib: ioapic.gsib(), version: 0, entries: 0 };
+        let version = ioapic.read(REG_VERSION);
+        ioapic.version = version as u8;
+        ioapic.entries = ((version >> 16) & 0xFF) + 1;
+        ioapic
+    }
+
+    /// Maps the register window of the given I/O APIC uncached at
+    /// an address taken from the arena, and returns a driver for
+    /// it.
+    ///
+    /// # Safety
+    /// No other driver may be using the I/O APIC.
+    pub unsafe fn map<F>(ioapic: &IOAPIC, arena: &Arena, allocator: &mut F) -> Result<IoApic>
+    where
+        F: FnMut() -> Result<PF4K>,
+    {
+        let regs = unsafe { mmio::map(arena, ioapic.hpa(), WINDOW_SIZE, allocator)? };
+        Ok(unsafe { IoApic::new(ioapic, regs) })
+    }
+
+    fn read(&mut self, reg: u32) -> u32 {
+        self.regs.select.write(reg);
+        self.regs.data.read()
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        self.regs.select.write(reg);
+        self.regs.data.write(value);
+    }
+
+    /// Returns the APIC ID of the I/O APIC.
+    pub fn id(&mut self) -> u8 {
+        ((self.read(REG_ID) >> 24) & 0xF) as u8
+    }
+
+    /// Returns the version of the I/O APIC.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the number of entries in the redirection table.
+    pub fn entries(&self) -> u32 {
+        self.entries
+    }
+
+    /// Returns the range of GSIs routed by this I/O APIC.
+    pub fn gsis(&self) -> Range<u32> {
+        self.gsib..self.gsib + self.entries
+    }
+
+    fn pin(&self, gsi: u32) -> Result<u32> {
+        if !self.gsis().contains(&gsi) {
+            return Err("GSI not routed by this IOAPIC");
+        }
+        Ok(gsi - self.gsib)
+    }
+
+    /// Reads the redirection entry for the given GSI.
+    pub fn entry(&mut self, gsi: u32) -> Result<RedirectionEntry> {
+        let reg = REG_REDIRECTION_TABLE + 2 * self.pin(gsi)?;
+        let lo = self.read(reg);
+        let hi = self.read(reg + 1);
+        Ok(RedirectionEntry(u64::from(hi) << 32 | u64::from(lo)))
+    }
+
+    /// Writes the redirection entry for the given GSI.  The low
+    /// half, holding the mask, is written last, so that a half
+    /// written entry is never unmasked.
+    ///
+    /// # Safety
+    /// The entry must deliver a vector with a handler installed
+    /// to a CPU that is ready for it, unless it is masked.
+    pub unsafe fn set_entry(&mut self, gsi: u32, entry: RedirectionEntry) -> Result<()> {
+        let reg = REG_REDIRECTION_TABLE + 2 * self.pin(gsi)?;
+        self.write(reg, RedirectionEntry::new().0 as u32);
+        self.write(reg + 1, (entry.0 >> 32) as u32);
+        self.write(reg, entry.0 as u32);
+        Ok(())
+    }
+
+    /// Routes the given GSI to a fixed vector on the given CPU.
+    ///
+    /// # Safety
+    /// The vector must have a handler installed on the CPU.
+    pub unsafe fn route(
+        &mut self,
+        gsi: u32,
+        vector: InterruptVector,
+        cpu: ProcessorID,
+        trigger_mode: TriggerMode,
+        polarity: Polarity,
+    ) -> Result<()> {
+        let destination =
+            u8::try_from(u32::from(cpu)).map_err(|_| "APIC ID out of IOAPIC range")?;
+        let entry = RedirectionEntry(0)
+            .with_vector(vector as u8)
+            .with_delivery_mode(DeliveryMode::Fixed)
+            .with_trigger_mode(trigger_mode)
+            .with_polarity(polarity)
+            .with_destination(destination);
+        unsafe { self.set_entry(gsi, entry) }
+    }
+
+    /// Masks the given GSI.
+    pub fn mask(&mut self, gsi: u32) -> Result<()> {
+        let entry = self.entry(gsi)?;
+        unsafe { self.set_entry(gsi, entry.with_masked(true)) }
+    }
+
+    /// Unmasks the given GSI.
+    ///
+    /// # Safety
+    /// The GSI's entry must have been programmed to deliver a
+    /// vector with a handler installed.
+    pub unsafe fn unmask(&mut self, gsi: u32) -> Result<()> {
+        let entry = self.entry(gsi)?;
+        unsafe { self.set_entry(gsi, entry.with_masked(false)) }
+    }
+
+    /// Masks every entry in the redirection table.
+    pub fn mask_all(&mut self) {
+        for gsi in self.gsis() {
+            self.mask(gsi).expect("masked a routed GSI");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirection_entry() {
+        let entry = RedirectionEntry(0)
+            .with_vector(0x31)
+            .with_delivery_mode(DeliveryMode::Fixed)
+            .with_trigger_mode(TriggerMode::Level)
+            .with_polarity(Polarity::ActiveLow)
+            .with_destination(3);
+        assert_eq!(entry.0, 0x0300_0000_0000_a031);
+        assert!(!entry.masked());
+        assert_eq!(entry.with_masked(true).0, 0x0300_0000_0001_a031);
+        assert!(RedirectionEntry::new().masked());
+        let entry = RedirectionEntry(0x0100_0000_0000_5040);
+        assert_eq!(entry.vector(), 0x40);
+        assert_eq!(entry.destination(), 1);
+        assert!(entry.remote_irr());
+        assert!(entry.pending());
+        assert_eq!(entry.polarity(), Polarity::ActiveHigh);
+        assert!(matches!(entry.trigger_mode(), TriggerMode::Edge));
+    }
+
+    #[test]
+    fn source_overrides() {
+        // The usual PC overrides: the PIT on IRQ0 is wired to GSI
+        // 2, and the SCI is level-triggered and active low.
+        let overrides = [
+            SourceOverride { irq: 0, gsi: 2, flags: 0 },
+            SourceOverride { irq: 9, gsi: 9, flags: 0b1111 },
+        ];
+        assert!(matches!(isa_irq(0, &overrides), (2, TriggerMode::Edge, Polarity::ActiveHigh)));
+        assert!(matches!(isa_irq(9, &overrides), (9, TriggerMode::Level, Polarity::ActiveLow)));
+        assert!(matches!(isa_irq(4, &overrides), (4, TriggerMode::Edge, Polarity::ActiveHigh)));
+        let high_level = SourceOverride { irq: 5, gsi: 5, flags: 0b1101 };
+        assert_eq!(high_level.polarity(), Polarity::ActiveHigh);
+        assert!(matches!(high_level.trigger_mode(), TriggerMode::Level));
+    }
+}
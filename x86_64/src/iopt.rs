@@ -0,0 +1,777 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Intel VT-d IO page tables
+//!
+//! The IOPT describes a guest's physical address space as seen by
+//! devices assigned to it, and is walked by the IOMMU.  We use the
+//! VT-d second-level table format with a four-level (48-bit) walk,
+//! selected for each device by a context entry, which is in turn
+//! selected by the root entry for the device's bus.
+//!
+//! As with the EPT, the paging structures are mapped as data into
+//! a dedicated slot of the host root table ("Device Page Table",
+//! per HDP 0003) at the addresses they would have if that slot were
+//! recursive.  The IOPT and L2PT of a virtual machine describe the
+//! same guest physical address space, so per HDP 0015 their tables
+//! are allocated together as pairs of physically contiguous frames,
+//! which prevents external fragmentation when creating VMs.
+
+use crate::ept::{self, EPTEFlags};
+use crate::vm::{self, Mmu, PTE, PTEFlags, Result};
+use crate::{GPA, HPA, MemoryType, PF4K, Page, PageFrame, V1GA, V2MA, V4KA, V512GA, VPageAddr};
+use bitflags::bitflags;
+use bitstruct::bitstruct;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+bitflags! {
+    #[derive(Clone, Copy, Debug)]
+    pub struct IOPTEFlags: u64 {
+        const READ      = 1;
+        const WRITE     = 1 << 1;
+        const HUGE      = 1 << 7;
+        const ACCESS    = 1 << 8;
+        const DIRTY     = 1 << 9;
+        const SNOOP     = 1 << 11;
+    }
+}
+
+/// IO page table entries.  These are read by the IOMMU
+/// concurrently with our updates, so are defined in terms of
+/// atomics.
+#[repr(transparent)]
+pub struct IOPTE(AtomicU64);
+
+impl IOPTE {
+    const PFA_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+    const PERM_MASK: u64 = IOPTEFlags::READ.bits() | IOPTEFlags::WRITE.bits();
+
+    /// Creates a new IOPTE from the given HPA and flags.
+    pub fn new(hpa: HPA, flags: IOPTEFlags) -> IOPTE {
+        let address = hpa.addr() & Self::PFA_MASK;
+        assert_eq!(hpa.addr(), address);
+        IOPTE(AtomicU64::new(address | flags.bits()))
+    }
+
+    /// Creates an empty (zero) IOPTE.
+    pub const fn empty() -> IOPTE {
+        IOPTE(AtomicU64::new(0))
+    }
+
+    /// Zeroes out the IOPTE.
+    pub fn clear(&self) {
+        self.0.store(0, Ordering::Relaxed)
+    }
+
+    /// Assign self the value of the given IOPTE.
+    pub fn assign(&self, iopte: IOPTE) {
+        self.0.store(iopte.0.into_inner(), Ordering::Relaxed);
+    }
+
+    /// Returns the physical frame address associated with the IOPTE.
+    pub fn pfa(&self) -> HPA {
+        HPA::new(self.0.load(Ordering::Relaxed) & Self::PFA_MASK)
+    }
+
+    /// Extracts and returns the flags attached to this IOPTE.
+    pub fn flags(&self) -> IOPTEFlags {
+        IOPTEFlags::from_bits_truncate(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Returns true iff the IOPTE grants any access.  As with the
+    /// EPT, there is no "present" bit.
+    pub fn is_present(&self) -> bool {
+        self.0.load(Ordering::Relaxed) & Self::PERM_MASK != 0
+    }
+
+    /// Returns true iff the bit marking this either a huge or large page is set.
+    pub fn is_big(&self) -> bool {
+        self.flags().contains(IOPTEFlags::HUGE)
+    }
+
+    /// Returns true iff the entry is zero.
+    pub fn is_zero(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl Clone for IOPTE {
+    fn clone(&self) -> IOPTE {
+        IOPTE(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl core::fmt::Debug for IOPTE {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flags = self.flags();
+        let flag_or = |f: IOPTEFlags, a, b| {
+            if flags.contains(f) { a } else { b }
+        };
+        f.write_fmt(format_args!("{:#x?}:", self.pfa().addr()))?;
+        f.write_str(flag_or(IOPTEFlags::SNOOP, "S", "-"))?;
+        f.write_str(flag_or(IOPTEFlags::HUGE, "H", "-"))?;
+        f.write_str(flag_or(IOPTEFlags::DIRTY, "D", "-"))?;
+        f.write_str(flag_or(IOPTEFlags::ACCESS, "A", "-"))?;
+        f.write_str(flag_or(IOPTEFlags::WRITE, "W", "-"))?;
+        f.write_str(flag_or(IOPTEFlags::READ, "R", "-"))
+    }
+}
+
+bitstruct! {
+    /// A root table entry selects the context table for a bus.
+    #[derive(Clone, Copy, Debug, Default)]
+    #[repr(transparent)]
+    pub struct RootEntry(u128) {
+        pub present: bool = 0;
+        raw_context_table: u64 = 12..64;
+        reserved: u64 = 64..128;
+    }
+}
+
+impl RootEntry {
+    /// Returns a root entry referring to the given context table.
+    pub fn new(context_table: PF4K) -> RootEntry {
+        RootEntry(0).with_present(true).with_raw_context_table(context_table.pfa().addr() >> 12)
+    }
+
+    /// Returns the address of the context table.
+    pub fn context_table(&self) -> HPA {
+        HPA::new(self.raw_context_table() << 12)
+    }
+}
+
+/// Translation types for context entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TranslationType {
+    Untranslated = 0b00,
+    All = 0b01,
+    PassThrough = 0b10,
+}
+
+bitstruct! {
+    /// A context entry describes the translation for a single
+    /// device function on a bus.
+    #[derive(Clone, Copy, Debug, Default)]
+    #[repr(transparent)]
+    pub struct ContextEntry(u128) {
+        pub present: bool = 0;
+        pub fault_processing_disable: bool = 1;
+        raw_translation_type: u8 = 2..4;
+        raw_iopt: u64 = 12..64;
+        pub address_width: u8 = 64..67;
+        pub domain_id: u16 = 72..88;
+    }
+}
+
+impl ContextEntry {
+    /// The adjusted guest address width encoding for a four-level
+    /// (48-bit) walk.
+    const AW_48: u8 = 0b010;
+
+    /// Returns a context entry translating untranslated requests
+    /// from the device through the IOPT with the given root, in
+    /// the given domain.
+    pub fn new(iopt: PF4K, domain_id: u16) -> ContextEntry {
+        ContextEntry(0)
+            .with_present(true)
+            .with_raw_translation_type(TranslationType::Untranslated as u8)
+            .with_raw_iopt(iopt.pfa().addr() >> 12)
+            .with_address_width(Self::AW_48)
+            .with_domain_id(domain_id)
+    }
+
+    /// Returns the address of the root of the IOPT.
+    pub fn iopt(&self) -> HPA {
+        HPA::new(self.raw_iopt() << 12)
+    }
+
+    pub fn translation_type(&self) -> Result<TranslationType> {
+        match self.raw_translation_type() {
+            0b00 => Ok(TranslationType::Untranslated),
+            0b01 => Ok(TranslationType::All),
+            0b10 => Ok(TranslationType::PassThrough),
+            _ => Err("reserved translation type"),
+        }
+    }
+}
+
+/// The root table, indexed by bus number.
+#[repr(C, align(4096))]
+pub struct RootTable([RootEntry; 256]);
+
+impl RootTable {
+    pub const fn empty() -> RootTable {
+        RootTable([RootEntry(0); 256])
+    }
+
+    /// Sets the context table for the given bus.  The caller must
+    /// invalidate the IOMMU's context cache if the entry was
+    /// previously present.
+    pub fn set(&mut self, bus: u8, context_table: PF4K) {
+        self.0[usize::from(bus)] = RootEntry::new(context_table);
+    }
+
+    pub fn entry(&self, bus: u8) -> RootEntry {
+        self.0[usize::from(bus)]
+    }
+}
+
+/// A context table, indexed by device and function numbers.
+#[repr(C, align(4096))]
+pub struct ContextTable([ContextEntry; 256]);
+
+impl ContextTable {
+    pub const fn empty() -> ContextTable {
+        ContextTable([ContextEntry(0); 256])
+    }
+
+    /// Sets the context entry for the given device function.  The
+    /// caller must invalidate the IOMMU's context cache if the entry
+    /// was previously present.
+    pub fn set(&mut self, device: u8, function: u8, entry: ContextEntry) {
+        self.0[Self::index(device, function)] = entry;
+    }
+
+    pub fn entry(&self, device: u8, function: u8) -> ContextEntry {
+        self.0[Self::index(device, function)]
+    }
+
+    fn index(device: u8, function: u8) -> usize {
+        assert!(device < 32 && function < 8, "invalid device function");
+        usize::from(device) << 3 | usize::from(function)
+    }
+}
+
+/// A pair of physically contiguous frames holding the L2PT and
+/// IOPT tables for the same region of guest physical address
+/// space, in that order.
+#[derive(Clone, Copy, Debug)]
+pub struct TablePair(PF4K);
+
+impl TablePair {
+    /// Creates a pair from the first of two contiguous frames.
+    pub fn new(first: PF4K) -> TablePair {
+        TablePair(first)
+    }
+
+    pub fn l2pt(self) -> PF4K {
+        self.0
+    }
+
+    pub fn iopt(self) -> PF4K {
+        const SIZE_4K: usize = <V4KA as VPageAddr>::PageType::SIZE;
+        PF4K::new(self.0.pfa().offset(SIZE_4K))
+    }
+}
+
+/// The index of the host root table slot where the IOPT is mirrored.
+pub const MIRROR_INDEX: usize = 509;
+
+/// The levels of the IOPT, as mirrored in the host.
+trait Level {
+    const BASE_ADDRESS: usize;
+    const PAGE_SHIFT: usize;
+
+    fn index(gpa: GPA) -> usize {
+        gpa.addr() as usize >> Self::PAGE_SHIFT
+    }
+
+    fn entry_address(gpa: GPA) -> usize {
+        Self::BASE_ADDRESS + Self::index(gpa) * core::mem::size_of::<IOPTE>()
+    }
+
+    fn table_address(gpa: GPA) -> V4KA {
+        V4KA::new_round_down(Self::entry_address(gpa))
+    }
+
+    fn iopte_ref<M: Mmu>(mmu: &M, gpa: GPA) -> &IOPTE {
+        iopte(mmu, Self::entry_address(gpa))
+    }
+}
+
+enum Level4 {}
+enum Level3 {}
+enum Level2 {}
+enum Level1 {}
+
+impl Level for Level4 {
+    const BASE_ADDRESS: usize = 0xFFFF_FEFF_7FBF_D000;
+    const PAGE_SHIFT: usize = 39;
+}
+
+impl Level for Level3 {
+    const BASE_ADDRESS: usize = 0xFFFF_FEFF_7FA0_0000;
+    const PAGE_SHIFT: usize = 30;
+}
+
+impl Level for Level2 {
+    const BASE_ADDRESS: usize = 0xFFFF_FEFF_4000_0000;
+    const PAGE_SHIFT: usize = 21;
+}
+
+impl Level for Level1 {
+    const BASE_ADDRESS: usize = 0xFFFF_FE80_0000_0000;
+    const PAGE_SHIFT: usize = 12;
+}
+
+// Returns a reference to the IOPT entry mirrored at the given
+// host virtual address.
+fn iopte<M: Mmu>(mmu: &M, va: usize) -> &IOPTE {
+    let pte: *const PTE = mmu.pte(va);
+    // As with EPT entries, both types are transparent wrappers
+    // around AtomicU64.
+    unsafe { &*pte.cast::<IOPTE>() }
+}
+
+// Host page flags for mapping IOPT tables into the mirror.
+fn mirror_flags() -> PTEFlags {
+    PTEFlags::PRESENT | PTEFlags::WRITE | PTEFlags::NX
+}
+
+// Maps a newly allocated table for the next level down at its
+// mirror address, and refers to it from the entry at this level,
+// unless there is already such a table.
+fn make_level<L, N, M, T, A>(mmu: &M, gpa: GPA, tables: &mut T, allocator: &mut A) -> Result<()>
+where
+    L: Level,
+    N: Level,
+    M: Mmu,
+    T: FnMut(&mut A) -> Result<PF4K>,
+    A: FnMut() -> Result<PF4K>,
+{
+    let entry = L::iopte_ref(mmu, gpa);
+    if entry.is_present() {
+        return if entry.is_big() { Err("mapped by a big page") } else { Ok(()) };
+    }
+    let table = tables(allocator)?;
+    vm::map_in(mmu, table, mirror_flags(), N::table_address(gpa), allocator)?;
+    entry.assign(IOPTE::new(table.pfa(), IOPTEFlags::READ | IOPTEFlags::WRITE));
+    Ok(())
+}
+
+// Returns the number of tables that mapping a 4KiB page at the
+// given GPA would create.
+fn missing_tables<M: Mmu>(mmu: &M, gpa: GPA) -> Result<usize> {
+    let levels = [Level4::iopte_ref as fn(&M, GPA) -> &IOPTE, Level3::iopte_ref, Level2::iopte_ref];
+    for (k, iopte_ref) in levels.iter().enumerate() {
+        let entry = iopte_ref(mmu, gpa);
+        if !entry.is_present() {
+            return Ok(levels.len() - k);
+        }
+        if entry.is_big() {
+            return Err("mapped by a big page");
+        }
+    }
+    Ok(0)
+}
+
+// Removes the last `count` tables on the walk to the given GPA,
+// as `ept::unmake_tables_in` does for the L2PT.
+fn unmake_tables_in<M: Mmu>(mmu: &M, gpa: GPA, count: usize) {
+    let entries =
+        [Level4::iopte_ref as fn(&M, GPA) -> &IOPTE, Level3::iopte_ref, Level2::iopte_ref];
+    let tables =
+        [Level3::table_address as fn(GPA) -> V4KA, Level2::table_address, Level1::table_address];
+    let first = entries.len() - count;
+    let made = (first..entries.len()).take_while(|&k| entries[k](mmu, gpa).is_present()).count();
+    for k in (first..first + made).rev() {
+        vm::unmap_in(mmu, tables[k](gpa));
+        entries[k](mmu, gpa).clear();
+    }
+}
+
+/// Maps the root table of an IOPT at its mirror address in the
+/// address space viewed through the given MMU.
+pub fn mount_in<M, A>(mmu: &M, root: PF4K, allocator: &mut A) -> Result<()>
+where
+    M: Mmu,
+    A: FnMut() -> Result<PF4K>,
+{
+    vm::map_in(mmu, root, mirror_flags(), Level4::table_address(GPA::new(0)), allocator)
+}
+
+/// Maps the roots of both the L2PT and IOPT, allocated as a pair,
+/// in the current address space.
+pub fn mount_pair<A>(roots: TablePair, allocator: &mut A) -> Result<()>
+where
+    A: FnMut() -> Result<PF4K>,
+{
+    mount_pair_in(&vm::HardMmu, roots, allocator)
+}
+
+/// Maps the roots of both the L2PT and IOPT, allocated as a pair,
+/// in the address space viewed through the given MMU.
+pub fn mount_pair_in<M, A>(mmu: &M, roots: TablePair, allocator: &mut A) -> Result<()>
+where
+    M: Mmu,
+    A: FnMut() -> Result<PF4K>,
+{
+    ept::mount_in(mmu, roots.l2pt(), allocator)?;
+    mount_in(mmu, roots.iopt(), allocator)
+}
+
+/// Translates the given GPA to a host physical address via the
+/// IOPT mounted in the current address space.
+pub fn translate(gpa: GPA) -> Option<HPA> {
+    translate_in(&vm::HardMmu, gpa)
+}
+
+/// Translates the given GPA to a host physical address via the
+/// IOPT mounted in the address space viewed through the MMU.
+pub fn translate_in<M: Mmu>(mmu: &M, gpa: GPA) -> Option<HPA> {
+    const MASK_1G: usize = <V1GA as VPageAddr>::PageType::MASK;
+    const MASK_2M: usize = <V2MA as VPageAddr>::PageType::MASK;
+    const MASK_4K: usize = <V4KA as VPageAddr>::PageType::MASK;
+    if gpa.addr() >= ept::MAX_GPA || !Level4::iopte_ref(mmu, gpa).is_present() {
+        return None;
+    }
+    let offset = gpa.addr() as usize;
+    let e3 = Level3::iopte_ref(mmu, gpa);
+    if !e3.is_present() {
+        return None;
+    }
+    if e3.is_big() {
+        return Some(e3.pfa().offset(offset & MASK_1G));
+    }
+    let e2 = Level2::iopte_ref(mmu, gpa);
+    if !e2.is_present() {
+        return None;
+    }
+    if e2.is_big() {
+        return Some(e2.pfa().offset(offset & MASK_2M));
+    }
+    let e1 = Level1::iopte_ref(mmu, gpa);
+    e1.is_present().then(|| e1.pfa().offset(offset & MASK_4K))
+}
+
+/// Maps the given PF4K at the given GPA in the IOPT mounted in the
+/// address space viewed through the given MMU.  IOPT tables are
+/// allocated from `tables`, which is given the allocator for host
+/// tables.
+fn map_tables_in<M, T, A>(
+    mmu: &M,
+    hpf: PF4K,
+    gpa: GPA,
+    flags: IOPTEFlags,
+    tables: &mut T,
+    allocator: &mut A,
+) -> Result<()>
+where
+    M: Mmu,
+    T: FnMut(&mut A) -> Result<PF4K>,
+    A: FnMut() -> Result<PF4K>,
+{
+    const MASK_4K: usize = <V4KA as VPageAddr>::PageType::MASK;
+    assert_eq!(gpa.addr() as usize & MASK_4K, 0, "unaligned GPA");
+    assert!(gpa.addr() < ept::MAX_GPA, "GPA out of range");
+    assert!(!flags.contains(IOPTEFlags::HUGE), "big pages are not supported");
+    make_level::<Level4, Level3, _, _, _>(mmu, gpa, tables, allocator)?;
+    make_level::<Level3, Level2, _, _, _>(mmu, gpa, tables, allocator)?;
+    make_level::<Level2, Level1, _, _, _>(mmu, gpa, tables, allocator)?;
+    let entry = Level1::iopte_ref(mmu, gpa);
+    if entry.is_present() {
+        return Err("Already mapped");
+    }
+    entry.assign(IOPTE::new(hpf.pfa(), flags));
+    Ok(())
+}
+
+/// Maps the given PF4K at the given GPA in both the L2PT and the
+/// IOPT mounted in the current address space.
+#[allow(clippy::too_many_arguments)]
+pub fn map_pair<P, A>(
+    hpf: PF4K,
+    gpa: GPA,
+    l2pt_flags: EPTEFlags,
+    iopt_flags: IOPTEFlags,
+    memory_type: MemoryType,
+    pairs: &mut P,
+    allocator: &mut A,
+) -> Result<()>
+where
+    P: FnMut() -> Result<TablePair>,
+    A: FnMut() -> Result<PF4K>,
+{
+    map_pair_in(&vm::HardMmu, hpf, gpa, l2pt_flags, iopt_flags, memory_type, pairs, allocator)
+}
+
+/// Maps the given PF4K at the given GPA in both the L2PT and the
+/// IOPT mounted in the address space viewed through the given MMU.
+/// Any tables required are allocated as pairs from `pairs`, while
+/// host tables for the mirrors come from `allocator`.  Both are
+/// assumed to return zeroed memory.  On failure, neither tree
+/// is changed, so that the two stay paired; tables already drawn
+/// are not freed.
+#[allow(clippy::too_many_arguments)]
+pub fn map_pair_in<M, P, A>(
+    mmu: &M,
+    hpf: PF4K,
+    gpa: GPA,
+    l2pt_flags: EPTEFlags,
+    iopt_flags: IOPTEFlags,
+    memory_type: MemoryType,
+    pairs: &mut P,
+    allocator: &mut A,
+) -> Result<()>
+where
+    M: Mmu,
+    P: FnMut() -> Result<TablePair>,
+    A: FnMut() -> Result<PF4K>,
+{
+    const MAX_TABLES: usize = 3;
+    let count = missing_tables(mmu, gpa)?;
+    if ept::missing_tables(mmu, gpa)? != count {
+        return Err("unpaired tables");
+    }
+    if count == 0 && Level1::iopte_ref(mmu, gpa).is_present() {
+        return Err("Already mapped");
+    }
+    // Every pair is drawn before either tree is touched, and a
+    // failure to make the mirror of a table is undone in both.
+    let mut drawn = [None; MAX_TABLES];
+    for pair in &mut drawn[..count] {
+        *pair = Some(pairs()?);
+    }
+    let drawn = drawn.into_iter().flatten();
+    let mut l2pt_tables = drawn.clone().map(TablePair::l2pt);
+    let mut tables = |_: &mut A| l2pt_tables.next().ok_or("unpaired tables");
+    let mapped = ept::map_tables_in(mmu, hpf, gpa, l2pt_flags, memory_type, &mut tables, allocator);
+    if let Err(err) = mapped {
+        ept::unmake_tables_in(mmu, gpa, count);
+        return Err(err);
+    }
+    let mut iopt_tables = drawn.map(TablePair::iopt);
+    let mut tables = |_: &mut A| iopt_tables.next().ok_or("unpaired tables");
+    map_tables_in(mmu, hpf, gpa, iopt_flags, &mut tables, allocator).inspect_err(|_| {
+        unmake_tables_in(mmu, gpa, count);
+        ept::unmap_in(mmu, gpa).expect("unmapped L2PT leaf");
+        ept::unmake_tables_in(mmu, gpa, count);
+    })
+}
+
+/// Unmaps the 4KiB page at the given GPA in the IOPT mounted in
+/// the current address space.
+pub fn unmap(gpa: GPA) -> Result<()> {
+    unmap_in(&vm::HardMmu, gpa)
+}
+
+/// Unmaps the 4KiB page at the given GPA in the IOPT mounted in
+/// the address space viewed through the given MMU.  Only the leaf
+/// entry is cleared.  The caller is responsible for invalidating
+/// the IOTLB.
+pub fn unmap_in<M: Mmu>(mmu: &M, gpa: GPA) -> Result<()> {
+    if gpa.addr() >= ept::MAX_GPA || !Level4::iopte_ref(mmu, gpa).is_present() {
+        return Ok(());
+    }
+    for entry in [Level3::iopte_ref(mmu, gpa), Level2::iopte_ref(mmu, gpa)] {
+        if !entry.is_present() {
+            return Ok(());
+        }
+        if entry.is_big() {
+            return Err("mapped by a big page");
+        }
+    }
+    Level1::iopte_ref(mmu, gpa).clear();
+    Ok(())
+}
+
+/// Returns the range of host virtual addresses reserved for
+/// the IOPT mirror.
+pub fn mirror_range() -> core::ops::Range<V4KA> {
+    const SIZE_512G: usize = <V512GA as VPageAddr>::PageType::SIZE;
+    V4KA::new(Level1::BASE_ADDRESS)..V4KA::new(Level1::BASE_ADDRESS + SIZE_512G)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::soft::SoftMmu;
+
+    fn pf(pa: u64) -> PF4K {
+        PF4K::new(HPA::new(pa))
+    }
+
+    fn pa(hpa: Option<HPA>) -> Option<u64> {
+        hpa.map(HPA::addr)
+    }
+
+    // Allocates a pair of contiguous frames from the soft MMU.
+    fn alloc_pair(mmu: &SoftMmu) -> Result<TablePair> {
+        let first = mmu.alloc()?;
+        let second = mmu.alloc()?;
+        assert_eq!(second.pfa().addr(), first.pfa().addr() + 4096);
+        Ok(TablePair::new(first))
+    }
+
+    #[test]
+    fn mirror_bases() {
+        let base = !0usize << 48 | MIRROR_INDEX << 39;
+        assert_eq!(base, Level1::BASE_ADDRESS);
+        let base = base | MIRROR_INDEX << 30;
+        assert_eq!(base, Level2::BASE_ADDRESS);
+        let base = base | MIRROR_INDEX << 21;
+        assert_eq!(base, Level3::BASE_ADDRESS);
+        let base = base | MIRROR_INDEX << 12;
+        assert_eq!(base, Level4::BASE_ADDRESS);
+        assert!(mirror_range().end <= ept::mirror_range().start);
+    }
+
+    #[test]
+    fn context_entries() {
+        let entry = ContextEntry::new(pf(0x1234_5000), 7);
+        assert_eq!(entry.0, 0x0000_0000_0000_0702_0000_0000_1234_5001);
+        assert_eq!(entry.iopt().addr(), 0x1234_5000);
+        assert_eq!(entry.translation_type(), Ok(TranslationType::Untranslated));
+        let mut table = ContextTable::empty();
+        table.set(3, 1, entry);
+        assert!(table.entry(3, 1).present());
+        assert!(!table.entry(3, 0).present());
+        assert_eq!(table.0[25].domain_id(), 7);
+
+        let mut root = RootTable::empty();
+        root.set(0x80, pf(0xabc000));
+        assert_eq!(root.entry(0x80).0, 0xabc001);
+        assert_eq!(root.entry(0x80).context_table().addr(), 0xabc000);
+        assert!(!root.entry(0).present());
+    }
+
+    #[test]
+    fn iopte_debug() {
+        let flags = IOPTEFlags::READ | IOPTEFlags::WRITE | IOPTEFlags::SNOOP;
+        let iopte = IOPTE::new(HPA::new(0xabc000), flags);
+        assert_eq!(format!("{:?}", iopte), "0xabc000:S---WR");
+        assert!(iopte.is_present());
+        assert!(!IOPTE::new(HPA::new(0xabc000), IOPTEFlags::SNOOP).is_present());
+    }
+
+    #[test]
+    fn paired_tables() {
+        let mmu = SoftMmu::new(128);
+        let roots = alloc_pair(&mmu).unwrap();
+        mount_pair_in(&mmu, roots, &mut || mmu.alloc()).unwrap();
+        let l2pt_flags = EPTEFlags::READ | EPTEFlags::WRITE | EPTEFlags::EXEC;
+        let iopt_flags = IOPTEFlags::READ | IOPTEFlags::WRITE;
+        let gpas = [GPA::new(0x20_0000), GPA::new(0x20_1000), GPA::new(0x80_4000_0000)];
+        for (k, &gpa) in gpas.iter().enumerate() {
+            let hpf = pf(0x4000_0000 + k as u64 * 0x1000);
+            map_pair_in(
+                &mmu,
+                hpf,
+                gpa,
+                l2pt_flags,
+                iopt_flags,
+                MemoryType::WriteBack,
+                &mut || alloc_pair(&mmu),
+                &mut || mmu.alloc(),
+            )
+            .unwrap();
+        }
+        for (k, &gpa) in gpas.iter().enumerate() {
+            let expected = Some(0x4000_0000 + k as u64 * 0x1000);
+            assert_eq!(pa(ept::translate_in(&mmu, gpa)), expected);
+            assert_eq!(pa(translate_in(&mmu, gpa)), expected);
+        }
+
+        // Each IOPT table sits immediately after its L2PT twin.
+        let leaf_table = |base: V4KA, gpa: GPA| {
+            let va = base.addr() + (gpa.addr() as usize >> 12) * 8;
+            pa(vm::translate_in(&mmu, va & !0xFFF))
+        };
+        for gpa in gpas {
+            let l2pt = leaf_table(ept::mirror_range().start, gpa);
+            assert_eq!(leaf_table(mirror_range().start, gpa), l2pt.map(|a| a + 4096));
+            let e2 = Level2::iopte_ref(&mmu, gpa);
+            assert_eq!(leaf_table(mirror_range().start, gpa), Some(e2.pfa().addr()));
+        }
+        let root = vm::translate_in(&mmu, Level4::table_address(GPA::new(0)).addr());
+        assert_eq!(pa(root), Some(roots.iopt().pfa().addr()));
+
+        unmap_in(&mmu, gpas[0]).unwrap();
+        assert_eq!(pa(translate_in(&mmu, gpas[0])), None);
+        assert_eq!(pa(ept::translate_in(&mmu, gpas[0])), Some(0x4000_0000));
+    }
+
+    #[test]
+    fn unpaired_tables() {
+        let mmu = SoftMmu::new(64);
+        let roots = alloc_pair(&mmu).unwrap();
+        mount_pair_in(&mmu, roots, &mut || mmu.alloc()).unwrap();
+        let gpa = GPA::new(0x4000_0000);
+        let flags = EPTEFlags::READ;
+        ept::map_in(&mmu, pf(0x1000), gpa, flags, MemoryType::WriteBack, &mut || mmu.alloc())
+            .unwrap();
+        let r = map_pair_in(
+            &mmu,
+            pf(0x2000),
+            gpa.offset(0x1000),
+            flags,
+            IOPTEFlags::READ,
+            MemoryType::WriteBack,
+            &mut || alloc_pair(&mmu),
+            &mut || mmu.alloc(),
+        );
+        assert_eq!(r, Err("unpaired tables"));
+    }
+
+    #[test]
+    fn failed_pairs_leave_tables_paired() {
+        let flags = EPTEFlags::READ;
+        let gpa = GPA::new(0x80_4000_0000);
+        type Pairs<'a> = &'a mut dyn FnMut() -> Result<TablePair>;
+        type Frames<'a> = &'a mut dyn FnMut() -> Result<PF4K>;
+        let map = |mmu: &SoftMmu, pairs: Pairs<'_>, allocator: Frames<'_>| {
+            map_pair_in(
+                mmu,
+                pf(0x5000),
+                gpa,
+                flags,
+                IOPTEFlags::READ,
+                MemoryType::WriteBack,
+                &mut || pairs(),
+                &mut || allocator(),
+            )
+        };
+        let paired = |mmu: &SoftMmu| {
+            assert_eq!(pa(ept::translate_in(mmu, gpa)), None);
+            assert_eq!(pa(translate_in(mmu, gpa)), None);
+            assert_eq!(ept::missing_tables(mmu, gpa), missing_tables(mmu, gpa));
+        };
+
+        // The pair allocator fails on its second call.
+        let mmu = SoftMmu::new(64);
+        mount_pair_in(&mmu, alloc_pair(&mmu).unwrap(), &mut || mmu.alloc()).unwrap();
+        let mut calls = 0;
+        let mut pairs = || {
+            calls += 1;
+            if calls == 2 { Err("out of pairs") } else { alloc_pair(&mmu) }
+        };
+        assert_eq!(map(&mmu, &mut pairs, &mut || mmu.alloc()), Err("out of pairs"));
+        paired(&mmu);
+        map(&mmu, &mut || alloc_pair(&mmu), &mut || mmu.alloc()).unwrap();
+        assert_eq!(pa(translate_in(&mmu, gpa)), Some(0x5000));
+
+        // The host allocator fails at each point in turn, while
+        // making the mirror of either tree.
+        for fail in 0.. {
+            let mmu = SoftMmu::new(64);
+            mount_pair_in(&mmu, alloc_pair(&mmu).unwrap(), &mut || mmu.alloc()).unwrap();
+            let mut calls = 0;
+            let mut allocator = || {
+                calls += 1;
+                if calls > fail { Err("out of frames") } else { mmu.alloc() }
+            };
+            if map(&mmu, &mut || alloc_pair(&mmu), &mut allocator).is_ok() {
+                assert!(fail > 0);
+                break;
+            }
+            paired(&mmu);
+            map(&mmu, &mut || alloc_pair(&mmu), &mut || mmu.alloc()).unwrap();
+            assert_eq!(pa(ept::translate_in(&mmu, gpa)), Some(0x5000));
+        }
+    }
+}
@@ -5,8 +5,29 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-use crate::ProcessorID;
+//! # Local APIC
+//!
+//! The local APIC is accessed in one of two ways: in x2APIC
+//! mode, through MSRs, or in xAPIC mode, through a 4KiB window
+//! of memory-mapped registers at the physical address given in
+//! IA32_APIC_BASE.  We use x2APIC mode wherever the CPU supports
+//! it, falling back to xAPIC mode elsewhere.  The two expose
+//! the same registers, save that xAPIC destinations are only 8
+//! bits wide, and the xAPIC ICR is written in two halves and
+//! reports when an IPI has been delivered.
+//!
+//! Each binary has its own copy of the register window address,
+//! null in x2APIC mode.  The loader enables the APIC, mapping the
+//! window if needed.  It shares the window into the address space
+//! of each binary it loads, and publishes its address by writing
+//! it into the binary's copy of `XAPIC_REGS`, which is exported
+//! under `XAPIC_REGS_SYMBOL` for that purpose, so that every
+//! binary accesses the APIC in the same mode.
+
+use crate::cpuid::{self, Features};
+use crate::{HPA, ProcessorID};
 use bitstruct::bitstruct;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use seq_macro::seq;
 
 #[allow(clippy::upper_case_acronyms)]
@@ -150,10 +171,195 @@ impl bitstruct::IntoRaw<bool, TriggerMode> for ICR {
     }
 }
 
-/// Writes to the ICR MSR.
+/// The timer modes selectable in the timer LVT entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
+
+bitstruct! {
+    /// Represents an entry in the Local Vector Table.  Not every
+    /// field is meaningful for every entry: the delivery mode is
+    /// reserved for the timer and error entries, and the timer
+    /// mode only exists in the timer entry.
+    #[derive(Clone, Copy, Default)]
+    pub struct LVT(pub u32) {
+        pub vector: u8 = 0..8;
+        raw_delivery_mode: u8 = 8..11;
+        pub pending: bool = 12;
+        pub masked: bool = 16;
+        raw_timer_mode: u8 = 17..19;
+    }
+}
+
+impl LVT {
+    /// Returns a masked entry, as the LVT holds after reset.
+    #[must_use]
+    pub fn new() -> LVT {
+        LVT(0).with_masked(true)
+    }
+
+    #[must_use]
+    pub fn with_delivery_mode(self, mode: DeliveryMode) -> LVT {
+        self.with_raw_delivery_mode(mode as u8)
+    }
+
+    #[must_use]
+    pub fn with_timer_mode(self, mode: TimerMode) -> LVT {
+        self.with_raw_timer_mode(mode as u8)
+    }
+
+    pub fn timer_mode(self) -> Result<TimerMode, u8> {
+        match self.raw_timer_mode() {
+            0b00 => Ok(TimerMode::OneShot),
+            0b01 => Ok(TimerMode::Periodic),
+            0b10 => Ok(TimerMode::TscDeadline),
+            o => Err(o),
+        }
+    }
+}
+
+/// The entries in the Local Vector Table.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug)]
+pub enum LocalVector {
+    CMCI,
+    Timer,
+    Thermal,
+    PerformanceCounter,
+    LINT0,
+    LINT1,
+    Error,
+}
+
+impl LocalVector {
+    fn register(self) -> Register {
+        match self {
+            Self::CMCI => Register::LvtCmci,
+            Self::Timer => Register::LvtTimer,
+            Self::Thermal => Register::LvtThermal,
+            Self::PerformanceCounter => Register::LvtPerformanceCounter,
+            Self::LINT0 => Register::LvtLint0,
+            Self::LINT1 => Register::LvtLint1,
+            Self::Error => Register::LvtError,
+        }
+    }
+}
+
+/// The means by which the local APIC is accessed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    XApic,
+    X2Apic,
+}
+
+/// The local APIC registers, as offsets into the xAPIC register
+/// window.  The corresponding x2APIC MSRs are numbered from
+/// 0x800 by the offset divided by 16.
+#[derive(Clone, Copy, Debug)]
+enum Register {
+    Id = 0x020,
+    Eoi = 0x0B0,
+    SpuriousVector = 0x0F0,
+    ErrorStatus = 0x280,
+    LvtCmci = 0x2F0,
+    IcrLow = 0x300,
+    IcrHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtThermal = 0x330,
+    LvtPerformanceCounter = 0x340,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivideConfig = 0x3E0,
+}
+
+impl Register {
+    fn offset(self) -> usize {
+        self as usize
+    }
+
+    fn msr(self) -> u32 {
+        0x800 + (self as u32 >> 4)
+    }
+}
+
+/// The name under which each binary exports its copy of the
+/// published xAPIC register window address.
+pub const XAPIC_REGS_SYMBOL: &str = "hypatia_xapic_regs";
+
+/// The xAPIC register window, or null in x2APIC mode.
+#[unsafe(export_name = "hypatia_xapic_regs")]
+pub static XAPIC_REGS: AtomicPtr<u32> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Returns the mode in which the local APIC is accessed.
+pub fn mode() -> Mode {
+    if XAPIC_REGS.load(Ordering::Relaxed).is_null() { Mode::X2Apic } else { Mode::XApic }
+}
+
+fn read(reg: Register) -> u32 {
+    let regs = XAPIC_REGS.load(Ordering::Relaxed);
+    unsafe {
+        if regs.is_null() {
+            x86::msr::rdmsr(reg.msr()) as u32
+        } else {
+            regs.byte_add(reg.offset()).read_volatile()
+        }
+    }
+}
+
+unsafe fn write(reg: Register, value: u32) {
+    let regs = XAPIC_REGS.load(Ordering::Relaxed);
+    unsafe {
+        if regs.is_null() {
+            x86::msr::wrmsr(reg.msr(), value.into());
+        } else {
+            regs.byte_add(reg.offset()).write_volatile(value);
+        }
+    }
+}
+
+/// The values by which the timer may divide the bus clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+// Splits an ICR into the high and low halves of the xAPIC ICR,
+// whose destination is the top 8 bits of the high half.
+fn xapic_icr(icr: ICR) -> (u32, u32) {
+    let destination = u8::try_from(icr.destination()).expect("xAPIC destination fits in 8 bits");
+    (u32::from(destination) << 24, icr.0 as u32)
+}
+
+/// Writes to the ICR.  In xAPIC mode, waits for the IPI to be
+/// delivered, as the ICR may not be rewritten until it has.
 unsafe fn write_icr(icr: ICR) {
+    const DELIVERY_PENDING: u32 = 1 << 12;
+    if mode() == Mode::X2Apic {
+        unsafe {
+            x86::msr::wrmsr(x86::msr::IA32_X2APIC_ICR, icr.0);
+        }
+        return;
+    }
+    let (high, low) = xapic_icr(icr);
     unsafe {
-        x86::msr::wrmsr(x86::msr::IA32_X2APIC_ICR, icr.0);
+        write(Register::IcrHigh, high);
+        write(Register::IcrLow, low);
+    }
+    while read(Register::IcrLow) & DELIVERY_PENDING != 0 {
+        crate::cpu::relax();
     }
 }
 
@@ -164,12 +370,208 @@ seq!(N in 32..=255 {
     }
 });
 
+/// The vector the local APIC delivers for spurious interrupts.
+/// These are not acknowledged.
+pub const SPURIOUS_VECTOR: InterruptVector = InterruptVector::Vector255;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Enables the local APIC, in x2APIC mode if the CPU supports
+/// it, and otherwise in xAPIC mode, in which case the given
+/// function is called to map the register window at the given
+/// physical address, uncached.  Returns the mode chosen.
+///
+/// # Safety
+/// The mapping returned must be valid for the life of the
+/// binary.
+pub unsafe fn enable<F>(map: F) -> Mode
+where
+    F: FnOnce(HPA) -> *mut u32,
+{
+    if cpuid::has(Features::X2APIC) {
+        enable_x2apic();
+        return Mode::X2Apic;
+    }
+    let apic_base = unsafe { x86::msr::rdmsr(x86::msr::IA32_APIC_BASE) };
+    let regs = map(HPA::new(apic_base & APIC_BASE_ADDR_MASK));
+    unsafe {
+        x86::msr::wrmsr(x86::msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+        enable_xapic(regs);
+    }
+    Mode::XApic
+}
+
+/// Enables the local APIC in x2APIC mode.
 pub fn enable_x2apic() {
+    assert!(cpuid::has(Features::X2APIC), "x2APIC not supported");
     let apic_base = unsafe { x86::msr::rdmsr(x86::msr::IA32_APIC_BASE) };
-    let apic_base = apic_base | (0b11 << 10);
+    let apic_base = apic_base | APIC_BASE_ENABLE | APIC_BASE_X2APIC;
     unsafe {
         x86::msr::wrmsr(x86::msr::IA32_APIC_BASE, apic_base);
     }
+    XAPIC_REGS.store(core::ptr::null_mut(), Ordering::Relaxed);
+}
+
+/// Accesses the local APIC in xAPIC mode, through the register
+/// window mapped at the given address.  This does not change
+/// the mode of the APIC itself, which must already be enabled
+/// in xAPIC mode; binaries loaded after the APIC was enabled
+/// may use this to adopt the same mode.
+///
+/// # Safety
+/// The address must map the local APIC's register window,
+/// uncached, for the life of the binary.
+pub unsafe fn enable_xapic(regs: *mut u32) {
+    assert!(!regs.is_null());
+    XAPIC_REGS.store(regs, Ordering::Relaxed);
+}
+
+/// Returns the local APIC ID of the current CPU.  The local
+/// APIC must be enabled.
+pub fn id() -> ProcessorID {
+    match mode() {
+        Mode::X2Apic => ProcessorID(read(Register::Id)),
+        Mode::XApic => ProcessorID(read(Register::Id) >> 24),
+    }
+}
+
+/// Signals the end of the interrupt currently in service.
+///
+/// # Safety
+/// Must only be called from the handler for an interrupt
+/// delivered by the local APIC.
+pub unsafe fn eoi() {
+    unsafe {
+        write(Register::Eoi, 0);
+    }
+}
+
+/// Sets the spurious interrupt vector and software-enables the
+/// local APIC.  Until this is done, every LVT entry is masked.
+///
+/// # Safety
+/// The vector must have a handler installed.
+pub unsafe fn software_enable(spurious: InterruptVector) {
+    const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+    unsafe {
+        write(Register::SpuriousVector, APIC_SOFTWARE_ENABLE | spurious as u32);
+    }
+}
+
+/// Reads an entry in the Local Vector Table.
+pub fn read_lvt(entry: LocalVector) -> LVT {
+    LVT(read(entry.register()))
+}
+
+/// Writes an entry in the Local Vector Table.
+///
+/// # Safety
+/// The vector and delivery mode must be appropriate for the
+/// entry, and any vector that may be delivered must have a
+/// handler.  Writing the timer entry may start or stop the
+/// timer.
+pub unsafe fn write_lvt(entry: LocalVector, lvt: LVT) {
+    unsafe {
+        write(entry.register(), lvt.0);
+    }
+}
+
+/// Routes APIC errors to the given vector.
+///
+/// # Safety
+/// The vector must have a handler installed.
+pub unsafe fn set_error_vector(vector: InterruptVector) {
+    unsafe {
+        write_lvt(LocalVector::Error, LVT(0).with_vector(vector as u8));
+    }
+}
+
+/// Routes thermal monitor interrupts to the given vector.
+///
+/// # Safety
+/// The vector must have a handler installed.
+pub unsafe fn set_thermal_vector(vector: InterruptVector) {
+    let lvt = LVT(0).with_vector(vector as u8).with_delivery_mode(DeliveryMode::Fixed);
+    unsafe {
+        write_lvt(LocalVector::Thermal, lvt);
+    }
+}
+
+/// Returns the errors the APIC has detected since the last call,
+/// and clears them.
+pub fn error_status() -> u32 {
+    unsafe {
+        write(Register::ErrorStatus, 0);
+    }
+    read(Register::ErrorStatus)
+}
+
+/// Returns true iff the timer supports TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    cpuid::has(Features::TSC_DEADLINE)
+}
+
+/// Starts the timer counting down from the given count, at the
+/// bus clock rate divided as given.  It interrupts on the given
+/// vector when it reaches zero, and in periodic mode, reloads the
+/// count and starts again.
+///
+/// # Safety
+/// The vector must have a handler installed.
+pub unsafe fn start_timer(
+    vector: InterruptVector,
+    mode: TimerMode,
+    divide: TimerDivide,
+    count: u32,
+) {
+    assert_ne!(mode, TimerMode::TscDeadline, "use start_deadline for TSC-deadline mode");
+    let lvt = LVT(0).with_vector(vector as u8).with_timer_mode(mode);
+    unsafe {
+        write(Register::TimerDivideConfig, divide as u32);
+        write_lvt(LocalVector::Timer, lvt);
+        write(Register::TimerInitialCount, count);
+    }
+}
+
+/// Arms the timer to interrupt on the given vector once the TSC
+/// reaches the given deadline.  A deadline in the past fires at
+/// once.
+///
+/// # Safety
+/// The vector must have a handler installed, and the CPU must
+/// support TSC-deadline mode.
+pub unsafe fn start_deadline(vector: InterruptVector, deadline: u64) {
+    let lvt = LVT(0).with_vector(vector as u8).with_timer_mode(TimerMode::TscDeadline);
+    unsafe {
+        if read_lvt(LocalVector::Timer).0 != lvt.0 {
+            write_lvt(LocalVector::Timer, lvt);
+            // Neither writes to x2APIC MSRs nor to the xAPIC
+            // window are ordered with the deadline MSR write, so
+            // order the mode change before it.
+            core::arch::asm!("mfence; lfence", options(nostack, preserves_flags));
+        }
+        x86::msr::wrmsr(x86::msr::IA32_TSC_DEADLINE, deadline);
+    }
+}
+
+/// Stops the timer and masks its LVT entry.
+pub fn stop_timer() {
+    let lvt = read_lvt(LocalVector::Timer);
+    unsafe {
+        match lvt.timer_mode() {
+            Ok(TimerMode::TscDeadline) => x86::msr::wrmsr(x86::msr::IA32_TSC_DEADLINE, 0),
+            _ => write(Register::TimerInitialCount, 0),
+        }
+        write_lvt(LocalVector::Timer, lvt.with_masked(true));
+    }
+}
+
+/// Returns the current count of the timer in one-shot or
+/// periodic mode.
+pub fn timer_count() -> u32 {
+    read(Register::TimerCurrentCount)
 }
 
 /// Sends an edge-triggered normal interrupt to a CPU.
@@ -240,7 +642,7 @@ pub unsafe fn send_sipi(cpu: ProcessorID, vector: u8) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use core::assert_matches::assert_matches;
+    use core::assert_matches;
 
     #[test]
     fn fixed() {
@@ -307,4 +709,65 @@ mod tests {
         assert_matches!(icr.trigger_mode(), TriggerMode::Edge);
         assert_matches!(icr.destination(), 0);
     }
+    #[test]
+    fn timer_lvt() {
+        let lvt = LVT(0).with_vector(0xEF).with_timer_mode(TimerMode::TscDeadline);
+        assert_eq!(lvt.0, 0x0004_00ef);
+        assert_eq!(lvt.timer_mode(), Ok(TimerMode::TscDeadline));
+        let lvt = lvt.with_timer_mode(TimerMode::Periodic).with_masked(true);
+        assert_eq!(lvt.0, 0x0003_00ef);
+        assert_eq!(LVT(0x0006_0000).timer_mode(), Err(0b11));
+        assert!(LVT::new().masked());
+    }
+
+    #[test]
+    fn thermal_lvt() {
+        let lvt = LVT(0).with_vector(0x20).with_delivery_mode(DeliveryMode::NMI);
+        assert_eq!(lvt.0, 0x0000_0420);
+        assert!(!LVT(0x0000_1420).masked());
+        assert!(LVT(0x0000_1420).pending());
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(Register::Id.msr(), x86::msr::IA32_X2APIC_APICID);
+        assert_eq!(Register::Eoi.msr(), x86::msr::IA32_X2APIC_EOI);
+        assert_eq!(Register::SpuriousVector.msr(), x86::msr::IA32_X2APIC_SIVR);
+        assert_eq!(Register::ErrorStatus.msr(), x86::msr::IA32_X2APIC_ESR);
+        assert_eq!(Register::IcrLow.msr(), x86::msr::IA32_X2APIC_ICR);
+        assert_eq!(LocalVector::CMCI.register().msr(), x86::msr::IA32_X2APIC_LVT_CMCI);
+        assert_eq!(LocalVector::Timer.register().msr(), x86::msr::IA32_X2APIC_LVT_TIMER);
+        assert_eq!(LocalVector::Thermal.register().msr(), x86::msr::IA32_X2APIC_LVT_THERMAL);
+        assert_eq!(LocalVector::PerformanceCounter.register().msr(), x86::msr::IA32_X2APIC_LVT_PMI);
+        assert_eq!(LocalVector::LINT0.register().msr(), x86::msr::IA32_X2APIC_LVT_LINT0);
+        assert_eq!(LocalVector::LINT1.register().msr(), x86::msr::IA32_X2APIC_LVT_LINT1);
+        assert_eq!(LocalVector::Error.register().msr(), x86::msr::IA32_X2APIC_LVT_ERROR);
+        assert_eq!(Register::TimerInitialCount.msr(), x86::msr::IA32_X2APIC_INIT_COUNT);
+        assert_eq!(Register::TimerCurrentCount.msr(), x86::msr::IA32_X2APIC_CUR_COUNT);
+        assert_eq!(Register::TimerDivideConfig.msr(), x86::msr::IA32_X2APIC_DIV_CONF);
+    }
+
+    #[test]
+    fn xapic_split() {
+        let icr =
+            ICR(0).with_vector(0xFE).with_delivery_mode(DeliveryMode::Fixed).with_destination(2);
+        assert_eq!(xapic_icr(icr), (0x0200_0000, 0x0000_00fe));
+        let icr = ICR::new()
+            .with_delivery_mode(DeliveryMode::Init)
+            .with_destination_shorthand(Some(DestinationShorthand::AllButSelf));
+        assert_eq!(xapic_icr(icr), (0, 0x000c_0500));
+    }
+
+    #[test]
+    #[should_panic]
+    fn xapic_wide_destination() {
+        xapic_icr(ICR::new().with_destination(0x100));
+    }
+
+    #[test]
+    fn timer_divide() {
+        assert_eq!(TimerDivide::By1 as u32, 0b1011);
+        assert_eq!(TimerDivide::By16 as u32, 0b0011);
+        assert_eq!(TimerDivide::By128 as u32, 0b1010);
+    }
 }
@@ -56,16 +56,34 @@ use core::fmt::Debug;
 use core::iter::Step;
 use zerocopy::FromBytes;
 
+pub mod backtrace;
 pub mod cpu;
+pub mod cpuid;
 pub(crate) mod debug;
+pub mod ept;
 pub mod gdt;
 pub mod idt;
 pub mod io;
+pub mod ioapic;
+pub mod iopt;
 pub mod lapic;
+pub mod mca;
+pub mod mmio;
+pub mod mtrr;
+pub mod pat;
+pub mod pcid;
+pub mod percpu;
+pub mod pic;
 pub mod segment;
+pub mod shootdown;
+pub mod sync;
+pub mod taint;
 pub mod trap;
+pub mod tsc;
 pub mod tss;
 pub mod vm;
+pub mod vmx;
+pub mod xsave;
 
 /// Useful constants for sizes.
 pub const TIB: usize = 1 << 40;
@@ -102,6 +120,60 @@ impl HPA {
     }
 }
 
+/// Guest Physical Address
+///
+/// A newtype representing an address in the physical address
+/// space of a virtual machine, as described by its second-level
+/// page table.  Guest physical addresses are in bijection with
+/// host virtual addresses in the lower half of the address space,
+/// but are only mapped there when the host must access them.
+#[derive(Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct GPA(u64);
+
+impl GPA {
+    /// Creates a new GPA from a u64.
+    pub const fn new(addr: u64) -> GPA {
+        GPA(addr)
+    }
+
+    /// Returns the address of this GPA as a u64.
+    pub const fn addr(self) -> u64 {
+        self.0
+    }
+
+    #[must_use]
+    pub const fn offset(self, offset: usize) -> GPA {
+        GPA::new(self.0 + offset as u64)
+    }
+}
+
+/// Memory types, with their architectural encodings.  These
+/// are common to the PAT, the MTRRs and the EPT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtect = 5,
+    WriteBack = 6,
+}
+
+impl TryFrom<u8> for MemoryType {
+    type Error = &'static str;
+    fn try_from(raw: u8) -> Result<Self, Self::Error> {
+        match raw {
+            0 => Ok(MemoryType::Uncacheable),
+            1 => Ok(MemoryType::WriteCombining),
+            4 => Ok(MemoryType::WriteThrough),
+            5 => Ok(MemoryType::WriteProtect),
+            6 => Ok(MemoryType::WriteBack),
+            _ => Err("reserved memory type"),
+        }
+    }
+}
+
 /// Page represents a page of some size that is mapped into
 /// the virtual address space.
 pub trait Page {
@@ -193,11 +265,34 @@ impl Page for Page512G {
     }
 }
 
+/// As for Page512G, the alignment cannot be expressed.
+#[repr(C)]
+pub struct Page256T([u8; 256 * TIB]);
+impl Page for Page256T {
+    const SIZE: usize = core::mem::size_of::<Self>();
+    type FrameType = PF256T;
+    type VPageAddrType = V256TA;
+
+    fn vaddr(&self) -> V256TA {
+        V256TA(self.0.as_ptr().addr())
+    }
+}
+
 pub trait PageFrame {
     type PageType: Page;
     fn new(pfa: HPA) -> Self;
 }
 
+#[derive(Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct PF256T(HPA);
+impl PageFrame for PF256T {
+    type PageType = Page256T;
+    fn new(pfa: HPA) -> Self {
+        Self(pfa)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
 pub struct PF512G(HPA);
@@ -301,26 +396,17 @@ impl Step for V4KA {
         let bck = start.0.checked_sub(diff)?;
         Some(V4KA(bck))
     }
-}
-
-#[cfg(test)]
-mod v4ka_tests {
-    use super::*;
-
-    #[test]
-    fn steps_between_works() {
-        let start = V4KA::new(0);
-        let end = V4KA::new(0);
-        assert_eq!(V4KA::steps_between(&start, &end), (0, Some(0)));
-
-        let end = V4KA::new_round_up(1);
-        assert_eq!(end.addr(), 4096);
-        assert_eq!(V4KA::steps_between(&start, &end), (1, Some(1)));
 
-        let end = V4KA::new(16 * KIB);
-        assert_eq!(V4KA::steps_between(&start, &end), (4, Some(4)));
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page4K::SIZE);
+        let (fwd, o2) = start.0.overflowing_add(diff);
+        (V4KA(fwd), o1 || o2)
+    }
 
-        assert_eq!(V4KA::steps_between(&end, &start), (0, None));
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page4K::SIZE);
+        let (bck, o2) = start.0.overflowing_sub(diff);
+        (V4KA(bck), o1 || o2)
     }
 }
 
@@ -360,6 +446,18 @@ impl Step for V2MA {
         let bck = start.0.checked_sub(diff)?;
         Some(V2MA(bck))
     }
+
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page2M::SIZE);
+        let (fwd, o2) = start.0.overflowing_add(diff);
+        (V2MA(fwd), o1 || o2)
+    }
+
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page2M::SIZE);
+        let (bck, o2) = start.0.overflowing_sub(diff);
+        (V2MA(bck), o1 || o2)
+    }
 }
 
 /// A type representing a 1GiB aligned address.
@@ -398,6 +496,18 @@ impl Step for V1GA {
         let bck = start.0.checked_sub(diff)?;
         Some(V1GA(bck))
     }
+
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page1G::SIZE);
+        let (fwd, o2) = start.0.overflowing_add(diff);
+        (V1GA(fwd), o1 || o2)
+    }
+
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page1G::SIZE);
+        let (bck, o2) = start.0.overflowing_sub(diff);
+        (V1GA(bck), o1 || o2)
+    }
 }
 
 /// A type representing a 512GiB aligned address.
@@ -436,6 +546,68 @@ impl Step for V512GA {
         let bck = start.0.checked_sub(diff)?;
         Some(V512GA(bck))
     }
+
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page512G::SIZE);
+        let (fwd, o2) = start.0.overflowing_add(diff);
+        (V512GA(fwd), o1 || o2)
+    }
+
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page512G::SIZE);
+        let (bck, o2) = start.0.overflowing_sub(diff);
+        (V512GA(bck), o1 || o2)
+    }
+}
+
+/// A type representing a 256TiB aligned address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd)]
+#[repr(transparent)]
+pub struct V256TA(usize);
+
+impl VPageAddr for V256TA {
+    type PageType = Page256T;
+
+    fn new(va: usize) -> V256TA {
+        assert_eq!(va & Page256T::MASK, 0);
+        V256TA(va)
+    }
+
+    fn addr(self) -> usize {
+        self.0
+    }
+}
+
+impl Step for V256TA {
+    fn steps_between(start: &V256TA, end: &V256TA) -> (usize, Option<usize>) {
+        let start = start.0 / Page256T::SIZE;
+        let end = end.0 / Page256T::SIZE;
+        usize::steps_between(&start, &end)
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        let diff = count.checked_mul(Page256T::SIZE)?;
+        let fwd = start.0.checked_add(diff)?;
+        Some(V256TA(fwd))
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        let diff = count.checked_mul(Page256T::SIZE)?;
+        let bck = start.0.checked_sub(diff)?;
+        Some(V256TA(bck))
+    }
+
+    fn forward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page256T::SIZE);
+        let (fwd, o2) = start.0.overflowing_add(diff);
+        (V256TA(fwd), o1 || o2)
+    }
+
+    fn backward_overflowing(start: Self, count: usize) -> (Self, bool) {
+        let (diff, o1) = count.overflowing_mul(Page256T::SIZE);
+        let (bck, o2) = start.0.overflowing_sub(diff);
+        (V256TA(bck), o1 || o2)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -516,4 +688,40 @@ impl IOAPIC {
     pub fn new(id: u32, hpa: HPA, gsib: u32) -> IOAPIC {
         IOAPIC { id, hpa, gsib }
     }
+
+    /// Returns the I/O APIC's ID.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the physical address of the register window.
+    pub fn hpa(&self) -> HPA {
+        self.hpa
+    }
+
+    /// Returns the first GSI routed by the I/O APIC.
+    pub fn gsib(&self) -> u32 {
+        self.gsib
+    }
+}
+
+#[cfg(test)]
+mod v4ka_tests {
+    use super::*;
+
+    #[test]
+    fn steps_between_works() {
+        let start = V4KA::new(0);
+        let end = V4KA::new(0);
+        assert_eq!(V4KA::steps_between(&start, &end), (0, Some(0)));
+
+        let end = V4KA::new_round_up(1);
+        assert_eq!(end.addr(), 4096);
+        assert_eq!(V4KA::steps_between(&start, &end), (1, Some(1)));
+
+        let end = V4KA::new(16 * KIB);
+        assert_eq!(V4KA::steps_between(&start, &end), (4, Some(4)));
+
+        assert_eq!(V4KA::steps_between(&end, &start), (0, None));
+    }
 }
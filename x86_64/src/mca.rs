@@ -0,0 +1,774 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Machine-check architecture
+//!
+//! The CPU reports hardware errors through a set of banks of
+//! MSRs, each covering some unit such as a cache or memory
+//! controller.  A bank with a valid error holds its status in
+//! MCi_STATUS, and possibly the address involved in MCi_ADDR and
+//! further details in MCi_MISC.
+//!
+//! Uncorrected errors raise a machine-check exception, #MC,
+//! which is taken on its own interrupt stack, since it may
+//! arrive at any point.  The handler records every error in the
+//! banks, and returns only if the errors need no action and the
+//! interrupted context may be restarted; otherwise the error is
+//! fatal.  Corrected errors raise no exception.  Where the CPU
+//! supports it, a bank may signal them with a corrected machine
+//! check interrupt, CMCI; otherwise they must be found by polling
+//! the banks periodically, with `poll`.  Not every bank of a CPU
+//! with CMCI need support it, so each CPU polls its banks every
+//! `POLL_INTERVAL` regardless, driven by its local APIC timer.
+//!
+//! Errors are recorded in a `Log`.  The binary handling machine
+//! checks maps one at `LOG_ADDRESS`, in the trace segment's
+//! region above its image, where it may be read after the fact,
+//! and installs it with `set_log`.
+//!
+//! The decoders take a function reading MSRs, so that they may
+//! be exercised with canned values.
+
+use crate::cpuid::{self, Features};
+use crate::lapic::{self, InterruptVector, LVT, LocalVector, TimerDivide, TimerMode};
+use crate::trap;
+use crate::tsc;
+use crate::vm::{self, PTEFlags};
+use crate::{PF4K, Page, Page4K, V4KA, VPageAddr};
+use bitstruct::bitstruct;
+use core::fmt;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86::controlregs::Cr4;
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+/// The exception vector for machine checks.
+pub const MACHINE_CHECK: u8 = 18;
+
+/// The interrupt vector reserved for corrected machine check
+/// interrupts.
+pub const CMCI_VECTOR: InterruptVector = InterruptVector::Vector252;
+
+/// The interrupt vector reserved for the timer driving `poll`.
+pub const POLL_VECTOR: InterruptVector = InterruptVector::Vector250;
+
+/// The interval at which each CPU polls its banks.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The address at which the log is mapped: in the trace
+/// segment's region, far above its image.
+pub const LOG_ADDRESS: usize = 0xFFFF_F980_0000_0000;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL2: u32 = 0x280;
+const IA32_MC0_CTL: u32 = 0x400;
+
+// The MSRs of each bank are at consecutive addresses.
+const fn bank_msr(bank: u8, offset: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + offset
+}
+const CTL: u32 = 0;
+const STATUS: u32 = 1;
+const ADDR: u32 = 2;
+const MISC: u32 = 3;
+
+bitstruct! {
+    /// IA32_MCG_CAP: the number of banks, and which optional
+    /// machine-check features the CPU has.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct McgCap(pub u64) {
+        pub count: u8 = 0..8;
+        pub ctl_present: bool = 8;
+        pub ext_present: bool = 9;
+        pub cmci: bool = 10;
+        pub threshold_status: bool = 11;
+        pub ext_count: u8 = 16..24;
+        pub software_recovery: bool = 24;
+        pub emc: bool = 25;
+        pub elog: bool = 26;
+        pub lmce: bool = 27;
+    }
+}
+
+bitstruct! {
+    /// IA32_MCG_STATUS: the state of the CPU when a machine check
+    /// was raised.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct McgStatus(pub u64) {
+        pub restart_ip_valid: bool = 0;
+        pub error_ip_valid: bool = 1;
+        pub in_progress: bool = 2;
+        pub local: bool = 3;
+    }
+}
+
+bitstruct! {
+    /// MCi_STATUS: the error held by a bank.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Status(pub u64) {
+        pub mca_code: u16 = 0..16;
+        pub model_code: u16 = 16..32;
+        pub corrected_count: u16 = 38..53;
+        pub threshold: u8 = 53..55;
+        pub action_required: bool = 55;
+        pub signaling: bool = 56;
+        pub context_corrupt: bool = 57;
+        pub addr_valid: bool = 58;
+        pub misc_valid: bool = 59;
+        pub enabled: bool = 60;
+        pub uncorrected: bool = 61;
+        pub overflow: bool = 62;
+        pub valid: bool = 63;
+    }
+}
+
+bitstruct! {
+    /// MCi_MISC: how to interpret MCi_ADDR, where the CPU
+    /// supports software error recovery.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Misc(pub u64) {
+        pub addr_lsb: u8 = 0..6;
+        pub addr_mode: u8 = 6..9;
+    }
+}
+
+/// How serious an error is, in increasing order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The hardware corrected the error.
+    Corrected,
+    /// An uncorrected error that no software need act upon,
+    /// such as one found by a memory scrubber (UCNA).
+    Uncorrected,
+    /// An uncorrected error that software should act upon, but
+    /// that did not affect the interrupted context (SRAO).
+    ActionOptional,
+    /// An uncorrected error that affected the interrupted
+    /// context, which cannot continue without recovery (SRAR).
+    ActionRequired,
+    /// An error from which there is no recovery.
+    Fatal,
+}
+
+impl Status {
+    /// Returns the severity of the error, given whether the CPU
+    /// supports software error recovery.  Without it, every
+    /// uncorrected error is fatal.
+    pub fn severity(self, software_recovery: bool) -> Severity {
+        match (self.uncorrected(), self.signaling(), self.action_required()) {
+            (false, _, _) => Severity::Corrected,
+            _ if self.context_corrupt() || !software_recovery => Severity::Fatal,
+            (true, false, _) => Severity::Uncorrected,
+            (true, true, false) => Severity::ActionOptional,
+            (true, true, true) => Severity::ActionRequired,
+        }
+    }
+
+    /// Returns the decoded architectural error code.
+    pub fn code(self) -> ErrorCode {
+        ErrorCode::decode(self.mca_code())
+    }
+}
+
+/// The architectural error codes in the low half of MCi_STATUS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError,
+    Unclassified,
+    MicrocodeParity,
+    External,
+    Frc,
+    InternalParity,
+    SmmHandler,
+    InternalTimer,
+    Internal,
+    /// An error in the cache hierarchy at the given level.
+    CacheHierarchy {
+        level: u8,
+    },
+    /// A TLB error of the given transaction type and level.
+    Tlb {
+        kind: u8,
+        level: u8,
+    },
+    /// A memory controller error of the given kind on the given
+    /// channel, which is 0xF if unknown.
+    Memory {
+        kind: u8,
+        channel: u8,
+    },
+    /// A cache error for a request of the given kind.
+    Cache {
+        request: u8,
+        kind: u8,
+        level: u8,
+    },
+    /// A bus or interconnect error for a request of the given
+    /// kind.
+    Bus {
+        request: u8,
+        level: u8,
+        timeout: bool,
+    },
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    /// Decodes an error code.  The filtering bit, which only
+    /// says whether corrected errors are reported, is ignored.
+    pub fn decode(code: u16) -> ErrorCode {
+        const FILTER: u16 = 1 << 12;
+        let level = (code & 0b11) as u8;
+        let kind = ((code >> 2) & 0b11) as u8;
+        let request = ((code >> 4) & 0xF) as u8;
+        match code {
+            0x0000 => ErrorCode::NoError,
+            0x0001 => ErrorCode::Unclassified,
+            0x0002 => ErrorCode::MicrocodeParity,
+            0x0003 => ErrorCode::External,
+            0x0004 => ErrorCode::Frc,
+            0x0005 => ErrorCode::InternalParity,
+            0x0006 => ErrorCode::SmmHandler,
+            0x0400 => ErrorCode::InternalTimer,
+            0x0401..=0x07FF => ErrorCode::Internal,
+            _ => match code & !FILTER {
+                c if c & 0xEFFC == 0x000C => ErrorCode::CacheHierarchy { level },
+                c if c & 0xEFF0 == 0x0010 => ErrorCode::Tlb { kind, level },
+                c if c & 0xEF80 == 0x0080 => {
+                    let kind = ((code >> 4) & 0b111) as u8;
+                    ErrorCode::Memory { kind, channel: (code & 0xF) as u8 }
+                }
+                c if c & 0xEF00 == 0x0100 => ErrorCode::Cache { request, kind, level },
+                c if c & 0xE800 == 0x0800 => {
+                    ErrorCode::Bus { request, level, timeout: code & (1 << 8) != 0 }
+                }
+                _ => ErrorCode::Unknown(code),
+            },
+        }
+    }
+}
+
+fn level_name(level: u8) -> &'static str {
+    ["L0", "L1", "L2", "generic"][usize::from(level & 0b11)]
+}
+
+fn kind_name(kind: u8) -> &'static str {
+    ["instruction", "data", "generic", "reserved"][usize::from(kind & 0b11)]
+}
+
+fn request_name(request: u8) -> &'static str {
+    match request {
+        0 => "generic",
+        1 => "read",
+        2 => "write",
+        3 => "data read",
+        4 => "data write",
+        5 => "fetch",
+        6 => "prefetch",
+        7 => "eviction",
+        8 => "snoop",
+        _ => "reserved",
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ErrorCode::NoError => write!(f, "no error"),
+            ErrorCode::Unclassified => write!(f, "unclassified error"),
+            ErrorCode::MicrocodeParity => write!(f, "microcode ROM parity error"),
+            ErrorCode::External => write!(f, "external error"),
+            ErrorCode::Frc => write!(f, "FRC error"),
+            ErrorCode::InternalParity => write!(f, "internal parity error"),
+            ErrorCode::SmmHandler => write!(f, "SMM handler code access violation"),
+            ErrorCode::InternalTimer => write!(f, "internal timer error"),
+            ErrorCode::Internal => write!(f, "internal unclassified error"),
+            ErrorCode::CacheHierarchy { level } => {
+                write!(f, "{} cache hierarchy error", level_name(level))
+            }
+            ErrorCode::Tlb { kind, level } => {
+                write!(f, "{} {} TLB error", level_name(level), kind_name(kind))
+            }
+            ErrorCode::Memory { kind, channel } => {
+                let kind = match kind {
+                    0 => "generic",
+                    1 => "read",
+                    2 => "write",
+                    3 => "address/command",
+                    4 => "scrubbing",
+                    _ => "reserved",
+                };
+                write!(f, "memory controller {kind} error")?;
+                if channel != 0xF {
+                    write!(f, " on channel {channel}")?;
+                }
+                Ok(())
+            }
+            ErrorCode::Cache { request, kind, level } => write!(
+                f,
+                "{} {} cache {} error",
+                level_name(level),
+                kind_name(kind),
+                request_name(request)
+            ),
+            ErrorCode::Bus { request, level, timeout } => {
+                write!(f, "{} bus {} error", level_name(level), request_name(request))?;
+                if timeout {
+                    write!(f, " (timeout)")?;
+                }
+                Ok(())
+            }
+            ErrorCode::Unknown(code) => write!(f, "unknown error {code:#06x}"),
+        }
+    }
+}
+
+/// An error read from a bank.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub cpu: u32,
+    pub bank: u8,
+    pub tsc: u64,
+    pub mcg_status: McgStatus,
+    pub status: Status,
+    pub addr: Option<u64>,
+    pub misc: Option<Misc>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = self.status;
+        write!(f, "cpu {} bank {}: {}", self.cpu, self.bank, status.code())?;
+        write!(f, " status={:#018x}", status.0)?;
+        if let Some(addr) = self.addr {
+            write!(f, " addr={addr:#x}")?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc={:#x}", misc.0)?;
+        }
+        if status.overflow() {
+            write!(f, " (overflow)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the given bank through the given MSR reading function,
+/// returning its error, if it holds a valid one.  The CPU, TSC
+/// and global status are left for the caller to fill in.
+pub fn read_bank<R: Fn(u32) -> u64>(rdmsr: R, bank: u8) -> Option<Record> {
+    let status = Status(rdmsr(bank_msr(bank, STATUS)));
+    if !status.valid() {
+        return None;
+    }
+    let addr = status.addr_valid().then(|| rdmsr(bank_msr(bank, ADDR)));
+    let misc = status.misc_valid().then(|| Misc(rdmsr(bank_msr(bank, MISC))));
+    Some(Record { cpu: 0, bank, tsc: 0, mcg_status: McgStatus(0), status, addr, misc })
+}
+
+/// The number of records a log holds before overwriting the
+/// oldest.
+pub const LOG_SIZE: usize = 64;
+
+// A slot in the log.  `seq` is one more than the index of the
+// record it holds, or zero while it is being written.
+struct Slot {
+    seq: AtomicUsize,
+    cpu_bank: AtomicU64,
+    tsc: AtomicU64,
+    mcg_status: AtomicU64,
+    status: AtomicU64,
+    addr: AtomicU64,
+    misc: AtomicU64,
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            seq: AtomicUsize::new(0),
+            cpu_bank: AtomicU64::new(0),
+            tsc: AtomicU64::new(0),
+            mcg_status: AtomicU64::new(0),
+            status: AtomicU64::new(0),
+            addr: AtomicU64::new(0),
+            misc: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A log of machine-check errors.  Records may be added from any
+/// CPU, including from the #MC handler, and so without locking:
+/// readers detect records overwritten as they read them.
+pub struct Log {
+    next: AtomicUsize,
+    slots: [Slot; LOG_SIZE],
+}
+
+impl Log {
+    /// Returns an empty log.
+    pub const fn new() -> Log {
+        Log { next: AtomicUsize::new(0), slots: [const { Slot::new() }; LOG_SIZE] }
+    }
+
+    /// Adds a record to the log.
+    pub fn record(&self, record: &Record) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[index % LOG_SIZE];
+        slot.seq.store(0, Ordering::Relaxed);
+        core::sync::atomic::fence(Ordering::Release);
+        slot.cpu_bank.store(u64::from(record.cpu) << 8 | u64::from(record.bank), Ordering::Relaxed);
+        slot.tsc.store(record.tsc, Ordering::Relaxed);
+        slot.mcg_status.store(record.mcg_status.0, Ordering::Relaxed);
+        slot.status.store(record.status.0, Ordering::Relaxed);
+        slot.addr.store(record.addr.unwrap_or(0), Ordering::Relaxed);
+        slot.misc.store(record.misc.map_or(0, |misc| misc.0), Ordering::Relaxed);
+        slot.seq.store(index + 1, Ordering::Release);
+    }
+
+    /// Returns the number of records ever added to the log.
+    pub fn count(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    /// Returns the record with the given index, if it is still
+    /// in the log and completely written.
+    pub fn get(&self, index: usize) -> Option<Record> {
+        let slot = &self.slots[index % LOG_SIZE];
+        if slot.seq.load(Ordering::Acquire) != index + 1 {
+            return None;
+        }
+        let cpu_bank = slot.cpu_bank.load(Ordering::Relaxed);
+        let status = Status(slot.status.load(Ordering::Relaxed));
+        let record = Record {
+            cpu: (cpu_bank >> 8) as u32,
+            bank: cpu_bank as u8,
+            tsc: slot.tsc.load(Ordering::Relaxed),
+            mcg_status: McgStatus(slot.mcg_status.load(Ordering::Relaxed)),
+            status,
+            addr: status.addr_valid().then(|| slot.addr.load(Ordering::Relaxed)),
+            misc: status.misc_valid().then(|| Misc(slot.misc.load(Ordering::Relaxed))),
+        };
+        core::sync::atomic::fence(Ordering::Acquire);
+        (slot.seq.load(Ordering::Relaxed) == index + 1).then_some(record)
+    }
+}
+
+impl Default for Log {
+    fn default() -> Log {
+        Log::new()
+    }
+}
+
+static LOG: AtomicPtr<Log> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Installs the log into which errors are recorded.  Until one
+/// is installed, errors are not recorded.
+pub fn set_log(log: &'static Log) {
+    LOG.store(core::ptr::from_ref(log).cast_mut(), Ordering::Release);
+}
+
+/// Maps a log at `LOG_ADDRESS`, with frames taken from the
+/// allocator, which must be zeroed, and installs it.
+pub fn map_log<F>(allocator: &mut F) -> Result<&'static Log>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    const FLAGS: PTEFlags =
+        PTEFlags::PRESENT.union(PTEFlags::WRITE).union(PTEFlags::NX).union(PTEFlags::GLOBAL);
+    let npages = core::mem::size_of::<Log>().div_ceil(Page4K::SIZE);
+    for k in 0..npages {
+        let frame = allocator()?;
+        vm::map(frame, FLAGS, V4KA::new(LOG_ADDRESS + k * Page4K::SIZE), allocator)?;
+    }
+    // Zeroed memory is an empty log.
+    let log = unsafe { &*Page4K::proto_ptr().with_addr(LOG_ADDRESS).cast::<Log>() };
+    set_log(log);
+    Ok(log)
+}
+
+fn log(record: &Record) {
+    let log = LOG.load(Ordering::Acquire);
+    if !log.is_null() {
+        unsafe { &*log }.record(record);
+    }
+}
+
+fn rdmsr(msr: u32) -> u64 {
+    unsafe { x86::msr::rdmsr(msr) }
+}
+
+fn capabilities() -> McgCap {
+    McgCap(rdmsr(IA32_MCG_CAP))
+}
+
+// Reads the given bank of the current CPU, with the details
+// that `read_bank` leaves out.
+fn read_local(bank: u8, mcg_status: McgStatus) -> Option<Record> {
+    read_bank(rdmsr, bank).map(|record| Record {
+        cpu: u32::from(lapic::id()),
+        tsc: crate::tsc::rdtsc(),
+        mcg_status,
+        ..record
+    })
+}
+
+fn clear(bank: u8) {
+    unsafe {
+        x86::msr::wrmsr(bank_msr(bank, STATUS), 0);
+    }
+}
+
+/// Enables error reporting in every bank of the current CPU,
+/// recording and clearing any errors left over from before
+/// reset, and then enables machine-check exceptions.  Where the
+/// CPU supports it, banks signal corrected errors on
+/// `CMCI_VECTOR`.  Must be called on each CPU, after the
+/// handlers for `MACHINE_CHECK` and `CMCI_VECTOR` have been
+/// registered, and the machine-check interrupt stack provided.
+pub fn init() -> Result<McgCap> {
+    const CMCI_ENABLE: u64 = 1 << 30;
+    const CMCI_THRESHOLD: u64 = 1;
+    if !cpuid::has(Features::MCE | Features::MCA) {
+        return Err("CPU lacks machine-check architecture");
+    }
+    let cap = capabilities();
+    let mut cmci = false;
+    unsafe {
+        if cap.ctl_present() {
+            x86::msr::wrmsr(IA32_MCG_CTL, !0);
+        }
+        for bank in 0..cap.count() {
+            if let Some(record) = read_local(bank, McgStatus(0)) {
+                log(&record);
+            }
+            x86::msr::wrmsr(bank_msr(bank, CTL), !0);
+            clear(bank);
+            if cap.cmci() {
+                let msr = IA32_MC0_CTL2 + u32::from(bank);
+                let ctl2 = rdmsr(msr) & !0x7FFF;
+                x86::msr::wrmsr(msr, ctl2 | CMCI_ENABLE | CMCI_THRESHOLD);
+                cmci |= rdmsr(msr) & CMCI_ENABLE != 0;
+            }
+        }
+        x86::msr::wrmsr(IA32_MCG_STATUS, 0);
+        if cmci {
+            lapic::write_lvt(LocalVector::CMCI, LVT(0).with_vector(CMCI_VECTOR as u8));
+        }
+        let cr4 = x86::controlregs::cr4();
+        x86::controlregs::cr4_write(cr4 | Cr4::CR4_ENABLE_MACHINE_CHECK);
+    }
+    Ok(cap)
+}
+
+/// Records and clears the errors in the current CPU's banks that
+/// need no action, returning how many there were.
+pub fn poll() -> usize {
+    let cap = capabilities();
+    let software_recovery = cap.software_recovery();
+    let mut found = 0;
+    for bank in 0..cap.count() {
+        let Some(record) = read_local(bank, McgStatus(0)) else {
+            continue;
+        };
+        if record.status.severity(software_recovery) > Severity::Uncorrected {
+            continue;
+        }
+        log(&record);
+        clear(bank);
+        found += 1;
+    }
+    found
+}
+
+/// The handler for corrected machine check interrupts.
+pub fn handle_cmci(_frame: &mut trap::Frame) {
+    poll();
+}
+
+/// Starts the current CPU's local APIC timer interrupting on
+/// `POLL_VECTOR` every `POLL_INTERVAL`, so that its banks are
+/// polled; the timer is given over to this.  Must be called on
+/// each CPU, after the handler for `POLL_VECTOR` has been
+/// registered and the TSC frequency found.
+pub fn start_polling() {
+    if lapic::has_tsc_deadline() {
+        arm_poll();
+        return;
+    }
+    // Otherwise, measure the bus clock against the TSC, and let
+    // the timer run periodically.
+    const DIVIDE: TimerDivide = TimerDivide::By128;
+    const SAMPLE: Duration = Duration::from_millis(10);
+    unsafe {
+        lapic::start_timer(POLL_VECTOR, TimerMode::OneShot, DIVIDE, u32::MAX);
+    }
+    let end = tsc::rdtsc() + tsc::ticks(SAMPLE);
+    while tsc::rdtsc() < end {
+        crate::cpu::relax();
+    }
+    let elapsed = u128::from(u32::MAX - lapic::timer_count());
+    lapic::stop_timer();
+    let count = elapsed * POLL_INTERVAL.as_nanos() / SAMPLE.as_nanos();
+    let count = u32::try_from(count).unwrap_or(u32::MAX);
+    unsafe {
+        lapic::start_timer(POLL_VECTOR, TimerMode::Periodic, DIVIDE, count);
+    }
+}
+
+fn arm_poll() {
+    unsafe {
+        lapic::start_deadline(POLL_VECTOR, tsc::rdtsc() + tsc::ticks(POLL_INTERVAL));
+    }
+}
+
+/// The handler for the poll timer.
+pub fn handle_poll(_frame: &mut trap::Frame) {
+    poll();
+    if lapic::has_tsc_deadline() {
+        arm_poll();
+    }
+}
+
+/// The handler for machine-check exceptions.  Every error in
+/// the current CPU's banks is recorded.  If they all need no
+/// immediate action, and the interrupted context may restart,
+/// the banks are cleared and the handler returns; otherwise,
+/// the machine check is fatal.
+pub fn handle(frame: &mut trap::Frame) {
+    let cap = capabilities();
+    let mcg_status = McgStatus(rdmsr(IA32_MCG_STATUS));
+    let mut worst: Option<Record> = None;
+    for bank in 0..cap.count() {
+        let Some(record) = read_local(bank, mcg_status) else {
+            continue;
+        };
+        log(&record);
+        let severity = record.status.severity(cap.software_recovery());
+        if worst.is_none_or(|w| w.status.severity(cap.software_recovery()) < severity) {
+            worst = Some(record);
+        }
+    }
+    let fatal =
+        worst.map(|w| w.status.severity(cap.software_recovery()) >= Severity::ActionRequired);
+    if !mcg_status.restart_ip_valid() || fatal.unwrap_or(false) {
+        match worst {
+            Some(record) => panic!("fatal machine check at rip {:#x}: {record}", frame.rip),
+            None => panic!("fatal machine check at rip {:#x}: no error logged", frame.rip),
+        }
+    }
+    for bank in 0..cap.count() {
+        clear(bank);
+    }
+    unsafe {
+        x86::msr::wrmsr(IA32_MCG_STATUS, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn canned(msrs: &[(u32, u64)]) -> impl Fn(u32) -> u64 {
+        let msrs: BTreeMap<u32, u64> = msrs.iter().copied().collect();
+        move |msr| msrs.get(&msr).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn banks() {
+        // A corrected memory read error with an address, as QEMU
+        // injects with `mce 0 8 0x9c00000000000091 0 0x1234000 0x86`,
+        // and an empty bank.
+        let msrs = canned(&[(0x421, 0x9c00_0000_0000_0091), (0x422, 0x123_4000), (0x423, 0x86)]);
+        let record = read_bank(&msrs, 8).expect("valid bank");
+        assert_eq!(record.bank, 8);
+        assert!(!record.status.uncorrected());
+        assert_eq!(record.addr, Some(0x123_4000));
+        let misc = record.misc.expect("valid misc");
+        assert_eq!(misc.addr_lsb(), 6);
+        assert_eq!(misc.addr_mode(), 2);
+        assert_eq!(record.status.severity(true), Severity::Corrected);
+        assert_eq!(record.status.code(), ErrorCode::Memory { kind: 1, channel: 1 });
+        assert!(read_bank(&msrs, 0).is_none());
+    }
+
+    #[test]
+    fn severities() {
+        let uc = Status(0).with_valid(true).with_uncorrected(true).with_enabled(true);
+        assert_eq!(Status(0).with_valid(true).severity(false), Severity::Corrected);
+        assert_eq!(uc.severity(true), Severity::Uncorrected);
+        assert_eq!(uc.severity(false), Severity::Fatal);
+        let srao = uc.with_signaling(true);
+        assert_eq!(srao.severity(true), Severity::ActionOptional);
+        assert_eq!(srao.with_action_required(true).severity(true), Severity::ActionRequired);
+        assert_eq!(srao.with_context_corrupt(true).severity(true), Severity::Fatal);
+        assert!(Severity::Corrected < Severity::Uncorrected);
+        assert!(Severity::ActionRequired < Severity::Fatal);
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(ErrorCode::decode(0), ErrorCode::NoError);
+        assert_eq!(ErrorCode::decode(0x0400), ErrorCode::InternalTimer);
+        assert_eq!(ErrorCode::decode(0x0405), ErrorCode::Internal);
+        assert_eq!(ErrorCode::decode(0x000F), ErrorCode::CacheHierarchy { level: 3 });
+        assert_eq!(ErrorCode::decode(0x0014), ErrorCode::Tlb { kind: 1, level: 0 });
+        assert_eq!(ErrorCode::decode(0x109F), ErrorCode::Memory { kind: 1, channel: 0xF });
+        let code = ErrorCode::decode(0x0136);
+        assert_eq!(code, ErrorCode::Cache { request: 3, kind: 1, level: 2 });
+        assert_eq!(code.to_string(), "L2 data cache data read error");
+        let code = ErrorCode::decode(0x0F0B);
+        assert_eq!(code, ErrorCode::Bus { request: 0, level: 3, timeout: true });
+        assert_eq!(code.to_string(), "generic bus generic error (timeout)");
+        assert_eq!(
+            ErrorCode::decode(0x0091).to_string(),
+            "memory controller read error on channel 1"
+        );
+        assert_eq!(ErrorCode::decode(0x2000), ErrorCode::Unknown(0x2000));
+    }
+
+    #[test]
+    fn log() {
+        let log = Log::new();
+        assert_eq!(log.count(), 0);
+        assert!(log.get(0).is_none());
+        let status = Status(0x9c00_0000_0000_0091);
+        let record = Record {
+            cpu: 3,
+            bank: 8,
+            tsc: 1000,
+            mcg_status: McgStatus(0).with_restart_ip_valid(true),
+            status,
+            addr: Some(0x123_4000),
+            misc: Some(Misc(0x86)),
+        };
+        log.record(&record);
+        assert_eq!(log.count(), 1);
+        assert_eq!(log.get(0), Some(record));
+        assert_eq!(
+            record.to_string(),
+            "cpu 3 bank 8: memory controller read error on channel 1 \
+             status=0x9c00000000000091 addr=0x1234000 misc=0x86"
+        );
+        for k in 1..=LOG_SIZE {
+            log.record(&Record { tsc: k as u64, ..record });
+        }
+        assert!(log.get(0).is_none());
+        assert_eq!(log.get(LOG_SIZE).map(|r| r.tsc), Some(LOG_SIZE as u64));
+    }
+
+    #[test]
+    fn log_address() {
+        let (name, offset) = crate::backtrace::region(LOG_ADDRESS).expect("in a segment");
+        assert_eq!(name, "trace");
+        assert!(offset >= 1 << 30, "above the trace segment's image");
+        assert_eq!(LOG_ADDRESS % Page4K::SIZE, 0);
+    }
+}
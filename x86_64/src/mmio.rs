@@ -0,0 +1,339 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Memory-mapped I/O
+//!
+//! The memory-mapped counterpart of `io`.  A `Register` is a
+//! device register of some width, typed by whether it may be
+//! read, written, or both, and accessed only with volatile loads
+//! and stores of exactly that width.  A `Block` is a window of
+//! device registers, from which registers are taken at given
+//! offsets, or which may be viewed as a `#[repr(C)]` structure of
+//! registers describing its layout.
+//!
+//! Windows are mapped uncached, with both PCD and PWT set in the
+//! PTE, so that they are uncacheable whatever the MTRRs say.  The
+//! "Device Page Table" slot of HDP 0003 already holds the IOPT
+//! mirror (see `iopt`), so device windows are mapped into the
+//! "Mapping Region" slot below it, at addresses handed out by an
+//! `Arena` belonging to the mapping binary.
+//!
+//! Nothing here depends on the memory actually being a device,
+//! so a block may be backed by an ordinary buffer for testing.
+
+use crate::vm::{self, HardMmu, Mmu, PTEFlags, Result};
+use crate::{HPA, MemoryType, PF4K, Page, Page4K, PageFrame, V4KA, VPageAddr};
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Range;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The region of the address space into which device windows
+/// are mapped: the "Mapping Region" slot of HDP 0003.
+pub const REGION: Range<usize> = 0xFFFF_FE00_0000_0000..0xFFFF_FE80_0000_0000;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The widths in which registers may be accessed.
+pub trait Width: Copy + private::Sealed {}
+
+impl private::Sealed for u8 {}
+impl private::Sealed for u16 {}
+impl private::Sealed for u32 {}
+impl private::Sealed for u64 {}
+impl Width for u8 {}
+impl Width for u16 {}
+impl Width for u32 {}
+impl Width for u64 {}
+
+/// Marks a register that may only be read.
+pub enum ReadOnly {}
+/// Marks a register that may only be written.
+pub enum WriteOnly {}
+/// Marks a register that may be both read and written.
+pub enum ReadWrite {}
+
+/// Implemented by the markers of readable registers.
+pub trait Readable {}
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+
+/// Implemented by the markers of writable registers.
+pub trait Writable {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// A memory-mapped register of width `T` and access `A`.
+#[repr(transparent)]
+pub struct Register<T: Width, A> {
+    value: UnsafeCell<T>,
+    access: PhantomData<A>,
+}
+
+impl<T: Width, A: Readable> Register<T, A> {
+    /// Reads the register.
+    pub fn read(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+}
+
+impl<T: Width, A: Writable> Register<T, A> {
+    /// Writes the register.
+    pub fn write(&self, value: T) {
+        unsafe {
+            self.value.get().write_volatile(value);
+        }
+    }
+}
+
+impl<T: Width> Register<T, ReadWrite> {
+    /// Reads the register, and writes back the result of
+    /// applying the given function to the value read.
+    pub fn modify<F: FnOnce(T) -> T>(&self, f: F) {
+        self.write(f(self.read()));
+    }
+}
+
+/// A window of device registers.
+#[derive(Clone, Copy, Debug)]
+pub struct Block<'a> {
+    base: NonNull<u8>,
+    len: usize,
+    lifetime: PhantomData<&'a UnsafeCell<[u8]>>,
+}
+
+impl<'a> Block<'a> {
+    /// Returns the block of the given length at the given
+    /// address.
+    ///
+    /// # Safety
+    /// The memory must be mapped for the lifetime of the block,
+    /// and must only be accessed through it.
+    pub unsafe fn new(base: *mut u8, len: usize) -> Block<'a> {
+        let base = NonNull::new(base).expect("non-null MMIO block");
+        Block { base, len, lifetime: PhantomData }
+    }
+
+    /// Returns the address of the block.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.base.as_ptr()
+    }
+
+    /// Returns the length of the block in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true iff the block is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the register at the given offset into the block,
+    /// which must lie within it, naturally aligned.
+    pub fn register<T: Width, A>(&self, offset: usize) -> &'a Register<T, A> {
+        let size = core::mem::size_of::<T>();
+        assert!(offset.checked_add(size).is_some_and(|end| end <= self.len), "register in block");
+        let ptr = self.as_ptr().wrapping_add(offset);
+        assert!(ptr.addr().is_multiple_of(size), "register is naturally aligned");
+        unsafe { &*ptr.cast::<Register<T, A>>() }
+    }
+
+    /// Returns the sub-block spanning the given range of offsets.
+    pub fn subblock(&self, range: Range<usize>) -> Block<'a> {
+        assert!(range.start <= range.end && range.end <= self.len, "sub-block in block");
+        let base = self.as_ptr().wrapping_add(range.start);
+        Block { base: NonNull::new(base).unwrap(), len: range.len(), lifetime: PhantomData }
+    }
+
+    /// Views the start of the block as a structure describing
+    /// its registers.
+    ///
+    /// # Safety
+    /// The structure must be `#[repr(C)]`, and consist only of
+    /// `Register`s and padding.
+    pub unsafe fn view<R>(&self) -> &'a R {
+        assert!(core::mem::size_of::<R>() <= self.len, "register layout fits block");
+        assert!(self.as_ptr().addr().is_multiple_of(core::mem::align_of::<R>()));
+        unsafe { &*self.as_ptr().cast::<R>() }
+    }
+}
+
+/// Hands out page-aligned ranges of virtual addresses for device
+/// windows.  Addresses are never returned.
+pub struct Arena {
+    next: AtomicUsize,
+    end: usize,
+}
+
+impl Arena {
+    /// Returns an arena covering the given range, which must be
+    /// page aligned.
+    pub const fn new(range: Range<usize>) -> Arena {
+        assert!(range.start.is_multiple_of(Page4K::SIZE));
+        assert!(range.end.is_multiple_of(Page4K::SIZE));
+        Arena { next: AtomicUsize::new(range.start), end: range.end }
+    }
+
+    /// Reserves the given number of pages, returning the address
+    /// of the first.
+    pub fn reserve(&self, npages: usize) -> Result<V4KA> {
+        let len = npages.checked_mul(Page4K::SIZE).ok_or("MMIO window too large")?;
+        self.next
+            .try_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                next.checked_add(len).filter(|&end| end <= self.end)
+            })
+            .map(V4KA::new)
+            .map_err(|_| "MMIO arena exhausted")
+    }
+}
+
+// Returns the first page frame and number of pages spanned by
+// the given physical range.
+fn frames(hpa: HPA, len: usize) -> (HPA, usize) {
+    let offset = hpa.addr() as usize & Page4K::MASK;
+    let start = HPA::new(hpa.addr() - offset as u64);
+    (start, (offset + len).div_ceil(Page4K::SIZE))
+}
+
+/// Maps the device registers at the given physical address,
+/// spanning the given length, uncached at addresses taken from
+/// the arena, and returns the block of registers.
+///
+/// # Safety
+/// The physical range must be device registers, and not be
+/// accessed other than through the returned block.
+pub unsafe fn map<F>(
+    arena: &Arena,
+    hpa: HPA,
+    len: usize,
+    allocator: &mut F,
+) -> Result<Block<'static>>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    unsafe { map_in(&HardMmu, arena, hpa, len, allocator) }
+}
+
+/// Maps device registers as `map` does, into the address space
+/// viewed through the given MMU.
+///
+/// # Safety
+/// As for `map`.
+pub unsafe fn map_in<M, F>(
+    mmu: &M,
+    arena: &Arena,
+    hpa: HPA,
+    len: usize,
+    allocator: &mut F,
+) -> Result<Block<'static>>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
+{
+    const FLAGS: PTEFlags = PTEFlags::PRESENT
+        .union(PTEFlags::WRITE)
+        .union(MemoryType::Uncacheable.pte_flags())
+        .union(PTEFlags::NX);
+    let (start, npages) = frames(hpa, len);
+    let va = arena.reserve(npages)?;
+    for (k, page) in (va..).take(npages).enumerate() {
+        let frame = PF4K::new(start.offset(k * Page4K::SIZE));
+        vm::map_in(mmu, frame, FLAGS, page, allocator)?;
+    }
+    let offset = hpa.addr() as usize & Page4K::MASK;
+    Ok(unsafe { Block::new((va.addr() + offset) as *mut u8, len) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::soft::SoftMmu;
+
+    #[repr(C, align(4096))]
+    struct Buffer([u64; 512]);
+
+    #[repr(C)]
+    struct Layout {
+        id: Register<u32, ReadOnly>,
+        _reserved: u32,
+        command: Register<u64, WriteOnly>,
+        control: Register<u16, ReadWrite>,
+    }
+
+    #[test]
+    fn registers() {
+        let mut buffer = Buffer([0; 512]);
+        buffer.0[0] = 0x1234_5678;
+        let block = unsafe { Block::new(buffer.0.as_mut_ptr().cast(), 4096) };
+        assert_eq!(block.register::<u32, ReadOnly>(0).read(), 0x1234_5678);
+        assert_eq!(block.register::<u8, ReadOnly>(1).read(), 0x56);
+        block.register::<u64, WriteOnly>(8).write(0xDEAD_BEEF);
+        let control = block.register::<u16, ReadWrite>(16);
+        control.write(0x10);
+        control.modify(|v| v | 1);
+        assert_eq!(control.read(), 0x11);
+        let layout = unsafe { block.view::<Layout>() };
+        assert_eq!(layout.id.read(), 0x1234_5678);
+        layout.command.write(0xFEED);
+        assert_eq!(layout.control.read(), 0x11);
+        let sub = block.subblock(8..16);
+        assert_eq!(sub.len(), 8);
+        assert_eq!(sub.register::<u64, ReadOnly>(0).read(), 0xFEED);
+        assert_eq!(buffer.0[1], 0xFEED);
+        assert_eq!(buffer.0[2], 0x11);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds() {
+        let mut buffer = Buffer([0; 512]);
+        let block = unsafe { Block::new(buffer.0.as_mut_ptr().cast(), 8) };
+        block.register::<u32, ReadOnly>(6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn misaligned() {
+        let mut buffer = Buffer([0; 512]);
+        let block = unsafe { Block::new(buffer.0.as_mut_ptr().cast(), 4096) };
+        block.register::<u32, ReadWrite>(2);
+    }
+
+    #[test]
+    fn arena() {
+        let arena = Arena::new(0x10_0000..0x10_4000);
+        assert_eq!(arena.reserve(1), Ok(V4KA::new(0x10_0000)));
+        assert_eq!(arena.reserve(2), Ok(V4KA::new(0x10_1000)));
+        assert!(arena.reserve(2).is_err());
+        assert_eq!(arena.reserve(1), Ok(V4KA::new(0x10_3000)));
+        assert!(arena.reserve(1).is_err());
+    }
+
+    #[test]
+    fn map_window() {
+        let mmu = SoftMmu::new(16);
+        let arena = Arena::new(REGION);
+        let span = |hpa, len| {
+            let (start, npages) = frames(HPA::new(hpa), len);
+            (start.addr(), npages)
+        };
+        assert_eq!(span(0xFEC0_0000, 0x20), (0xFEC0_0000, 1));
+        assert_eq!(span(0xFED0_0FF0, 0x20), (0xFED0_0000, 2));
+        let hpa = HPA::new(0xFED0_0FF0);
+        let block = unsafe { map_in(&mmu, &arena, hpa, 0x20, &mut || mmu.alloc()) }.unwrap();
+        assert_eq!(block.as_ptr().addr(), REGION.start + 0xFF0);
+        assert_eq!(block.len(), 0x20);
+        let translate = |va| vm::translate_in(&mmu, va).map(HPA::addr);
+        assert_eq!(translate(REGION.start + 0xFF0), Some(0xFED0_0FF0));
+        assert_eq!(translate(REGION.start + 0x1008), Some(0xFED0_1008));
+        assert_eq!(translate(REGION.start + 0x2000), None);
+    }
+}
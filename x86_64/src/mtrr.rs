@@ -0,0 +1,407 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Memory Type Range Registers
+//!
+//! The firmware sets the MTRRs to describe the memory type of
+//! each range of the physical address space, and the type a
+//! CPU uses for an access combines the MTRR type with that
+//! selected through the PAT.  Hypatia leaves the MTRRs as the
+//! firmware set them, but inspects them to find ranges where
+//! they conflict with the type Hypatia wants: RAM covered by
+//! an uncacheable range, say, or overlapping ranges whose
+//! combined type is undefined.
+//!
+//! The first megabyte may be described by fixed-range MTRRs:
+//! eight 64KiB ranges, sixteen 16KiB ranges and sixty-four
+//! 4KiB ranges.  The variable-range MTRRs each match addresses
+//! that agree with a base under a mask, and anything matched
+//! by neither takes the default type.
+
+use crate::MemoryType;
+use crate::cpuid::{self, Features};
+use bitstruct::bitstruct;
+use core::ops::Range;
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+pub const IA32_MTRRCAP: u32 = 0xFE;
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+pub const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+pub const IA32_MTRR_PHYSMASK0: u32 = 0x201;
+pub const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+pub const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+pub const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+
+/// The most variable-range MTRRs that are inspected.
+pub const MAX_VARIABLE: usize = 32;
+
+// The fixed-range MTRRs, with the address and size of the
+// ranges covered by each.  Each register describes eight
+// consecutive ranges, one per byte.
+const FIXED: [(u32, u64, u64); 11] = [
+    (IA32_MTRR_FIX64K_00000, 0x0_0000, 0x1_0000),
+    (IA32_MTRR_FIX16K_80000, 0x8_0000, 0x4000),
+    (IA32_MTRR_FIX16K_80000 + 1, 0xA_0000, 0x4000),
+    (IA32_MTRR_FIX4K_C0000, 0xC_0000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 1, 0xC_8000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 2, 0xD_0000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 3, 0xD_8000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 4, 0xE_0000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 5, 0xE_8000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 6, 0xF_0000, 0x1000),
+    (IA32_MTRR_FIX4K_C0000 + 7, 0xF_8000, 0x1000),
+];
+
+// The end of the range covered by the fixed-range MTRRs.
+const FIXED_END: u64 = 0x10_0000;
+
+bitstruct! {
+    /// The MTRR capabilities register.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct MtrrCap(pub u64) {
+        pub count: u8 = 0..8;
+        pub fixed: bool = 8;
+        pub write_combining: bool = 10;
+        pub smrr: bool = 11;
+    }
+}
+
+bitstruct! {
+    /// The register holding the default memory type, and
+    /// enabling the MTRRs.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct DefType(pub u64) {
+        pub default: u8 = 0..8;
+        pub fixed_enabled: bool = 10;
+        pub enabled: bool = 11;
+    }
+}
+
+/// A variable-range MTRR, as its base and mask registers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Variable {
+    pub base: u64,
+    pub mask: u64,
+}
+
+impl Variable {
+    const VALID: u64 = 1 << 11;
+    const ADDR_MASK: u64 = !0xFFF;
+
+    /// Returns true iff the range is enabled.
+    pub fn is_valid(&self) -> bool {
+        self.mask & Self::VALID != 0
+    }
+
+    /// Returns the raw memory type of the range.
+    pub fn raw_type(&self) -> u8 {
+        self.base as u8
+    }
+
+    fn addr_mask(&self) -> u64 {
+        self.mask & Self::ADDR_MASK
+    }
+
+    /// Returns true iff the range matches the given address.
+    pub fn contains(&self, addr: u64) -> bool {
+        let mask = self.addr_mask();
+        self.is_valid() && addr & mask == self.base & mask
+    }
+
+    // Returns the lowest address above the given one at which
+    // membership of the range may change.  Masks are almost
+    // always contiguous, describing a single naturally aligned
+    // range, but need not be; membership of a range with holes
+    // is constant only on blocks the size of its lowest set bit.
+    fn next_boundary(&self, addr: u64) -> Option<u64> {
+        let mask = self.addr_mask();
+        if !self.is_valid() || mask == 0 {
+            return None;
+        }
+        let size = 1 << mask.trailing_zeros();
+        let top = u64::MAX.checked_shl(64 - mask.leading_zeros()).unwrap_or(0);
+        if (mask | top).count_zeros() == mask.trailing_zeros() {
+            let start = self.base & mask;
+            let end = start.checked_add(size)?;
+            return [start, end].into_iter().find(|&boundary| addr < boundary);
+        }
+        (addr | (size - 1)).checked_add(1)
+    }
+}
+
+/// A range where the MTRRs conflict with the wanted memory
+/// type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub addr: u64,
+    pub wanted: MemoryType,
+    /// The type from the MTRRs, or an error if it is reserved
+    /// or undefined.
+    pub mtrr: Result<MemoryType>,
+}
+
+impl core::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#x}: wanted {:?}, ", self.addr, self.wanted)?;
+        match self.mtrr {
+            Ok(mtrr) => {
+                write!(f, "MTRRs say {mtrr:?}, giving {:?}", effective(self.wanted, mtrr))
+            }
+            Err(err) => f.write_str(err),
+        }
+    }
+}
+
+/// Returns the memory type used for an access, given the types
+/// selected by the PAT and by the MTRRs.
+pub fn effective(pat: MemoryType, mtrr: MemoryType) -> MemoryType {
+    use MemoryType::*;
+    match (pat, mtrr) {
+        (Uncacheable, _) => Uncacheable,
+        (WriteCombining, _) => WriteCombining,
+        (WriteBack, mtrr) => mtrr,
+        (_, Uncacheable | WriteCombining) => Uncacheable,
+        (WriteThrough, _) => WriteThrough,
+        (WriteProtect, _) => WriteProtect,
+    }
+}
+
+/// A snapshot of the MTRRs of a CPU.
+#[derive(Clone, Debug)]
+pub struct Mtrrs {
+    cap: MtrrCap,
+    def_type: DefType,
+    fixed: [u64; FIXED.len()],
+    variable: [Variable; MAX_VARIABLE],
+}
+
+impl Mtrrs {
+    /// Reads the MTRRs through the given function, which reads
+    /// a model specific register.
+    pub fn decode<F: Fn(u32) -> u64>(rdmsr: F) -> Mtrrs {
+        let cap = MtrrCap(rdmsr(IA32_MTRRCAP));
+        let def_type = DefType(rdmsr(IA32_MTRR_DEF_TYPE));
+        let mut fixed = [0; FIXED.len()];
+        if cap.fixed() {
+            for (value, &(msr, _, _)) in fixed.iter_mut().zip(FIXED.iter()) {
+                *value = rdmsr(msr);
+            }
+        }
+        let mut variable = [Variable::default(); MAX_VARIABLE];
+        for (k, range) in variable.iter_mut().enumerate().take(usize::from(cap.count())) {
+            let k = k as u32;
+            range.base = rdmsr(IA32_MTRR_PHYSBASE0 + 2 * k);
+            range.mask = rdmsr(IA32_MTRR_PHYSMASK0 + 2 * k);
+        }
+        Mtrrs { cap, def_type, fixed, variable }
+    }
+
+    /// Reads the MTRRs of the current CPU, if it has them.
+    pub fn read() -> Option<Mtrrs> {
+        if !cpuid::has(Features::MTRR) {
+            return None;
+        }
+        Some(Mtrrs::decode(|msr| unsafe { x86::msr::rdmsr(msr) }))
+    }
+
+    /// Returns the capabilities register.
+    pub fn cap(&self) -> MtrrCap {
+        self.cap
+    }
+
+    /// Returns the default type register.
+    pub fn def_type(&self) -> DefType {
+        self.def_type
+    }
+
+    /// Returns the variable-range MTRRs.
+    pub fn variable(&self) -> &[Variable] {
+        let count = usize::from(self.cap.count()).min(MAX_VARIABLE);
+        &self.variable[..count]
+    }
+
+    fn fixed_enabled(&self) -> bool {
+        self.cap.fixed() && self.def_type.fixed_enabled()
+    }
+
+    /// Returns the memory type the MTRRs select for the given
+    /// physical address.  Where variable ranges overlap, UC
+    /// takes precedence, then WT over WB; any other overlap is
+    /// undefined, and an error.
+    pub fn memory_type(&self, addr: u64) -> Result<MemoryType> {
+        if !self.def_type.enabled() {
+            return Ok(MemoryType::Uncacheable);
+        }
+        if addr < FIXED_END && self.fixed_enabled() {
+            let (k, &(_, start, size)) = FIXED
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, (_, start, _))| *start <= addr)
+                .expect("fixed ranges start at zero");
+            let byte = (addr - start) / size;
+            return MemoryType::try_from((self.fixed[k] >> (byte * 8)) as u8)
+                .map_err(|_| "reserved fixed-range MTRR type");
+        }
+        let mut matched: Option<MemoryType> = None;
+        for range in self.variable().iter().filter(|range| range.contains(addr)) {
+            let typ = MemoryType::try_from(range.raw_type())
+                .map_err(|_| "reserved variable-range MTRR type")?;
+            matched = Some(match (matched, typ) {
+                (None, typ) => typ,
+                (Some(prev), typ) if prev == typ => typ,
+                (Some(MemoryType::Uncacheable), _) | (_, MemoryType::Uncacheable) => {
+                    MemoryType::Uncacheable
+                }
+                (Some(MemoryType::WriteThrough), MemoryType::WriteBack)
+                | (Some(MemoryType::WriteBack), MemoryType::WriteThrough) => {
+                    MemoryType::WriteThrough
+                }
+                _ => return Err("overlapping MTRRs with undefined type"),
+            });
+        }
+        match matched {
+            Some(typ) => Ok(typ),
+            None => MemoryType::try_from(self.def_type.default())
+                .map_err(|_| "reserved default MTRR type"),
+        }
+    }
+
+    // Returns the lowest address above the given one at which the
+    // type selected by the MTRRs may change.
+    fn next_boundary(&self, addr: u64) -> Option<u64> {
+        if addr < FIXED_END && self.fixed_enabled() {
+            let size = FIXED.iter().rev().find(|(_, start, _)| *start <= addr).unwrap().2;
+            return Some((addr | (size - 1)) + 1);
+        }
+        self.variable().iter().filter_map(|range| range.next_boundary(addr)).min()
+    }
+
+    /// Checks that accesses anywhere in the given physical
+    /// address range, mapped with the wanted type through the
+    /// PAT, would use that type, returning the first conflict
+    /// with the MTRRs otherwise.
+    pub fn check(
+        &self,
+        range: Range<u64>,
+        wanted: MemoryType,
+    ) -> core::result::Result<(), Conflict> {
+        let mut addr = range.start;
+        while addr < range.end {
+            match self.memory_type(addr) {
+                Ok(mtrr) if effective(wanted, mtrr) == wanted => {}
+                mtrr => return Err(Conflict { addr, wanted, mtrr }),
+            }
+            match self.next_boundary(addr) {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    const WB: u64 = MemoryType::WriteBack as u64;
+    const UC: u64 = MemoryType::Uncacheable as u64;
+    const WT: u64 = MemoryType::WriteThrough as u64;
+    const WC: u64 = MemoryType::WriteCombining as u64;
+
+    // A typical PC: write back by default, the legacy video
+    // window uncached, and the top of the low 4GiB uncached
+    // for devices.
+    fn pc(variable: &[(u64, u64)]) -> Mtrrs {
+        let mut msrs = BTreeMap::new();
+        msrs.insert(IA32_MTRRCAP, 0x500 | variable.len() as u64);
+        msrs.insert(IA32_MTRR_DEF_TYPE, 0xC00 | WB);
+        for &(msr, _, _) in FIXED.iter() {
+            msrs.insert(msr, 0x0606_0606_0606_0606);
+        }
+        msrs.insert(IA32_MTRR_FIX16K_80000 + 1, 0);
+        for (k, &(base, mask)) in variable.iter().enumerate() {
+            msrs.insert(IA32_MTRR_PHYSBASE0 + 2 * k as u32, base);
+            msrs.insert(IA32_MTRR_PHYSMASK0 + 2 * k as u32, mask);
+        }
+        Mtrrs::decode(|msr| msrs.get(&msr).copied().unwrap_or(0))
+    }
+
+    // A contiguous, valid mask for a range of the given size in
+    // a 46-bit physical address space.
+    fn mask(size: u64) -> u64 {
+        (!(size - 1) & ((1 << 46) - 1)) | Variable::VALID
+    }
+
+    #[test]
+    fn effective_types() {
+        use MemoryType::*;
+        assert_eq!(effective(WriteBack, Uncacheable), Uncacheable);
+        assert_eq!(effective(WriteCombining, WriteBack), WriteCombining);
+        assert_eq!(effective(Uncacheable, WriteBack), Uncacheable);
+        assert_eq!(effective(WriteThrough, WriteBack), WriteThrough);
+        assert_eq!(effective(WriteThrough, WriteCombining), Uncacheable);
+        assert_eq!(effective(WriteProtect, WriteThrough), WriteProtect);
+        assert_eq!(effective(WriteBack, WriteProtect), WriteProtect);
+    }
+
+    #[test]
+    fn memory_types() {
+        let mtrrs = pc(&[(0xC000_0000 | UC, mask(0x4000_0000))]);
+        assert_eq!(mtrrs.variable().len(), 1);
+        assert_eq!(mtrrs.memory_type(0x1000), Ok(MemoryType::WriteBack));
+        assert_eq!(mtrrs.memory_type(0xA_0000), Ok(MemoryType::Uncacheable));
+        assert_eq!(mtrrs.memory_type(0xB_FFFF), Ok(MemoryType::Uncacheable));
+        assert_eq!(mtrrs.memory_type(0xC_0000), Ok(MemoryType::WriteBack));
+        assert_eq!(mtrrs.memory_type(0xBFFF_F000), Ok(MemoryType::WriteBack));
+        assert_eq!(mtrrs.memory_type(0xFEE0_0000), Ok(MemoryType::Uncacheable));
+        assert_eq!(mtrrs.memory_type(0x1_0000_0000), Ok(MemoryType::WriteBack));
+
+        let overlap = pc(&[(WT, mask(0x1000_0000)), (WB, mask(0x4000_0000))]);
+        assert_eq!(overlap.memory_type(0x20_0000), Ok(MemoryType::WriteThrough));
+        assert_eq!(overlap.memory_type(0x1000_0000), Ok(MemoryType::WriteBack));
+        let undefined = pc(&[(WC, mask(0x1000_0000)), (WB, mask(0x4000_0000))]);
+        assert!(undefined.memory_type(0x20_0000).is_err());
+    }
+
+    #[test]
+    fn conflicts() {
+        let mtrrs = pc(&[(0xC000_0000 | UC, mask(0x4000_0000)), (0x8000_0000 | UC, mask(0x1000))]);
+        assert_eq!(mtrrs.check(0x10_0000..0x8000_0000, MemoryType::WriteBack), Ok(()));
+        // Device windows are uncached, whatever the MTRRs say.
+        assert_eq!(mtrrs.check(0xFEC0_0000..0xFEC0_1000, MemoryType::Uncacheable), Ok(()));
+        assert_eq!(mtrrs.check(0..0x1_0000_0000, MemoryType::Uncacheable), Ok(()));
+        // Write combining overrides the MTRRs too.
+        assert_eq!(mtrrs.check(0xE000_0000..0xF000_0000, MemoryType::WriteCombining), Ok(()));
+
+        let conflict = mtrrs.check(0x10_0000..0x1_0000_0000, MemoryType::WriteBack).unwrap_err();
+        assert_eq!(conflict.addr, 0x8000_0000);
+        assert_eq!(conflict.mtrr, Ok(MemoryType::Uncacheable));
+        let conflict = mtrrs.check(0x9000_0000..0xD000_0000, MemoryType::WriteBack).unwrap_err();
+        assert_eq!(conflict.addr, 0xC000_0000);
+        let conflict = mtrrs.check(0..0x10_0000, MemoryType::WriteBack).unwrap_err();
+        assert_eq!(conflict.addr, 0xA_0000);
+
+        // A mask with a hole matches every other 1MiB block.
+        let holes = pc(&[(0x10_0000 | UC, mask(0x10_0000) & !0x20_0000)]);
+        let starts: Vec<u64> = (0..8)
+            .map(|k| k * 0x10_0000 + 0x10_0000)
+            .filter(|&addr| holes.check(addr..addr + 0x10_0000, MemoryType::WriteBack).is_err())
+            .collect();
+        assert_eq!(starts, [0x10_0000, 0x30_0000]);
+        let conflict = holes.check(0x20_0000..0x40_0000, MemoryType::WriteBack).unwrap_err();
+        assert_eq!(conflict.addr, 0x30_0000);
+
+        let disabled = Mtrrs::decode(|_| 0);
+        assert_eq!(disabled.memory_type(0x1000), Ok(MemoryType::Uncacheable));
+        assert!(disabled.check(0x1000..0x2000, MemoryType::WriteBack).is_err());
+    }
+}
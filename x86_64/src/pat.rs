@@ -0,0 +1,205 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Page Attribute Table
+//!
+//! The memory type of a page mapping is selected by an index
+//! into the page attribute table, formed from the PAT, PCD
+//! and PWT bits of the mapping's page table entry.  Hypatia
+//! programs the same fixed table on every CPU.  Its first four
+//! entries match the power-on defaults except that PWT alone
+//! selects write combining, rather than write through, and the
+//! remaining types are reached through the PAT bit.
+//!
+//! | Index | PAT | PCD | PWT | Type |
+//! |-------|-----|-----|-----|------|
+//! | 0     | 0   | 0   | 0   | WB   |
+//! | 1     | 0   | 0   | 1   | WC   |
+//! | 2     | 0   | 1   | 0   | UC-  |
+//! | 3     | 0   | 1   | 1   | UC   |
+//! | 4     | 1   | 0   | 0   | WB   |
+//! | 5     | 1   | 0   | 1   | WP   |
+//! | 6     | 1   | 1   | 0   | UC-  |
+//! | 7     | 1   | 1   | 1   | WT   |
+//!
+//! The type a CPU actually uses also depends on the MTRRs; see
+//! the `mtrr` module.
+
+use crate::MemoryType;
+use crate::cpuid::{self, Features};
+use crate::vm::{self, PTEFlags};
+
+/// The model specific register holding the table.
+pub const IA32_PAT: u32 = 0x277;
+
+// The encoding of the "uncached minus" type, which exists only
+// in the PAT.  It is uncacheable, unless the MTRRs select write
+// combining.
+const UNCACHED_MINUS: u8 = 7;
+
+/// Hypatia's page attribute table, by index.
+pub const LAYOUT: [u8; 8] = [
+    MemoryType::WriteBack as u8,
+    MemoryType::WriteCombining as u8,
+    UNCACHED_MINUS,
+    MemoryType::Uncacheable as u8,
+    MemoryType::WriteBack as u8,
+    MemoryType::WriteProtect as u8,
+    UNCACHED_MINUS,
+    MemoryType::WriteThrough as u8,
+];
+
+/// The value of IA32_PAT holding `LAYOUT`.
+pub const VALUE: u64 = {
+    let mut value = 0;
+    let mut k = 0;
+    while k < LAYOUT.len() {
+        value |= (LAYOUT[k] as u64) << (k * 8);
+        k += 1;
+    }
+    value
+};
+
+impl MemoryType {
+    /// Returns the index in `LAYOUT` selecting this type.
+    pub const fn pat_index(self) -> usize {
+        match self {
+            MemoryType::WriteBack => 0,
+            MemoryType::WriteCombining => 1,
+            MemoryType::Uncacheable => 3,
+            MemoryType::WriteProtect => 5,
+            MemoryType::WriteThrough => 7,
+        }
+    }
+
+    /// Returns the page table entry flags selecting this type
+    /// in a 4KiB page entry.
+    pub const fn pte_flags(self) -> PTEFlags {
+        index_flags(self.pat_index(), PTEFlags::PAT_4K)
+    }
+
+    /// Returns the page table entry flags selecting this type
+    /// in a 2MiB or 1GiB page entry.
+    pub const fn big_pte_flags(self) -> PTEFlags {
+        index_flags(self.pat_index(), PTEFlags::PAT_LARGE)
+    }
+
+    /// Returns the memory type selected by the given flags of a
+    /// 4KiB page entry, or an error if they select an entry
+    /// with a type that is not otherwise used.
+    pub fn from_pte_flags(flags: PTEFlags) -> Result<MemoryType, &'static str> {
+        from_index(flags_index(flags, PTEFlags::PAT_4K))
+    }
+
+    /// Returns the memory type selected by the given flags of a
+    /// 2MiB or 1GiB page entry, as for `from_pte_flags`.
+    pub fn from_big_pte_flags(flags: PTEFlags) -> Result<MemoryType, &'static str> {
+        from_index(flags_index(flags, PTEFlags::PAT_LARGE))
+    }
+}
+
+const fn index_flags(index: usize, pat: PTEFlags) -> PTEFlags {
+    let mut flags = PTEFlags::empty();
+    if index & 0b001 != 0 {
+        flags = flags.union(PTEFlags::WRTHRU);
+    }
+    if index & 0b010 != 0 {
+        flags = flags.union(PTEFlags::NOCACHE);
+    }
+    if index & 0b100 != 0 {
+        flags = flags.union(pat);
+    }
+    flags
+}
+
+fn flags_index(flags: PTEFlags, pat: PTEFlags) -> usize {
+    usize::from(flags.contains(pat)) << 2
+        | usize::from(flags.contains(PTEFlags::NOCACHE)) << 1
+        | usize::from(flags.contains(PTEFlags::WRTHRU))
+}
+
+fn from_index(index: usize) -> Result<MemoryType, &'static str> {
+    MemoryType::try_from(LAYOUT[index]).map_err(|_| "PAT entry is uncached minus")
+}
+
+/// Programs Hypatia's page attribute table on the current CPU.
+///
+/// Mappings may already be using the entries being changed, so
+/// this follows the procedure the SDM gives for changing memory
+/// types: with interrupts disabled, caching is disabled, and the
+/// caches written back and the TLB flushed both before and after
+/// the update, so that no line or translation remains with the
+/// old type.
+pub fn init() {
+    use x86::bits64::rflags::{self, RFlags};
+    use x86::controlregs::Cr0;
+    assert!(cpuid::has(Features::PAT), "CPU lacks a page attribute table");
+    if unsafe { x86::msr::rdmsr(IA32_PAT) } == VALUE {
+        return;
+    }
+    let interrupts = rflags::read().contains(RFlags::FLAGS_IF);
+    unsafe {
+        core::arch::asm!("cli", options(nomem, nostack));
+        let cr0 = x86::controlregs::cr0() - Cr0::CR0_NOT_WRITE_THROUGH;
+        x86::controlregs::cr0_write(cr0 | Cr0::CR0_CACHE_DISABLE);
+        core::arch::asm!("wbinvd");
+        vm::flush_tlb();
+        x86::msr::wrmsr(IA32_PAT, VALUE);
+        core::arch::asm!("wbinvd");
+        vm::flush_tlb();
+        x86::controlregs::cr0_write(cr0 - Cr0::CR0_CACHE_DISABLE);
+        if interrupts {
+            core::arch::asm!("sti", options(nomem, nostack));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [MemoryType; 5] = [
+        MemoryType::Uncacheable,
+        MemoryType::WriteCombining,
+        MemoryType::WriteThrough,
+        MemoryType::WriteProtect,
+        MemoryType::WriteBack,
+    ];
+
+    #[test]
+    fn layout() {
+        const POWER_ON: u64 = 0x0007_0406_0007_0406;
+        assert_eq!(VALUE, 0x0407_0506_0007_0106);
+        for typ in TYPES {
+            assert_eq!(LAYOUT[typ.pat_index()], typ as u8);
+        }
+        // The entries reachable without the PAT bit keep their
+        // power-on types, but for index 1.
+        assert_eq!(VALUE & 0xFFFF_00FF, POWER_ON & 0xFFFF_00FF);
+    }
+
+    #[test]
+    fn pte_flags() {
+        assert!(MemoryType::WriteBack.pte_flags().is_empty());
+        assert_eq!(
+            MemoryType::Uncacheable.pte_flags().bits(),
+            (PTEFlags::NOCACHE | PTEFlags::WRTHRU).bits()
+        );
+        assert_eq!(MemoryType::WriteCombining.pte_flags().bits(), PTEFlags::WRTHRU.bits());
+        assert_eq!(MemoryType::WriteThrough.pte_flags().bits(), 0b1001_1000);
+        assert_eq!(MemoryType::WriteThrough.big_pte_flags().bits(), 0b1_0000_0001_1000);
+        assert_eq!(MemoryType::WriteProtect.big_pte_flags().bits(), 0b1_0000_0000_1000);
+        for typ in TYPES {
+            assert_eq!(MemoryType::from_pte_flags(typ.pte_flags()), Ok(typ));
+            assert_eq!(MemoryType::from_big_pte_flags(typ.big_pte_flags()), Ok(typ));
+            // The large PAT bit is never confused with HUGE.
+            let big = typ.big_pte_flags() | PTEFlags::HUGE;
+            assert_eq!(MemoryType::from_big_pte_flags(big), Ok(typ));
+        }
+        assert!(MemoryType::from_pte_flags(PTEFlags::NOCACHE).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Process-context identifiers
+//!
+//! Every task runs in its own address space, and without PCIDs,
+//! each switch between tasks reloads %cr3 and discards the whole
+//! TLB.  When the CPU supports them, we tag each task address
+//! space with a PCID so that its translations survive switches,
+//! and map segments, which are identical in every address space,
+//! with global pages.
+//!
+//! Translations tagged with a PCID are private to that address
+//! space, and INVLPG only removes those of the current one (and
+//! any global translation).  Software that changes the mappings of
+//! an address space that is not current, such as through the side
+//! load window, must invalidate its context before it next runs.
+//! Similarly, a PCID must be invalidated before it is reused, as
+//! the new owner loads it without flushing.
+//!
+//! Where the CPU lacks PCIDs, loading an address space flushes the
+//! TLB as before.  Where it lacks INVPCID, targeted invalidations
+//! of other contexts fall back to flushing everything.
+
+use crate::PF4K;
+use crate::cpuid::{self, Features};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::controlregs::Cr4;
+
+/// A process-context identifier.  PCID 0 is reserved for address
+/// spaces that are not tasks, such as the loader's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PCID(u16);
+
+impl PCID {
+    /// The number of distinct PCIDs.
+    pub const COUNT: usize = 1 << 12;
+
+    /// The PCID of address spaces that are not tagged.
+    pub const UNTAGGED: PCID = PCID(0);
+
+    /// Returns the given PCID, if it is in range.
+    pub const fn new(id: u16) -> Option<PCID> {
+        if (id as usize) < Self::COUNT { Some(PCID(id)) } else { None }
+    }
+
+    /// Returns the numeric value of the PCID.
+    pub const fn id(self) -> u16 {
+        self.0
+    }
+}
+
+/// How this CPU tags and invalidates translations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// No PCIDs: every load of %cr3 flushes the TLB.
+    Untagged,
+    /// PCIDs, but no INVPCID instruction.
+    Tagged,
+    /// PCIDs with the INVPCID instruction.
+    Invpcid,
+}
+
+/// Allocates PCIDs for task address spaces.
+pub struct Allocator {
+    map: [AtomicU64; PCID::COUNT / 64],
+}
+
+impl Allocator {
+    /// Returns a new allocator with only the untagged PCID in use.
+    pub const fn new() -> Allocator {
+        let mut map = [const { AtomicU64::new(0) }; PCID::COUNT / 64];
+        map[0] = AtomicU64::new(1);
+        Allocator { map }
+    }
+
+    /// Allocates an unused PCID, if there is one.
+    pub fn alloc(&self) -> Option<PCID> {
+        for (k, word) in self.map.iter().enumerate() {
+            let mut bits = word.load(Ordering::Relaxed);
+            while bits != !0 {
+                let bit = bits.trailing_ones();
+                match word.compare_exchange_weak(
+                    bits,
+                    bits | 1 << bit,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return PCID::new((k * 64) as u16 + bit as u16),
+                    Err(current) => bits = current,
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns a PCID to the allocator.  Its context must already
+    /// have been invalidated on every CPU.
+    pub fn free(&self, pcid: PCID) {
+        assert_ne!(pcid, PCID::UNTAGGED, "freeing the untagged PCID");
+        let id = usize::from(pcid.id());
+        let bit = 1 << (id % 64);
+        let prev = self.map[id / 64].fetch_and(!bit, Ordering::Release);
+        assert_ne!(prev & bit, 0, "freeing an unallocated PCID");
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Allocator {
+        Allocator::new()
+    }
+}
+
+const CR3_NOFLUSH: u64 = 1 << 63;
+const CR3_PCID_MASK: u64 = PCID::COUNT as u64 - 1;
+
+/// Returns the value to load into %cr3 for the given root and
+/// PCID.  If `keep` is set, translations already tagged with the
+/// PCID are retained.
+pub fn cr3_value(root: PF4K, pcid: PCID, keep: bool) -> u64 {
+    let noflush = if keep { CR3_NOFLUSH } else { 0 };
+    root.pfa().addr() | u64::from(pcid.id()) | noflush
+}
+
+/// Returns how this CPU tags and invalidates translations, which
+/// follows from whether `init` enabled PCIDs.
+pub fn mode() -> Mode {
+    let tagged = unsafe { x86::controlregs::cr4() }.contains(Cr4::CR4_ENABLE_PCID);
+    match (tagged, cpuid::has(Features::INVPCID)) {
+        (false, _) => Mode::Untagged,
+        (true, false) => Mode::Tagged,
+        (true, true) => Mode::Invpcid,
+    }
+}
+
+/// Enables global pages and, if the CPU supports them, PCIDs.
+/// Must be called on each CPU while an untagged address space
+/// is loaded.
+pub fn init() {
+    let mut cr4 = unsafe { x86::controlregs::cr4() };
+    if cpuid::has(Features::PGE) {
+        cr4 |= Cr4::CR4_ENABLE_GLOBAL_PAGES;
+    }
+    if cpuid::has(Features::PCID) {
+        let cr3 = unsafe { x86::controlregs::cr3() };
+        assert_eq!(cr3 & CR3_PCID_MASK, 0, "PCIDs enabled with a tagged %cr3");
+        cr4 |= Cr4::CR4_ENABLE_PCID;
+    }
+    unsafe {
+        x86::controlregs::cr4_write(cr4);
+    }
+}
+
+/// Returns true iff global pages are enabled.
+pub fn globals_enabled() -> bool {
+    unsafe { x86::controlregs::cr4() }.contains(Cr4::CR4_ENABLE_GLOBAL_PAGES)
+}
+
+/// Returns the PCID of the current address space.
+pub fn current() -> PCID {
+    match mode() {
+        Mode::Untagged => PCID::UNTAGGED,
+        _ => PCID((unsafe { x86::controlregs::cr3() } & CR3_PCID_MASK) as u16),
+    }
+}
+
+/// Loads the address space with the given root and PCID,
+/// retaining any translations already tagged with the PCID.
+/// Without PCIDs, this flushes the TLB.
+///
+/// # Safety
+/// The root must be that of a valid address space that maps
+/// the currently executing code, and the PCID must belong to it.
+pub unsafe fn load(root: PF4K, pcid: PCID) {
+    let cr3 = match mode() {
+        Mode::Untagged => cr3_value(root, PCID::UNTAGGED, false),
+        _ => cr3_value(root, pcid, true),
+    };
+    unsafe {
+        x86::controlregs::cr3_write(cr3);
+    }
+}
+
+#[repr(u64)]
+enum InvpcidType {
+    Address = 0,
+    Context = 1,
+    All = 2,
+}
+
+#[repr(C)]
+struct InvpcidDescriptor {
+    pcid: u64,
+    addr: u64,
+}
+
+unsafe fn invpcid(typ: InvpcidType, pcid: PCID, addr: usize) {
+    let desc = InvpcidDescriptor { pcid: u64::from(pcid.id()), addr: addr as u64 };
+    unsafe {
+        asm!("invpcid {}, [{}]", in(reg) typ as u64, in(reg) &desc, options(nostack));
+    }
+}
+
+/// Invalidates the translation for the given address in the
+/// context with the given PCID on the current CPU.
+pub fn invalidate_page(pcid: PCID, va: usize) {
+    match mode() {
+        Mode::Invpcid => unsafe { invpcid(InvpcidType::Address, pcid, va) },
+        _ if pcid == current() => unsafe { x86::tlb::flush(va) },
+        _ => flush_all(),
+    }
+}
+
+/// Invalidates every non-global translation tagged with the given
+/// PCID on the current CPU.
+pub fn invalidate_context(pcid: PCID) {
+    match mode() {
+        Mode::Invpcid => unsafe { invpcid(InvpcidType::Context, pcid, 0) },
+        _ => flush_all(),
+    }
+}
+
+/// Invalidates every translation on the current CPU, in every
+/// context, including global translations.
+pub fn flush_all() {
+    match mode() {
+        Mode::Invpcid => unsafe { invpcid(InvpcidType::All, PCID::UNTAGGED, 0) },
+        Mode::Untagged if !globals_enabled() => unsafe {
+            x86::controlregs::cr3_write(x86::controlregs::cr3());
+        },
+        _ => unsafe {
+            let cr4 = x86::controlregs::cr4();
+            x86::controlregs::cr4_write(cr4 ^ Cr4::CR4_ENABLE_GLOBAL_PAGES);
+            x86::controlregs::cr4_write(cr4);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HPA, PageFrame};
+
+    #[test]
+    fn pcid_range() {
+        assert_eq!(PCID::new(4095).map(PCID::id), Some(4095));
+        assert_eq!(PCID::new(4096), None);
+    }
+
+    #[test]
+    fn cr3_values() {
+        let root = PF4K::new(HPA::new(0x1234_5000));
+        let pcid = PCID::new(0x2a).unwrap();
+        assert_eq!(cr3_value(root, pcid, false), 0x1234_502a);
+        assert_eq!(cr3_value(root, pcid, true), 0x8000_0000_1234_502a);
+        assert_eq!(cr3_value(root, PCID::UNTAGGED, false), 0x1234_5000);
+    }
+
+    #[test]
+    fn allocate_all() {
+        let allocator = Allocator::new();
+        for k in 1..PCID::COUNT {
+            assert_eq!(allocator.alloc().map(|p| usize::from(p.id())), Some(k));
+        }
+        assert_eq!(allocator.alloc(), None);
+        let pcid = PCID::new(100).unwrap();
+        allocator.free(pcid);
+        assert_eq!(allocator.alloc(), Some(pcid));
+    }
+
+    #[test]
+    #[should_panic(expected = "freeing the untagged PCID")]
+    fn free_untagged() {
+        Allocator::new().free(PCID::UNTAGGED);
+    }
+}
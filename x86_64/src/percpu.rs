@@ -0,0 +1,424 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Per-CPU data
+//!
+//! Each CPU has its own `PerCpu` structure, which it locates
+//! through the %gs segment base.  While a CPU executes
+//! hypervisor code, its GS_BASE MSR holds the address of its
+//! structure; the trap path exchanges GS_BASE with
+//! KERNEL_GS_BASE via `swapgs` on entry from, and exit to, user
+//! mode, so that user code keeps its own %gs.
+//!
+//! The first word of the structure points to the structure
+//! itself, so that a reference to it may be had with a single
+//! %gs-relative load.  Assembler code may also address the
+//! scratch words directly, as `%gs:SCRATCH_OFFSET`.
+//!
+//! Accessors are conveniently invoked on the current CPU's
+//! structure via the `cpu_local!` macro; for example,
+//! `cpu_local!(id)` returns the current CPU's ID.
+//!
+//! The structures live in the "Per-CPU Area" of HDP 0003, which
+//! is mapped identically into every address space, so that
+//! segments and tasks may rely on finding them.  Each CPU has a
+//! window of `WINDOW_SIZE` bytes in the area, indexed by its
+//! APIC ID, and its structure is at the start of its window.
+//! The interrupt stacks for traps that may arrive on a bad
+//! stack follow it, each above a guard page that is never
+//! mapped, so that overflowing one faults rather than silently
+//! corrupting whatever lies below.  The CPU's TSS, whose
+//! interrupt stack entries point at those stacks, follows them,
+//! so that every binary loading a task register may use it.  The
+//! last window of the area
+//! belongs to no CPU: it holds state shared by all of them, such
+//! as the rendezvous used to stun hyperthread siblings, which
+//! every binary must see alike.
+
+use crate::gdt::GDT;
+use crate::idt;
+use crate::tss::TSS;
+use crate::vm::{self, HardMmu, Mmu, PTEFlags, Result};
+use crate::{HyperStack, PF4K, Page, Page4K, ProcessorID, StackIndex, V4KA, VPageAddr};
+use core::cell::Cell;
+use core::mem;
+use core::ops::Range;
+use static_assertions::{const_assert, const_assert_eq};
+
+/// The region of the address space holding per-CPU data: the
+/// "Per-CPU Area" of HDP 0003.
+pub const AREA: Range<usize> = 0xFFFF_FB00_0000_0000..0xFFFF_FB40_0000_0000;
+
+/// The size of each CPU's window into the area.
+pub const WINDOW_SIZE: usize = 1 << 20;
+
+/// The number of CPUs that have windows in the area.
+pub const CPU_WINDOWS: usize = (AREA.end - AREA.start) / WINDOW_SIZE - 1;
+
+/// The window holding state shared by all CPUs.
+pub const SHARED: Range<usize> = AREA.end - WINDOW_SIZE..AREA.end;
+
+/// The pages of its window holding a CPU's structure.
+pub const STRUCTURE_PAGES: Range<usize> = 0..1;
+const_assert!(mem::size_of::<PerCpu>() <= Page4K::SIZE);
+
+/// The interrupt stacks each CPU has in its window, in order
+/// after its structure.
+pub const INTERRUPT_STACKS: [StackIndex; 4] =
+    [idt::NMI_STACK, idt::DEBUG_STACK, idt::DOUBLE_FAULT_STACK, idt::MACHINE_CHECK_STACK];
+
+/// The number of pages in each interrupt stack.
+pub const INTERRUPT_STACK_PAGES: usize = 4;
+
+// Returns the pages of the window holding the given interrupt
+// stack, which lie above its guard page.
+const fn interrupt_stack_pages(k: usize) -> Range<usize> {
+    let start = STRUCTURE_PAGES.end + k * (INTERRUPT_STACK_PAGES + 1) + 1;
+    start..start + INTERRUPT_STACK_PAGES
+}
+const_assert!(interrupt_stack_pages(INTERRUPT_STACKS.len()).start * Page4K::SIZE <= WINDOW_SIZE);
+
+/// The pages of its window holding a CPU's TSS, after its
+/// interrupt stacks.
+pub const TSS_PAGES: Range<usize> = {
+    let start = interrupt_stack_pages(INTERRUPT_STACKS.len() - 1).end;
+    start..start + 1
+};
+const_assert!(mem::size_of::<TSS>() <= Page4K::SIZE);
+const_assert!(TSS_PAGES.end * Page4K::SIZE <= WINDOW_SIZE);
+
+/// The number of scratch words in each CPU's structure.
+pub const SCRATCH_WORDS: usize = 4;
+
+/// The offset of the scratch words in the structure, for use
+/// with %gs-relative addressing from assembler.
+pub const SCRATCH_OFFSET: usize = mem::offset_of!(PerCpu, scratch);
+
+/// The data private to a CPU.
+///
+/// The structure is only ever accessed by its own CPU, hence
+/// the interior mutability via `Cell`.
+#[repr(C, align(64))]
+pub struct PerCpu {
+    this: *const PerCpu,
+    id: ProcessorID,
+    task: Cell<usize>,
+    gdt: *const GDT,
+    tss: *const TSS,
+    scratch: [Cell<u64>; SCRATCH_WORDS],
+    held_locks: Cell<u64>,
+    taint_depth: Cell<u32>,
+}
+const_assert_eq!(mem::offset_of!(PerCpu, this), 0);
+
+impl PerCpu {
+    /// Returns a new per-CPU structure for the CPU with the
+    /// given ID, which uses the given GDT and TSS.
+    pub fn new(id: ProcessorID, gdt: &'static GDT, tss: &'static TSS) -> PerCpu {
+        PerCpu {
+            this: core::ptr::null(),
+            id,
+            task: Cell::new(0),
+            gdt,
+            tss,
+            scratch: [const { Cell::new(0) }; SCRATCH_WORDS],
+            held_locks: Cell::new(0),
+            taint_depth: Cell::new(0),
+        }
+    }
+
+    /// Returns the ID of the CPU.
+    pub fn id(&self) -> ProcessorID {
+        self.id
+    }
+
+    /// Returns the address of the task running on the CPU, or
+    /// zero if there is none.
+    pub fn task(&self) -> usize {
+        self.task.get()
+    }
+
+    /// Records the address of the task running on the CPU.
+    pub fn set_task(&self, task: usize) {
+        self.task.set(task);
+    }
+
+    /// Returns the CPU's GDT.
+    pub fn gdt(&self) -> &'static GDT {
+        unsafe { &*self.gdt }
+    }
+
+    /// Returns the CPU's TSS.
+    pub fn tss(&self) -> &'static TSS {
+        unsafe { &*self.tss }
+    }
+
+    /// Returns the CPU's scratch words.
+    pub fn scratch(&self) -> &[Cell<u64>; SCRATCH_WORDS] {
+        &self.scratch
+    }
+
+    /// Returns the set of lock ranks held by the CPU, as
+    /// maintained by the lock order checker.
+    pub fn held_locks(&self) -> u64 {
+        self.held_locks.get()
+    }
+
+    /// Records the set of lock ranks held by the CPU.
+    pub fn set_held_locks(&self, held: u64) {
+        self.held_locks.set(held);
+    }
+
+    /// Returns the depth of nested calls into privileged
+    /// segments on the CPU; it is tainted while this is nonzero.
+    pub fn taint_depth(&self) -> u32 {
+        self.taint_depth.get()
+    }
+
+    /// Records the depth of nested privileged segment calls.
+    pub fn set_taint_depth(&self, depth: u32) {
+        self.taint_depth.set(depth);
+    }
+}
+
+/// Returns the address of the given CPU's window into the
+/// per-CPU area.
+pub fn window(cpu: ProcessorID) -> V4KA {
+    let id = u32::from(cpu) as usize;
+    assert!(id < CPU_WINDOWS, "APIC ID out of range for per-CPU area");
+    V4KA::new(AREA.start + id * WINDOW_SIZE)
+}
+
+/// Maps the given pages of the given CPU's window, with frames
+/// taken from the allocator.  Pages of the window left unmapped
+/// fault on access.
+pub fn map_window<F>(cpu: ProcessorID, pages: Range<usize>, allocator: &mut F) -> Result<()>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    map_window_in(&HardMmu, cpu, pages, allocator)
+}
+
+/// Maps pages of a CPU's window as `map_window` does, into the
+/// address space viewed through the given MMU.
+pub fn map_window_in<M, F>(
+    mmu: &M,
+    cpu: ProcessorID,
+    pages: Range<usize>,
+    allocator: &mut F,
+) -> Result<()>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
+{
+    map_pages_in(mmu, window(cpu).addr(), pages, allocator)
+}
+
+/// Maps the given pages of the shared window, with frames taken
+/// from the allocator.
+pub fn map_shared<F>(pages: Range<usize>, allocator: &mut F) -> Result<()>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    map_pages_in(&HardMmu, SHARED.start, pages, allocator)
+}
+
+// Maps the given pages of the window starting at `start`.
+fn map_pages_in<M, F>(mmu: &M, start: usize, pages: Range<usize>, allocator: &mut F) -> Result<()>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
+{
+    const FLAGS: PTEFlags =
+        PTEFlags::PRESENT.union(PTEFlags::WRITE).union(PTEFlags::NX).union(PTEFlags::GLOBAL);
+    assert!(pages.end * Page4K::SIZE <= WINDOW_SIZE, "pages lie in the window");
+    for page in pages {
+        let va = V4KA::new(start + page * Page4K::SIZE);
+        let frame = allocator()?;
+        vm::map_in(mmu, frame, FLAGS, va, allocator)?;
+    }
+    Ok(())
+}
+
+/// Moves the given structure to the start of its CPU's window,
+/// mapping that with a frame taken from the allocator, and
+/// returns it in place, ready to be installed.
+pub fn place<F>(percpu: PerCpu, allocator: &mut F) -> Result<&'static mut PerCpu>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    let cpu = percpu.id();
+    map_window(cpu, STRUCTURE_PAGES, allocator)?;
+    let ptr = Page4K::proto_ptr().with_addr(window(cpu).addr()).cast::<PerCpu>().cast_mut();
+    unsafe {
+        ptr.write(percpu);
+        Ok(&mut *ptr)
+    }
+}
+
+/// Maps the given CPU's interrupt stacks, leaving the guard page
+/// below each unmapped, with frames taken from the allocator.
+pub fn map_interrupt_stacks<F>(cpu: ProcessorID, allocator: &mut F) -> Result<()>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    map_interrupt_stacks_in(&HardMmu, cpu, allocator)
+}
+
+/// Maps a CPU's interrupt stacks as `map_interrupt_stacks`
+/// does, into the address space viewed through the given MMU.
+pub fn map_interrupt_stacks_in<M, F>(mmu: &M, cpu: ProcessorID, allocator: &mut F) -> Result<()>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
+{
+    for k in 0..INTERRUPT_STACKS.len() {
+        map_window_in(mmu, cpu, interrupt_stack_pages(k), allocator)?;
+    }
+    Ok(())
+}
+
+/// Points the interrupt stack entries of the given TSS at the
+/// given CPU's interrupt stacks, which must be mapped.
+pub fn set_interrupt_stacks(tss: &mut TSS, cpu: ProcessorID) {
+    let base = window(cpu).addr();
+    for (k, &index) in INTERRUPT_STACKS.iter().enumerate() {
+        let pages = interrupt_stack_pages(k);
+        let address = core::ptr::without_provenance(base + pages.start * Page4K::SIZE);
+        let mut stack = HyperStack { address, size: pages.len() * Page4K::SIZE };
+        tss.set_stack(index, &mut stack);
+    }
+}
+
+/// Places a TSS for the given CPU in its window, mapping that
+/// with a frame taken from the allocator, and returns it, with
+/// its interrupt stack entries pointing at the CPU's interrupt
+/// stacks, which must be mapped.
+pub fn place_tss<F>(cpu: ProcessorID, allocator: &mut F) -> Result<&'static mut TSS>
+where
+    F: FnMut() -> Result<PF4K>,
+{
+    map_window(cpu, TSS_PAGES, allocator)?;
+    let va = window(cpu).addr() + TSS_PAGES.start * Page4K::SIZE;
+    let ptr = Page4K::proto_ptr().with_addr(va).cast::<TSS>().cast_mut();
+    let tss = unsafe {
+        ptr.write(TSS::empty());
+        &mut *ptr
+    };
+    set_interrupt_stacks(tss, cpu);
+    Ok(tss)
+}
+
+/// Makes the given structure that of the current CPU, by
+/// pointing GS_BASE at it.  KERNEL_GS_BASE, which holds the
+/// user's %gs base while in the hypervisor, is cleared.
+///
+/// # Safety
+/// The structure must describe the current CPU, and must not
+/// be installed on any other.
+pub unsafe fn install(percpu: &'static mut PerCpu) {
+    percpu.this = percpu;
+    let base = (percpu as *const PerCpu).addr() as u64;
+    unsafe {
+        x86::msr::wrmsr(x86::msr::IA32_GS_BASE, base);
+        x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, 0);
+    }
+}
+
+/// Returns the current CPU's structure.  It is a fatal error to
+/// call this before a structure has been installed.
+#[inline(always)]
+pub fn this() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        core::arch::asm!(
+            "movq %gs:0, {}",
+            out(reg) this,
+            options(att_syntax, nostack, preserves_flags, readonly)
+        );
+        &*this
+    }
+}
+
+/// Invokes the named accessor on the current CPU's `PerCpu`.
+#[macro_export]
+macro_rules! cpu_local {
+    ($accessor:ident) => {
+        $crate::percpu::this().$accessor()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        assert_eq!(SCRATCH_OFFSET, 40);
+        assert_eq!(mem::size_of::<PerCpu>(), 128);
+    }
+
+    #[test]
+    fn fields() {
+        static GDT: GDT = GDT::empty();
+        static TSS: TSS = TSS::empty();
+        let percpu = PerCpu::new(ProcessorID(3), &GDT, &TSS);
+        assert_eq!(u32::from(percpu.id()), 3);
+        assert_eq!(percpu.task(), 0);
+        percpu.set_task(0x1000);
+        assert_eq!(percpu.task(), 0x1000);
+        assert!(core::ptr::eq(percpu.gdt(), &GDT));
+        assert!(core::ptr::eq(percpu.tss(), &TSS));
+        assert!(percpu.scratch().iter().all(|word| word.get() == 0));
+        assert_eq!(percpu.held_locks(), 0);
+        assert_eq!(percpu.taint_depth(), 0);
+    }
+
+    #[test]
+    fn windows() {
+        use crate::vm::soft::SoftMmu;
+        assert_eq!(window(ProcessorID(0)).addr(), 0xFFFF_FB00_0000_0000);
+        assert_eq!(window(ProcessorID(3)).addr(), 0xFFFF_FB00_0030_0000);
+        let last = ProcessorID((CPU_WINDOWS - 1) as u32);
+        assert_eq!(window(last).addr() + WINDOW_SIZE, SHARED.start);
+
+        let mmu = SoftMmu::new(16);
+        let cpu = ProcessorID(3);
+        map_window_in(&mmu, cpu, 1..3, &mut || mmu.alloc()).unwrap();
+        let base = window(cpu).addr();
+        assert!(vm::translate_in(&mmu, base).is_none());
+        assert!(vm::translate_in(&mmu, base + Page4K::SIZE).is_some());
+        assert!(vm::translate_in(&mmu, base + 2 * Page4K::SIZE).is_some());
+        assert!(vm::translate_in(&mmu, base + 3 * Page4K::SIZE).is_none());
+    }
+
+    #[test]
+    fn interrupt_stacks() {
+        use crate::vm::soft::SoftMmu;
+        assert_eq!(interrupt_stack_pages(0), 2..6);
+        assert_eq!(interrupt_stack_pages(3), 17..21);
+        let mmu = SoftMmu::new(32);
+        let cpu = ProcessorID(1);
+        map_interrupt_stacks_in(&mmu, cpu, &mut || mmu.alloc()).unwrap();
+        let base = window(cpu).addr();
+        let mapped = |page: usize| vm::translate_in(&mmu, base + page * Page4K::SIZE).is_some();
+        for k in 0..INTERRUPT_STACKS.len() {
+            let pages = interrupt_stack_pages(k);
+            assert!(!mapped(pages.start - 1), "guard page is unmapped");
+            assert!(pages.clone().all(mapped));
+        }
+        assert!(!mapped(0));
+        assert!(!mapped(interrupt_stack_pages(3).end));
+        assert_eq!(TSS_PAGES, 21..22);
+    }
+
+    #[test]
+    #[should_panic]
+    fn window_out_of_range() {
+        window(ProcessorID(CPU_WINDOWS as u32));
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Legacy 8259 PICs
+//!
+//! We route interrupts through the I/O APICs, so the 8259s are
+//! only disabled.  Masking every input is not quite enough: a
+//! masked PIC may still raise a spurious IRQ 7 or 15, which at
+//! the BIOS's vector offsets would arrive as an exception.  So we
+//! first reinitialize them to deliver on interrupt vectors.
+
+/// The vector on which the primary PIC delivers IRQ 0; the
+/// secondary delivers IRQs 8 to 15 on the next eight vectors.
+pub const VECTOR_BASE: u8 = 0x20;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+// Writes to an unused port, giving the PICs time to settle
+// between initialization words.
+unsafe fn io_wait() {
+    unsafe {
+        x86::io::outb(0x80, 0);
+    }
+}
+
+/// Remaps the PICs onto interrupt vectors starting at
+/// `VECTOR_BASE`, and masks every input.
+///
+/// # Safety
+/// Interrupts must be disabled, and the PICs unused.
+pub unsafe fn disable() {
+    const ICW1_INIT_ICW4: u8 = 0x11;
+    const ICW3_PIC1_CASCADE: u8 = 1 << 2;
+    const ICW3_PIC2_ID: u8 = 2;
+    const ICW4_8086: u8 = 0x01;
+    const MASK_ALL: u8 = 0xFF;
+    let init = [
+        (PIC1_COMMAND, ICW1_INIT_ICW4),
+        (PIC2_COMMAND, ICW1_INIT_ICW4),
+        (PIC1_DATA, VECTOR_BASE),
+        (PIC2_DATA, VECTOR_BASE + 8),
+        (PIC1_DATA, ICW3_PIC1_CASCADE),
+        (PIC2_DATA, ICW3_PIC2_ID),
+        (PIC1_DATA, ICW4_8086),
+        (PIC2_DATA, ICW4_8086),
+        (PIC1_DATA, MASK_ALL),
+        (PIC2_DATA, MASK_ALL),
+    ];
+    for (port, word) in init {
+        unsafe {
+            x86::io::outb(port, word);
+            io_wait();
+        }
+    }
+}
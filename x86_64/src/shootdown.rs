@@ -0,0 +1,328 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Cross-CPU TLB shootdown
+//!
+//! Segments are mapped into every address space, so when one CPU
+//! changes a shared mapping, any other CPU may still hold a stale
+//! translation for it in its TLB.  To remove those, the CPU making
+//! the change posts a request describing the affected addresses in
+//! a mailbox, sends an IPI on a reserved vector to every other
+//! online CPU, and waits for each to acknowledge that it has
+//! invalidated them.
+//!
+//! Only one request is in flight at a time.  A CPU waiting to post
+//! a request services any request posted to it in the meantime, so
+//! that CPUs shooting at one another with interrupts disabled do
+//! not deadlock.
+
+use crate::ProcessorID;
+use crate::cpu;
+use crate::lapic::{self, InterruptVector};
+use crate::trap;
+use crate::vm::{HardMmu, Mmu};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// The interrupt vector reserved for shootdown IPIs.
+pub const VECTOR: InterruptVector = InterruptVector::Vector253;
+
+/// One more than the largest APIC ID that may take part in
+/// shootdowns.
+pub const MAX_CPUS: usize = 256;
+
+/// The most pages a single request may invalidate individually.
+/// Larger requests are turned into full flushes.
+pub const MAX_PAGES: usize = 32;
+
+const NWORDS: usize = MAX_CPUS / 64;
+const PAGE_SIZE: usize = 4096;
+
+const KIND_ALL: usize = 0;
+const KIND_RANGE: usize = 1;
+const KIND_PAGES: usize = 2;
+
+/// Describes the translations a shootdown invalidates.
+#[derive(Clone, Debug)]
+pub enum Request<'a> {
+    /// Every translation in the TLB.
+    All,
+    /// Translations for every page in a range of addresses.
+    Range(Range<usize>),
+    /// Translations for a batch of individual pages.
+    Pages(&'a [usize]),
+}
+
+/// The state shared between CPUs taking part in the shootdown
+/// protocol: the set of online CPUs, and a mailbox holding the
+/// request in flight along with the CPUs yet to acknowledge it.
+pub struct Shootdown {
+    lock: AtomicBool,
+    online: [AtomicU64; NWORDS],
+    pending: [AtomicU64; NWORDS],
+    kind: AtomicUsize,
+    len: AtomicUsize,
+    pages: [AtomicUsize; MAX_PAGES],
+}
+
+impl Shootdown {
+    /// Returns a new mailbox with no online CPUs.
+    pub const fn new() -> Shootdown {
+        Shootdown {
+            lock: AtomicBool::new(false),
+            online: [const { AtomicU64::new(0) }; NWORDS],
+            pending: [const { AtomicU64::new(0) }; NWORDS],
+            kind: AtomicUsize::new(KIND_ALL),
+            len: AtomicUsize::new(0),
+            pages: [const { AtomicUsize::new(0) }; MAX_PAGES],
+        }
+    }
+
+    /// Adds the given CPU to the set that receives requests.
+    pub fn online(&self, cpu: ProcessorID) {
+        let (word, bit) = slot(cpu);
+        self.online[word].fetch_or(bit, Ordering::AcqRel);
+    }
+
+    /// Removes the given CPU from the set that receives requests.
+    pub fn offline(&self, cpu: ProcessorID) {
+        let (word, bit) = slot(cpu);
+        self.online[word].fetch_and(!bit, Ordering::AcqRel);
+    }
+
+    /// Returns true iff any CPU is online.
+    pub fn any_online(&self) -> bool {
+        self.online.iter().any(|word| word.load(Ordering::Acquire) != 0)
+    }
+
+    /// Posts the request to every online CPU other than `me`,
+    /// calls `signal` for each, and waits until all of them have
+    /// acknowledged it.  Requests posted to `me` while waiting are
+    /// serviced through the given MMU.  Invalidating translations
+    /// on `me` is left to the caller.
+    pub fn initiate<M, S>(&self, me: ProcessorID, mmu: &M, request: Request<'_>, mut signal: S)
+    where
+        M: Mmu,
+        S: FnMut(ProcessorID),
+    {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.service(me, mmu);
+            cpu::relax();
+        }
+        self.post(request);
+        let (my_word, my_bit) = slot(me);
+        for (word, pending) in self.pending.iter().enumerate() {
+            let mut targets = self.online[word].load(Ordering::Acquire);
+            if word == my_word {
+                targets &= !my_bit;
+            }
+            pending.store(targets, Ordering::Release);
+        }
+        for (word, pending) in self.pending.iter().enumerate() {
+            let mut targets = pending.load(Ordering::Relaxed);
+            while targets != 0 {
+                let bit = targets.trailing_zeros();
+                targets &= targets - 1;
+                signal(ProcessorID((word * 64) as u32 + bit));
+            }
+        }
+        while self.pending.iter().any(|pending| pending.load(Ordering::Acquire) != 0) {
+            cpu::relax();
+        }
+        self.lock.store(false, Ordering::Release);
+    }
+
+    /// Invalidates the translations described by the request in
+    /// flight, if it is pending on `me`, and acknowledges it.
+    /// Returns true iff there was such a request.
+    pub fn service<M: Mmu>(&self, me: ProcessorID, mmu: &M) -> bool {
+        let (word, bit) = slot(me);
+        if self.pending[word].load(Ordering::Acquire) & bit == 0 {
+            return false;
+        }
+        let len = self.len.load(Ordering::Relaxed);
+        match self.kind.load(Ordering::Relaxed) {
+            KIND_RANGE => {
+                let start = self.pages[0].load(Ordering::Relaxed);
+                let end = self.pages[1].load(Ordering::Relaxed);
+                for va in (start..end).step_by(PAGE_SIZE) {
+                    mmu.invalidate(va);
+                }
+            }
+            KIND_PAGES => {
+                for page in &self.pages[..len] {
+                    mmu.invalidate(page.load(Ordering::Relaxed));
+                }
+            }
+            _ => mmu.flush_tlb(),
+        }
+        self.pending[word].fetch_and(!bit, Ordering::Release);
+        true
+    }
+
+    // Writes the request into the mailbox.  The caller must hold
+    // the lock, and publishes the request by setting the pending
+    // CPUs.
+    fn post(&self, request: Request<'_>) {
+        match request {
+            Request::Range(range)
+                if range.end.saturating_sub(range.start) / PAGE_SIZE <= MAX_PAGES =>
+            {
+                self.kind.store(KIND_RANGE, Ordering::Relaxed);
+                self.pages[0].store(range.start & !(PAGE_SIZE - 1), Ordering::Relaxed);
+                self.pages[1].store(range.end, Ordering::Relaxed);
+            }
+            Request::Pages(pages) if pages.len() <= MAX_PAGES => {
+                self.kind.store(KIND_PAGES, Ordering::Relaxed);
+                self.len.store(pages.len(), Ordering::Relaxed);
+                for (slot, &page) in self.pages.iter().zip(pages) {
+                    slot.store(page, Ordering::Relaxed);
+                }
+            }
+            _ => self.kind.store(KIND_ALL, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Shootdown {
+    fn default() -> Shootdown {
+        Shootdown::new()
+    }
+}
+
+// Returns the word index and bit for the given CPU in a CPU set.
+fn slot(cpu: ProcessorID) -> (usize, u64) {
+    let id = u32::from(cpu) as usize;
+    assert!(id < MAX_CPUS, "APIC ID out of range for shootdown");
+    (id / 64, 1 << (id % 64))
+}
+
+static SHOOTDOWN: Shootdown = Shootdown::new();
+
+/// Adds the current CPU to the set receiving shootdowns.  The
+/// trap handler for `VECTOR` must already be registered.
+pub fn online() {
+    SHOOTDOWN.online(lapic::id());
+}
+
+/// Removes the current CPU from the set receiving shootdowns.
+pub fn offline() {
+    SHOOTDOWN.offline(lapic::id());
+}
+
+/// Invalidates the translations described by the request on
+/// every other online CPU, returning once all have done so.
+pub fn shoot_down(request: Request<'_>) {
+    if !SHOOTDOWN.any_online() {
+        return;
+    }
+    SHOOTDOWN.initiate(lapic::id(), &HardMmu, request, |cpu| unsafe {
+        lapic::send_ipi(cpu, VECTOR);
+    });
+}
+
+/// Services a shootdown request posted to the current CPU, if
+/// any.  Returns true iff there was such a request.
+pub fn service() -> bool {
+    SHOOTDOWN.service(lapic::id(), &HardMmu)
+}
+
+/// Services a shootdown request posted to the current CPU.  This
+/// is the trap handler for `VECTOR`.
+pub fn handle(_frame: &mut trap::Frame) {
+    service();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::soft::SoftMmu;
+    use std::thread;
+
+    // Runs a CPU that services requests until told to stop, and
+    // returns the number of pages it invalidated and the number
+    // of times it flushed its TLB.
+    fn receiver(shootdown: &Shootdown, me: ProcessorID, stop: &AtomicBool) -> (usize, usize) {
+        let mmu = SoftMmu::new(1);
+        while !stop.load(Ordering::Acquire) {
+            shootdown.service(me, &mmu);
+            cpu::relax();
+        }
+        (mmu.invalidations(), mmu.flushes())
+    }
+
+    #[test]
+    fn no_other_cpus() {
+        let shootdown = Shootdown::new();
+        let mmu = SoftMmu::new(1);
+        shootdown.online(ProcessorID(3));
+        shootdown.initiate(ProcessorID(3), &mmu, Request::All, |_| panic!("signalled"));
+        assert!(!shootdown.service(ProcessorID(3), &mmu));
+        assert_eq!(mmu.flushes(), 0);
+    }
+
+    #[test]
+    fn requests_reach_online_cpus() {
+        let shootdown = Shootdown::new();
+        let stop = AtomicBool::new(false);
+        for id in [0, 1, 65] {
+            shootdown.online(ProcessorID(id));
+        }
+        thread::scope(|s| {
+            let one = s.spawn(|| receiver(&shootdown, ProcessorID(1), &stop));
+            let other = s.spawn(|| receiver(&shootdown, ProcessorID(65), &stop));
+            let offline = s.spawn(|| receiver(&shootdown, ProcessorID(2), &stop));
+            let mmu = SoftMmu::new(1);
+            let mut signalled = Vec::new();
+            let pages = [0x1000, 0x5000, 0x9000];
+            shootdown.initiate(ProcessorID(0), &mmu, Request::Pages(&pages), |cpu| {
+                signalled.push(cpu.0)
+            });
+            assert_eq!(signalled, [1, 65]);
+            shootdown.initiate(ProcessorID(0), &mmu, Request::Range(0x4000..0x8000), |_| {});
+            let big = 0..(MAX_PAGES + 1) * PAGE_SIZE;
+            shootdown.initiate(ProcessorID(0), &mmu, Request::Range(big), |_| {});
+            stop.store(true, Ordering::Release);
+            assert_eq!(one.join().unwrap(), (7, 1));
+            assert_eq!(other.join().unwrap(), (7, 1));
+            assert_eq!(offline.join().unwrap(), (0, 0));
+            assert_eq!(mmu.invalidations() + mmu.flushes(), 0);
+        });
+    }
+
+    #[test]
+    fn concurrent_initiators() {
+        let shootdown = Shootdown::new();
+        let done = [AtomicBool::new(false), AtomicBool::new(false)];
+        shootdown.online(ProcessorID(0));
+        shootdown.online(ProcessorID(1));
+        thread::scope(|s| {
+            let cpus = [0, 1].map(|id| {
+                let (shootdown, done) = (&shootdown, &done);
+                s.spawn(move || {
+                    let mmu = SoftMmu::new(1);
+                    let me = ProcessorID(id);
+                    for _ in 0..100 {
+                        shootdown.initiate(me, &mmu, Request::All, |_| {});
+                    }
+                    done[id as usize].store(true, Ordering::Release);
+                    while !done.iter().all(|d| d.load(Ordering::Acquire)) {
+                        shootdown.service(me, &mmu);
+                    }
+                    mmu.flushes()
+                })
+            });
+            for cpu in cpus {
+                assert_eq!(cpu.join().unwrap(), 100);
+            }
+        });
+    }
+}
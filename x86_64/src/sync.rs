@@ -0,0 +1,37 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Synchronization primitives
+//!
+//! Spinning locks and one-time initialization, for use anywhere
+//! in the system, including segment code.  None of them
+//! allocates.
+//!
+//! * `TicketLock` grants the lock in arrival order.  It is small
+//!   and cheap when uncontended, but every waiter spins on the
+//!   same cache line.
+//! * `McsLock` queues waiters, each of which spins on a node of
+//!   its own, on its stack.  It is held for the duration of a
+//!   closure, and is the better choice for locks that may be
+//!   heavily contended.
+//! * `Once` runs an initializer exactly once, no matter how many
+//!   CPUs race to run it, and holds its result.
+//!
+//! Locks may be given a `Rank`; in debug builds, the `order`
+//! module then checks that each CPU acquires ranked locks in
+//! increasing order of rank, which rules out deadlock between
+//! them.
+
+pub mod mcs;
+pub mod once;
+pub mod order;
+pub mod ticket;
+
+pub use mcs::McsLock;
+pub use once::Once;
+pub use order::Rank;
+pub use ticket::{TicketGuard, TicketLock};
@@ -0,0 +1,227 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # MCS queue locks
+//!
+//! After Mellor-Crummey and Scott.  Waiters form a queue of
+//! nodes, the tail of which is held by the lock; each waiter
+//! enqueues its own node, and spins on a flag in that node until
+//! its predecessor, on releasing the lock, clears it.  Since no
+//! two CPUs spin on the same cache line, contention does not
+//! generate coherence traffic beyond the hand-off itself.
+//!
+//! A node must stay put until the lock is released, as its
+//! successor writes through a pointer to it.  The lock is thus
+//! only held for the duration of a closure, with the node on the
+//! stack of the function that calls it: a guard that could be
+//! leaked would leave the lock holding a dangling node.
+
+use super::order::{self, Rank};
+use crate::cpu;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+// A waiter's place in the queue of an `McsLock`.
+#[repr(align(64))]
+struct McsNode {
+    next: AtomicPtr<McsNode>,
+    waiting: AtomicBool,
+}
+
+impl McsNode {
+    const fn new() -> McsNode {
+        McsNode { next: AtomicPtr::new(ptr::null_mut()), waiting: AtomicBool::new(false) }
+    }
+}
+
+/// An MCS lock protecting a value of type `T`.
+pub struct McsLock<T: ?Sized> {
+    tail: AtomicPtr<McsNode>,
+    rank: Option<Rank>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for McsLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for McsLock<T> {}
+
+impl<T> McsLock<T> {
+    /// Returns a new, unlocked, unranked lock holding the value.
+    pub const fn new(value: T) -> McsLock<T> {
+        McsLock { tail: AtomicPtr::new(ptr::null_mut()), rank: None, value: UnsafeCell::new(value) }
+    }
+
+    /// Returns a new, unlocked lock of the given rank holding
+    /// the value.
+    pub const fn with_rank(value: T, rank: Rank) -> McsLock<T> {
+        let mut lock = Self::new(value);
+        lock.rank = Some(rank);
+        lock
+    }
+
+    /// Consumes the lock, returning the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> McsLock<T> {
+    /// Acquires the lock, spinning until it is handed to this
+    /// CPU, calls the function with the value, and releases the
+    /// lock.  Returns the function's result.
+    pub fn with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let node = McsNode::new();
+        let mut guard = self.lock(&node);
+        f(&mut guard)
+    }
+
+    /// Calls the function with the value, as `with_lock` does, if
+    /// the lock is available without waiting.
+    pub fn try_with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        let node = McsNode::new();
+        let mut guard = self.try_lock(&node)?;
+        Some(f(&mut guard))
+    }
+
+    // Acquires the lock, queueing the given node and spinning
+    // until the lock is handed to it.  The guard must be dropped
+    // before the node is.
+    fn lock<'a>(&'a self, node: &'a McsNode) -> McsGuard<'a, T> {
+        order::acquire(self.rank);
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.waiting.store(true, Ordering::Relaxed);
+        let me = ptr::from_ref(node).cast_mut();
+        let prev = self.tail.swap(me, Ordering::AcqRel);
+        if !prev.is_null() {
+            unsafe { &*prev }.next.store(me, Ordering::Release);
+            while node.waiting.load(Ordering::Acquire) {
+                cpu::relax();
+            }
+        }
+        McsGuard { lock: self, node }
+    }
+
+    // Acquires the lock with the given node if it is available
+    // without waiting.  As for `lock`, the guard must be dropped
+    // before the node is.
+    fn try_lock<'a>(&'a self, node: &'a McsNode) -> Option<McsGuard<'a, T>> {
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        let me = ptr::from_ref(node).cast_mut();
+        self.tail
+            .compare_exchange(ptr::null_mut(), me, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        order::acquire(self.rank);
+        Some(McsGuard { lock: self, node })
+    }
+
+    /// Returns true iff the lock is held.
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Returns a mutable reference to the value, which needs no
+    /// locking as the lock is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+// Holds an MCS lock, releasing it when dropped, even should the
+// function holding it unwind.
+struct McsGuard<'a, T: ?Sized> {
+    lock: &'a McsLock<T>,
+    node: &'a McsNode,
+}
+
+impl<T: ?Sized> Deref for McsGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for McsGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for McsGuard<'_, T> {
+    fn drop(&mut self) {
+        let me = ptr::from_ref(self.node).cast_mut();
+        let mut next = self.node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // No successor is visible: if we are still the tail,
+            // the queue is empty, and we are done.  Otherwise, a
+            // successor is enqueueing itself; wait for it to link
+            // itself to us.
+            let empty = self.lock.tail.compare_exchange(
+                me,
+                ptr::null_mut(),
+                Ordering::Release,
+                Ordering::Relaxed,
+            );
+            if empty.is_err() {
+                loop {
+                    next = self.node.next.load(Ordering::Acquire);
+                    if !next.is_null() {
+                        break;
+                    }
+                    cpu::relax();
+                }
+            }
+        }
+        if !next.is_null() {
+            unsafe { &*next }.waiting.store(false, Ordering::Release);
+        }
+        order::release(self.lock.rank);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn try_with_lock() {
+        let lock = McsLock::new(1);
+        let held = lock.try_with_lock(|value| {
+            *value += 1;
+            assert!(lock.is_locked());
+            assert_eq!(lock.try_with_lock(|_| ()), None);
+        });
+        assert_eq!(held, Some(()));
+        assert!(!lock.is_locked());
+        assert_eq!(lock.with_lock(|value| *value), 2);
+        assert_eq!(lock.into_inner(), 2);
+    }
+
+    #[test]
+    fn contention() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 10_000;
+        let lock = Arc::new(McsLock::with_rank(0usize, Rank::new(1)));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                std::thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        lock.with_lock(|value| *value += 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(!lock.is_locked());
+        assert_eq!(lock.with_lock(|value| *value), THREADS * ROUNDS);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # One-time initialization
+//!
+//! The first CPU to call `Once::call_once` runs the initializer;
+//! any others spin until it has finished, and all of them then
+//! share its result.  We build with `panic = "abort"`, so an
+//! initializer that panics never returns, and there is no
+//! poisoned state.
+
+use crate::cpu;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value of type `T` that is initialized exactly once.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    /// Returns a new, uninitialized value.
+    pub const fn new() -> Once<T> {
+        Once { state: AtomicU8::new(INCOMPLETE), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// Initializes the value with the given function, if it has
+    /// not been already, and returns a reference to it.
+    pub fn call_once<F: FnOnce() -> T>(&self, init: F) -> &T {
+        match self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(init()) };
+                self.state.store(COMPLETE, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != COMPLETE {
+                    cpu::relax();
+                }
+            }
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Returns the value, if it has been initialized.
+    pub fn get(&self) -> Option<&T> {
+        self.is_completed().then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Returns true iff the value has been initialized.
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Once<T> {
+        Once::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn runs_once() {
+        const THREADS: usize = 8;
+        let once = Arc::new(Once::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|k| {
+                let once = Arc::clone(&once);
+                let calls = Arc::clone(&calls);
+                std::thread::spawn(move || {
+                    *once.call_once(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        k
+                    })
+                })
+            })
+            .collect();
+        let values: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(values.iter().all(|&v| Some(&v) == once.get()));
+    }
+
+    #[test]
+    fn get() {
+        let once = Once::new();
+        assert!(!once.is_completed());
+        assert_eq!(once.get(), None);
+        assert_eq!(*once.call_once(|| String::from("hello")), "hello");
+        assert_eq!(*once.call_once(|| String::from("again")), "hello");
+        assert_eq!(once.get().map(String::as_str), Some("hello"));
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Lock ordering
+//!
+//! A lock may be assigned a rank, and a CPU holding a ranked
+//! lock may only acquire ranked locks of strictly higher rank.
+//! If every CPU follows this rule, no cycle of CPUs can each
+//! wait on a lock held by the next, and so ranked locks cannot
+//! deadlock.  Unranked locks are not checked.
+//!
+//! The ranks each CPU holds are recorded as a bit set in its
+//! per-CPU area, so checking must be enabled explicitly in each
+//! binary, once the per-CPU area is installed.  In release
+//! builds, checking is compiled out.
+
+use crate::percpu;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The rank of a lock, from 0 to 63.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rank(u8);
+
+impl Rank {
+    /// The number of distinct ranks.
+    pub const COUNT: u8 = 64;
+
+    /// Returns the given rank.
+    pub const fn new(rank: u8) -> Rank {
+        assert!(rank < Self::COUNT, "lock rank out of range");
+        Rank(rank)
+    }
+
+    fn bit(self) -> u64 {
+        1 << self.0
+    }
+}
+
+impl fmt::Display for Rank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables lock order checking in this binary.  This has no
+/// effect in release builds.
+///
+/// # Safety
+/// The per-CPU area must be installed on every CPU that may
+/// acquire a ranked lock from here on.
+pub unsafe fn enable() {
+    ENABLED.store(cfg!(debug_assertions), Ordering::Relaxed);
+}
+
+/// Returns true iff lock order checking is enabled.
+pub fn enabled() -> bool {
+    cfg!(debug_assertions) && ENABLED.load(Ordering::Relaxed)
+}
+
+// Returns the set of ranks held once the given rank is acquired
+// with the given set held, or the highest rank held that
+// forbids it.
+fn acquire_in(held: u64, rank: Rank) -> Result<u64, Rank> {
+    let forbidden = held & !(rank.bit() - 1);
+    if forbidden != 0 {
+        return Err(Rank(63 - forbidden.leading_zeros() as u8));
+    }
+    Ok(held | rank.bit())
+}
+
+fn release_in(held: u64, rank: Rank) -> u64 {
+    debug_assert_ne!(held & rank.bit(), 0, "releasing lock of rank {rank} not held");
+    held & !rank.bit()
+}
+
+/// Records that the current CPU is acquiring a lock of the
+/// given rank, panicking if that would violate the order.
+pub(crate) fn acquire(rank: Option<Rank>) {
+    if let Some(rank) = rank
+        && enabled()
+    {
+        let percpu = percpu::this();
+        match acquire_in(percpu.held_locks(), rank) {
+            Ok(held) => percpu.set_held_locks(held),
+            Err(held) => panic!("lock order: acquiring rank {rank} while holding rank {held}"),
+        }
+    }
+}
+
+/// Records that the current CPU has released a lock of the
+/// given rank.
+pub(crate) fn release(rank: Option<Rank>) {
+    if let Some(rank) = rank
+        && enabled()
+    {
+        let percpu = percpu::this();
+        percpu.set_held_locks(release_in(percpu.held_locks(), rank));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increasing_order() {
+        let held = acquire_in(0, Rank::new(3)).unwrap();
+        let held = acquire_in(held, Rank::new(10)).unwrap();
+        assert_eq!(held, (1 << 3) | (1 << 10));
+        assert_eq!(acquire_in(held, Rank::new(63)), Ok(held | (1 << 63)));
+        // Releasing out of order is fine.
+        let held = release_in(held, Rank::new(3));
+        assert_eq!(held, 1 << 10);
+        assert_eq!(release_in(held, Rank::new(10)), 0);
+    }
+
+    #[test]
+    fn violations() {
+        let held = (1 << 3) | (1 << 10);
+        assert_eq!(acquire_in(held, Rank::new(10)), Err(Rank::new(10)));
+        assert_eq!(acquire_in(held, Rank::new(5)), Err(Rank::new(10)));
+        assert_eq!(acquire_in(held, Rank::new(0)), Err(Rank::new(10)));
+        assert_eq!(acquire_in(1 << 3, Rank::new(2)), Err(Rank::new(3)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rank_range() {
+        Rank::new(Rank::COUNT);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Ticket locks
+//!
+//! A CPU wanting the lock takes the next ticket, and waits until
+//! that ticket is served; releasing the lock serves the next.
+//! Thus the lock is granted in the order in which it was
+//! requested.
+
+use super::order::{self, Rank};
+use crate::cpu;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A ticket lock protecting a value of type `T`.
+pub struct TicketLock<T: ?Sized> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    rank: Option<Rank>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    /// Returns a new, unlocked, unranked lock holding the value.
+    pub const fn new(value: T) -> TicketLock<T> {
+        TicketLock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            rank: None,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a new, unlocked lock of the given rank holding
+    /// the value.
+    pub const fn with_rank(value: T, rank: Rank) -> TicketLock<T> {
+        let mut lock = Self::new(value);
+        lock.rank = Some(rank);
+        lock
+    }
+
+    /// Consumes the lock, returning the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    /// Acquires the lock, spinning until it is available.
+    pub fn lock(&self) -> TicketGuard<'_, T> {
+        order::acquire(self.rank);
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            cpu::relax();
+        }
+        TicketGuard { lock: self }
+    }
+
+    /// Acquires the lock if it is available without waiting.
+    pub fn try_lock(&self) -> Option<TicketGuard<'_, T>> {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        order::acquire(self.rank);
+        Some(TicketGuard { lock: self })
+    }
+
+    /// Returns true iff the lock is held.
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
+    /// Returns a mutable reference to the value, which needs no
+    /// locking as the lock is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// Holds a ticket lock, releasing it when dropped.
+pub struct TicketGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T: ?Sized> Deref for TicketGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketGuard<'_, T> {
+    fn drop(&mut self) {
+        // Only the holder advances `serving`.
+        let serving = self.lock.serving.load(Ordering::Relaxed);
+        self.lock.serving.store(serving.wrapping_add(1), Ordering::Release);
+        order::release(self.lock.rank);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn try_lock() {
+        let lock = TicketLock::new(1);
+        let mut guard = lock.try_lock().unwrap();
+        *guard += 1;
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.lock(), 2);
+        assert_eq!(lock.into_inner(), 2);
+    }
+
+    #[test]
+    fn contention() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 10_000;
+        let lock = Arc::new(TicketLock::with_rank(0usize, Rank::new(1)));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                std::thread::spawn(move || {
+                    for _ in 0..ROUNDS {
+                        *lock.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), THREADS * ROUNDS);
+    }
+}
@@ -0,0 +1,591 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Privileged segment taint
+//!
+//! Privileged segments hold state that unprivileged code must
+//! not be able to infer through the microarchitecture.  Calling
+//! into one through its transfer vector taints the CPU: while it
+//! is tainted, its hyperthread siblings are stunned, spinning in
+//! an interrupt handler so that they cannot sample the resources
+//! they share with it, and before it returns to unprivileged
+//! code, it flushes the L1 data cache and clears its other
+//! buffers, and releases its siblings.
+//!
+//! Each of those steps is only taken where the CPU is
+//! vulnerable to the attack it defends against, as reported by
+//! CPUID and IA32_ARCH_CAPABILITIES: the L1D flush defends
+//! against L1 terminal fault, the VERW buffer clear against
+//! microarchitectural data sampling, and the stun against both
+//! being mounted from a sibling thread.
+//!
+//! Calls into privileged segments may nest; the CPU is tainted
+//! from the outermost entry until the matching exit.  Stunning a
+//! sibling is a rendezvous: a CPU stunning its siblings posts a
+//! request to each, sends each an IPI on a reserved vector, and
+//! waits until each has acknowledged it.  Only one sibling of a
+//! core may stun the others at once, and one waiting its turn is
+//! stunned by the other meanwhile, so that siblings entering
+//! privileged segments together do not deadlock.  A stunned CPU,
+//! and one waiting on its siblings, still services TLB
+//! shootdowns, as the privileged segment may change shared
+//! mappings while its siblings are stunned.
+//!
+//! The rendezvous and the choice of mitigations are shared by
+//! every binary, and so live in the shared window of the per-CPU
+//! area rather than in statics, of which each binary has its own
+//! copy.  Privileged segments route each entry in their transfer
+//! vectors through `trampoline`, which taints the CPU around the
+//! call.
+
+use crate::cpu;
+use crate::cpuid::{self, Features};
+use crate::lapic::{self, InterruptVector};
+use crate::percpu;
+use crate::shootdown;
+use crate::trap;
+use crate::vm;
+use crate::{PF4K, Page, Page4K, ProcessorID};
+use bitflags::bitflags;
+use bitstruct::bitstruct;
+use core::arch::naked_asm;
+use core::mem;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+use static_assertions::const_assert;
+
+/// The interrupt vector reserved for stun IPIs.
+pub const VECTOR: InterruptVector = InterruptVector::Vector251;
+
+/// One more than the largest APIC ID that may take part in
+/// stuns.
+pub const MAX_CPUS: usize = 256;
+
+const NWORDS: usize = MAX_CPUS / 64;
+
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+const IA32_FLUSH_CMD: u32 = 0x10B;
+const L1D_FLUSH: u64 = 1;
+
+bitstruct! {
+    /// IA32_ARCH_CAPABILITIES: the speculative execution attacks
+    /// to which the CPU is not vulnerable.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct ArchCapabilities(pub u64) {
+        pub rdcl_no: bool = 0;
+        pub ibrs_all: bool = 1;
+        pub rsba: bool = 2;
+        pub skip_l1dfl_vmentry: bool = 3;
+        pub ssb_no: bool = 4;
+        pub mds_no: bool = 5;
+        pub if_pschange_mc_no: bool = 6;
+        pub tsx_ctrl: bool = 7;
+        pub taa_no: bool = 8;
+        pub sbdr_ssdp_no: bool = 13;
+        pub fbsdp_no: bool = 14;
+        pub psdp_no: bool = 15;
+        pub fb_clear: bool = 17;
+    }
+}
+
+impl ArchCapabilities {
+    /// Reads the current CPU's capabilities.  CPUs without the
+    /// MSR are assumed vulnerable to everything.
+    pub fn read() -> ArchCapabilities {
+        if !cpuid::has(Features::ARCH_CAPABILITIES) {
+            return ArchCapabilities(0);
+        }
+        ArchCapabilities(unsafe { x86::msr::rdmsr(IA32_ARCH_CAPABILITIES) })
+    }
+}
+
+bitflags! {
+    /// The steps taken on leaving a privileged segment.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Mitigations: u32 {
+        const L1D_FLUSH = 1 << 0;
+        const VERW = 1 << 1;
+        const STUN = 1 << 2;
+    }
+}
+
+impl Mitigations {
+    /// Returns the mitigations needed by a CPU with the given
+    /// features and capabilities, and which has hyperthread
+    /// siblings iff `smt` is set.  Mitigations the CPU cannot
+    /// perform are left out.
+    pub fn needed(features: Features, caps: ArchCapabilities, smt: bool) -> Mitigations {
+        let l1tf = !caps.rdcl_no();
+        let mds = !caps.mds_no();
+        let stale_data =
+            caps.fb_clear() && !(caps.fbsdp_no() && caps.psdp_no() && caps.sbdr_ssdp_no());
+        let mut mitigations = Mitigations::empty();
+        if l1tf && features.contains(Features::L1D_FLUSH) {
+            mitigations |= Mitigations::L1D_FLUSH;
+        }
+        if (mds && features.contains(Features::MD_CLEAR)) || stale_data {
+            mitigations |= Mitigations::VERW;
+        }
+        if smt && (l1tf || mds) {
+            mitigations |= Mitigations::STUN;
+        }
+        mitigations
+    }
+}
+
+/// The state shared between sibling CPUs taking part in the stun
+/// protocol: the set of online CPUs, a lock for each core held by
+/// the sibling stunning the others, and for each CPU, the request
+/// it is to service and the last it acknowledged.
+pub struct Stun {
+    online: [AtomicU64; NWORDS],
+    locks: [AtomicBool; MAX_CPUS],
+    requests: [AtomicU32; MAX_CPUS],
+    acks: [AtomicU32; MAX_CPUS],
+    generation: AtomicU32,
+}
+
+impl Stun {
+    /// Returns a new rendezvous with no online CPUs.
+    pub const fn new() -> Stun {
+        Stun {
+            online: [const { AtomicU64::new(0) }; NWORDS],
+            locks: [const { AtomicBool::new(false) }; MAX_CPUS],
+            requests: [const { AtomicU32::new(0) }; MAX_CPUS],
+            acks: [const { AtomicU32::new(0) }; MAX_CPUS],
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    /// Adds the given CPU to the set that may be stunned.
+    pub fn online(&self, cpu: ProcessorID) {
+        let id = index(cpu);
+        self.online[id / 64].fetch_or(1 << (id % 64), Ordering::AcqRel);
+    }
+
+    /// Removes the given CPU from the set that may be stunned.
+    pub fn offline(&self, cpu: ProcessorID) {
+        let id = index(cpu);
+        self.online[id / 64].fetch_and(!(1 << (id % 64)), Ordering::AcqRel);
+    }
+
+    // Returns the online siblings of `me`: the CPUs whose APIC
+    // IDs differ from it only in the low `thread_shift` bits.
+    fn siblings(&self, me: ProcessorID, thread_shift: u8) -> impl Iterator<Item = usize> + '_ {
+        let me = index(me);
+        (core(me, thread_shift)..core(me, thread_shift) + (1 << thread_shift)).filter(move |&id| {
+            id != me
+                && id < MAX_CPUS
+                && self.online[id / 64].load(Ordering::Acquire) & (1 << (id % 64)) != 0
+        })
+    }
+
+    /// Asks every online sibling of `me` to stay stunned, calls
+    /// `signal` for each, and waits until all of them are.  Only
+    /// one sibling of a core stuns the others at once; while
+    /// waiting for another to finish, `me` services its requests.
+    /// `poll` is called while waiting, to service other requests
+    /// posted to `me`.  Returns the number of siblings stunned.
+    pub fn stun<S, P>(&self, me: ProcessorID, thread_shift: u8, mut signal: S, mut poll: P) -> usize
+    where
+        S: FnMut(ProcessorID),
+        P: FnMut(),
+    {
+        let lock = &self.locks[core(index(me), thread_shift)];
+        while lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err()
+        {
+            self.service(me, &mut poll);
+            cpu::relax();
+        }
+        let mut generation = 0;
+        while generation == 0 {
+            generation = self.generation.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        }
+        let mut count = 0;
+        for id in self.siblings(me, thread_shift) {
+            self.requests[id].store(generation, Ordering::Release);
+            signal(ProcessorID(id as u32));
+            count += 1;
+        }
+        for id in self.siblings(me, thread_shift) {
+            while self.acks[id].load(Ordering::Acquire) != generation {
+                poll();
+                cpu::relax();
+            }
+        }
+        count
+    }
+
+    /// Releases the siblings stunned by `stun`.
+    pub fn release(&self, me: ProcessorID, thread_shift: u8) {
+        for id in self.siblings(me, thread_shift) {
+            self.requests[id].store(0, Ordering::Release);
+        }
+        self.locks[core(index(me), thread_shift)].store(false, Ordering::Release);
+    }
+
+    /// Stays stunned until released, if a sibling has asked `me`
+    /// to, calling `poll` meanwhile to service other requests
+    /// posted to `me`.  Returns true iff there was such a request.
+    pub fn service<P: FnMut()>(&self, me: ProcessorID, mut poll: P) -> bool {
+        let id = index(me);
+        let request = self.requests[id].load(Ordering::Acquire);
+        if request == 0 {
+            return false;
+        }
+        self.acks[id].store(request, Ordering::Release);
+        while self.requests[id].load(Ordering::Acquire) == request {
+            poll();
+            cpu::relax();
+        }
+        true
+    }
+}
+
+impl Default for Stun {
+    fn default() -> Stun {
+        Stun::new()
+    }
+}
+
+// Returns the ID of the first thread of the core holding the
+// CPU with the given ID.
+fn core(id: usize, thread_shift: u8) -> usize {
+    id >> thread_shift << thread_shift
+}
+
+fn index(cpu: ProcessorID) -> usize {
+    let id = u32::from(cpu) as usize;
+    assert!(id < MAX_CPUS, "APIC ID out of range for stun");
+    id
+}
+
+// The state shared by every CPU: the rendezvous, the mitigations
+// needed, and the width of the thread field of the APIC ID.
+// Every CPU is assumed to be alike.  Zeroed memory is a valid
+// `Shared`.
+#[repr(C)]
+struct Shared {
+    stun: Stun,
+    mitigations: AtomicU32,
+    thread_shift: AtomicU8,
+}
+
+// The pages of the shared window of the per-CPU area holding the
+// shared state.
+const SHARED_PAGES: Range<usize> = 0..1;
+const_assert!(mem::size_of::<Shared>() <= SHARED_PAGES.end * Page4K::SIZE);
+
+fn shared() -> &'static Shared {
+    let start = percpu::SHARED.start + SHARED_PAGES.start * Page4K::SIZE;
+    let ptr = Page4K::proto_ptr().with_addr(start).cast::<Shared>();
+    unsafe { &*ptr }
+}
+
+/// Maps the shared state, with frames taken from the allocator,
+/// which must be zeroed.  Must be called once, before `init` is
+/// called on any CPU.
+pub fn map<F>(allocator: &mut F) -> vm::Result<()>
+where
+    F: FnMut() -> vm::Result<PF4K>,
+{
+    percpu::map_shared(SHARED_PAGES, allocator)
+}
+
+/// Returns the mitigations taken on leaving privileged segments.
+pub fn mitigations() -> Mitigations {
+    Mitigations::from_bits_truncate(shared().mitigations.load(Ordering::Relaxed))
+}
+
+/// Decides which mitigations are needed and, if siblings are to
+/// be stunned, adds the current CPU to the set of those that may
+/// be.  Must be called on each CPU, after the shared state has
+/// been mapped and the trap handler for `VECTOR` registered.
+/// Returns the mitigations.
+pub fn init() -> Mitigations {
+    let thread_shift = cpuid::topology().map_or(0, |topology| topology.thread_shift());
+    let smt = thread_shift != 0;
+    let mitigations = Mitigations::needed(cpuid::features(), ArchCapabilities::read(), smt);
+    let shared = shared();
+    shared.thread_shift.store(thread_shift, Ordering::Relaxed);
+    shared.mitigations.store(mitigations.bits(), Ordering::Relaxed);
+    if mitigations.contains(Mitigations::STUN) {
+        shared.stun.online(lapic::id());
+    }
+    mitigations
+}
+
+/// Writes back and invalidates the L1 data cache.
+pub fn flush_l1d() {
+    unsafe {
+        x86::msr::wrmsr(IA32_FLUSH_CMD, L1D_FLUSH);
+    }
+}
+
+/// Clears the CPU's store, fill and load buffers.  This is a
+/// side effect of the memory operand form of VERW, whatever the
+/// selector it checks.
+pub fn clear_buffers() {
+    let selector: u16 = 0;
+    unsafe {
+        core::arch::asm!("verw ({})", in(reg) &selector, options(att_syntax, nostack));
+    }
+}
+
+/// Returns true iff the current CPU is running a privileged
+/// segment.
+pub fn is_tainted() -> bool {
+    percpu::this().taint_depth() != 0
+}
+
+/// Marks the current CPU as tainted on entry to a privileged
+/// segment, stunning its siblings if needed.
+pub extern "C" fn enter() {
+    let this = percpu::this();
+    let depth = this.taint_depth();
+    this.set_taint_depth(depth + 1);
+    if depth == 0 && mitigations().contains(Mitigations::STUN) {
+        let shared = shared();
+        let shift = shared.thread_shift.load(Ordering::Relaxed);
+        let signal = |cpu| unsafe { lapic::send_ipi(cpu, VECTOR) };
+        shared.stun.stun(this.id(), shift, signal, || {
+            shootdown::service();
+        });
+    }
+}
+
+/// Leaves a privileged segment.  On return to unprivileged code,
+/// flushes microarchitectural state as needed, releases the
+/// siblings, and clears the taint.
+pub extern "C" fn exit() {
+    let this = percpu::this();
+    let depth = this.taint_depth();
+    assert_ne!(depth, 0, "leaving a privileged segment never entered");
+    this.set_taint_depth(depth - 1);
+    if depth != 1 {
+        return;
+    }
+    let mitigations = mitigations();
+    if mitigations.contains(Mitigations::L1D_FLUSH) {
+        flush_l1d();
+    }
+    if mitigations.contains(Mitigations::VERW) {
+        clear_buffers();
+    }
+    if mitigations.contains(Mitigations::STUN) {
+        let shared = shared();
+        shared.stun.release(this.id(), shared.thread_shift.load(Ordering::Relaxed));
+    }
+}
+
+/// Calls the entry point into a privileged segment whose address
+/// is in %r11, with the arguments it was itself called with,
+/// tainting the CPU for the duration of the call, and returns
+/// the entry point's result.  Transfer vector entries of
+/// privileged segments load %r11 and jump here.  Only arguments
+/// passed in integer registers are passed on.
+///
+/// # Safety
+/// Must only be jumped to, from a transfer vector entry, with
+/// %r11 holding the address of an `extern "C"` function.
+#[unsafe(naked)]
+pub unsafe extern "C" fn trampoline() {
+    naked_asm!(r#"
+        pushq %rbp
+        movq %rsp, %rbp
+        pushq %r11
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %r8
+        pushq %r9
+        subq $8, %rsp
+        callq {enter}
+        addq $8, %rsp
+        popq %r9
+        popq %r8
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %r11
+        callq *%r11
+        pushq %rax
+        pushq %rdx
+        callq {exit}
+        popq %rdx
+        popq %rax
+        popq %rbp
+        retq
+        "#,
+        enter = sym enter,
+        exit = sym exit,
+        options(att_syntax));
+}
+
+/// Stays stunned while a sibling runs a privileged segment,
+/// servicing TLB shootdowns meanwhile.  This is the trap handler
+/// for `VECTOR`.
+pub fn handle(_frame: &mut trap::Frame) {
+    shared().stun.service(lapic::id(), || {
+        shootdown::service();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shootdown::{Request, Shootdown};
+    use crate::vm::soft::SoftMmu;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn needed() {
+        let all = Features::L1D_FLUSH | Features::MD_CLEAR;
+        let vulnerable = ArchCapabilities(0);
+        assert_eq!(Mitigations::needed(all, vulnerable, true), Mitigations::all());
+        let only_flush = Mitigations::L1D_FLUSH | Mitigations::VERW;
+        assert_eq!(Mitigations::needed(all, vulnerable, false), only_flush);
+        let fixed = ArchCapabilities(0).with_rdcl_no(true).with_mds_no(true);
+        assert_eq!(Mitigations::needed(all, fixed, true), Mitigations::empty());
+        let mds_only = ArchCapabilities(0).with_rdcl_no(true);
+        let verw_stun = Mitigations::VERW | Mitigations::STUN;
+        assert_eq!(Mitigations::needed(all, mds_only, true), verw_stun);
+        assert_eq!(Mitigations::needed(Features::empty(), vulnerable, false), Mitigations::empty());
+        let stale = fixed.with_fb_clear(true);
+        assert_eq!(Mitigations::needed(all, stale, false), Mitigations::VERW);
+        let stale_fixed = stale.with_fbsdp_no(true).with_psdp_no(true).with_sbdr_ssdp_no(true);
+        assert_eq!(Mitigations::needed(all, stale_fixed, false), Mitigations::empty());
+    }
+
+    #[test]
+    fn siblings() {
+        let stun = Stun::new();
+        for id in [0, 1, 2, 3, 5] {
+            stun.online(ProcessorID(id));
+        }
+        let siblings = |me, shift| stun.siblings(ProcessorID(me), shift).collect::<Vec<_>>();
+        assert_eq!(siblings(0, 1), [1]);
+        assert_eq!(siblings(3, 1), [2]);
+        assert_eq!(siblings(4, 1), [5]);
+        assert_eq!(siblings(1, 2), [0, 2, 3]);
+        assert!(siblings(1, 0).is_empty());
+        stun.offline(ProcessorID(1));
+        assert!(siblings(0, 1).is_empty());
+    }
+
+    #[test]
+    fn rendezvous() {
+        let stun = Stun::new();
+        stun.online(ProcessorID(0));
+        stun.online(ProcessorID(1));
+        let stop = AtomicBool::new(false);
+        let serviced = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !stop.load(Ordering::Acquire) {
+                    if stun.service(ProcessorID(1), || {}) {
+                        serviced.fetch_add(1, Ordering::Relaxed);
+                    }
+                    thread::yield_now();
+                }
+            });
+            for _ in 0..10 {
+                assert_eq!(stun.stun(ProcessorID(0), 1, |_| {}, || {}), 1);
+                let generation = stun.requests[1].load(Ordering::Relaxed);
+                assert_eq!(stun.acks[1].load(Ordering::Acquire), generation);
+                stun.release(ProcessorID(0), 1);
+            }
+            stop.store(true, Ordering::Release);
+        });
+        // A sibling released and asked again before it notices
+        // stays stunned throughout.
+        assert!((1..=10).contains(&serviced.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn mutual() {
+        // Two siblings stunning each other at once both succeed.
+        let stun = Stun::new();
+        stun.online(ProcessorID(0));
+        stun.online(ProcessorID(1));
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for me in 0..2 {
+                let (stun, done) = (&stun, &done);
+                s.spawn(move || {
+                    for _ in 0..10 {
+                        stun.stun(ProcessorID(me), 1, |_| {}, || {});
+                        stun.release(ProcessorID(me), 1);
+                        stun.service(ProcessorID(me), || {});
+                    }
+                    done.fetch_add(1, Ordering::AcqRel);
+                    while done.load(Ordering::Acquire) < 2 {
+                        stun.service(ProcessorID(me), || {});
+                        thread::yield_now();
+                    }
+                });
+            }
+        });
+        assert!(stun.requests.iter().all(|request| request.load(Ordering::Relaxed) == 0));
+        assert!(stun.locks.iter().all(|lock| !lock.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn stunned_siblings_service_shootdowns() {
+        // A CPU changing a shared mapping while its sibling is
+        // stunned shoots down the sibling's stale translations
+        // without waiting for its release, and the sibling's own
+        // shootdowns reach a CPU stunning it.
+        let stun = Stun::new();
+        let shootdown = Shootdown::new();
+        for id in 0..2 {
+            stun.online(ProcessorID(id));
+            shootdown.online(ProcessorID(id));
+        }
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            let sibling = s.spawn(|| {
+                let me = ProcessorID(1);
+                let mmu = SoftMmu::new(1);
+                let mut stunned = 0;
+                let poll = || {
+                    shootdown.service(me, &mmu);
+                };
+                while !stop.load(Ordering::Acquire) {
+                    if stun.service(me, poll) {
+                        stunned += 1;
+                        shootdown.initiate(me, &mmu, Request::All, |_| {});
+                    }
+                    poll();
+                    thread::yield_now();
+                }
+                (stunned, mmu.flushes())
+            });
+            let me = ProcessorID(0);
+            let mmu = SoftMmu::new(1);
+            let poll = || {
+                shootdown.service(me, &mmu);
+            };
+            for _ in 0..10 {
+                assert_eq!(stun.stun(me, 1, |_| {}, poll), 1);
+                shootdown.initiate(me, &mmu, Request::All, |_| {});
+                stun.release(me, 1);
+            }
+            stop.store(true, Ordering::Release);
+            while !sibling.is_finished() {
+                poll();
+            }
+            let (stunned, flushes) = sibling.join().unwrap();
+            assert!((1..=10).contains(&stunned));
+            assert_eq!(flushes, 10);
+            assert_eq!(mmu.flushes(), stunned);
+        });
+    }
+}
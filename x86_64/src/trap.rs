@@ -1,4 +1,10 @@
+use crate::backtrace::Backtrace;
+use crate::lapic;
+use bit_field::BitField;
+use bitflags::bitflags;
 use core::arch::naked_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use seq_macro::seq;
 
 #[derive(Clone, Debug)]
@@ -220,6 +226,429 @@ pub unsafe extern "C" fn trap() -> ! {
         options(att_syntax))
 }
 
-extern "C" fn dispatch(_vector: u8, _trap_frame: &mut Frame) -> u32 {
+impl Frame {
+    /// Returns the vector number of the trap.
+    pub fn vector(&self) -> u8 {
+        self.vector as u8
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let regs = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("rsp", self.rsp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("rip", self.rip),
+            ("rflags", self.rflags),
+            ("error", self.error),
+            ("vector", self.vector),
+            ("cs", self.cs),
+            ("ss", self.ss),
+            ("ds", self.ds),
+            ("es", self.es),
+            ("fs", self.fs),
+            ("gs", self.gs),
+        ];
+        for (k, (name, value)) in regs.iter().enumerate() {
+            let sep = if k % 3 == 2 || k == regs.len() - 1 { "\n" } else { "  " };
+            write!(f, "{name:>6}={value:#018x}{sep}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The first vector used for interrupts, rather than exceptions.
+pub const FIRST_INTERRUPT: u8 = 32;
+
+/// The breakpoint exception's vector.  It is a trap, rather than
+/// a fault, so execution may resume after the `int3` that raised
+/// it.
+pub const BREAKPOINT: u8 = 3;
+
+/// A handler for a trap vector.  It may modify the frame, which
+/// is restored on return from the trap.
+pub type Handler = fn(&mut Frame);
+
+/// A table of handlers, indexed by vector.
+pub struct Handlers([AtomicUsize; 256]);
+
+impl Handlers {
+    /// Returns a table with no handlers.
+    pub const fn new() -> Handlers {
+        Handlers([const { AtomicUsize::new(0) }; 256])
+    }
+
+    /// Registers a handler for the given vector, which must not
+    /// already have one.
+    pub fn register(&self, vector: u8, handler: Handler) -> Result<(), &'static str> {
+        let slot = &self.0[usize::from(vector)];
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| "vector already has a handler")
+    }
+
+    /// Removes the handler for the given vector, if any.
+    pub fn unregister(&self, vector: u8) {
+        self.0[usize::from(vector)].store(0, Ordering::Release);
+    }
+
+    /// Returns the handler for the given vector, if any.
+    pub fn get(&self, vector: u8) -> Option<Handler> {
+        match self.0[usize::from(vector)].load(Ordering::Acquire) {
+            0 => None,
+            addr => Some(unsafe { core::mem::transmute::<usize, Handler>(addr) }),
+        }
+    }
+}
+
+impl Default for Handlers {
+    fn default() -> Handlers {
+        Handlers::new()
+    }
+}
+
+static HANDLERS: Handlers = Handlers::new();
+
+/// Registers a handler for the given vector on every CPU.
+pub fn register(vector: u8, handler: Handler) -> Result<(), &'static str> {
+    HANDLERS.register(vector, handler)
+}
+
+/// Removes the handler for the given vector.
+pub fn unregister(vector: u8) {
+    HANDLERS.unregister(vector);
+}
+
+/// Returns the mnemonic and name of the given exception vector.
+pub fn exception_name(vector: u8) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "divide error"),
+        1 => ("#DB", "debug"),
+        2 => ("NMI", "non-maskable interrupt"),
+        3 => ("#BP", "breakpoint"),
+        4 => ("#OF", "overflow"),
+        5 => ("#BR", "bound range exceeded"),
+        6 => ("#UD", "invalid opcode"),
+        7 => ("#NM", "device not available"),
+        8 => ("#DF", "double fault"),
+        9 => ("#MP", "coprocessor segment overrun"),
+        10 => ("#TS", "invalid TSS"),
+        11 => ("#NP", "segment not present"),
+        12 => ("#SS", "stack-segment fault"),
+        13 => ("#GP", "general protection"),
+        14 => ("#PF", "page fault"),
+        16 => ("#MF", "x87 floating-point error"),
+        17 => ("#AC", "alignment check"),
+        18 => ("#MC", "machine check"),
+        19 => ("#XM", "SIMD floating-point exception"),
+        20 => ("#VE", "virtualization exception"),
+        21 => ("#CP", "control protection"),
+        28 => ("#HV", "hypervisor injection"),
+        29 => ("#VC", "VMM communication"),
+        30 => ("#SX", "security exception"),
+        15 | 22..=27 | 31 => ("#??", "reserved"),
+        _ => ("INT", "interrupt"),
+    }
+}
+
+bitflags! {
+    /// The error code pushed for a page fault.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct PageFaultError: u64 {
+        const PRESENT     = 1;
+        const WRITE       = 1 << 1;
+        const USER        = 1 << 2;
+        const RESERVED    = 1 << 3;
+        const FETCH       = 1 << 4;
+        const PROTKEY     = 1 << 5;
+        const SHADOWSTACK = 1 << 6;
+        const SGX         = 1 << 15;
+    }
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let who = if self.contains(Self::USER) { "user" } else { "supervisor" };
+        let what = match (self.contains(Self::FETCH), self.contains(Self::WRITE)) {
+            (true, _) => "fetch",
+            (false, true) => "write",
+            (false, false) => "read",
+        };
+        let why = if self.contains(Self::PRESENT) { "protection" } else { "not present" };
+        write!(f, "{who} {what}, {why}")?;
+        for (flag, name) in [
+            (Self::RESERVED, "reserved bit set"),
+            (Self::PROTKEY, "protection key"),
+            (Self::SHADOWSTACK, "shadow stack"),
+            (Self::SGX, "SGX"),
+        ] {
+            if self.contains(flag) {
+                write!(f, ", {name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The table a faulting selector refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorTable {
+    GDT,
+    IDT,
+    LDT,
+}
+
+/// The error code pushed for exceptions caused by a segment
+/// selector or gate: #TS, #NP, #SS and #GP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelectorError {
+    pub external: bool,
+    pub table: DescriptorTable,
+    pub index: u16,
+}
+
+impl SelectorError {
+    /// Decodes a selector error code.  A zero error code does not
+    /// refer to a selector, and yields `None`.
+    pub fn new(error: u64) -> Option<SelectorError> {
+        if error == 0 {
+            return None;
+        }
+        let table = match (error.get_bit(1), error.get_bit(2)) {
+            (true, _) => DescriptorTable::IDT,
+            (false, false) => DescriptorTable::GDT,
+            (false, true) => DescriptorTable::LDT,
+        };
+        let index = error.get_bits(3..16) as u16;
+        Some(SelectorError { external: error.get_bit(0), table, index })
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} index {:#x}", self.table, self.index)?;
+        if self.external {
+            write!(f, " (external event)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The details of an exception, beyond what is in the frame.
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    PageFault { addr: u64, error: PageFaultError },
+    Selector(Option<SelectorError>),
+    InvalidOpcode(Option<[u8; 16]>),
+    DoubleFault,
+    Other,
+}
+
+/// A report of an exception that no handler claimed.
+pub struct Report<'a> {
+    frame: &'a Frame,
+    fault: Fault,
+}
+
+impl<'a> Report<'a> {
+    /// Returns a report of the exception described by the given
+    /// frame and fault details.
+    pub fn new(frame: &'a Frame, fault: Fault) -> Report<'a> {
+        Report { frame, fault }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vector = self.frame.vector();
+        let (mnemonic, name) = exception_name(vector);
+        writeln!(f, "unhandled {mnemonic} ({name}, vector {vector}) at rip {:#x}", self.frame.rip)?;
+        match self.fault {
+            Fault::PageFault { addr, error } => {
+                writeln!(f, "cr2={addr:#x} error={:#x}: {error}", error.bits())?;
+            }
+            Fault::Selector(Some(selector)) => {
+                writeln!(f, "error={:#x}: {selector}", self.frame.error)?;
+            }
+            Fault::Selector(None) => writeln!(f, "error=0: no selector")?,
+            Fault::InvalidOpcode(Some(bytes)) => writeln!(f, "opcode bytes: {bytes:02x?}")?,
+            Fault::InvalidOpcode(None) => writeln!(f, "opcode bytes: unmapped")?,
+            Fault::DoubleFault => writeln!(f, "double faults are not recoverable")?,
+            Fault::Other => {}
+        }
+        write!(f, "{}", self.frame)
+    }
+}
+
+// Collects the details of an exception, other than the frame.
+fn fault(frame: &Frame) -> Fault {
+    match frame.vector() {
+        6 => {
+            let rip = frame.rip as usize;
+            let mapped = |va| crate::vm::translate(va).is_some();
+            let bytes = (mapped(rip) && mapped(rip + 15))
+                .then(|| unsafe { core::ptr::read_unaligned(rip as *const [u8; 16]) });
+            Fault::InvalidOpcode(bytes)
+        }
+        8 => Fault::DoubleFault,
+        10..=13 => Fault::Selector(SelectorError::new(frame.error)),
+        14 => {
+            let addr = unsafe { x86::controlregs::cr2() } as u64;
+            Fault::PageFault { addr, error: PageFaultError::from_bits_retain(frame.error) }
+        }
+        _ => Fault::Other,
+    }
+}
+
+// Returns true iff an exception with the given vector is fatal
+// when no handler claims it.  Breakpoints are reported, and
+// execution resumes.
+fn fatal(vector: u8) -> bool {
+    vector < FIRST_INTERRUPT && vector != BREAKPOINT
+}
+
+/// Dispatches a trap to the handler registered for its vector.
+/// Exceptions without a handler are reported, and, other than
+/// breakpoints, are fatal.  Interrupts are acknowledged after
+/// their handler returns, unless spurious.
+extern "C" fn dispatch(vector: u8, frame: &mut Frame) -> u32 {
+    match HANDLERS.get(vector) {
+        Some(handler) => handler(frame),
+        None if fatal(vector) => {
+            let backtrace = Backtrace::with_pc(frame.rip as usize, frame.rbp as usize);
+            panic!("{}{backtrace}", Report::new(frame, fault(frame)));
+        }
+        None if vector == BREAKPOINT => {
+            let backtrace = Backtrace::with_pc(frame.rip as usize, frame.rbp as usize);
+            crate::println!("{}{backtrace}", Report::new(frame, fault(frame)));
+        }
+        None => {}
+    }
+    if vector >= FIRST_INTERRUPT && vector != lapic::SPURIOUS_VECTOR as u8 {
+        unsafe {
+            lapic::eoi();
+        }
+    }
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(vector: u8, error: u64) -> Frame {
+        Frame {
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: 0,
+            rsi: 0,
+            rdi: 0,
+            rbp: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0xf00f,
+            ds: 0,
+            es: 0,
+            fs: 0,
+            gs: 0,
+            vector: u64::from(vector),
+            error,
+            rip: 0xFFFF_FC00_0000_1234,
+            cs: 8,
+            rflags: 0x202,
+            rsp: 0xFFFF_FC00_0010_0000,
+            ss: 16,
+        }
+    }
+
+    #[test]
+    fn handlers() {
+        fn nop(_frame: &mut Frame) {}
+        fn set(frame: &mut Frame) {
+            frame.rax = 1;
+        }
+        let handlers = Handlers::new();
+        assert!(handlers.get(32).is_none());
+        assert_eq!(handlers.register(32, set), Ok(()));
+        assert_eq!(handlers.register(32, nop), Err("vector already has a handler"));
+        let mut f = frame(32, 0);
+        handlers.get(32).expect("registered handler")(&mut f);
+        assert_eq!(f.rax, 1);
+        handlers.unregister(32);
+        assert!(handlers.get(32).is_none());
+    }
+
+    #[test]
+    fn unhandled_breakpoints_are_not_fatal() {
+        assert!(!fatal(BREAKPOINT));
+        assert!(fatal(6));
+        assert!(fatal(8));
+        assert!(fatal(14));
+        assert!(!fatal(FIRST_INTERRUPT));
+        assert!(!fatal(lapic::SPURIOUS_VECTOR as u8));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(exception_name(14), ("#PF", "page fault"));
+        assert_eq!(exception_name(15), ("#??", "reserved"));
+        assert_eq!(exception_name(32).0, "INT");
+    }
+
+    #[test]
+    fn selector_errors() {
+        assert_eq!(SelectorError::new(0), None);
+        let err = SelectorError::new(0x18).unwrap();
+        assert_eq!(err, SelectorError { external: false, table: DescriptorTable::GDT, index: 3 });
+        let err = SelectorError::new(0x6b).unwrap();
+        assert_eq!(err, SelectorError { external: true, table: DescriptorTable::IDT, index: 13 });
+        assert_eq!(err.to_string(), "IDT index 0xd (external event)");
+        let err = SelectorError::new(0x14).unwrap();
+        assert_eq!(err.table, DescriptorTable::LDT);
+    }
+
+    #[test]
+    fn page_fault_report() {
+        let f = frame(14, 0x7);
+        let error = PageFaultError::from_bits_retain(f.error);
+        let report = Report::new(&f, Fault::PageFault { addr: 0xdead_b000, error }).to_string();
+        let mut lines = report.lines();
+        assert_eq!(
+            lines.next(),
+            Some("unhandled #PF (page fault, vector 14) at rip 0xfffffc0000001234")
+        );
+        assert_eq!(lines.next(), Some("cr2=0xdeadb000 error=0x7: user write, protection"));
+        assert!(report.contains("   r15=0x000000000000f00f"));
+        assert!(report.ends_with("gs=0x0000000000000000\n"));
+        let fetch = PageFaultError::FETCH | PageFaultError::RESERVED;
+        assert_eq!(fetch.to_string(), "supervisor fetch, not present, reserved bit set");
+    }
+
+    #[test]
+    fn general_protection_report() {
+        let f = frame(13, 0x18);
+        let report = Report::new(&f, Fault::Selector(SelectorError::new(f.error))).to_string();
+        assert!(report.starts_with("unhandled #GP (general protection, vector 13)"));
+        assert!(report.contains("error=0x18: GDT index 0x3\n"));
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Time stamp counter
+//!
+//! We measure time with the TSC, so we must know how fast it
+//! ticks.  Recent CPUs report the frequency directly through
+//! CPUID leaf 0x15, either in full or as a ratio to a crystal
+//! whose frequency is inferred from leaf 0x16.  Elsewhere, the
+//! loader calibrates the TSC against a clock of known frequency:
+//! the HPET, the ACPI PM timer, or failing those, the PIT.
+//!
+//! The loader computes the frequency once, and publishes it to
+//! each binary it loads by writing it into the binary's copy of
+//! `FREQUENCY`, which is exported under `FREQUENCY_SYMBOL` for
+//! that purpose, before running the binary's initializer, if it
+//! has one.  Binaries that never consult the frequency have no
+//! copy.
+
+use crate::cpu;
+use crate::cpuid::{self, Features};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// The name under which each binary exports its copy of the
+/// published TSC frequency.
+pub const FREQUENCY_SYMBOL: &str = "hypatia_tsc_hz";
+
+/// The TSC frequency in Hertz, or zero if not yet known.
+#[unsafe(export_name = "hypatia_tsc_hz")]
+pub static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The frequency assumed when it can be neither discovered
+/// nor has been published.
+const DEFAULT_HZ: u64 = 2_000_000_000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Returns the current value of the TSC.
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns true iff the TSC ticks at a constant rate regardless
+/// of power state, and so is usable as a clock.
+pub fn invariant() -> bool {
+    cpuid::has(Features::INVARIANT_TSC)
+}
+
+/// Returns the TSC frequency in Hertz as reported by CPUID, if
+/// the CPU reports it.
+pub fn cpuid_frequency() -> Option<u64> {
+    let cpuid = x86::cpuid::CpuId::new();
+    let tsc = cpuid
+        .get_tsc_info()
+        .map(|info| (info.denominator(), info.numerator(), info.nominal_frequency()));
+    let base_mhz = cpuid.get_processor_frequency_info().map(|f| f.processor_base_frequency());
+    frequency_from_leaves(tsc, base_mhz.map(u32::from))
+}
+
+// Computes the TSC frequency from the contents of CPUID leaf
+// 0x15, as the ratio denominator and numerator and the crystal
+// frequency in Hertz, and the base frequency in MHz from leaf
+// 0x16.  Where the crystal frequency is not given, the base
+// frequency is taken as the TSC frequency, per the SDM.
+fn frequency_from_leaves(tsc: Option<(u32, u32, u32)>, base_mhz: Option<u32>) -> Option<u64> {
+    let (denominator, numerator, crystal_hz) = tsc?;
+    if denominator == 0 || numerator == 0 {
+        return None;
+    }
+    if crystal_hz != 0 {
+        let hz = u64::from(crystal_hz) * u64::from(numerator) / u64::from(denominator);
+        return Some(hz);
+    }
+    match base_mhz? {
+        0 => None,
+        mhz => Some(u64::from(mhz) * 1_000_000),
+    }
+}
+
+/// Publishes the TSC frequency to this binary.
+pub fn publish(hz: u64) {
+    FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+/// Returns the TSC frequency in Hertz: that published by the
+/// loader, or if none was, that reported by CPUID.  Failing
+/// both, a default is assumed.
+pub fn frequency() -> u64 {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => {
+            let hz = cpuid_frequency().unwrap_or(DEFAULT_HZ);
+            publish(hz);
+            hz
+        }
+        hz => hz,
+    }
+}
+
+/// Converts a duration to a number of TSC ticks.
+pub fn ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * u128::from(frequency()) / u128::from(NANOS_PER_SEC);
+    u64::try_from(ticks).expect("duration in TSC ticks fits in 64 bits")
+}
+
+/// A clock of known frequency against which the TSC may be
+/// calibrated.
+pub trait Clock {
+    /// Returns the frequency of the clock in Hertz.
+    fn frequency(&self) -> u64;
+
+    /// Returns the number of bits in the counter, which counts
+    /// up and wraps.
+    fn width(&self) -> u32;
+
+    /// Reads the counter.
+    fn read(&mut self) -> u64;
+}
+
+/// Measures the TSC frequency against the given clock over
+/// (approximately) the given duration, returning it in Hertz.
+/// The clock is polled far more often than it wraps.
+pub fn calibrate<C: Clock>(clock: &mut C, duration: Duration) -> u64 {
+    calibrate_with(clock, duration, rdtsc)
+}
+
+fn calibrate_with<C, T>(clock: &mut C, duration: Duration, mut tsc: T) -> u64
+where
+    C: Clock,
+    T: FnMut() -> u64,
+{
+    let mask = u64::MAX >> (64 - clock.width());
+    let hz = u128::from(clock.frequency());
+    let target = duration.as_nanos() * hz / u128::from(NANOS_PER_SEC);
+    let mut last = clock.read();
+    let start = tsc();
+    let mut elapsed = 0u128;
+    while elapsed < target.max(1) {
+        let now = clock.read();
+        elapsed += u128::from(now.wrapping_sub(last) & mask);
+        last = now;
+        cpu::relax();
+    }
+    let cycles = u128::from(tsc().wrapping_sub(start));
+    (cycles * hz / elapsed) as u64
+}
+
+/// The 8254 programmable interval timer, whose second channel
+/// is free for calibration.
+pub struct Pit(());
+
+impl Pit {
+    const HZ: u64 = 1_193_182;
+    const CHANNEL2: u16 = 0x42;
+    const COMMAND: u16 = 0x43;
+    const GATE: u16 = 0x61;
+
+    /// Starts channel 2 counting down from its maximum, with the
+    /// speaker disconnected.
+    ///
+    /// # Safety
+    /// Nothing else may be using the PIT.
+    pub unsafe fn new() -> Pit {
+        const GATE_ENABLE: u8 = 0b01;
+        const SPEAKER_ENABLE: u8 = 0b10;
+        // Channel 2, low then high byte, rate generator, binary.
+        const PROGRAM_CHANNEL2: u8 = 0b1011_0100;
+        unsafe {
+            let gate = x86::io::inb(Self::GATE);
+            x86::io::outb(Self::GATE, (gate & !SPEAKER_ENABLE) | GATE_ENABLE);
+            x86::io::outb(Self::COMMAND, PROGRAM_CHANNEL2);
+            x86::io::outb(Self::CHANNEL2, 0);
+            x86::io::outb(Self::CHANNEL2, 0);
+        }
+        Pit(())
+    }
+}
+
+impl Clock for Pit {
+    fn frequency(&self) -> u64 {
+        Self::HZ
+    }
+
+    fn width(&self) -> u32 {
+        16
+    }
+
+    fn read(&mut self) -> u64 {
+        const LATCH_CHANNEL2: u8 = 0b1000_0000;
+        let count = unsafe {
+            x86::io::outb(Self::COMMAND, LATCH_CHANNEL2);
+            let lo = x86::io::inb(Self::CHANNEL2);
+            let hi = x86::io::inb(Self::CHANNEL2);
+            u16::from_le_bytes([lo, hi])
+        };
+        // The channel counts down; present it as counting up.
+        u64::from(count.wrapping_neg())
+    }
+}
+
+/// The ACPI power management timer.
+pub struct PmTimer {
+    port: u16,
+    width: u32,
+}
+
+impl PmTimer {
+    const HZ: u64 = 3_579_545;
+
+    /// Returns the PM timer at the given I/O port, as given in
+    /// the FADT, which is either 24 or 32 bits wide.
+    ///
+    /// # Safety
+    /// The port must be that of the PM timer.
+    pub unsafe fn new(port: u16, extended: bool) -> PmTimer {
+        PmTimer { port, width: if extended { 32 } else { 24 } }
+    }
+}
+
+impl Clock for PmTimer {
+    fn frequency(&self) -> u64 {
+        Self::HZ
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn read(&mut self) -> u64 {
+        u64::from(unsafe { x86::io::inl(self.port) })
+    }
+}
+
+/// The main counter of the high precision event timer.
+pub struct Hpet {
+    regs: *mut u64,
+}
+
+impl Hpet {
+    /// The size of the HPET's register block.
+    pub const WINDOW_SIZE: usize = 0x400;
+
+    const CAPABILITIES: usize = 0x00;
+    const CONFIGURATION: usize = 0x10 / 8;
+    const MAIN_COUNTER: usize = 0xF0 / 8;
+    const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+    /// Returns the HPET whose registers are mapped at the given
+    /// address, having started its main counter.
+    ///
+    /// # Safety
+    /// The address must be that of the HPET's register block,
+    /// mapped uncached.
+    pub unsafe fn new(regs: *mut u64) -> Hpet {
+        const ENABLE_CNF: u64 = 1;
+        unsafe {
+            let config = regs.add(Self::CONFIGURATION);
+            config.write_volatile(config.read_volatile() | ENABLE_CNF);
+        }
+        Hpet { regs }
+    }
+
+    fn capabilities(&self) -> u64 {
+        unsafe { self.regs.add(Self::CAPABILITIES).read_volatile() }
+    }
+}
+
+impl Clock for Hpet {
+    fn frequency(&self) -> u64 {
+        let period_fs = self.capabilities() >> 32;
+        Self::FEMTOS_PER_SEC / period_fs
+    }
+
+    fn width(&self) -> u32 {
+        const COUNT_SIZE_CAP: u64 = 1 << 13;
+        if self.capabilities() & COUNT_SIZE_CAP != 0 { 64 } else { 32 }
+    }
+
+    fn read(&mut self) -> u64 {
+        unsafe { self.regs.add(Self::MAIN_COUNTER).read_volatile() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn leaves() {
+        // A 24MHz crystal with a 2:125 ratio runs the TSC at 1.5GHz.
+        let hz = frequency_from_leaves(Some((2, 125, 24_000_000)), None);
+        assert_eq!(hz, Some(1_500_000_000));
+        let hz = frequency_from_leaves(Some((2, 125, 0)), Some(2_100));
+        assert_eq!(hz, Some(2_100_000_000));
+        assert_eq!(frequency_from_leaves(Some((2, 125, 0)), None), None);
+        assert_eq!(frequency_from_leaves(Some((0, 0, 24_000_000)), Some(2_100)), None);
+        assert_eq!(frequency_from_leaves(None, Some(2_100)), None);
+    }
+
+    // A 16-bit clock at 1MHz that advances 100 ticks per read,
+    // starting just short of wrapping, and a TSC that advances
+    // 1000 cycles per clock read, so the TSC runs at 10MHz.
+    struct FakeClock<'a> {
+        count: u64,
+        tsc: &'a Cell<u64>,
+    }
+
+    impl Clock for FakeClock<'_> {
+        fn frequency(&self) -> u64 {
+            1_000_000
+        }
+
+        fn width(&self) -> u32 {
+            16
+        }
+
+        fn read(&mut self) -> u64 {
+            self.count = (self.count + 100) & 0xFFFF;
+            self.tsc.set(self.tsc.get() + 1000);
+            self.count
+        }
+    }
+
+    #[test]
+    fn calibration() {
+        let tsc = Cell::new(0);
+        let mut clock = FakeClock { count: 0xFF00, tsc: &tsc };
+        let hz = calibrate_with(&mut clock, Duration::from_millis(100), || tsc.get());
+        assert_eq!(hz, 10_000_000);
+    }
+}
@@ -10,11 +10,12 @@
 //! Hypatia uses recursive page tables with side-loading for
 //! address space inspection and manipulation.
 
-use crate::{HPA, PF1G, PF2M, PF4K, Page, PageFrame, V1GA, V2MA, V4KA, V512GA, VPageAddr};
+use crate::shootdown::{self, Request};
+use crate::{HPA, PF1G, PF2M, PF4K, Page, PageFrame, V1GA, V2MA, V4KA, V256TA, V512GA, VPageAddr};
 use bitflags::bitflags;
 use core::ops::Range;
 //use core::marker::PhantomData;    // XXX(cross): Not yet.
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
 
 pub type Result<T> = core::result::Result<T, &'static str>;
 
@@ -31,9 +32,20 @@ bitflags! {
         const HUGE    = 1 << 7;
         const GLOBAL  = 1 << 8;
         const NX      = 1 << 63;
+        // The PAT bit of 2MiB and 1GiB page entries, which is an
+        // address bit in smaller entries.
+        const PAT_LARGE = 1 << 12;
     }
 }
 
+impl PTEFlags {
+    /// The PAT bit of 4KiB page entries.  It shares its position
+    /// with `HUGE`, which is only meaningful in larger entries,
+    /// and so is kept out of the named flags; in larger entries,
+    /// the PAT bit moves to `PAT_LARGE`.
+    pub const PAT_4K: PTEFlags = PTEFlags::HUGE;
+}
+
 /// Page table entries are 64-bit integers, but we must be
 /// careful when accessing them, so we define them in terms
 /// of atomics.
@@ -41,7 +53,7 @@ bitflags! {
 pub struct PTE(AtomicU64);
 
 impl PTE {
-    const PFA_MASK: u64 = 0x0000_7FFF_FFFF_F000;
+    const PFA_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 
     /// Creates a new PTE from the given HPA and flags.
     ///
@@ -79,6 +91,26 @@ impl PTE {
         self.0.fetch_and(!PTEFlags::PRESENT.bits(), Ordering::AcqRel);
     }
 
+    /// Replaces the permission bits (PRESENT, WRITE and NX) in
+    /// the PTE, preserving all others, including any accessed
+    /// or dirty bits set concurrently by the hardware.
+    pub fn set_perms(&self, perms: PTEFlags) {
+        let mask = (PTEFlags::PRESENT | PTEFlags::WRITE | PTEFlags::NX).bits();
+        let mut pte = self.0.load(Ordering::Relaxed);
+        let update = |pte| pte & !mask | perms.bits() & mask;
+        while let Err(current) =
+            self.0.compare_exchange_weak(pte, update(pte), Ordering::AcqRel, Ordering::Relaxed)
+        {
+            pte = current;
+        }
+    }
+
+    /// Atomically clears the given flags in the PTE, returning
+    /// true iff any of them were set.
+    pub fn test_and_clear(&self, flags: PTEFlags) -> bool {
+        self.0.fetch_and(!flags.bits(), Ordering::AcqRel) & flags.bits() != 0
+    }
+
     /// Assign self the value of the given PTE.
     pub fn assign(&self, pte: PTE) {
         self.0.store(pte.0.into_inner(), Ordering::Relaxed);
@@ -89,9 +121,23 @@ impl PTE {
         HPA(self.0.load(Ordering::Relaxed) & Self::PFA_MASK)
     }
 
+    /// Returns the physical frame address of a 2MiB or 1GiB
+    /// page entry, excluding the large PAT bit.
+    pub fn big_pfa(&self) -> HPA {
+        HPA(self.0.load(Ordering::Relaxed) & Self::PFA_MASK & !PTEFlags::PAT_LARGE.bits())
+    }
+
     /// Extracts and returns the flags attached to this PTE.
+    /// Bits of the frame address are never returned as flags.
     pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits_truncate(self.0.load(Ordering::Relaxed))
+        PTEFlags::from_bits_truncate(self.0.load(Ordering::Relaxed) & !Self::PFA_MASK)
+    }
+
+    /// Extracts and returns the flags attached to a 2MiB or 1GiB
+    /// page entry, including the large PAT bit.
+    pub fn big_flags(&self) -> PTEFlags {
+        let pte = self.0.load(Ordering::Relaxed);
+        self.flags() | PTEFlags::from_bits_truncate(pte & PTEFlags::PAT_LARGE.bits())
     }
 
     /// Returns true iff the PTE is marked "PRESENT".
@@ -135,10 +181,192 @@ impl core::fmt::Debug for PTE {
     }
 }
 
+/// The depth of the paging structures: four levels, translating
+/// 48-bit virtual addresses, or five, translating 57-bit virtual
+/// addresses when CR4.LA57 is set.
+///
+/// With four levels, the root is a PML4 laid out as in HDP 0003,
+/// with the recursive entry in its last slot and the side-load
+/// slot below.  With five, the root is a PML5 whose last slot
+/// points to a PML4 laid out as before, except that its recursive
+/// and side-load slots are unused: those move to the PML5.  Every
+/// fixed address in the layout is thus the same at either depth.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Paging {
+    FourLevel,
+    FiveLevel,
+}
+
+impl Paging {
+    /// Returns the paging depth of the current CPU.
+    pub fn current() -> Paging {
+        use x86::controlregs::Cr4;
+        let cr4 = unsafe { x86::controlregs::cr4() };
+        if cr4.contains(Cr4::CR4_ENABLE_LA57) { Paging::FiveLevel } else { Paging::FourLevel }
+    }
+
+    /// Returns the number of levels of paging structures.
+    pub const fn levels(self) -> usize {
+        match self {
+            Paging::FourLevel => 4,
+            Paging::FiveLevel => 5,
+        }
+    }
+
+    /// Returns the number of significant bits in a virtual
+    /// address.
+    pub const fn address_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    /// Returns the index of the recursive entry in the root.
+    pub const fn self_index(self) -> usize {
+        match self {
+            Paging::FourLevel => 511,
+            Paging::FiveLevel => 510,
+        }
+    }
+
+    /// Returns the index of the side-load entry in the root.
+    pub const fn side_index(self) -> usize {
+        match self {
+            Paging::FourLevel => 507,
+            Paging::FiveLevel => 509,
+        }
+    }
+
+    /// Returns the address at which the tables of the given
+    /// level appear in the recursive window, where level 1 holds
+    /// the leaf entries and the root is at `levels()`.
+    pub const fn base_address(self, level: usize) -> usize {
+        self.window_base(level, self.self_index())
+    }
+
+    /// Returns the address at which the tables of the given
+    /// level of a side-loaded address space appear.
+    pub const fn side_base_address(self, level: usize) -> usize {
+        self.window_base(level, self.side_index())
+    }
+
+    // Returns the size of the region mapped by a root entry,
+    // which is the size of each window.
+    const fn window_size(self) -> usize {
+        1 << (self.address_bits() - 9)
+    }
+
+    // Returns the address of the tables of the given level in
+    // the window through the given root entry, found by walking
+    // through the recursive entry once for each level above the
+    // leaves, and then through the given entry.
+    const fn window_base(self, level: usize, window: usize) -> usize {
+        assert!(0 < level && level <= self.levels());
+        let mut va = 0;
+        let mut k = 1;
+        while k <= level {
+            let index = if k < level { self.self_index() } else { window };
+            va |= index << (12 + 9 * (self.levels() - k));
+            k += 1;
+        }
+        let extension = usize::BITS as usize - self.address_bits();
+        (((va << extension) as isize) >> extension) as usize
+    }
+}
+
+/// An Mmu provides access to the paging structures for an
+/// address space through the recursive and side-load windows
+/// at the top of the virtual address space.
+///
+/// On real hardware, the windows are ordinary virtual addresses
+/// that the MMU resolves by recursing through the root table.
+/// Abstracting over this lets the code that manipulates page
+/// tables run against a software model on the host.
+pub trait Mmu {
+    /// Returns a reference to the page table entry at the given
+    /// virtual address, which must lie in one of the windows or
+    /// in a region where paging structures are mapped as data.
+    fn pte(&self, va: usize) -> &PTE;
+
+    /// Returns the depth of the paging structures.
+    fn paging(&self) -> Paging;
+
+    /// Invalidates any translations cached from the paging
+    /// structures.
+    fn flush_tlb(&self);
+
+    /// Invalidates any cached translation for the page containing
+    /// the given virtual address, along with any cached paging
+    /// structure entries.
+    fn invalidate(&self, va: usize);
+
+    /// Invalidates any guest-physical translations cached from
+    /// the EPT with the given EPT pointer, on the local CPU.
+    fn invept(&self, eptp: u64);
+
+    /// Invalidates the translations described by the request on
+    /// every other CPU that may have cached them, returning once
+    /// all have done so.
+    fn shoot_down(&self, request: Request<'_>);
+}
+
+/// The MMU of the current CPU, operating on whatever address
+/// space is loaded in %cr3.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HardMmu;
+
+// The paging depth, as its number of levels, or zero until it
+// has been read from CR4.  The depth cannot change while paging
+// is enabled, so it is read only once.
+static PAGING: AtomicU8 = AtomicU8::new(0);
+
+impl Mmu for HardMmu {
+    fn pte(&self, va: usize) -> &PTE {
+        unsafe { &*PTE::proto_ptr().with_addr(va) }
+    }
+
+    fn paging(&self) -> Paging {
+        match PAGING.load(Ordering::Relaxed) {
+            4 => Paging::FourLevel,
+            5 => Paging::FiveLevel,
+            _ => {
+                let paging = Paging::current();
+                PAGING.store(paging.levels() as u8, Ordering::Relaxed);
+                paging
+            }
+        }
+    }
+
+    fn flush_tlb(&self) {
+        flush_tlb();
+    }
+
+    fn invalidate(&self, va: usize) {
+        unsafe {
+            x86::tlb::flush(va);
+        }
+    }
+
+    fn invept(&self, eptp: u64) {
+        unsafe {
+            crate::ept::invept(eptp);
+        }
+    }
+
+    fn shoot_down(&self, request: Request<'_>) {
+        shootdown::shoot_down(request);
+    }
+}
+
 // XXX: Figure out why Rust thinks this Entry is unused.
 #[allow(dead_code)]
 trait Entry {}
 
+#[repr(u64)]
+enum L5E {
+    #[allow(dead_code)]
+    Next(PTE),
+}
+impl Entry for L5E {}
+
 #[repr(u64)]
 enum L4E {
     #[allow(dead_code)]
@@ -170,9 +398,9 @@ impl Entry for L1E {}
 ///
 /// The nature of the recursive entry in the table root is that
 /// the nodes in the paging radix trees are all accessible via
-/// fixed locations in the virtual address space.  The constants
-/// below are the beginnings of the virtual address regions for
-/// all entries.
+/// fixed locations in the virtual address space.  Those locations
+/// depend on the depth of paging; see `Paging::base_address` for
+/// the beginnings of the virtual address regions for all entries.
 ///
 /// This also means that radix nodes at any given level of the
 /// tree for contiguous regions of the virtual address space are
@@ -182,37 +410,51 @@ impl Entry for L1E {}
 trait Level {
     type EntryType: Entry;
     type VPageAddrType: VPageAddr + core::iter::Step;
-    const BASE_ADDRESS: usize;
-    const SIDE_BASE_ADDRESS: usize;
+    const LEVEL: usize;
     const PAGE_SHIFT: usize;
 
-    fn index(va: usize) -> usize {
+    fn base_address(paging: Paging) -> usize {
+        paging.base_address(Self::LEVEL)
+    }
+
+    fn side_base_address(paging: Paging) -> usize {
+        paging.side_base_address(Self::LEVEL)
+    }
+
+    fn index(paging: Paging, va: usize) -> usize {
         const WORD_SIZE: usize = 64;
-        const ADDRESS_BITS: usize = 48;
-        const SIGN_EXTENSION_BITS: usize = WORD_SIZE - ADDRESS_BITS;
-        const ADDRESS_MASK: usize = !0 >> SIGN_EXTENSION_BITS;
-        (va & ADDRESS_MASK) >> Self::PAGE_SHIFT
+        let sign_extension_bits = WORD_SIZE - paging.address_bits();
+        let address_mask = !0 >> sign_extension_bits;
+        (va & address_mask) >> Self::PAGE_SHIFT
     }
 
     fn decode(pte: PTE) -> Option<Self::EntryType>;
 
-    fn pte_ref(va: usize) -> &'static PTE {
-        let addr = Self::BASE_ADDRESS + Self::index(va) * core::mem::size_of::<PTE>();
-        unsafe { &*PTE::proto_ptr().with_addr(addr) }
+    /// Returns the address of the table holding the entry for the
+    /// given virtual address, in the recursive window.
+    fn table_address(paging: Paging, va: usize) -> usize {
+        const MASK_4K: usize = <V4KA as VPageAddr>::PageType::MASK;
+        let base = Self::base_address(paging);
+        (base + Self::index(paging, va) * core::mem::size_of::<PTE>()) & !MASK_4K
     }
 
-    fn entry(va: usize) -> Option<Self::EntryType> {
-        let pte = Self::pte_ref(va).clone();
+    fn pte_ref<M: Mmu>(mmu: &M, va: usize) -> &PTE {
+        let paging = mmu.paging();
+        mmu.pte(Self::base_address(paging) + Self::index(paging, va) * core::mem::size_of::<PTE>())
+    }
+
+    fn entry<M: Mmu>(mmu: &M, va: usize) -> Option<Self::EntryType> {
+        let pte = Self::pte_ref(mmu, va).clone();
         Self::decode(pte)
     }
 
-    fn set_entry(va: usize, pte: PTE) {
-        let entry = Self::pte_ref(va);
+    fn set_entry<M: Mmu>(mmu: &M, va: usize, pte: PTE) {
+        let entry = Self::pte_ref(mmu, va);
         entry.assign(pte);
     }
 
-    fn clear(va: usize) {
-        let entry = Self::pte_ref(va);
+    fn clear<M: Mmu>(mmu: &M, va: usize) {
+        let entry = Self::pte_ref(mmu, va);
         entry.clear();
     }
 
@@ -220,17 +462,18 @@ trait Level {
     ///
     /// This is not safe.  It requires that some address space is side-loaded
     /// before calling.
-    unsafe fn side_pte_ref(va: usize) -> &'static PTE {
-        let addr = Self::SIDE_BASE_ADDRESS + Self::index(va) * core::mem::size_of::<PTE>();
-        unsafe { &*PTE::proto_ptr().with_addr(addr) }
+    unsafe fn side_pte_ref<M: Mmu>(mmu: &M, va: usize) -> &PTE {
+        let paging = mmu.paging();
+        let base = Self::side_base_address(paging);
+        mmu.pte(base + Self::index(paging, va) * core::mem::size_of::<PTE>())
     }
 
     /// # Safety
     ///
     /// This is not safe.  It requires that some address space is side-loaded
     /// before calling.
-    unsafe fn side_entry(va: usize) -> Option<Self::EntryType> {
-        let pte = unsafe { Self::side_pte_ref(va).clone() };
+    unsafe fn side_entry<M: Mmu>(mmu: &M, va: usize) -> Option<Self::EntryType> {
+        let pte = unsafe { Self::side_pte_ref(mmu, va).clone() };
         Self::decode(pte)
     }
 
@@ -238,8 +481,8 @@ trait Level {
     ///
     /// This is not safe.  It requires that some address space is side-loaded
     /// before calling.
-    unsafe fn set_side_entry(va: usize, pte: PTE) {
-        let entry = unsafe { Self::side_pte_ref(va) };
+    unsafe fn set_side_entry<M: Mmu>(mmu: &M, va: usize, pte: PTE) {
+        let entry = unsafe { Self::side_pte_ref(mmu, va) };
         entry.assign(pte);
     }
 
@@ -247,40 +490,68 @@ trait Level {
     ///
     /// This is note safe.  It rquires that some address space is side-loaded
     /// before calling.
-    unsafe fn make_side_level<A>(va: V4KA, allocator: &mut A) -> Result<()>
+    unsafe fn make_side_level<M, A>(mmu: &M, va: V4KA, allocator: &mut A) -> Result<()>
     where
+        M: Mmu,
         A: FnMut() -> Result<PF4K>;
 }
 
+/// The level 5 tables exist only under five-level paging, and
+/// must not be touched otherwise.
+enum Level5 {}
 enum Level4 {}
 enum Level3 {}
 enum Level2 {}
 enum Level1 {}
 
-impl Level4 {
-    #[cfg(test)]
-    const SELF_INDEX: usize = 511;
-    const SIDE_INDEX: usize = 510;
+impl Level for Level5 {
+    type EntryType = L5E;
+    type VPageAddrType = V256TA;
+    const LEVEL: usize = 5;
+    const PAGE_SHIFT: usize = 48;
+
+    fn decode(pte: PTE) -> Option<Self::EntryType> {
+        if pte.is_present() { Some(L5E::Next(pte)) } else { None }
+    }
+
+    /// Does nothing under four-level paging, where the level 4
+    /// table is the root.
+    unsafe fn make_side_level<M, A>(mmu: &M, va: V4KA, allocator: &mut A) -> Result<()>
+    where
+        M: Mmu,
+        A: FnMut() -> Result<PF4K>,
+    {
+        if mmu.paging() == Paging::FourLevel {
+            return Ok(());
+        }
+        unsafe {
+            if Level5::side_entry(mmu, va.addr()).is_none() {
+                Level5::set_side_entry(mmu, va.addr(), alloc_inner(allocator)?);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Level for Level4 {
     type EntryType = L4E;
     type VPageAddrType = V512GA;
-    const BASE_ADDRESS: usize = 0xFFFF_FFFF_FFFF_F000;
-    const SIDE_BASE_ADDRESS: usize = 0xFFFF_FFFF_FFFF_E000;
+    const LEVEL: usize = 4;
     const PAGE_SHIFT: usize = 39;
 
     fn decode(pte: PTE) -> Option<Self::EntryType> {
         if pte.is_present() { Some(L4E::Next(pte)) } else { None }
     }
 
-    unsafe fn make_side_level<A>(va: V4KA, allocator: &mut A) -> Result<()>
+    unsafe fn make_side_level<M, A>(mmu: &M, va: V4KA, allocator: &mut A) -> Result<()>
     where
+        M: Mmu,
         A: FnMut() -> Result<PF4K>,
     {
         unsafe {
-            if Level4::side_entry(va.addr()).is_none() {
-                Level4::set_side_entry(va.addr(), alloc_inner(allocator)?);
+            Level5::make_side_level(mmu, va, allocator)?;
+            if Level4::side_entry(mmu, va.addr()).is_none() {
+                Level4::set_side_entry(mmu, va.addr(), alloc_inner(allocator)?);
             }
         }
         Ok(())
@@ -290,28 +561,28 @@ impl Level for Level4 {
 impl Level for Level3 {
     type EntryType = L3E;
     type VPageAddrType = V1GA;
-    const BASE_ADDRESS: usize = 0xFFFF_FFFF_FFE0_0000;
-    const SIDE_BASE_ADDRESS: usize = 0xFFFF_FFFF_FFC0_0000;
+    const LEVEL: usize = 3;
     const PAGE_SHIFT: usize = 30;
 
     fn decode(pte: PTE) -> Option<Self::EntryType> {
         if !pte.is_present() {
             None
         } else if pte.is_big() {
-            Some(L3E::Page(PF1G(pte.pfa())))
+            Some(L3E::Page(PF1G(pte.big_pfa())))
         } else {
             Some(L3E::Next(pte))
         }
     }
 
-    unsafe fn make_side_level<A>(va: V4KA, allocator: &mut A) -> Result<()>
+    unsafe fn make_side_level<M, A>(mmu: &M, va: V4KA, allocator: &mut A) -> Result<()>
     where
+        M: Mmu,
         A: FnMut() -> Result<PF4K>,
     {
         unsafe {
-            Level4::make_side_level(va, allocator)?;
-            if Level3::side_entry(va.addr()).is_none() {
-                Level3::set_side_entry(va.addr(), alloc_inner(allocator)?);
+            Level4::make_side_level(mmu, va, allocator)?;
+            if Level3::side_entry(mmu, va.addr()).is_none() {
+                Level3::set_side_entry(mmu, va.addr(), alloc_inner(allocator)?);
             }
         }
         Ok(())
@@ -321,28 +592,28 @@ impl Level for Level3 {
 impl Level for Level2 {
     type EntryType = L2E;
     type VPageAddrType = V2MA;
-    const BASE_ADDRESS: usize = 0xFFFF_FFFF_C000_0000;
-    const SIDE_BASE_ADDRESS: usize = 0xFFFF_FFFF_8000_0000;
+    const LEVEL: usize = 2;
     const PAGE_SHIFT: usize = 21;
 
     fn decode(pte: PTE) -> Option<Self::EntryType> {
         if !pte.is_present() {
             None
         } else if pte.is_big() {
-            Some(L2E::Page(PF2M(pte.pfa())))
+            Some(L2E::Page(PF2M(pte.big_pfa())))
         } else {
             Some(L2E::Next(pte))
         }
     }
 
-    unsafe fn make_side_level<A>(va: V4KA, allocator: &mut A) -> Result<()>
+    unsafe fn make_side_level<M, A>(mmu: &M, va: V4KA, allocator: &mut A) -> Result<()>
     where
+        M: Mmu,
         A: FnMut() -> Result<PF4K>,
     {
         unsafe {
-            Level3::make_side_level(va, allocator)?;
-            if Level2::side_entry(va.addr()).is_none() {
-                Level2::set_side_entry(va.addr(), alloc_inner(allocator)?);
+            Level3::make_side_level(mmu, va, allocator)?;
+            if Level2::side_entry(mmu, va.addr()).is_none() {
+                Level2::set_side_entry(mmu, va.addr(), alloc_inner(allocator)?);
             }
         }
         Ok(())
@@ -352,78 +623,65 @@ impl Level for Level2 {
 impl Level for Level1 {
     type EntryType = L1E;
     type VPageAddrType = V4KA;
-    const BASE_ADDRESS: usize = 0xFFFF_FF80_0000_0000;
-    const SIDE_BASE_ADDRESS: usize = 0xFFFF_FF00_0000_0000;
+    const LEVEL: usize = 1;
     const PAGE_SHIFT: usize = 12;
 
     fn decode(pte: PTE) -> Option<Self::EntryType> {
         if !pte.is_present() { None } else { Some(L1E::Page(PF4K(pte.pfa()))) }
     }
 
-    unsafe fn make_side_level<A>(va: V4KA, allocator: &mut A) -> Result<()>
+    unsafe fn make_side_level<M, A>(mmu: &M, va: V4KA, allocator: &mut A) -> Result<()>
     where
+        M: Mmu,
         A: FnMut() -> Result<PF4K>,
     {
         unsafe {
-            Level2::make_side_level(va, allocator)?;
-            if Level1::side_entry(va.addr()).is_none() {
-                Level1::set_side_entry(va.addr(), alloc_inner(allocator)?);
+            Level2::make_side_level(mmu, va, allocator)?;
+            if Level1::side_entry(mmu, va.addr()).is_none() {
+                Level1::set_side_entry(mmu, va.addr(), alloc_inner(allocator)?);
             }
         }
         Ok(())
     }
 }
 
-#[repr(C, align(4096))]
-struct PageTable {
-    entries: [PTE; 512],
-}
-
-impl PageTable {
-    pub fn _is_empty(&self) -> bool {
-        self.entries.iter().all(|entry| entry.is_zero())
-    }
-
-    pub fn root_addr(&self) -> HPA {
-        translate_ptr(self).expect("mapped object is mapped")
-    }
-
-    pub const fn proto_ptr() -> *const PageTable {
-        core::ptr::null()
-    }
-}
-
 /// A walk represents a path of page table entries from the root
-/// down to the leaf level of paging radix tree.
+/// down to the leaf level of paging radix tree.  Under five-level
+/// paging, the level 5 entry is left out: if it is not present,
+/// the walk ends as if the level 4 entry were not.
 struct Walk(Option<L4E>, Option<L3E>, Option<L2E>, Option<L1E>);
 
 /// Performs a page table walk for the virtual address of the given
 /// pointer in the current address space.
 #[allow(dead_code)]
 fn walk_ptr<T>(p: *const T) -> Walk {
-    walk(p.addr())
+    walk(&HardMmu, p.addr())
 }
 
-fn walk(va: usize) -> Walk {
-    let pt4e = Level4::entry(va);
+fn walk<M: Mmu>(mmu: &M, va: usize) -> Walk {
+    if !has_level4(mmu, va) {
+        return Walk(None, None, None, None);
+    }
+
+    let pt4e = Level4::entry(mmu, va);
     match pt4e {
         Some(L4E::Next(_)) => {}
         _ => return Walk(pt4e, None, None, None),
     }
 
-    let pt3e = Level3::entry(va);
+    let pt3e = Level3::entry(mmu, va);
     match pt3e {
         Some(L3E::Next(_)) => {}
         _ => return Walk(pt4e, pt3e, None, None),
     }
 
-    let pt2e = Level2::entry(va);
+    let pt2e = Level2::entry(mmu, va);
     match pt2e {
         Some(L2E::Next(_)) => {}
         _ => return Walk(pt4e, pt3e, pt2e, None),
     }
 
-    let pt1e = Level1::entry(va);
+    let pt1e = Level1::entry(mmu, va);
 
     Walk(pt4e, pt3e, pt2e, pt1e)
 }
@@ -435,7 +693,13 @@ pub fn translate_ptr<T>(p: *const T) -> Option<HPA> {
 }
 
 pub fn translate(va: usize) -> Option<HPA> {
-    translate_walk(va, walk(va))
+    translate_in(&HardMmu, va)
+}
+
+/// Translates the given virtual address to a host physical
+/// address in the address space viewed through the given MMU.
+pub fn translate_in<M: Mmu>(mmu: &M, va: usize) -> Option<HPA> {
+    translate_walk(va, walk(mmu, va))
 }
 
 fn translate_walk(va: usize, w: Walk) -> Option<HPA> {
@@ -458,22 +722,35 @@ fn translate_walk(va: usize, w: Walk) -> Option<HPA> {
 pub fn map<F>(hpf: PF4K, flags: PTEFlags, va: V4KA, allocator: &mut F) -> Result<()>
 where
     F: FnMut() -> Result<PF4K>,
+{
+    map_in(&HardMmu, hpf, flags, va, allocator)
+}
+
+/// Maps the given PF4K to the given virtual address in the
+/// address space viewed through the given MMU.
+pub fn map_in<M, F>(mmu: &M, hpf: PF4K, flags: PTEFlags, va: V4KA, allocator: &mut F) -> Result<()>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
 {
     let va = va.addr();
-    assert!(va < Level1::SIDE_BASE_ADDRESS, "attempting to map in the recursive region");
+    assert!(!in_paging_window(mmu.paging(), va), "attempting to map in the recursive region");
 
-    let w = walk(va);
+    let w = walk(mmu, va);
     if let Walk(None, _, _, _) = w {
-        Level4::set_entry(va, alloc_inner(allocator)?);
+        if !has_level4(mmu, va) {
+            Level5::set_entry(mmu, va, alloc_inner(allocator)?);
+        }
+        Level4::set_entry(mmu, va, alloc_inner(allocator)?);
     }
     if let Walk(_, None, _, _) = w {
-        Level3::set_entry(va, alloc_inner(allocator)?);
+        Level3::set_entry(mmu, va, alloc_inner(allocator)?);
     }
     if let Walk(_, _, None, _) = w {
-        Level2::set_entry(va, alloc_inner(allocator)?);
+        Level2::set_entry(mmu, va, alloc_inner(allocator)?);
     }
     if let Walk(_, _, _, None) = w {
-        Level1::set_entry(va, PTE::new(hpf.pfa(), flags));
+        Level1::set_entry(mmu, va, PTE::new(hpf.pfa(), flags));
         Ok(())
     } else {
         Err("Already mapped")
@@ -484,20 +761,345 @@ where
 /// intermediate paging structures for the mapping already
 /// exist.
 pub fn map_leaf(hpf: PF4K, va: V4KA, r: bool, w: bool, x: bool) -> Result<()> {
+    map_leaf_in(&HardMmu, hpf, va, r, w, x)
+}
+
+/// Maps a leaf node into the address space viewed through the
+/// given MMU.  Requires that the intermediate paging structures
+/// for the mapping already exist.
+pub fn map_leaf_in<M: Mmu>(mmu: &M, hpf: PF4K, va: V4KA, r: bool, w: bool, x: bool) -> Result<()> {
     let flags = page_perm_flags(r, w, x);
     let mut allocator = || Err("not a leaf");
-    map(hpf, flags, va, &mut allocator)
+    map_in(mmu, hpf, flags, va, &mut allocator)
+}
+
+/// Maps the given PF4K as a global page at the given virtual
+/// address in the current address space.  Global translations
+/// survive address space switches, so this is only for mappings
+/// that are identical in every address space, such as those of
+/// segments.
+pub fn map_global_leaf(hpf: PF4K, va: V4KA, r: bool, w: bool, x: bool) -> Result<()> {
+    map_global_leaf_in(&HardMmu, hpf, va, r, w, x)
+}
+
+/// Maps the given PF4K as a global page at the given virtual
+/// address in the address space viewed through the given MMU.
+pub fn map_global_leaf_in<M: Mmu>(
+    mmu: &M,
+    hpf: PF4K,
+    va: V4KA,
+    r: bool,
+    w: bool,
+    x: bool,
+) -> Result<()> {
+    assert!(is_shared(mmu.paging(), va.addr()), "global page outside the shared half");
+    let flags = page_perm_flags(r, w, x) | PTEFlags::GLOBAL;
+    let mut allocator = || Err("not a leaf");
+    map_in(mmu, hpf, flags, va, &mut allocator)
 }
 
 /// Unmaps the given virtual address in the current address space.
 /// Only clears the leaf entry, ignoring interior nodes.
 pub fn unmap(va: V4KA) {
+    unmap_in(&HardMmu, va)
+}
+
+/// Unmaps the given virtual address in the address space viewed
+/// through the given MMU.  Only clears the leaf entry, ignoring
+/// interior nodes.
+pub fn unmap_in<M: Mmu>(mmu: &M, va: V4KA) {
     let va = va.addr();
-    if let Walk(Some(_), Some(_), Some(_), Some(_)) = walk(va) {
-        Level4::clear(va);
+    if let Walk(Some(_), Some(_), Some(_), Some(_)) = walk(mmu, va) {
+        Level1::clear(mmu, va);
+        TLBInvalidator::new(mmu).add(va);
     }
 }
 
+/// Maps the given PF4K at every page in the given range of the
+/// current address space.  As with `map_leaf`, the intermediate
+/// paging structures must already exist.
+pub fn map_leaf_range(hpf: PF4K, range: Range<V4KA>, r: bool, w: bool, x: bool) -> Result<()> {
+    map_leaf_range_in(&HardMmu, hpf, range, r, w, x)
+}
+
+/// Maps the given PF4K at every page in the given range of the
+/// address space viewed through the given MMU.
+pub fn map_leaf_range_in<M: Mmu>(
+    mmu: &M,
+    hpf: PF4K,
+    range: Range<V4KA>,
+    r: bool,
+    w: bool,
+    x: bool,
+) -> Result<()> {
+    for va in range {
+        map_leaf_in(mmu, hpf, va, r, w, x)?;
+    }
+    Ok(())
+}
+
+/// Changes the permissions of every page in the given range of
+/// the current address space.
+pub fn protect(range: Range<V4KA>, r: bool, w: bool, x: bool) -> Result<()> {
+    protect_in(&HardMmu, range, r, w, x)
+}
+
+/// Changes the permissions of every page in the given range of
+/// the address space viewed through the given MMU.  Every page in
+/// the range must be mapped, though possibly inaccessible due to
+/// an earlier change of permissions; if not, nothing is changed.
+/// Accessed and dirty bits are preserved.
+pub fn protect_in<M: Mmu>(mmu: &M, range: Range<V4KA>, r: bool, w: bool, x: bool) -> Result<()> {
+    assert_not_in_window(mmu.paging(), &range);
+    for va in range.clone() {
+        let va = va.addr();
+        match walk(mmu, va) {
+            Walk(Some(_), Some(L3E::Next(_)), Some(L2E::Next(_)), _) => {}
+            Walk(_, Some(L3E::Page(_)), _, _) | Walk(_, _, Some(L2E::Page(_)), _) => {
+                return Err("big page in range");
+            }
+            _ => return Err("not mapped"),
+        }
+        if Level1::pte_ref(mmu, va).is_zero() {
+            return Err("not mapped");
+        }
+    }
+    let perms = page_perm_flags(r, w, x);
+    let mut tlb = TLBInvalidator::new(mmu);
+    for va in range {
+        Level1::pte_ref(mmu, va.addr()).set_perms(perms);
+        tlb.add(va.addr());
+    }
+    Ok(())
+}
+
+/// Collects and clears the dirty bits for the given range of the
+/// current address space into a bitmap.
+pub fn harvest_dirty(range: Range<V4KA>, bitmap: &mut [u64]) -> Result<usize> {
+    harvest_in(&HardMmu, range, PTEFlags::DIRTY, bitmap)
+}
+
+/// Collects and clears the accessed bits for the given range of
+/// the current address space into a bitmap.
+pub fn harvest_accessed(range: Range<V4KA>, bitmap: &mut [u64]) -> Result<usize> {
+    harvest_in(&HardMmu, range, PTEFlags::ACCESS, bitmap)
+}
+
+/// Collects and clears the dirty bits for the given range of the
+/// address space viewed through the given MMU, setting bit `k` in
+/// the bitmap iff the `k`th page in the range was dirty, and
+/// returns the number of dirty pages.
+///
+/// The leaf entries for each 2MiB region are adjacent in the
+/// recursive window, so this is a linear scan with a walk per
+/// region, not per page.  A big page is treated as a unit: if it
+/// was dirty, every page in it that is in the range is marked.
+/// Unmapped pages are never marked.  The affected pages are
+/// invalidated so that the hardware sets the bit again on next use.
+pub fn harvest_dirty_in<M: Mmu>(mmu: &M, range: Range<V4KA>, bitmap: &mut [u64]) -> Result<usize> {
+    harvest_in(mmu, range, PTEFlags::DIRTY, bitmap)
+}
+
+/// Collects and clears the accessed bits for the given range of
+/// the address space viewed through the given MMU, as
+/// `harvest_dirty_in` does for dirty bits.
+pub fn harvest_accessed_in<M: Mmu>(
+    mmu: &M,
+    range: Range<V4KA>,
+    bitmap: &mut [u64],
+) -> Result<usize> {
+    harvest_in(mmu, range, PTEFlags::ACCESS, bitmap)
+}
+
+// Tests and clears the given flag in the leaf entries for every
+// page in the range, recording those where it was set in the
+// bitmap.
+fn harvest_in<M: Mmu>(
+    mmu: &M,
+    range: Range<V4KA>,
+    flag: PTEFlags,
+    bitmap: &mut [u64],
+) -> Result<usize> {
+    const SIZE_4K: usize = <V4KA as VPageAddr>::PageType::SIZE;
+    const MASK_2M: usize = <V2MA as VPageAddr>::PageType::MASK;
+    const MASK_1G: usize = <V1GA as VPageAddr>::PageType::MASK;
+    assert_not_in_window(mmu.paging(), &range);
+    let (start, end) = (range.start.addr(), range.end.addr());
+    let npages = end.saturating_sub(start) / SIZE_4K;
+    let bitmap = bitmap.get_mut(..npages.div_ceil(64)).ok_or("bitmap too small")?;
+    bitmap.fill(0);
+    let mut mark =
+        |va: usize| bitmap[(va - start) / SIZE_4K / 64] |= 1 << ((va - start) / SIZE_4K % 64);
+    let mut tlb = TLBInvalidator::new(mmu);
+    let mut count = 0;
+    let mut va = start;
+    while va < end {
+        let (big, mask) = match walk(mmu, va) {
+            Walk(_, Some(L3E::Next(_)), Some(L2E::Next(_)), _) => (None, MASK_2M),
+            Walk(_, Some(L3E::Next(_)), Some(L2E::Page(_)), _) => {
+                (Some(Level2::pte_ref(mmu, va)), MASK_2M)
+            }
+            Walk(_, Some(L3E::Page(_)), _, _) => (Some(Level3::pte_ref(mmu, va)), MASK_1G),
+            Walk(_, _, _, _) => {
+                va = usize::min((va | MASK_2M) + 1, end);
+                continue;
+            }
+        };
+        let next = usize::min((va | mask) + 1, end);
+        match big {
+            Some(pte) if pte.test_and_clear(flag) => {
+                for page in (va..next).step_by(SIZE_4K) {
+                    mark(page);
+                }
+                count += (next - va) / SIZE_4K;
+                tlb.add(va);
+            }
+            Some(_) => {}
+            None => {
+                for page in (va..next).step_by(SIZE_4K) {
+                    if Level1::pte_ref(mmu, page).test_and_clear(flag) {
+                        mark(page);
+                        count += 1;
+                        tlb.add(page);
+                    }
+                }
+            }
+        }
+        va = next;
+    }
+    Ok(count)
+}
+
+/// Unmaps every page in the given range of the current address
+/// space, freeing any paging structures that become empty.
+pub fn unmap_range<D>(range: Range<V4KA>, deallocator: &mut D)
+where
+    D: FnMut(PF4K),
+{
+    unmap_range_in(&HardMmu, range, deallocator)
+}
+
+/// Unmaps every page in the given range of the address space
+/// viewed through the given MMU.  Any tables below the root that
+/// become empty as a result are unlinked and passed to the
+/// deallocator, but only once no translation through them can
+/// remain in the TLB.
+///
+/// As the tables are freed, this must not be used on ranges whose
+/// paging structures are shared with other address spaces, though
+/// other CPUs running in this one are shot down as usual.
+pub fn unmap_range_in<M, D>(mmu: &M, range: Range<V4KA>, deallocator: &mut D)
+where
+    M: Mmu,
+    D: FnMut(PF4K),
+{
+    let paging = mmu.paging();
+    assert_not_in_window(paging, &range);
+    let mut reclaimer = Reclaimer::new(mmu, deallocator);
+    for va in range.clone() {
+        let va = va.addr();
+        if let Walk(Some(_), Some(L3E::Next(_)), Some(L2E::Next(_)), _) = walk(mmu, va) {
+            let pte = Level1::pte_ref(mmu, va);
+            if !pte.is_zero() {
+                pte.clear();
+                reclaimer.invalidate(va);
+            }
+        }
+    }
+    let (start, end) = (range.start.addr(), range.end.addr());
+    for addr in V2MA::new_round_down(start)..V2MA::new_round_up(end) {
+        let va = addr.addr();
+        if let Walk(_, Some(L3E::Next(_)), Some(L2E::Next(pte)), _) = walk(mmu, va)
+            && table_is_empty::<Level1, _>(mmu, va)
+        {
+            Level2::clear(mmu, va);
+            reclaimer.free(PF4K(pte.pfa()), va, Level1::table_address(paging, va));
+        }
+    }
+    for addr in V1GA::new_round_down(start)..V1GA::new_round_up(end) {
+        let va = addr.addr();
+        if let Walk(_, Some(L3E::Next(pte)), _, _) = walk(mmu, va)
+            && table_is_empty::<Level2, _>(mmu, va)
+        {
+            Level3::clear(mmu, va);
+            reclaimer.free(PF4K(pte.pfa()), va, Level2::table_address(paging, va));
+        }
+    }
+    for addr in V512GA::new_round_down(start)..V512GA::new_round_up(end) {
+        let va = addr.addr();
+        if let Walk(Some(L4E::Next(pte)), _, _, _) = walk(mmu, va)
+            && table_is_empty::<Level3, _>(mmu, va)
+        {
+            Level4::clear(mmu, va);
+            reclaimer.free(PF4K(pte.pfa()), va, Level3::table_address(paging, va));
+        }
+    }
+    if paging == Paging::FourLevel {
+        return;
+    }
+    for addr in V256TA::new_round_down(start)..V256TA::new_round_up(end) {
+        let va = addr.addr();
+        if let Some(L5E::Next(pte)) = Level5::entry(mmu, va)
+            && table_is_empty::<Level4, _>(mmu, va)
+        {
+            Level5::clear(mmu, va);
+            reclaimer.free(PF4K(pte.pfa()), va, Level4::table_address(paging, va));
+        }
+    }
+}
+
+// Asserts that no part of the given range lies in the recursive
+// or side-load windows.
+fn assert_not_in_window(paging: Paging, range: &Range<V4KA>) {
+    if range.start.addr() < range.end.addr() {
+        assert!(
+            !in_paging_window(paging, range.start.addr())
+                && !in_paging_window(paging, range.end.addr() - 1),
+            "attempting to modify the recursive region"
+        );
+    }
+}
+
+// Returns true iff the table at the given level holding the entry
+// for the given address is entirely zero.
+fn table_is_empty<L: Level, M: Mmu>(mmu: &M, va: usize) -> bool {
+    const NENTRIES: usize = 512;
+    let table = L::table_address(mmu.paging(), va);
+    (0..NENTRIES).all(|k| mmu.pte(table + k * core::mem::size_of::<PTE>()).is_zero())
+}
+
+// Returns true iff the level 4 table covering the given address
+// exists, as it always does when it is the root.
+fn has_level4<M: Mmu>(mmu: &M, va: usize) -> bool {
+    mmu.paging() == Paging::FourLevel || Level5::entry(mmu, va).is_some()
+}
+
+// Returns true iff the given address lies in either the recursive
+// or side-load windows, where the paging structures appear.  Note
+// that the L2PT and IOPT mirrors are not windows in this sense, and
+// are mapped as ordinary data.
+fn in_paging_window(paging: Paging, va: usize) -> bool {
+    let size = paging.window_size();
+    let recursive = Level1::base_address(paging);
+    let side = Level1::side_base_address(paging);
+    va.wrapping_sub(recursive) < size || va.wrapping_sub(side) < size
+}
+
+// Returns true iff translations for the given address may be
+// cached by other CPUs.  Segments, which are mapped into every
+// address space, all live in the upper half; the paging windows
+// are private to each address space.
+fn is_shared(paging: Paging, va: usize) -> bool {
+    const UPPER_HALF: usize = 0xFFFF_8000_0000_0000;
+    va >= UPPER_HALF && !in_paging_window(paging, va)
+}
+
+// Returns true iff any part of the given range is shared.
+fn range_is_shared(paging: Paging, range: &Range<V4KA>) -> bool {
+    range.start.addr() < range.end.addr()
+        && (is_shared(paging, range.start.addr()) || is_shared(paging, range.end.addr() - 1))
+}
+
 // Converts RWX permissions to page flags.
 fn page_perm_flags(r: bool, w: bool, x: bool) -> PTEFlags {
     let mut flags = PTEFlags::empty();
@@ -526,23 +1128,21 @@ where
 
 // Makes the paging structures at a given level for the
 // specified regions and page permissions.
-fn make_ranges_level<L, F>(ranges: &[Range<V4KA>], allocator: &mut F) -> Result<()>
+fn make_ranges_level<L, M, F>(mmu: &M, ranges: &[Range<V4KA>], allocator: &mut F) -> Result<()>
 where
     F: FnMut() -> Result<PF4K>,
+    M: Mmu,
     L: Level,
 {
     for range in ranges.iter() {
+        assert_not_in_window(mmu.paging(), range);
         let start = L::VPageAddrType::new_round_down(range.start.addr());
         let end = L::VPageAddrType::new_round_up(range.end.addr());
-        assert!(
-            end.addr() < Level1::SIDE_BASE_ADDRESS,
-            "attempting to map in the recursive region"
-        );
         for addr in start..end {
             let va = addr.addr();
-            if L::entry(va).is_none() {
+            if L::entry(mmu, va).is_none() {
                 let pf = allocator()?;
-                L::set_entry(va, PTE::new(pf.pfa(), PTEFlags::WRITE | PTEFlags::PRESENT));
+                L::set_entry(mmu, va, PTE::new(pf.pfa(), PTEFlags::WRITE | PTEFlags::PRESENT));
             }
         }
     }
@@ -559,9 +1159,24 @@ pub fn make_ranges<F>(ranges: &[Range<V4KA>], allocator: &mut F) -> Result<()>
 where
     F: FnMut() -> Result<PF4K>,
 {
-    make_ranges_level::<Level4, _>(ranges, allocator)?;
-    make_ranges_level::<Level3, _>(ranges, allocator)?;
-    make_ranges_level::<Level2, _>(ranges, allocator)?;
+    make_ranges_in(&HardMmu, ranges, allocator)
+}
+
+/// Creates paging structures corresponding to the given ranges
+/// of addresses in the address space viewed through the given
+/// MMU.  As with `make_ranges`, it is assumed that the allocator
+/// returns zeroed pages.
+pub fn make_ranges_in<M, F>(mmu: &M, ranges: &[Range<V4KA>], allocator: &mut F) -> Result<()>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
+{
+    if mmu.paging() == Paging::FiveLevel {
+        make_ranges_level::<Level5, _, _>(mmu, ranges, allocator)?;
+    }
+    make_ranges_level::<Level4, _, _>(mmu, ranges, allocator)?;
+    make_ranges_level::<Level3, _, _>(mmu, ranges, allocator)?;
+    make_ranges_level::<Level2, _, _>(mmu, ranges, allocator)?;
     Ok(())
 }
 
@@ -574,38 +1189,59 @@ pub fn make_shared_ranges<A>(ranges: &[Range<V4KA>], side: PF4K, allocator: &mut
 where
     A: FnMut() -> Result<PF4K>,
 {
-    fn make_shared_ranges_level4<A>(ranges: &[Range<V4KA>], allocator: &mut A) -> Result<()>
+    make_shared_ranges_in(&HardMmu, ranges, side, allocator)
+}
+
+/// Creates paging structures corresponding to the given ranges
+/// of addresses in both the address space viewed through the
+/// given MMU and the side-loaded address space.
+pub fn make_shared_ranges_in<M, A>(
+    mmu: &M,
+    ranges: &[Range<V4KA>],
+    side: PF4K,
+    allocator: &mut A,
+) -> Result<PF4K>
+where
+    M: Mmu,
+    A: FnMut() -> Result<PF4K>,
+{
+    fn make_shared_ranges_level4<M, A>(
+        mmu: &M,
+        ranges: &[Range<V4KA>],
+        allocator: &mut A,
+    ) -> Result<()>
     where
+        M: Mmu,
         A: FnMut() -> Result<PF4K>,
     {
         for range in ranges {
             let start = V512GA::new_round_down(range.start.addr());
             let end = V512GA::new_round_up(range.end.addr());
-            assert!(
-                end.addr() < Level1::SIDE_BASE_ADDRESS,
-                "attempting to map in the recursive region"
-            );
             for addr in start..end {
                 let va = addr.addr();
-                let entry = Level4::pte_ref(va);
+                let entry = Level4::pte_ref(mmu, va);
                 if entry.is_zero() {
                     let pf = allocator()?;
                     entry.assign(PTE::new(pf.pfa(), PTEFlags::WRITE | PTEFlags::PRESENT));
                 }
                 unsafe {
-                    Level4::set_side_entry(va, entry.clone());
+                    Level5::make_side_level(mmu, V4KA::new(va), allocator)?;
+                    Level4::set_side_entry(mmu, va, entry.clone());
                 }
             }
         }
         Ok(())
     }
     unsafe {
-        side_load(side)?;
+        side_load_in(mmu, side)?;
+    }
+    if mmu.paging() == Paging::FiveLevel {
+        make_ranges_level::<Level5, _, _>(mmu, ranges, allocator)?;
     }
-    make_shared_ranges_level4::<_>(ranges, allocator)?;
-    make_ranges_level::<Level3, _>(ranges, allocator)?;
-    make_ranges_level::<Level2, _>(ranges, allocator)?;
-    unload_side()
+    make_shared_ranges_level4::<_, _>(mmu, ranges, allocator)?;
+    make_ranges_level::<Level3, _, _>(mmu, ranges, allocator)?;
+    make_ranges_level::<Level2, _, _>(mmu, ranges, allocator)?;
+    unload_side_in(mmu)
 }
 
 /// Shares some subtree of an address space into a side-loaded
@@ -613,40 +1249,56 @@ where
 pub fn share_range<A>(range: Range<V4KA>, side: PF4K, allocator: &mut A) -> Result<PF4K>
 where
     A: FnMut() -> Result<PF4K>,
+{
+    share_range_in(&HardMmu, range, side, allocator)
+}
+
+/// Shares some subtree of the address space viewed through the
+/// given MMU into a side-loaded space.
+pub fn share_range_in<M, A>(
+    mmu: &M,
+    range: Range<V4KA>,
+    side: PF4K,
+    allocator: &mut A,
+) -> Result<PF4K>
+where
+    M: Mmu,
+    A: FnMut() -> Result<PF4K>,
 {
     const SIZE_512G: usize = <V512GA as VPageAddr>::PageType::SIZE;
     const SIZE_1G: usize = <V1GA as VPageAddr>::PageType::SIZE;
     const SIZE_2M: usize = <V2MA as VPageAddr>::PageType::SIZE;
     const SIZE_4K: usize = <V4KA as VPageAddr>::PageType::SIZE;
 
+    assert_not_in_window(mmu.paging(), &range);
     let mut va = range.start.addr();
     let end = range.end.addr();
-    assert!(end <= Level1::SIDE_BASE_ADDRESS, "attempting to map in the recursive region");
     unsafe {
-        side_load(side)?;
+        side_load_in(mmu, side)?;
     }
     while va != end {
         let len = if end.wrapping_sub(va) >= SIZE_512G && va % SIZE_512G == 0 {
             unsafe {
-                Level4::set_side_entry(va, Level4::pte_ref(va).clone());
+                Level5::make_side_level(mmu, V4KA::new(va), allocator)?;
+                Level4::set_side_entry(mmu, va, Level4::pte_ref(mmu, va).clone());
             }
             SIZE_512G
         } else if end.wrapping_sub(va) >= SIZE_1G && va % SIZE_1G == 0 {
             unsafe {
-                Level4::make_side_level(V4KA::new(va), allocator)?;
-                Level3::set_side_entry(va, Level3::pte_ref(va).clone());
+                Level4::make_side_level(mmu, V4KA::new(va), allocator)?;
+                Level3::set_side_entry(mmu, va, Level3::pte_ref(mmu, va).clone());
             }
             SIZE_1G
         } else if end.wrapping_sub(va) >= SIZE_2M && va % SIZE_2M == 0 {
             unsafe {
-                Level3::make_side_level(V4KA::new(va), allocator)?;
-                Level2::set_side_entry(va, Level2::pte_ref(va).clone());
+                Level3::make_side_level(mmu, V4KA::new(va), allocator)?;
+                Level2::set_side_entry(mmu, va, Level2::pte_ref(mmu, va).clone());
             }
             SIZE_2M
         } else if end.wrapping_sub(va) >= SIZE_4K && va % SIZE_4K == 0 {
             unsafe {
-                Level2::make_side_level(V4KA::new(va), allocator)?;
-                Level1::set_side_entry(va, Level1::pte_ref(va).clone());
+                Level2::make_side_level(mmu, V4KA::new(va), allocator)?;
+                Level1::set_side_entry(mmu, va, Level1::pte_ref(mmu, va).clone());
             }
             SIZE_4K
         } else {
@@ -654,19 +1306,30 @@ where
         };
         va += len;
     }
-    unload_side()
+    unload_side_in(mmu)
 }
 
 /// unmaps a region by clearing its root level PTEs.  Only
 /// useful for segments and tasks.
 pub fn unmap_root_ranges(ranges: &[Range<V4KA>]) {
-    let _tlb = TLBFlushGuard::new();
+    unmap_root_ranges_in(&HardMmu, ranges)
+}
+
+/// unmaps a region by clearing its root level PTEs in the address
+/// space viewed through the given MMU.
+pub fn unmap_root_ranges_in<M: Mmu>(mmu: &M, ranges: &[Range<V4KA>]) {
+    let _tlb = if ranges.iter().any(|range| range_is_shared(mmu.paging(), range)) {
+        TLBFlushGuard::shared(mmu)
+    } else {
+        TLBFlushGuard::new(mmu)
+    };
     for range in ranges {
         let start = V512GA::new_round_down(range.start.addr());
         let end = V512GA::new_round_up(range.end.addr());
         for addr in start..end {
-            let entry = Level4::pte_ref(addr.addr());
-            entry.clear();
+            if has_level4(mmu, addr.addr()) {
+                Level4::clear(mmu, addr.addr());
+            }
         }
     }
 }
@@ -678,12 +1341,27 @@ pub fn unmap_root_ranges(ranges: &[Range<V4KA>]) {
 /// This is not safe.  The side-loaded address space may not
 /// be loaded.
 pub unsafe fn unmap_side_root_ranges(ranges: &[Range<V4KA>]) {
-    let _tlb = TLBFlushGuard::new();
+    unsafe { unmap_side_root_ranges_in(&HardMmu, ranges) }
+}
+
+/// unmaps a side region by clearing its root level PTEs, via the
+/// given MMU.
+///
+/// # Safety
+/// This is not safe.  The side-loaded address space may not
+/// be loaded.
+pub unsafe fn unmap_side_root_ranges_in<M: Mmu>(mmu: &M, ranges: &[Range<V4KA>]) {
+    let _tlb = TLBFlushGuard::new(mmu);
     for range in ranges {
         let start = V512GA::new_round_down(range.start.addr());
         let end = V512GA::new_round_up(range.end.addr());
         for addr in start..end {
-            let entry = unsafe { Level4::side_pte_ref(addr.addr()) };
+            let va = addr.addr();
+            if mmu.paging() == Paging::FiveLevel && unsafe { Level5::side_entry(mmu, va) }.is_none()
+            {
+                continue;
+            }
+            let entry = unsafe { Level4::side_pte_ref(mmu, va) };
             entry.clear();
         }
     }
@@ -696,9 +1374,20 @@ pub unsafe fn unmap_side_root_ranges(ranges: &[Range<V4KA>]) {
 /// This is not safe.  The side-loaded "address space" may not
 /// be an address space at all.
 pub unsafe fn side_load(pf: PF4K) -> Result<()> {
-    let _tlb = TLBFlushGuard::new();
-    let table = unsafe { &mut *PageTable::proto_ptr().with_addr(Level4::BASE_ADDRESS).cast_mut() };
-    table.entries[Level4::SIDE_INDEX] = PTE::new(pf.pfa(), PTEFlags::PRESENT | PTEFlags::WRITE);
+    unsafe { side_load_in(&HardMmu, pf) }
+}
+
+/// Maps an address space in the side-load slot of the address
+/// space viewed through the given MMU.
+///
+/// # Safety
+///
+/// This is not safe.  The side-loaded "address space" may not
+/// be an address space at all.
+pub unsafe fn side_load_in<M: Mmu>(mmu: &M, pf: PF4K) -> Result<()> {
+    let _tlb = TLBFlushGuard::new(mmu);
+    let entry = side_slot(mmu);
+    entry.assign(PTE::new(pf.pfa(), PTEFlags::PRESENT | PTEFlags::WRITE));
     Ok(())
 }
 
@@ -709,19 +1398,30 @@ pub unsafe fn side_load(pf: PF4K) -> Result<()> {
 /// This is not safe.  The side-loaded address space may not
 /// be loaded.
 pub fn unload_side() -> Result<PF4K> {
-    let _tlb = TLBFlushGuard::new();
-    let table = unsafe { &mut *PageTable::proto_ptr().with_addr(Level4::BASE_ADDRESS).cast_mut() };
-    let entry = table.entries[Level4::SIDE_INDEX].pfa();
-    table.entries[Level4::SIDE_INDEX].clear();
-    Ok(PF4K::new(entry))
+    unload_side_in(&HardMmu)
 }
 
-/// Performs a TLB flush on the local CPU.
+/// Unmaps a side-loaded address space from the address space
+/// viewed through the given MMU.
+pub fn unload_side_in<M: Mmu>(mmu: &M) -> Result<PF4K> {
+    let _tlb = TLBFlushGuard::new(mmu);
+    let entry = side_slot(mmu);
+    let pfa = entry.pfa();
+    entry.clear();
+    Ok(PF4K::new(pfa))
+}
+
+// Returns the root table entry for the side-load slot.
+fn side_slot<M: Mmu>(mmu: &M) -> &PTE {
+    let paging = mmu.paging();
+    let root = paging.base_address(paging.levels());
+    mmu.pte(root + paging.side_index() * core::mem::size_of::<PTE>())
+}
+
+/// Performs a TLB flush on the local CPU.  This includes global
+/// translations and those tagged with other PCIDs, if enabled.
 pub fn flush_tlb() {
-    unsafe {
-        let cr3 = x86::controlregs::cr3();
-        x86::controlregs::cr3_write(cr3);
-    }
+    crate::pcid::flush_all();
 }
 
 /// Perform a walk against a side-loaded page table.
@@ -734,26 +1434,30 @@ pub fn flush_tlb() {
 ///
 /// XXX(cross): We should figure out some way to at least improve
 /// safety here.
-unsafe fn side_walk(va: usize) -> Walk {
-    let pt4e = unsafe { Level4::side_entry(va) };
+unsafe fn side_walk<M: Mmu>(mmu: &M, va: usize) -> Walk {
+    if mmu.paging() == Paging::FiveLevel && unsafe { Level5::side_entry(mmu, va) }.is_none() {
+        return Walk(None, None, None, None);
+    }
+
+    let pt4e = unsafe { Level4::side_entry(mmu, va) };
     match pt4e {
         Some(_) => {}
         _ => return Walk(pt4e, None, None, None),
     }
 
-    let pt3e = unsafe { Level3::side_entry(va) };
+    let pt3e = unsafe { Level3::side_entry(mmu, va) };
     match pt3e {
         Some(L3E::Next(_)) => {}
         _ => return Walk(pt4e, pt3e, None, None),
     }
 
-    let pt2e = unsafe { Level2::side_entry(va) };
+    let pt2e = unsafe { Level2::side_entry(mmu, va) };
     match pt2e {
         Some(L2E::Next(_)) => {}
         _ => return Walk(pt4e, pt3e, pt2e, None),
     }
 
-    let pt1e = unsafe { Level1::side_entry(va) };
+    let pt1e = unsafe { Level1::side_entry(mmu, va) };
 
     Walk(pt4e, pt3e, pt2e, pt1e)
 }
@@ -770,7 +1474,18 @@ unsafe fn side_walk(va: usize) -> Walk {
 /// XXX(cross): We should figure out some way to at least improve
 /// safety here.
 pub unsafe fn side_translate(va: usize) -> Option<HPA> {
-    translate_walk(va, unsafe { side_walk(va) })
+    unsafe { side_translate_in(&HardMmu, va) }
+}
+
+/// Translate a given virtual address into a host physical
+/// address against the page table side-loaded into the address
+/// space viewed through the given MMU.
+///
+/// # Safety
+///
+/// As for `side_translate`.
+pub unsafe fn side_translate_in<M: Mmu>(mmu: &M, va: usize) -> Option<HPA> {
+    translate_walk(va, unsafe { side_walk(mmu, va) })
 }
 
 /// Maps the given PF4K to the given virtual address in the currently
@@ -783,27 +1498,48 @@ pub unsafe fn side_translate(va: usize) -> Option<HPA> {
 pub unsafe fn side_map<F>(hpf: PF4K, flags: PTEFlags, va: V4KA, allocator: &mut F) -> Result<()>
 where
     F: FnMut() -> Result<PF4K>,
+{
+    unsafe { side_map_in(&HardMmu, hpf, flags, va, allocator) }
+}
+
+/// Maps the given PF4K to the given virtual address in the address
+/// space side-loaded into the space viewed through the given MMU.
+///
+/// # Safety
+///
+/// As for `side_map`.
+pub unsafe fn side_map_in<M, F>(
+    mmu: &M,
+    hpf: PF4K,
+    flags: PTEFlags,
+    va: V4KA,
+    allocator: &mut F,
+) -> Result<()>
+where
+    M: Mmu,
+    F: FnMut() -> Result<PF4K>,
 {
     let va = va.addr();
-    let w = unsafe { side_walk(va) };
+    let w = unsafe { side_walk(mmu, va) };
     if let Walk(None, _, _, _) = w {
         unsafe {
-            Level4::set_side_entry(va, alloc_inner(allocator)?);
+            Level5::make_side_level(mmu, V4KA::new(va), allocator)?;
+            Level4::set_side_entry(mmu, va, alloc_inner(allocator)?);
         }
     }
     if let Walk(_, None, _, _) = w {
         unsafe {
-            Level3::set_side_entry(va, alloc_inner(allocator)?);
+            Level3::set_side_entry(mmu, va, alloc_inner(allocator)?);
         }
     }
     if let Walk(_, _, None, _) = w {
         unsafe {
-            Level2::set_side_entry(va, alloc_inner(allocator)?);
+            Level2::set_side_entry(mmu, va, alloc_inner(allocator)?);
         }
     }
     if let Walk(_, _, _, None) = w {
         unsafe {
-            Level1::set_side_entry(va, PTE::new(hpf.pfa(), flags));
+            Level1::set_side_entry(mmu, va, PTE::new(hpf.pfa(), flags));
         }
         Ok(())
     } else {
@@ -814,67 +1550,263 @@ where
 /// Returns the host physical address of the address space root for
 /// the currently loaded address space.
 pub fn address_space_root() -> HPA {
-    let table = unsafe { &*PageTable::proto_ptr().with_addr(Level4::BASE_ADDRESS) };
-    table.root_addr()
+    address_space_root_in(&HardMmu)
+}
+
+/// Returns the host physical address of the root of the address
+/// space viewed through the given MMU.  The recursive entry means
+/// that the root table is mapped at the base of the root level's
+/// window.
+pub fn address_space_root_in<M: Mmu>(mmu: &M) -> HPA {
+    let paging = mmu.paging();
+    translate_in(mmu, paging.base_address(paging.levels())).expect("mapped object is mapped")
+}
+
+/// The number of pages above which we flush the entire TLB
+/// rather than invalidating pages individually.
+const INVLPG_THRESHOLD: usize = 32;
+
+/// Accumulates virtual addresses whose cached translations must
+/// be invalidated, and invalidates them when flushed or dropped:
+/// individually, if there are few enough, or else by flushing the
+/// entire TLB.  If any of the addresses are shared, the batch is
+/// also shot down on other CPUs.
+struct TLBInvalidator<'a, M: Mmu> {
+    mmu: &'a M,
+    pages: [usize; INVLPG_THRESHOLD],
+    len: usize,
+    overflowed: bool,
+    shared: bool,
 }
 
-struct TLBFlushGuard {}
-impl TLBFlushGuard {
-    pub fn new() -> TLBFlushGuard {
-        TLBFlushGuard {}
+impl<'a, M: Mmu> TLBInvalidator<'a, M> {
+    fn new(mmu: &'a M) -> TLBInvalidator<'a, M> {
+        TLBInvalidator {
+            mmu,
+            pages: [0; INVLPG_THRESHOLD],
+            len: 0,
+            overflowed: false,
+            shared: false,
+        }
+    }
+
+    // Marks the batch as shared if the given address is.
+    fn share(&mut self, va: usize) {
+        self.shared |= is_shared(self.mmu.paging(), va);
+    }
+
+    fn add(&mut self, va: usize) {
+        self.share(va);
+        if self.len < INVLPG_THRESHOLD {
+            self.pages[self.len] = va;
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.overflowed {
+            self.mmu.flush_tlb();
+        } else {
+            for &va in &self.pages[..self.len] {
+                self.mmu.invalidate(va);
+            }
+        }
+        if self.shared {
+            let pages = &self.pages[..self.len];
+            self.mmu.shoot_down(if self.overflowed { Request::All } else { Request::Pages(pages) });
+        }
+        self.len = 0;
+        self.overflowed = false;
+        self.shared = false;
     }
 }
-impl Drop for TLBFlushGuard {
+
+impl<M: Mmu> Drop for TLBInvalidator<'_, M> {
     fn drop(&mut self) {
-        flush_tlb();
+        self.flush();
+    }
+}
+
+/// The number of freed paging structures we hold before
+/// invalidating the TLB and passing them to the deallocator.
+const RECLAIM_BATCH: usize = 16;
+
+/// Collects pages to invalidate and paging structures to free
+/// when unmapping, ensuring that no table is freed while some
+/// translation through it may remain cached.
+struct Reclaimer<'a, M: Mmu, D: FnMut(PF4K)> {
+    tlb: TLBInvalidator<'a, M>,
+    tables: [Option<PF4K>; RECLAIM_BATCH],
+    len: usize,
+    deallocator: &'a mut D,
+}
+
+impl<'a, M: Mmu, D: FnMut(PF4K)> Reclaimer<'a, M, D> {
+    fn new(mmu: &'a M, deallocator: &'a mut D) -> Reclaimer<'a, M, D> {
+        let tlb = TLBInvalidator::new(mmu);
+        Reclaimer { tlb, tables: [None; RECLAIM_BATCH], len: 0, deallocator }
+    }
+
+    fn invalidate(&mut self, va: usize) {
+        self.tlb.add(va);
+    }
+
+    // Frees a table that has been unlinked from its parent, which
+    // mapped the given address and was visible at the given address
+    // in the recursive window.  Other CPUs sharing the mapping may
+    // also see the table through their own windows.
+    fn free(&mut self, table: PF4K, va: usize, window: usize) {
+        self.tlb.add(window);
+        self.tlb.share(va);
+        if self.len == RECLAIM_BATCH {
+            self.release();
+        }
+        self.tables[self.len] = Some(table);
+        self.len += 1;
+    }
+
+    fn release(&mut self) {
+        self.tlb.flush();
+        for table in self.tables[..self.len].iter_mut() {
+            (self.deallocator)(table.take().expect("reclaimed table"));
+        }
+        self.len = 0;
     }
 }
 
+impl<M: Mmu, D: FnMut(PF4K)> Drop for Reclaimer<'_, M, D> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+struct TLBFlushGuard<'a, M: Mmu> {
+    mmu: &'a M,
+    shared: bool,
+}
+impl<'a, M: Mmu> TLBFlushGuard<'a, M> {
+    pub fn new(mmu: &'a M) -> TLBFlushGuard<'a, M> {
+        TLBFlushGuard { mmu, shared: false }
+    }
+
+    /// Returns a guard that also flushes the TLBs of other CPUs.
+    pub fn shared(mmu: &'a M) -> TLBFlushGuard<'a, M> {
+        TLBFlushGuard { mmu, shared: true }
+    }
+}
+impl<M: Mmu> Drop for TLBFlushGuard<'_, M> {
+    fn drop(&mut self) {
+        self.mmu.flush_tlb();
+        if self.shared {
+            self.mmu.shoot_down(Request::All);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod soft;
+
 #[cfg(test)]
 mod tests {
-    use super::Level;
+    use super::{Level, Paging};
+
+    const FOUR: Paging = Paging::FourLevel;
+    const FIVE: Paging = Paging::FiveLevel;
+
+    #[test]
+    fn level5_base() {
+        use super::Level5;
+        let base = !0usize << 57;
+        let base = base | FIVE.self_index() << 48;
+        let base = base | FIVE.self_index() << 39;
+        let base = base | FIVE.self_index() << 30;
+        let base = base | FIVE.self_index() << 21;
+        let side = base | FIVE.side_index() << 12;
+        let base = base | FIVE.self_index() << 12;
+        assert_eq!(side, Level5::side_base_address(FIVE));
+        assert_eq!(base, Level5::base_address(FIVE));
+    }
+
+    #[test]
+    fn level5_index() {
+        use super::Level5;
+        assert_eq!(Level5::index(FIVE, 0x0000_0000_0000_0000), 0);
+        assert_eq!(Level5::index(FIVE, 0x0000_8000_0000_0000), 0);
+        assert_eq!(Level5::index(FIVE, 0x0001_0000_0000_0000), 1);
+        assert_eq!(Level5::index(FIVE, 0x00FF_FFFF_FFFF_FFFF), 255);
+        assert_eq!(Level5::index(FIVE, 0xFF00_0000_0000_0000), 256);
+        assert_eq!(Level5::index(FIVE, 0xFFFF_8000_0000_0000), 511);
+        assert_eq!(Level5::index(FIVE, Level5::base_address(FIVE)), FIVE.self_index());
+        assert_eq!(Level5::index(FIVE, Level5::side_base_address(FIVE)), FIVE.self_index());
+    }
 
     #[test]
     fn level4_base() {
         use super::Level4;
         let base = !0usize << 48;
-        let base = base | Level4::SELF_INDEX << 39;
-        let base = base | Level4::SELF_INDEX << 30;
-        let base = base | Level4::SELF_INDEX << 21;
-        let side = base | Level4::SIDE_INDEX << 12;
-        let base = base | Level4::SELF_INDEX << 12;
-        assert_eq!(side, Level4::SIDE_BASE_ADDRESS);
-        assert_eq!(base, Level4::BASE_ADDRESS);
+        let base = base | FOUR.self_index() << 39;
+        let base = base | FOUR.self_index() << 30;
+        let base = base | FOUR.self_index() << 21;
+        let side = base | FOUR.side_index() << 12;
+        let base = base | FOUR.self_index() << 12;
+        assert_eq!(side, Level4::side_base_address(FOUR));
+        assert_eq!(base, Level4::base_address(FOUR));
+
+        let base = !0usize << 57;
+        let base = base | FIVE.self_index() << 48;
+        let base = base | FIVE.self_index() << 39;
+        let base = base | FIVE.self_index() << 30;
+        let side = base | FIVE.side_index() << 21;
+        let base = base | FIVE.self_index() << 21;
+        assert_eq!(side, Level4::side_base_address(FIVE));
+        assert_eq!(base, Level4::base_address(FIVE));
     }
 
     #[test]
     fn level4_index() {
         use super::Level4;
-        assert_eq!(Level4::index(0x0000_0000_0000_0000), 0);
-        assert_eq!(Level4::index(0x0000_0000_0001_0000), 0);
-        assert_eq!(Level4::index(0x0000_0000_0020_0000), 0);
-        assert_eq!(Level4::index(0x0000_0000_4000_0000), 0);
-        assert_eq!(Level4::index(0x0000_0080_0000_0000), 1);
-        assert_eq!(Level4::index(0x0000_7FFF_FFFF_FFFF), 255);
-        assert_eq!(Level4::index(0xFFFF_8000_0000_0000), 256);
-        assert_eq!(Level4::index(0xFFFF_8000_0000_1000), 256);
-        assert_eq!(Level4::index(0xFFFF_8000_0020_0000), 256);
-        assert_eq!(Level4::index(0xFFFF_8000_4000_0000), 256);
-        assert_eq!(Level4::index(0xFFFF_8080_0000_0000), 257);
-        assert_eq!(Level4::index(Level4::BASE_ADDRESS), 511);
-        assert_eq!(Level4::index(Level4::SIDE_BASE_ADDRESS), 511);
+        assert_eq!(Level4::index(FOUR, 0x0000_0000_0000_0000), 0);
+        assert_eq!(Level4::index(FOUR, 0x0000_0000_0001_0000), 0);
+        assert_eq!(Level4::index(FOUR, 0x0000_0000_0020_0000), 0);
+        assert_eq!(Level4::index(FOUR, 0x0000_0000_4000_0000), 0);
+        assert_eq!(Level4::index(FOUR, 0x0000_0080_0000_0000), 1);
+        assert_eq!(Level4::index(FOUR, 0x0000_7FFF_FFFF_FFFF), 255);
+        assert_eq!(Level4::index(FOUR, 0xFFFF_8000_0000_0000), 256);
+        assert_eq!(Level4::index(FOUR, 0xFFFF_8000_0000_1000), 256);
+        assert_eq!(Level4::index(FOUR, 0xFFFF_8000_0020_0000), 256);
+        assert_eq!(Level4::index(FOUR, 0xFFFF_8000_4000_0000), 256);
+        assert_eq!(Level4::index(FOUR, 0xFFFF_8080_0000_0000), 257);
+        assert_eq!(Level4::index(FOUR, Level4::base_address(FOUR)), 511);
+        assert_eq!(Level4::index(FOUR, Level4::side_base_address(FOUR)), 511);
+        // With five levels, the index spans both the PML5 and PML4.
+        let this = FIVE.self_index();
+        assert_eq!(Level4::index(FIVE, 0x0000_8000_0000_0000), 256);
+        assert_eq!(Level4::index(FIVE, 0x0001_0080_0000_0000), 512 + 1);
+        assert_eq!(Level4::index(FIVE, 0xFF00_7F80_0000_0000), 256 * 512 + 255);
+        assert_eq!(Level4::index(FIVE, 0xFFFF_8000_0000_0000), 511 * 512 + 256);
+        assert_eq!(Level4::index(FIVE, Level4::base_address(FIVE)), this * 512 + this);
+        assert_eq!(Level4::index(FIVE, Level4::side_base_address(FIVE)), this * 512 + this);
     }
 
     #[test]
     fn level3_base() {
-        use super::Level4;
         let base = !0usize << 48;
-        let base = base | Level4::SELF_INDEX << 39;
-        let base = base | Level4::SELF_INDEX << 30;
-        let side = base | Level4::SIDE_INDEX << 21;
-        let base = base | Level4::SELF_INDEX << 21;
-        assert_eq!(side, super::Level3::SIDE_BASE_ADDRESS);
-        assert_eq!(base, super::Level3::BASE_ADDRESS);
+        let base = base | FOUR.self_index() << 39;
+        let base = base | FOUR.self_index() << 30;
+        let side = base | FOUR.side_index() << 21;
+        let base = base | FOUR.self_index() << 21;
+        assert_eq!(side, super::Level3::side_base_address(FOUR));
+        assert_eq!(base, super::Level3::base_address(FOUR));
+
+        let base = !0usize << 57;
+        let base = base | FIVE.self_index() << 48;
+        let base = base | FIVE.self_index() << 39;
+        let side = base | FIVE.side_index() << 30;
+        let base = base | FIVE.self_index() << 30;
+        assert_eq!(side, super::Level3::side_base_address(FIVE));
+        assert_eq!(base, super::Level3::base_address(FIVE));
     }
 
     #[test]
@@ -883,30 +1815,36 @@ mod tests {
         const INDEX_BITS: usize = 18;
         const UPPER: usize = 1 << INDEX_BITS;
         const HALFWAY: usize = UPPER / 2;
-        assert_eq!(Level3::index(0x0000_0000_0000_0000), 0);
-        assert_eq!(Level3::index(0x0000_0000_0000_1000), 0);
-        assert_eq!(Level3::index(0x0000_0000_0020_0000), 0);
-        assert_eq!(Level3::index(0x0000_0000_4000_0000), 1);
-        assert_eq!(Level3::index(0x0000_0080_0000_0000), 512);
-        assert_eq!(Level3::index(0x0000_7FFF_FFFF_FFFF), HALFWAY - 1);
-        assert_eq!(Level3::index(0xFFFF_8000_0000_0000), HALFWAY);
-        assert_eq!(Level3::index(0xFFFF_8000_0000_1000), HALFWAY);
-        assert_eq!(Level3::index(0xFFFF_8000_0020_0000), HALFWAY);
-        assert_eq!(Level3::index(0xFFFF_8000_4000_0000), HALFWAY + 1);
-        assert_eq!(Level3::index(0xFFFF_8080_0000_0000), HALFWAY + 512);
-        assert_eq!(Level3::index(0xFFFF_FFFF_FFFF_F000), UPPER - 1);
-        assert_eq!(Level3::index(0xFFFF_FFFF_FFFF_E000), UPPER - 1);
+        assert_eq!(Level3::index(FOUR, 0x0000_0000_0000_0000), 0);
+        assert_eq!(Level3::index(FOUR, 0x0000_0000_0000_1000), 0);
+        assert_eq!(Level3::index(FOUR, 0x0000_0000_0020_0000), 0);
+        assert_eq!(Level3::index(FOUR, 0x0000_0000_4000_0000), 1);
+        assert_eq!(Level3::index(FOUR, 0x0000_0080_0000_0000), 512);
+        assert_eq!(Level3::index(FOUR, 0x0000_7FFF_FFFF_FFFF), HALFWAY - 1);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_8000_0000_0000), HALFWAY);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_8000_0000_1000), HALFWAY);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_8000_0020_0000), HALFWAY);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_8000_4000_0000), HALFWAY + 1);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_8080_0000_0000), HALFWAY + 512);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_FFFF_FFFF_F000), UPPER - 1);
+        assert_eq!(Level3::index(FOUR, 0xFFFF_FFFF_FFFF_E000), UPPER - 1);
     }
 
     #[test]
     fn level2_base() {
-        use super::Level4;
         let base = !0usize << 48;
-        let base = base | Level4::SELF_INDEX << 39;
-        let side = base | Level4::SIDE_INDEX << 30;
-        let base = base | Level4::SELF_INDEX << 30;
-        assert_eq!(side, super::Level2::SIDE_BASE_ADDRESS);
-        assert_eq!(base, super::Level2::BASE_ADDRESS);
+        let base = base | FOUR.self_index() << 39;
+        let side = base | FOUR.side_index() << 30;
+        let base = base | FOUR.self_index() << 30;
+        assert_eq!(side, super::Level2::side_base_address(FOUR));
+        assert_eq!(base, super::Level2::base_address(FOUR));
+
+        let base = !0usize << 57;
+        let base = base | FIVE.self_index() << 48;
+        let side = base | FIVE.side_index() << 39;
+        let base = base | FIVE.self_index() << 39;
+        assert_eq!(side, super::Level2::side_base_address(FIVE));
+        assert_eq!(base, super::Level2::base_address(FIVE));
     }
 
     #[test]
@@ -915,29 +1853,62 @@ mod tests {
         const INDEX_BITS: usize = 27;
         const UPPER: usize = 1 << INDEX_BITS;
         const HALFWAY: usize = UPPER / 2;
-        assert_eq!(Level2::index(0x0000_0000_0000_0000), 0);
-        assert_eq!(Level2::index(0x0000_0000_0000_1000), 0);
-        assert_eq!(Level2::index(0x0000_0000_0020_0000), 1);
-        assert_eq!(Level2::index(0x0000_0000_4000_0000), 512);
-        assert_eq!(Level2::index(0x0000_0080_0000_0000), 512 * 512);
-        assert_eq!(Level2::index(0x0000_7FFF_FFFF_FFFF), HALFWAY - 1);
-        assert_eq!(Level2::index(0xFFFF_8000_0000_0000), HALFWAY);
-        assert_eq!(Level2::index(0xFFFF_8000_0000_1000), HALFWAY);
-        assert_eq!(Level2::index(0xFFFF_8000_0020_0000), HALFWAY + 1);
-        assert_eq!(Level2::index(0xFFFF_8000_4000_0000), HALFWAY + 512);
-        assert_eq!(Level2::index(0xFFFF_8080_0000_0000), HALFWAY + 512 * 512);
-        assert_eq!(Level2::index(0xFFFF_FFFF_FFFF_F000), UPPER - 1);
-        assert_eq!(Level2::index(0xFFFF_FFFF_FFFF_E000), UPPER - 1);
+        assert_eq!(Level2::index(FOUR, 0x0000_0000_0000_0000), 0);
+        assert_eq!(Level2::index(FOUR, 0x0000_0000_0000_1000), 0);
+        assert_eq!(Level2::index(FOUR, 0x0000_0000_0020_0000), 1);
+        assert_eq!(Level2::index(FOUR, 0x0000_0000_4000_0000), 512);
+        assert_eq!(Level2::index(FOUR, 0x0000_0080_0000_0000), 512 * 512);
+        assert_eq!(Level2::index(FOUR, 0x0000_7FFF_FFFF_FFFF), HALFWAY - 1);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_8000_0000_0000), HALFWAY);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_8000_0000_1000), HALFWAY);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_8000_0020_0000), HALFWAY + 1);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_8000_4000_0000), HALFWAY + 512);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_8080_0000_0000), HALFWAY + 512 * 512);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_FFFF_FFFF_F000), UPPER - 1);
+        assert_eq!(Level2::index(FOUR, 0xFFFF_FFFF_FFFF_E000), UPPER - 1);
     }
 
     #[test]
     fn level1_base() {
-        use super::Level4;
         let base = !0usize << 48;
-        let side = base | Level4::SIDE_INDEX << 39;
-        let base = base | Level4::SELF_INDEX << 39;
-        assert_eq!(side, super::Level1::SIDE_BASE_ADDRESS);
-        assert_eq!(base, super::Level1::BASE_ADDRESS);
+        let side = base | FOUR.side_index() << 39;
+        let base = base | FOUR.self_index() << 39;
+        assert_eq!(side, super::Level1::side_base_address(FOUR));
+        assert_eq!(base, super::Level1::base_address(FOUR));
+
+        let base = !0usize << 57;
+        let side = base | FIVE.side_index() << 48;
+        let base = base | FIVE.self_index() << 48;
+        assert_eq!(side, super::Level1::side_base_address(FIVE));
+        assert_eq!(base, super::Level1::base_address(FIVE));
+    }
+
+    #[test]
+    fn windows() {
+        use super::{Level1, in_paging_window, is_shared};
+        // The four-level windows are the slots of HDP 0003.
+        assert_eq!(Level1::base_address(FOUR), 0xFFFF_FF80_0000_0000);
+        assert_eq!(Level1::side_base_address(FOUR), 0xFFFF_FD80_0000_0000);
+        assert_eq!(FOUR.base_address(4), 0xFFFF_FFFF_FFFF_F000);
+        // The five-level windows lie just below the PML4 that
+        // holds that layout.
+        assert_eq!(Level1::base_address(FIVE), 0xFFFE_0000_0000_0000);
+        assert_eq!(Level1::side_base_address(FIVE), 0xFFFD_0000_0000_0000);
+        assert_eq!(FIVE.base_address(5), 0xFFFE_FF7F_BFDF_E000);
+        for paging in [FOUR, FIVE] {
+            let recursive = Level1::base_address(paging);
+            let side = Level1::side_base_address(paging);
+            assert!(in_paging_window(paging, recursive));
+            assert!(in_paging_window(paging, side));
+            assert!(in_paging_window(paging, paging.base_address(paging.levels())));
+            assert!(!in_paging_window(paging, 0xFFFF_FE00_0000_0000));
+            assert!(!in_paging_window(paging, 0x1000));
+            assert!(is_shared(paging, 0xFFFF_FB80_0000_0000));
+            assert!(!is_shared(paging, recursive));
+        }
+        assert!(in_paging_window(FOUR, 0xFFFF_FFFF_FFFF_FFF8));
+        assert!(!in_paging_window(FIVE, 0xFFFF_FFFF_FFFF_FFF8));
+        assert!(!in_paging_window(FIVE, 0xFFFD_0000_0000_0000 - 8));
     }
 
     #[test]
@@ -946,19 +1917,395 @@ mod tests {
         const INDEX_BITS: usize = 36;
         const UPPER: usize = 1 << INDEX_BITS;
         const HALFWAY: usize = UPPER / 2;
-        assert_eq!(Level1::index(0x0000_0000_0000_0000), 0);
-        assert_eq!(Level1::index(0x0000_0000_0000_1000), 1);
-        assert_eq!(Level1::index(0x0000_0000_0020_0000), 512);
-        assert_eq!(Level1::index(0x0000_0000_4000_0000), 512 * 512);
-        assert_eq!(Level1::index(0x0000_0080_0000_0000), 512 * 512 * 512);
-        assert_eq!(Level1::index(0x0000_7FFF_FFFF_FFFF), HALFWAY - 1);
-        assert_eq!(Level1::index(0xFFFF_8000_0000_0000), HALFWAY);
-        assert_eq!(Level1::index(0xFFFF_8000_0000_1000), HALFWAY + 1);
-        assert_eq!(Level1::index(0xFFFF_8000_0020_0000), HALFWAY + 512);
-        assert_eq!(Level1::index(0xFFFF_8000_4000_0000), HALFWAY + 512 * 512);
-        assert_eq!(Level1::index(0xFFFF_8080_0000_0000), HALFWAY + 512 * 512 * 512);
-        assert_eq!(Level1::index(0xFFFF_FFFF_FFFF_F000), UPPER - 1);
-        assert_eq!(Level1::index(0xFFFF_FFFF_FFFF_E000), UPPER - 2);
+        assert_eq!(Level1::index(FOUR, 0x0000_0000_0000_0000), 0);
+        assert_eq!(Level1::index(FOUR, 0x0000_0000_0000_1000), 1);
+        assert_eq!(Level1::index(FOUR, 0x0000_0000_0020_0000), 512);
+        assert_eq!(Level1::index(FOUR, 0x0000_0000_4000_0000), 512 * 512);
+        assert_eq!(Level1::index(FOUR, 0x0000_0080_0000_0000), 512 * 512 * 512);
+        assert_eq!(Level1::index(FOUR, 0x0000_7FFF_FFFF_FFFF), HALFWAY - 1);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_8000_0000_0000), HALFWAY);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_8000_0000_1000), HALFWAY + 1);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_8000_0020_0000), HALFWAY + 512);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_8000_4000_0000), HALFWAY + 512 * 512);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_8080_0000_0000), HALFWAY + 512 * 512 * 512);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_FFFF_FFFF_F000), UPPER - 1);
+        assert_eq!(Level1::index(FOUR, 0xFFFF_FFFF_FFFF_E000), UPPER - 2);
+    }
+
+    mod soft {
+        use super::super::soft::SoftMmu;
+        use super::super::*;
+        use crate::{PF4K, V4KA, VPageAddr};
+        use std::collections::BTreeMap;
+
+        fn pf(pa: u64) -> PF4K {
+            PF4K::new(HPA::new(pa))
+        }
+
+        fn pa(hpa: Option<HPA>) -> Option<u64> {
+            hpa.map(HPA::addr)
+        }
+
+        #[test]
+        fn map_translate() {
+            let mmu = SoftMmu::new(16);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let va = V4KA::new(0xFFFF_8000_0020_3000);
+            map_in(&mmu, pf(0xabc000), flags, va, &mut || mmu.alloc()).unwrap();
+            assert_eq!(mmu.allocated(), 4);
+            assert_eq!(pa(translate_in(&mmu, va.addr())), Some(0xabc000));
+            assert_eq!(pa(translate_in(&mmu, va.addr() + 0x123)), Some(0xabc123));
+            assert_eq!(pa(translate_in(&mmu, va.addr() + 0x1000)), None);
+            assert_eq!(pa(translate_in(&mmu, 0x1000)), None);
+            assert!(map_in(&mmu, pf(0xdef000), flags, va, &mut || mmu.alloc()).is_err());
+        }
+
+        #[test]
+        fn five_level() {
+            let mmu = SoftMmu::with_paging(32, Paging::FiveLevel);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            // An address that only exists with 57-bit paging, and
+            // one in the PML4 holding the four-level layout.
+            let low = V4KA::new(0x00FF_0000_0000_0000);
+            let high = V4KA::new(0xFFFF_8000_0020_3000);
+            map_in(&mmu, pf(0xabc000), flags, low, &mut || mmu.alloc()).unwrap();
+            assert_eq!(mmu.allocated(), 5);
+            map_in(&mmu, pf(0xdef000), flags, high, &mut || mmu.alloc()).unwrap();
+            assert_eq!(mmu.allocated(), 9);
+            assert_eq!(pa(translate_in(&mmu, low.addr() + 0x123)), Some(0xabc123));
+            assert_eq!(pa(translate_in(&mmu, high.addr())), Some(0xdef000));
+            assert_eq!(pa(translate_in(&mmu, 0xFFFF_0000_0000_0000)), None);
+
+            let side = mmu.new_space().unwrap();
+            unsafe {
+                side_load_in(&mmu, side).unwrap();
+                side_map_in(&mmu, pf(0x7000), flags, high, &mut || mmu.alloc()).unwrap();
+                assert_eq!(pa(side_translate_in(&mmu, high.addr())), Some(0x7000));
+            }
+            assert_eq!(unload_side_in(&mmu).unwrap().pfa().addr(), side.pfa().addr());
+
+            // Unmapping frees the PML4 along with the tables below.
+            let mut freed = 0;
+            let end = V4KA::new(0x00FF_0080_0000_0000);
+            unmap_range_in(&mmu, low..end, &mut |_| freed += 1);
+            assert_eq!(freed, 4);
+            assert!(Level5::entry(&mmu, low.addr()).is_none());
+            assert_eq!(pa(translate_in(&mmu, high.addr())), Some(0xdef000));
+        }
+
+        #[test]
+        fn map_leaf_needs_interior() {
+            let mmu = SoftMmu::new(16);
+            let va = V4KA::new(0x20_0000);
+            assert!(map_leaf_in(&mmu, pf(0x5000), va, true, false, false).is_err());
+            let range = va..V4KA::new(0x40_0000);
+            make_ranges_in(&mmu, core::slice::from_ref(&range), &mut || mmu.alloc()).unwrap();
+            map_leaf_in(&mmu, pf(0x5000), va, true, false, false).unwrap();
+            assert_eq!(pa(translate_in(&mmu, va.addr())), Some(0x5000));
+            let flags = Level1::pte_ref(&mmu, va.addr()).flags();
+            assert_eq!(flags.bits(), (PTEFlags::PRESENT | PTEFlags::NX).bits());
+        }
+
+        #[test]
+        fn unmap_clears_leaf() {
+            let mmu = SoftMmu::new(16);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let a = V4KA::new(0x1000);
+            let b = V4KA::new(0x2000);
+            map_in(&mmu, pf(0xa000), flags, a, &mut || mmu.alloc()).unwrap();
+            map_in(&mmu, pf(0xb000), flags, b, &mut || mmu.alloc()).unwrap();
+            unmap_in(&mmu, a);
+            assert_eq!(pa(translate_in(&mmu, a.addr())), None);
+            assert_eq!(pa(translate_in(&mmu, b.addr())), Some(0xb000));
+            assert!(Level4::entry(&mmu, a.addr()).is_some());
+        }
+
+        #[test]
+        fn side_load_and_map() {
+            let mmu = SoftMmu::new(32);
+            let side = mmu.new_space().unwrap();
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let va = V4KA::new(0xFFFF_F000_0000_0000);
+            unsafe {
+                side_load_in(&mmu, side).unwrap();
+                side_map_in(&mmu, pf(0x7000), flags, va, &mut || mmu.alloc()).unwrap();
+                assert_eq!(pa(side_translate_in(&mmu, va.addr())), Some(0x7000));
+            }
+            assert_eq!(pa(translate_in(&mmu, va.addr())), None);
+            assert_eq!(unload_side_in(&mmu).unwrap().pfa().addr(), side.pfa().addr());
+            mmu.load(side);
+            assert_eq!(pa(translate_in(&mmu, va.addr())), Some(0x7000));
+            assert_eq!(address_space_root_in(&mmu).addr(), side.pfa().addr());
+        }
+
+        #[test]
+        fn shared_ranges() {
+            let mmu = SoftMmu::new(32);
+            let side = mmu.new_space().unwrap();
+            let start = V4KA::new(0xFFFF_FB40_0000_0000);
+            let end = V4KA::new(0xFFFF_FB40_0000_4000);
+            let range = start..end;
+            let ranges = core::slice::from_ref(&range);
+            let root = make_shared_ranges_in(&mmu, ranges, side, &mut || mmu.alloc()).unwrap();
+            assert_eq!(root.pfa().addr(), side.pfa().addr());
+            for (k, va) in (start..end).enumerate() {
+                let pfa = 0x10_0000_0000 + (k as u64) * 0x1000;
+                map_leaf_in(&mmu, pf(pfa), va, true, true, false).unwrap();
+            }
+            let task = mmu.load(side);
+            for (k, va) in (start..end).enumerate() {
+                let pfa = 0x10_0000_0000 + (k as u64) * 0x1000;
+                assert_eq!(pa(translate_in(&mmu, va.addr())), Some(pfa));
+            }
+            mmu.load(task);
+            unmap_root_ranges_in(&mmu, ranges);
+            assert_eq!(pa(translate_in(&mmu, start.addr())), None);
+            mmu.load(side);
+            assert_eq!(pa(translate_in(&mmu, start.addr())), Some(0x10_0000_0000));
+        }
+
+        #[test]
+        fn share_range_mixed_sizes() {
+            let mmu = SoftMmu::new(64);
+            let side = mmu.new_space().unwrap();
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            // Starts 4KiB below a 2MiB boundary and ends 4KiB
+            // above the next, so that the share uses every size
+            // up to 2MiB.
+            let start = V4KA::new(0x1F_F000);
+            let end = V4KA::new(0x40_1000);
+            let pages = [start, V4KA::new(0x20_0000), V4KA::new(0x3F_F000), V4KA::new(0x40_0000)];
+            for (k, &va) in pages.iter().enumerate() {
+                let pfa = 0x100_0000 + (k as u64) * 0x1000;
+                map_in(&mmu, pf(pfa), flags, va, &mut || mmu.alloc()).unwrap();
+            }
+            share_range_in(&mmu, start..end, side, &mut || mmu.alloc()).unwrap();
+            mmu.load(side);
+            for (k, &va) in pages.iter().enumerate() {
+                let pfa = 0x100_0000 + (k as u64) * 0x1000;
+                assert_eq!(pa(translate_in(&mmu, va.addr())), Some(pfa));
+            }
+            assert_eq!(pa(translate_in(&mmu, end.addr())), None);
+        }
+
+        #[test]
+        fn protect_range() {
+            let mmu = SoftMmu::new(16);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE | PTEFlags::GLOBAL;
+            let start = V4KA::new(0x1F_E000);
+            let end = V4KA::new(0x20_2000);
+            for (k, va) in (start..end).enumerate() {
+                map_in(&mmu, pf(0x10_0000_0000 + k as u64 * 0x1000), flags, va, &mut || {
+                    mmu.alloc()
+                })
+                .unwrap();
+            }
+            Level1::pte_ref(&mmu, start.addr())
+                .0
+                .fetch_or(PTEFlags::DIRTY.bits(), Ordering::Relaxed);
+            protect_in(&mmu, start..end, true, false, false).unwrap();
+            assert_eq!(mmu.invalidations(), 4);
+            for va in start..end {
+                let flags = Level1::pte_ref(&mmu, va.addr()).flags();
+                assert!(flags.contains(PTEFlags::PRESENT | PTEFlags::NX | PTEFlags::GLOBAL));
+                assert!(!flags.contains(PTEFlags::WRITE));
+            }
+            assert!(Level1::pte_ref(&mmu, start.addr()).flags().contains(PTEFlags::DIRTY));
+
+            // Removing read access leaves the frame in place, so it
+            // may be restored.
+            protect_in(&mmu, start..end, false, false, false).unwrap();
+            assert_eq!(pa(translate_in(&mmu, start.addr())), None);
+            protect_in(&mmu, start..end, true, true, true).unwrap();
+            assert_eq!(pa(translate_in(&mmu, start.addr())), Some(0x10_0000_0000));
+
+            // Nothing changes if any page in the range is unmapped.
+            let r = protect_in(&mmu, start..V4KA::new(0x20_3000), true, false, true);
+            assert_eq!(r, Err("not mapped"));
+            assert!(Level1::pte_ref(&mmu, start.addr()).flags().contains(PTEFlags::WRITE));
+        }
+
+        #[test]
+        fn protect_flushes_above_threshold() {
+            let mmu = SoftMmu::new(16);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let start = V4KA::new(0x40_0000);
+            let end = V4KA::new(0x40_0000 + (INVLPG_THRESHOLD + 1) * 4096);
+            for va in start..end {
+                map_in(&mmu, pf(0x5000), flags, va, &mut || mmu.alloc()).unwrap();
+            }
+            protect_in(&mmu, start..end, true, false, false).unwrap();
+            assert_eq!(mmu.invalidations(), 0);
+            assert_eq!(mmu.flushes(), 1);
+        }
+
+        #[test]
+        fn shared_changes_shoot_down() {
+            let mmu = SoftMmu::new(16);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let private = V4KA::new(0x40_0000)..V4KA::new(0x40_2000);
+            let shared = V4KA::new(0xFFFF_FB40_0000_0000)..V4KA::new(0xFFFF_FB40_0000_2000);
+            for va in private.clone().chain(shared.clone()) {
+                map_in(&mmu, pf(0x5000), flags, va, &mut || mmu.alloc()).unwrap();
+            }
+            protect_in(&mmu, private.clone(), true, false, false).unwrap();
+            unmap_in(&mmu, private.start);
+            assert_eq!(mmu.shootdowns(), 0);
+            protect_in(&mmu, shared.clone(), true, false, false).unwrap();
+            assert_eq!(mmu.shootdowns(), 1);
+            unmap_in(&mmu, shared.start);
+            assert_eq!(mmu.shootdowns(), 2);
+            unmap_root_ranges_in(&mmu, core::slice::from_ref(&private));
+            assert_eq!(mmu.shootdowns(), 2);
+            unmap_root_ranges_in(&mmu, core::slice::from_ref(&shared));
+            assert_eq!(mmu.shootdowns(), 3);
+        }
+
+        #[test]
+        fn global_leaf() {
+            let mmu = SoftMmu::new(16);
+            let va = V4KA::new(0xFFFF_FC00_0000_0000);
+            let range = va..V4KA::new(0xFFFF_FC00_0000_1000);
+            make_ranges_in(&mmu, core::slice::from_ref(&range), &mut || mmu.alloc()).unwrap();
+            map_global_leaf_in(&mmu, pf(0x5000), va, true, false, true).unwrap();
+            let flags = Level1::pte_ref(&mmu, va.addr()).flags();
+            assert!(flags.contains(PTEFlags::PRESENT | PTEFlags::GLOBAL));
+            assert!(!flags.intersects(PTEFlags::WRITE | PTEFlags::NX));
+        }
+
+        #[test]
+        fn harvest_bits() {
+            let mmu = SoftMmu::new(16);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let start = V4KA::new(0x1F_0000);
+            let end = V4KA::new(0x21_0000);
+            for va in (start..end).step_by(2) {
+                map_in(&mmu, pf(0x5000), flags, va, &mut || mmu.alloc()).unwrap();
+            }
+            let set = |va: usize, flag: PTEFlags| {
+                Level1::pte_ref(&mmu, va).0.fetch_or(flag.bits(), Ordering::Relaxed);
+            };
+            set(0x1F_0000, PTEFlags::DIRTY | PTEFlags::ACCESS);
+            set(0x1F_2000, PTEFlags::ACCESS);
+            set(0x20_0000, PTEFlags::DIRTY);
+            let mut bitmap = [!0u64; 1];
+            assert_eq!(harvest_dirty_in(&mmu, start..end, &mut bitmap), Ok(2));
+            assert_eq!(bitmap, [1 | 1 << 16]);
+            assert_eq!(mmu.invalidations(), 2);
+            assert_eq!(harvest_dirty_in(&mmu, start..end, &mut bitmap), Ok(0));
+            assert_eq!(bitmap, [0]);
+            assert_eq!(harvest_accessed_in(&mmu, start..end, &mut bitmap), Ok(2));
+            assert_eq!(bitmap, [0b101]);
+            let flags = Level1::pte_ref(&mmu, start.addr()).flags();
+            assert!(!flags.intersects(PTEFlags::DIRTY | PTEFlags::ACCESS));
+
+            // Unmapped regions are skipped.
+            set(0x20_0000, PTEFlags::DIRTY);
+            let far = V4KA::new(0x4_0000_0000);
+            let mut bitmap = [0u64; 64];
+            let r = harvest_dirty_in(&mmu, V4KA::new(0x1000)..far, &mut bitmap);
+            assert_eq!(r, Err("bitmap too small"));
+            let r = harvest_dirty_in(&mmu, V4KA::new(0x1F_F000)..V4KA::new(0x60_0000), &mut bitmap);
+            assert_eq!(r, Ok(1));
+            assert_eq!(bitmap[0], 0b10);
+        }
+
+        #[test]
+        fn unmap_range_frees_tables() {
+            let mmu = SoftMmu::new(32);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            // Two pages in one 2MiB region, and one in the next, all
+            // under the same PML3 and PML2.
+            let pages = [V4KA::new(0x20_0000), V4KA::new(0x20_1000), V4KA::new(0x40_0000)];
+            for va in pages {
+                map_in(&mmu, pf(0x5000), flags, va, &mut || mmu.alloc()).unwrap();
+            }
+            let l1 = Level2::pte_ref(&mmu, pages[0].addr()).pfa().addr();
+            let mut freed = Vec::new();
+            unmap_range_in(&mmu, pages[0]..V4KA::new(0x20_1000), &mut |pf: PF4K| {
+                freed.push(pf.pfa().addr())
+            });
+            assert!(freed.is_empty());
+            assert_eq!(pa(translate_in(&mmu, pages[0].addr())), None);
+            assert_eq!(pa(translate_in(&mmu, pages[1].addr())), Some(0x5000));
+            unmap_range_in(&mmu, pages[1]..V4KA::new(0x20_2000), &mut |pf: PF4K| {
+                freed.push(pf.pfa().addr())
+            });
+            assert_eq!(freed, [l1]);
+            assert!(Level2::entry(&mmu, pages[0].addr()).is_none());
+            assert_eq!(pa(translate_in(&mmu, pages[2].addr())), Some(0x5000));
+
+            // Unmapping the last page frees everything below the root.
+            freed.clear();
+            unmap_range_in(&mmu, V4KA::new(0)..V4KA::new(0x4000_0000), &mut |pf: PF4K| {
+                freed.push(pf.pfa().addr())
+            });
+            assert_eq!(freed.len(), 3);
+            assert!(Level4::entry(&mmu, 0).is_none());
+        }
+
+        #[test]
+        fn map_leaf_range_aliases() {
+            let mmu = SoftMmu::new(16);
+            let start = V4KA::new(0xFFFF_FB40_0000_1000);
+            let end = V4KA::new(0xFFFF_FB40_0001_0000);
+            let range = start..end;
+            make_ranges_in(&mmu, core::slice::from_ref(&range), &mut || mmu.alloc()).unwrap();
+            map_leaf_range_in(&mmu, pf(0x9000), start..end, true, false, false).unwrap();
+            for va in start..end {
+                assert_eq!(pa(translate_in(&mmu, va.addr())), Some(0x9000));
+            }
+            assert!(map_leaf_range_in(&mmu, pf(0x9000), start..end, true, false, false).is_err());
+        }
+
+        // A small xorshift generator, so that property tests are
+        // reproducible without external dependencies.
+        struct Rng(u64);
+        impl Rng {
+            fn next(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+        }
+
+        #[test]
+        fn map_unmap_matches_model() {
+            const NPAGES: u64 = 2048;
+            // Spread pages over 4 root slots, 2 PML3 slots and 8
+            // PML2 slots in the lower half and the upper half.
+            fn va(k: u64) -> V4KA {
+                let (a, b, c, d) = (k % 4, (k / 4) % 2, (k / 8) % 8, k / 64);
+                let va = a << 39 | b << 30 | c << 21 | d << 12;
+                let va = if k % 3 == 0 { va | 0xFFFF_8000_0000_0000 } else { va };
+                V4KA::new(va as usize)
+            }
+            let mmu = SoftMmu::new(512);
+            let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+            let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+            let mut model = BTreeMap::new();
+            for _ in 0..10_000 {
+                let k = rng.next() % NPAGES;
+                let page = va(k);
+                if rng.next() % 3 == 0 {
+                    unmap_in(&mmu, page);
+                    model.remove(&k);
+                } else {
+                    let pfa = (rng.next() % (1 << 30)) << 12;
+                    let r = map_in(&mmu, pf(pfa), flags, page, &mut || mmu.alloc());
+                    assert_eq!(r.is_ok(), !model.contains_key(&k));
+                    model.entry(k).or_insert(pfa);
+                }
+                let probe = rng.next() % NPAGES;
+                let offset = rng.next() % 4096;
+                let expected = model.get(&probe).map(|pfa| pfa + offset);
+                assert_eq!(pa(translate_in(&mmu, va(probe).addr() + offset as usize)), expected);
+            }
+            for k in 0..NPAGES {
+                assert_eq!(pa(translate_in(&mmu, va(k).addr())), model.get(&k).copied());
+            }
+        }
     }
 
     #[test]
@@ -971,4 +2318,14 @@ mod tests {
         let pte = PTE::new(HPA::new(0xfff000), F::NOCACHE | F::USER | F::WRITE | F::PRESENT);
         assert_eq!(format!("{:?}", pte), "X:0xfff000:----C̶UWR");
     }
+
+    #[test]
+    fn pte_wide_pfa() {
+        use super::{HPA, PTE, PTEFlags as F};
+
+        let hpa = HPA::new(0x000F_FFFF_FFFF_F000);
+        let pte = PTE::new(hpa, F::NX | F::PRESENT);
+        assert_eq!(pte.pfa().addr(), hpa.addr());
+        assert_eq!(pte.flags().bits(), (F::NX | F::PRESENT).bits());
+    }
 }
@@ -0,0 +1,210 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # A software model of the x86_64 MMU
+//!
+//! `SoftMmu` holds a small arena of page frames in ordinary heap
+//! memory and emulates the hardware page walk over them.  Since
+//! the root table of each address space it creates refers to
+//! itself in the recursive slot, the recursive and side-load
+//! windows resolve exactly as they do on the machine, at either
+//! paging depth, which lets us test the code in `vm` on the host.
+
+use super::{Mmu, PTE, PTEFlags, Paging, Result};
+use crate::shootdown::Request;
+use crate::{HPA, PF4K, PageFrame};
+use core::cell::Cell;
+
+const NENTRIES: usize = 512;
+const FRAME_SIZE: usize = 4096;
+
+#[repr(C, align(4096))]
+struct Frame([PTE; NENTRIES]);
+
+impl Frame {
+    fn new() -> Frame {
+        Frame([const { PTE::empty() }; NENTRIES])
+    }
+}
+
+/// A software MMU over a fixed-size arena of page frames.
+pub(crate) struct SoftMmu {
+    paging: Paging,
+    frames: Box<[Frame]>,
+    next: Cell<usize>,
+    root: Cell<HPA>,
+    invepts: Cell<usize>,
+    invalidations: Cell<usize>,
+    flushes: Cell<usize>,
+    shootdowns: Cell<usize>,
+}
+
+impl SoftMmu {
+    /// The emulated physical address of the first frame in the
+    /// arena.  Chosen to be nonzero so that a zeroed entry is
+    /// never mistaken for a reference to a frame.
+    const BASE_PA: u64 = 0x10_0000;
+
+    /// Creates a new MMU with an arena of `nframes` zeroed page
+    /// frames and loads a fresh, empty address space, using
+    /// four-level paging.
+    pub(crate) fn new(nframes: usize) -> SoftMmu {
+        SoftMmu::with_paging(nframes, Paging::FourLevel)
+    }
+
+    /// Creates a new MMU as `new` does, but with the given depth
+    /// of paging.
+    pub(crate) fn with_paging(nframes: usize, paging: Paging) -> SoftMmu {
+        let frames = (0..nframes).map(|_| Frame::new()).collect();
+        let mmu = SoftMmu {
+            paging,
+            frames,
+            next: Cell::new(0),
+            root: Cell::new(HPA::new(0)),
+            invepts: Cell::new(0),
+            invalidations: Cell::new(0),
+            flushes: Cell::new(0),
+            shootdowns: Cell::new(0),
+        };
+        let root = mmu.new_space().expect("allocated root");
+        mmu.load(root);
+        mmu
+    }
+
+    /// Allocates a zeroed page frame from the arena.
+    pub(crate) fn alloc(&self) -> Result<PF4K> {
+        let k = self.next.get();
+        if k == self.frames.len() {
+            return Err("soft MMU out of frames");
+        }
+        self.next.set(k + 1);
+        Ok(PF4K::new(HPA::new(Self::BASE_PA + (k * FRAME_SIZE) as u64)))
+    }
+
+    /// Returns the number of frames allocated from the arena.
+    pub(crate) fn allocated(&self) -> usize {
+        self.next.get()
+    }
+
+    /// Allocates a root table for a new address space, and
+    /// installs the recursive entry in it.
+    pub(crate) fn new_space(&self) -> Result<PF4K> {
+        let root = self.alloc()?;
+        let flags = PTEFlags::PRESENT | PTEFlags::WRITE;
+        self.frame(root.pfa()).0[self.paging.self_index()].assign(PTE::new(root.pfa(), flags));
+        Ok(root)
+    }
+
+    /// Loads the given root, as a write to %cr3 would, and
+    /// returns the previously loaded root.
+    pub(crate) fn load(&self, root: PF4K) -> PF4K {
+        PF4K::new(self.root.replace(root.pfa()))
+    }
+
+    /// Returns the number of single-page TLB invalidations
+    /// performed.
+    pub(crate) fn invalidations(&self) -> usize {
+        self.invalidations.get()
+    }
+
+    /// Returns the number of full TLB flushes performed.
+    pub(crate) fn flushes(&self) -> usize {
+        self.flushes.get()
+    }
+
+    /// Returns the number of EPT invalidations performed.
+    pub(crate) fn invepts(&self) -> usize {
+        self.invepts.get()
+    }
+
+    /// Returns the number of shootdowns requested of other CPUs.
+    pub(crate) fn shootdowns(&self) -> usize {
+        self.shootdowns.get()
+    }
+
+    /// Returns the currently loaded root.
+    pub(crate) fn root(&self) -> PF4K {
+        PF4K::new(self.root.get())
+    }
+
+    // Returns the frame at the given emulated physical address.
+    fn frame(&self, pa: HPA) -> &Frame {
+        let offset = pa.addr().checked_sub(Self::BASE_PA).expect("physical address in arena");
+        let k = offset as usize / FRAME_SIZE;
+        assert_eq!(offset as usize % FRAME_SIZE, 0, "frame address is aligned");
+        self.frames.get(k).expect("physical address in arena")
+    }
+}
+
+impl Mmu for SoftMmu {
+    /// Walks the paging structures from the loaded root as the
+    /// hardware would, faulting (panicking) on any non-present
+    /// entry.  Paging structures are always mapped with 4KiB
+    /// pages, so big pages in the walk are also an error.
+    fn pte(&self, va: usize) -> &PTE {
+        const PTE_SIZE: usize = core::mem::size_of::<PTE>();
+        assert_eq!(va % PTE_SIZE, 0, "misaligned PTE address {va:#x}");
+        let mut frame = self.frame(self.root.get());
+        for level in (1..=self.paging.levels()).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let entry = &frame.0[(va >> shift) % NENTRIES];
+            assert!(entry.is_present(), "soft MMU page fault at {va:#x}");
+            assert!(shift == 12 || !entry.is_big(), "big page in paging window at {va:#x}");
+            frame = self.frame(entry.pfa());
+        }
+        &frame.0[(va % FRAME_SIZE) / PTE_SIZE]
+    }
+
+    fn paging(&self) -> Paging {
+        self.paging
+    }
+
+    fn flush_tlb(&self) {
+        self.flushes.set(self.flushes.get() + 1);
+    }
+
+    fn invalidate(&self, _va: usize) {
+        self.invalidations.set(self.invalidations.get() + 1);
+    }
+
+    fn invept(&self, _eptp: u64) {
+        self.invepts.set(self.invepts.get() + 1);
+    }
+
+    fn shoot_down(&self, _request: Request<'_>) {
+        self.shootdowns.set(self.shootdowns.get() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SoftMmu;
+    use crate::vm::{self, Mmu};
+
+    #[test]
+    fn recursive_root() {
+        let mmu = SoftMmu::new(4);
+        assert_eq!(vm::address_space_root_in(&mmu).addr(), mmu.root().pfa().addr());
+        let mmu = SoftMmu::with_paging(4, vm::Paging::FiveLevel);
+        assert_eq!(vm::address_space_root_in(&mmu).addr(), mmu.root().pfa().addr());
+    }
+
+    #[test]
+    fn out_of_frames() {
+        let mmu = SoftMmu::new(2);
+        assert!(mmu.alloc().is_ok());
+        assert!(mmu.alloc().is_err());
+        assert_eq!(mmu.allocated(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "soft MMU page fault")]
+    fn faults_on_unmapped() {
+        let mmu = SoftMmu::new(4);
+        mmu.pte(0x1000);
+    }
+}
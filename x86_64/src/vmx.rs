@@ -0,0 +1,425 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # VMX capabilities
+//!
+//! The VMX capability MSRs describe the format of the VMCS, and
+//! which settings of each VM-execution, VM-exit and VM-entry
+//! control field the CPU supports.  Each control MSR gives the
+//! bits that must be one in its low half, and the bits that may
+//! be one in its high half; bits outside the latter must be zero.
+//! Where IA32_VMX_BASIC says so, the TRUE_* variants of the MSRs
+//! supersede the originals, and allow some default-one bits to be
+//! cleared.
+//!
+//! The decoders take a function reading MSRs, so that they may be
+//! exercised with canned values.
+
+use bitflags::{Flags, bitflags};
+use bitstruct::bitstruct;
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+bitstruct! {
+    /// IA32_VMX_BASIC: the VMCS revision and region size, and
+    /// which other capability MSRs exist.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Basic(pub u64) {
+        pub revision: u32 = 0..31;
+        pub region_size: u16 = 32..45;
+        pub addresses_32bit: bool = 48;
+        pub dual_monitor: bool = 49;
+        pub memory_type: u8 = 50..54;
+        pub ins_outs_info: bool = 54;
+        pub true_controls: bool = 55;
+        pub no_error_code_check: bool = 56;
+    }
+}
+
+impl Basic {
+    /// The memory type for the VMCS and related structures
+    /// when they must be accessed write-back.
+    pub const WRITE_BACK: u8 = 6;
+}
+
+bitstruct! {
+    /// IA32_VMX_MISC: miscellaneous VMX capabilities.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Misc(pub u64) {
+        pub preemption_timer_shift: u8 = 0..5;
+        pub stores_lma: bool = 5;
+        pub activity_hlt: bool = 6;
+        pub activity_shutdown: bool = 7;
+        pub activity_wait_for_sipi: bool = 8;
+        pub pt_in_vmx: bool = 14;
+        pub smbase_rdmsr: bool = 15;
+        pub cr3_targets: u16 = 16..25;
+        raw_max_msrs: u16 = 25..28;
+        pub smm_monitor_ctl: bool = 28;
+        pub vmwrite_any_field: bool = 29;
+        pub zero_length_injection: bool = 30;
+        pub mseg_revision: u32 = 32..64;
+    }
+}
+
+impl Misc {
+    /// Returns the recommended maximum number of MSRs in each
+    /// of the VM-exit and VM-entry MSR lists.
+    pub fn max_msrs(self) -> usize {
+        512 * (usize::from(self.raw_max_msrs()) + 1)
+    }
+}
+
+bitstruct! {
+    /// IA32_VMX_EPT_VPID_CAP: the supported EPT features and
+    /// INVEPT and INVVPID variants.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct EptVpidCap(pub u64) {
+        pub execute_only: bool = 0;
+        pub walk_length_4: bool = 6;
+        pub walk_length_5: bool = 7;
+        pub uncacheable: bool = 8;
+        pub write_back: bool = 14;
+        pub pde_2m: bool = 16;
+        pub pdpte_1g: bool = 17;
+        pub invept: bool = 20;
+        pub accessed_dirty: bool = 21;
+        pub advanced_exit_info: bool = 22;
+        pub supervisor_shadow_stack: bool = 23;
+        pub invept_single_context: bool = 25;
+        pub invept_all_context: bool = 26;
+        pub invvpid: bool = 32;
+        pub invvpid_individual_address: bool = 40;
+        pub invvpid_single_context: bool = 41;
+        pub invvpid_all_context: bool = 42;
+        pub invvpid_single_context_retaining_globals: bool = 43;
+    }
+}
+
+bitflags! {
+    /// Pin-based VM-execution controls.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct PinBased: u32 {
+        const EXTERNAL_INTERRUPT_EXITING = 1 << 0;
+        const NMI_EXITING = 1 << 3;
+        const VIRTUAL_NMIS = 1 << 5;
+        const PREEMPTION_TIMER = 1 << 6;
+        const POSTED_INTERRUPTS = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// Primary processor-based VM-execution controls.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ProcBased: u32 {
+        const INTERRUPT_WINDOW_EXITING = 1 << 2;
+        const TSC_OFFSETTING = 1 << 3;
+        const HLT_EXITING = 1 << 7;
+        const INVLPG_EXITING = 1 << 9;
+        const MWAIT_EXITING = 1 << 10;
+        const RDPMC_EXITING = 1 << 11;
+        const RDTSC_EXITING = 1 << 12;
+        const CR3_LOAD_EXITING = 1 << 15;
+        const CR3_STORE_EXITING = 1 << 16;
+        const TERTIARY_CONTROLS = 1 << 17;
+        const CR8_LOAD_EXITING = 1 << 19;
+        const CR8_STORE_EXITING = 1 << 20;
+        const TPR_SHADOW = 1 << 21;
+        const NMI_WINDOW_EXITING = 1 << 22;
+        const MOV_DR_EXITING = 1 << 23;
+        const UNCONDITIONAL_IO_EXITING = 1 << 24;
+        const IO_BITMAPS = 1 << 25;
+        const MONITOR_TRAP_FLAG = 1 << 27;
+        const MSR_BITMAPS = 1 << 28;
+        const MONITOR_EXITING = 1 << 29;
+        const PAUSE_EXITING = 1 << 30;
+        const SECONDARY_CONTROLS = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// Secondary processor-based VM-execution controls.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ProcBased2: u32 {
+        const VIRTUALIZE_APIC_ACCESSES = 1 << 0;
+        const EPT = 1 << 1;
+        const DESCRIPTOR_TABLE_EXITING = 1 << 2;
+        const RDTSCP = 1 << 3;
+        const VIRTUALIZE_X2APIC = 1 << 4;
+        const VPID = 1 << 5;
+        const WBINVD_EXITING = 1 << 6;
+        const UNRESTRICTED_GUEST = 1 << 7;
+        const APIC_REGISTER_VIRTUALIZATION = 1 << 8;
+        const VIRTUAL_INTERRUPT_DELIVERY = 1 << 9;
+        const PAUSE_LOOP_EXITING = 1 << 10;
+        const RDRAND_EXITING = 1 << 11;
+        const INVPCID = 1 << 12;
+        const VMFUNC = 1 << 13;
+        const VMCS_SHADOWING = 1 << 14;
+        const ENCLS_EXITING = 1 << 15;
+        const RDSEED_EXITING = 1 << 16;
+        const PAGE_MODIFICATION_LOGGING = 1 << 17;
+        const EPT_VIOLATION_VE = 1 << 18;
+        const CONCEAL_VMX_FROM_PT = 1 << 19;
+        const XSAVES = 1 << 20;
+        const MODE_BASED_EPT_EXECUTE = 1 << 22;
+        const SUBPAGE_WRITE_PERMISSIONS = 1 << 23;
+        const PT_USES_GUEST_PHYSICAL = 1 << 24;
+        const TSC_SCALING = 1 << 25;
+        const USER_WAIT_PAUSE = 1 << 26;
+    }
+}
+
+bitflags! {
+    /// VM-exit controls.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ExitControls: u32 {
+        const SAVE_DEBUG_CONTROLS = 1 << 2;
+        const HOST_ADDRESS_SPACE_SIZE = 1 << 9;
+        const LOAD_PERF_GLOBAL_CTRL = 1 << 12;
+        const ACKNOWLEDGE_INTERRUPT = 1 << 15;
+        const SAVE_PAT = 1 << 18;
+        const LOAD_PAT = 1 << 19;
+        const SAVE_EFER = 1 << 20;
+        const LOAD_EFER = 1 << 21;
+        const SAVE_PREEMPTION_TIMER = 1 << 22;
+        const CLEAR_BNDCFGS = 1 << 23;
+        const CONCEAL_VMX_FROM_PT = 1 << 24;
+    }
+}
+
+bitflags! {
+    /// VM-entry controls.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct EntryControls: u32 {
+        const LOAD_DEBUG_CONTROLS = 1 << 2;
+        const IA32E_MODE_GUEST = 1 << 9;
+        const ENTRY_TO_SMM = 1 << 10;
+        const DEACTIVATE_DUAL_MONITOR = 1 << 11;
+        const LOAD_PERF_GLOBAL_CTRL = 1 << 13;
+        const LOAD_PAT = 1 << 14;
+        const LOAD_EFER = 1 << 15;
+        const LOAD_BNDCFGS = 1 << 16;
+        const CONCEAL_VMX_FROM_PT = 1 << 17;
+    }
+}
+
+/// The allowed settings of a VMX control field, as given by its
+/// capability MSR.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Controls {
+    must_be_one: u32,
+    may_be_one: u32,
+}
+
+impl Controls {
+    /// Decodes a control capability MSR.
+    pub const fn new(msr: u64) -> Controls {
+        Controls { must_be_one: msr as u32, may_be_one: (msr >> 32) as u32 }
+    }
+
+    /// Returns the bits that must be set in the control field.
+    pub fn must_be_one(&self) -> u32 {
+        self.must_be_one
+    }
+
+    /// Returns the bits that may be set in the control field.
+    pub fn may_be_one(&self) -> u32 {
+        self.may_be_one
+    }
+
+    /// Returns true iff every given control may be set.
+    pub fn allows<F: Flags<Bits = u32>>(&self, controls: F) -> bool {
+        controls.bits() & !self.may_be_one == 0
+    }
+
+    /// Computes a legal value for the control field, with every
+    /// required control set, as many of the desired controls as
+    /// the CPU supports, and any it insists upon.  Fails if some
+    /// required control cannot be set.  Any bits the CPU insists
+    /// upon that are not named by `F` are retained.
+    pub fn negotiate<F: Flags<Bits = u32>>(&self, desired: F, required: F) -> Result<F> {
+        if required.bits() & !self.may_be_one != 0 {
+            return Err("required VMX control not supported");
+        }
+        let bits = (desired.bits() | required.bits()) & self.may_be_one | self.must_be_one;
+        Ok(F::from_bits_retain(bits))
+    }
+}
+
+/// The VMX capabilities of a CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub basic: Basic,
+    pub pinbased: Controls,
+    pub procbased: Controls,
+    pub procbased2: Controls,
+    pub exit: Controls,
+    pub entry: Controls,
+    pub ept_vpid: EptVpidCap,
+    pub misc: Misc,
+}
+
+impl Capabilities {
+    /// Decodes the capabilities from MSRs read through the given
+    /// function, which is only asked for MSRs that exist.
+    pub fn decode<R: Fn(u32) -> u64>(rdmsr: R) -> Capabilities {
+        use x86::msr;
+        let basic = Basic(rdmsr(msr::IA32_VMX_BASIC));
+        let [pinbased, procbased, exit, entry] = if basic.true_controls() {
+            [
+                msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+                msr::IA32_VMX_TRUE_EXIT_CTLS,
+                msr::IA32_VMX_TRUE_ENTRY_CTLS,
+            ]
+        } else {
+            [
+                msr::IA32_VMX_PINBASED_CTLS,
+                msr::IA32_VMX_PROCBASED_CTLS,
+                msr::IA32_VMX_EXIT_CTLS,
+                msr::IA32_VMX_ENTRY_CTLS,
+            ]
+        }
+        .map(|msr| Controls::new(rdmsr(msr)));
+        let procbased2 = if procbased.allows(ProcBased::SECONDARY_CONTROLS) {
+            Controls::new(rdmsr(msr::IA32_VMX_PROCBASED_CTLS2))
+        } else {
+            Controls::default()
+        };
+        let ept_vpid = if procbased2.allows(ProcBased2::EPT) || procbased2.allows(ProcBased2::VPID)
+        {
+            EptVpidCap(rdmsr(msr::IA32_VMX_EPT_VPID_CAP))
+        } else {
+            EptVpidCap::default()
+        };
+        let misc = Misc(rdmsr(msr::IA32_VMX_MISC));
+        Capabilities { basic, pinbased, procbased, procbased2, exit, entry, ept_vpid, misc }
+    }
+
+    /// Reads the capabilities of the current CPU, which must
+    /// support VMX.
+    pub fn read() -> Capabilities {
+        Self::decode(|msr| unsafe { x86::msr::rdmsr(msr) })
+    }
+
+    /// Returns true iff the CPU supports EPT with the features we
+    /// rely upon: four-level, write-back tables, and the INVEPT
+    /// instruction.
+    pub fn has_ept(&self) -> bool {
+        self.procbased.allows(ProcBased::SECONDARY_CONTROLS)
+            && self.procbased2.allows(ProcBased2::EPT)
+            && self.ept_vpid.walk_length_4()
+            && self.ept_vpid.write_back()
+            && self.ept_vpid.invept()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Capability MSRs modeled on those of a Skylake client part.
+    const SKYLAKE: &[(u32, u64)] = &[
+        (0x480, 0x00da_0400_0000_0004),
+        (0x481, 0x0000_007f_0000_0016),
+        (0x482, 0xfff9_fffe_0401_e172),
+        (0x483, 0x01ff_ffff_0003_6dff),
+        (0x484, 0x0003_ffff_0000_11ff),
+        (0x485, 0x0000_0000_7004_c1e7),
+        (0x48b, 0x0077_7fff_0000_0000),
+        (0x48c, 0x0000_0f01_0673_4141),
+        (0x48d, 0x0000_007f_0000_0016),
+        (0x48e, 0xfff9_fffe_0400_6172),
+        (0x48f, 0x01ff_ffff_0003_6dfb),
+        (0x490, 0x0003_ffff_0000_11fb),
+    ];
+
+    // Capability MSRs modeled on those of a Nehalem part, which has
+    // no TRUE_* MSRs, and no EPT accessed and dirty flags.
+    const NEHALEM: &[(u32, u64)] = &[
+        (0x480, 0x005a_0400_0000_000e),
+        (0x481, 0x0000_003f_0000_0016),
+        (0x482, 0xf7f9_fffe_0401_e172),
+        (0x483, 0x0003_6fff_0003_6dff),
+        (0x484, 0x0000_33ff_0000_11ff),
+        (0x485, 0x0000_0000_0003_01e5),
+        (0x48b, 0x0000_006b_0000_0000),
+        (0x48c, 0x0000_0f01_0611_4041),
+    ];
+
+    // A CPU without secondary controls, whose secondary control
+    // and EPT MSRs must not be read.
+    const NO_SECONDARY: &[(u32, u64)] = &[
+        (0x480, 0x005a_0400_0000_000d),
+        (0x481, 0x0000_003f_0000_0016),
+        (0x482, 0x77f9_fffe_0401_e172),
+        (0x483, 0x0003_6fff_0003_6dff),
+        (0x484, 0x0000_33ff_0000_11ff),
+        (0x485, 0x0000_0000_0000_0000),
+    ];
+
+    fn decode(msrs: &[(u32, u64)]) -> Capabilities {
+        let msrs: BTreeMap<u32, u64> = msrs.iter().copied().collect();
+        Capabilities::decode(|msr| *msrs.get(&msr).expect("read an MSR that does not exist"))
+    }
+
+    #[test]
+    fn skylake() {
+        let caps = decode(SKYLAKE);
+        assert_eq!(caps.basic.revision(), 4);
+        assert_eq!(caps.basic.region_size(), 0x400);
+        assert_eq!(caps.basic.memory_type(), Basic::WRITE_BACK);
+        assert!(caps.basic.true_controls());
+        // The TRUE MSR lets CR3 load and store exiting be cleared.
+        assert_eq!(caps.procbased.must_be_one(), 0x0400_6172);
+        assert!(caps.ept_vpid.accessed_dirty());
+        assert!(caps.ept_vpid.pdpte_1g());
+        assert!(caps.ept_vpid.invvpid_single_context());
+        assert!(caps.has_ept());
+        assert_eq!(caps.misc.preemption_timer_shift(), 7);
+        assert!(caps.misc.activity_hlt());
+        assert_eq!(caps.misc.max_msrs(), 512);
+    }
+
+    #[test]
+    fn nehalem() {
+        let caps = decode(NEHALEM);
+        assert!(!caps.basic.true_controls());
+        assert_eq!(caps.procbased.must_be_one(), 0x0401_e172);
+        assert!(caps.procbased2.allows(ProcBased2::EPT | ProcBased2::VPID));
+        assert!(!caps.procbased2.allows(ProcBased2::UNRESTRICTED_GUEST | ProcBased2::EPT));
+        assert!(!caps.ept_vpid.accessed_dirty());
+        assert!(!caps.ept_vpid.pdpte_1g());
+        assert!(caps.has_ept());
+    }
+
+    #[test]
+    fn no_secondary_controls() {
+        let caps = decode(NO_SECONDARY);
+        assert_eq!(caps.procbased2, Controls::default());
+        assert_eq!(caps.ept_vpid, EptVpidCap::default());
+        assert!(!caps.has_ept());
+    }
+
+    #[test]
+    fn negotiate() {
+        let caps = decode(SKYLAKE);
+        let desired = ProcBased::HLT_EXITING | ProcBased::MONITOR_TRAP_FLAG;
+        let required = ProcBased::SECONDARY_CONTROLS | ProcBased::MSR_BITMAPS;
+        let procbased = caps.procbased.negotiate(desired, required).unwrap();
+        assert_eq!(procbased.bits(), 0x9c00_61f2);
+        let pinbased = caps.pinbased.negotiate(PinBased::POSTED_INTERRUPTS, PinBased::NMI_EXITING);
+        assert_eq!(pinbased.unwrap().bits(), 0x0000_001e);
+        let required = ProcBased2::EPT | ProcBased2::XSAVES;
+        assert!(decode(NEHALEM).procbased2.negotiate(ProcBased2::empty(), required).is_err());
+        let entry = EntryControls::IA32E_MODE_GUEST | EntryControls::LOAD_EFER;
+        let entry = caps.entry.negotiate(EntryControls::empty(), entry).unwrap();
+        assert_eq!(entry.bits(), 0x0000_93fb);
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright 2026  The Hypatia Authors
+// All rights reserved
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! # Extended processor state
+//!
+//! Hypatia itself is built without floating point or SIMD, so
+//! the x87, SSE and AVX registers belong entirely to tasks and
+//! guests.  Neither the trap frame nor the VM entry path saves
+//! them, and so whatever switches between tasks, or enters and
+//! exits a guest, must save and restore them explicitly, with
+//! XSAVE and XRSTOR, into an `Area` private to each.
+//!
+//! XCR0 selects the state components that XSAVE manages and
+//! that software may use.  We enable those components that the
+//! CPU supports and that we know how to manage; that is the host
+//! value.  A guest sets its own XCR0 with XSETBV, which always
+//! exits, and that value must be loaded only while the guest
+//! runs: `Guest` keeps it, validated against the host value,
+//! alongside the guest's saved state, and its `enter` and `exit`
+//! methods switch both around VM entry and exit.
+//!
+//! The size of an area depends on the components it holds, and
+//! their offsets are reported by CPUID leaf 0xD.  Areas here are
+//! a fixed size, sufficient for every component we enable.
+//!
+//! The loader enables XSAVE on every CPU, and publishes the host
+//! value of XCR0, and whether the CPU has XSAVEOPT, to each
+//! binary it loads, by writing them into the binary's copies of
+//! `HOST` and `OPTIMIZED`, exported under `HOST_SYMBOL` and
+//! `OPTIMIZED_SYMBOL` for that purpose, as it does the TSC
+//! frequency.
+
+use crate::cpuid::{self, Features, Registers};
+use bitflags::bitflags;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86::controlregs::Cr4;
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+bitflags! {
+    /// The state components, as represented in XCR0 and in the
+    /// XSTATE_BV field of an XSAVE area header.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Components: u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREGS = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+        const TILECFG = 1 << 17;
+        const TILEDATA = 1 << 18;
+    }
+}
+
+impl Components {
+    /// The AVX-512 components, which are enabled together.
+    pub const AVX512: Components =
+        Components::OPMASK.union(Components::ZMM_HI256).union(Components::HI16_ZMM);
+
+    /// The MPX components, which are enabled together.
+    pub const MPX: Components = Components::BNDREGS.union(Components::BNDCSR);
+
+    /// The AMX components, which are enabled together.
+    pub const AMX: Components = Components::TILECFG.union(Components::TILEDATA);
+
+    /// The components that we manage on behalf of tasks and
+    /// guests, where the CPU supports them.  MPX is deprecated,
+    /// and the AMX tile data would not fit in an `Area`.
+    pub const MANAGED: Components =
+        Components::X87.union(Components::SSE).union(Components::AVX).union(Components::AVX512);
+
+    /// Returns true iff this is a combination of components that
+    /// may be loaded into XCR0.
+    pub fn is_valid_xcr0(self) -> bool {
+        let all_or_none = |group: Components| self.contains(group) || !self.intersects(group);
+        self.contains(Components::X87)
+            && (!self.contains(Components::AVX) || self.contains(Components::SSE))
+            && all_or_none(Components::AVX512)
+            && (!self.intersects(Components::AVX512) || self.contains(Components::AVX))
+            && all_or_none(Components::MPX)
+            && all_or_none(Components::AMX)
+    }
+
+    /// Returns the components supported for XCR0 by the CPU, as
+    /// reported through the given CPUID function.
+    pub fn supported<F: Fn(u32, u32) -> Registers>(cpuid: F) -> Components {
+        if cpuid(0, 0).eax < 0xD {
+            return Components::empty();
+        }
+        let regs = cpuid(0xD, 0);
+        Components::from_bits_truncate(u64::from(regs.edx) << 32 | u64::from(regs.eax))
+    }
+
+    /// Returns the host value of XCR0, given the supported
+    /// components: those we manage, less any partially
+    /// supported group.
+    pub fn host(supported: Components) -> Components {
+        let mut xcr0 = supported & Components::MANAGED;
+        if !xcr0.contains(Components::AVX512) || !xcr0.contains(Components::AVX) {
+            xcr0.remove(Components::AVX512);
+        }
+        if !xcr0.contains(Components::SSE) {
+            xcr0.remove(Components::AVX);
+        }
+        xcr0
+    }
+}
+
+/// The size of the legacy FXSAVE region at the start of an area.
+pub const LEGACY_SIZE: usize = 512;
+
+/// The size of the XSAVE header that follows the legacy region.
+pub const HEADER_SIZE: usize = 64;
+
+/// Returns the size of an area holding the given components in
+/// the standard format, as reported through the given CPUID
+/// function.
+pub fn area_size<F: Fn(u32, u32) -> Registers>(cpuid: F, components: Components) -> usize {
+    let mut size = LEGACY_SIZE + HEADER_SIZE;
+    for bit in 2..64 {
+        if components.bits() & (1 << bit) != 0 {
+            let regs = cpuid(0xD, bit);
+            size = size.max(regs.ebx as usize + regs.eax as usize);
+        }
+    }
+    size
+}
+
+/// The size of an `Area`.
+pub const AREA_SIZE: usize = 4096;
+
+const MXCSR_OFFSET: usize = 24;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// Memory holding a saved copy of extended processor state.
+#[repr(C, align(64))]
+pub struct Area([u8; AREA_SIZE]);
+
+impl Area {
+    /// Returns an area holding the initial state of every
+    /// component.  XRSTOR loads MXCSR from the legacy region
+    /// whenever it restores SSE or AVX state, so that is set to
+    /// its reset value; the zeroed header marks every other
+    /// component as being in its initial configuration.
+    pub const fn new() -> Area {
+        let mut bytes = [0; AREA_SIZE];
+        let mxcsr = MXCSR_DEFAULT.to_le_bytes();
+        let mut k = 0;
+        while k < mxcsr.len() {
+            bytes[MXCSR_OFFSET + k] = mxcsr[k];
+            k += 1;
+        }
+        Area(bytes)
+    }
+
+    /// Returns the components whose state the area holds, as
+    /// recorded in the XSTATE_BV field of its header.  Others
+    /// are in their initial configuration.
+    pub fn components(&self) -> Components {
+        let bv = &self.0[LEGACY_SIZE..LEGACY_SIZE + 8];
+        Components::from_bits_truncate(u64::from_le_bytes(bv.try_into().unwrap()))
+    }
+
+    // Marks the given components as being in their initial
+    // configuration.
+    fn reset(&mut self, components: Components) {
+        let bv = self.components().difference(components);
+        self.0[LEGACY_SIZE..LEGACY_SIZE + 8].copy_from_slice(&bv.bits().to_le_bytes());
+    }
+
+    /// Saves the given components of the current CPU's state
+    /// into the area, using XSAVEOPT if the CPU has it.
+    ///
+    /// # Safety
+    /// `init` must have been called on this CPU, and the
+    /// components must be enabled in XCR0.
+    pub unsafe fn save(&mut self, components: Components) {
+        let mask = components.bits();
+        let area = self.0.as_mut_ptr();
+        if OPTIMIZED.load(Ordering::Relaxed) {
+            unsafe {
+                asm!("xsaveopt64 ({})",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(att_syntax, nostack, preserves_flags));
+            }
+        } else {
+            unsafe {
+                asm!("xsave64 ({})",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(att_syntax, nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Restores the given components of the current CPU's state
+    /// from the area.
+    ///
+    /// # Safety
+    /// `init` must have been called on this CPU, the components
+    /// must be enabled in XCR0, and the area must hold state
+    /// saved by `save` or created by `new`.
+    pub unsafe fn restore(&self, components: Components) {
+        let mask = components.bits();
+        unsafe {
+            asm!("xrstor64 ({})",
+                in(reg) self.0.as_ptr(),
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+                options(att_syntax, nostack, preserves_flags, readonly));
+        }
+    }
+}
+
+impl Default for Area {
+    fn default() -> Area {
+        Area::new()
+    }
+}
+
+/// The name under which each binary exports its copy of the
+/// published host value of XCR0.
+pub const HOST_SYMBOL: &str = "hypatia_xsave_host";
+
+/// The name under which each binary exports its copy of the
+/// published XSAVEOPT support.
+pub const OPTIMIZED_SYMBOL: &str = "hypatia_xsave_optimized";
+
+/// The host value of XCR0, or zero before `init` or publication.
+#[unsafe(export_name = "hypatia_xsave_host")]
+pub static HOST: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the CPU has XSAVEOPT.
+#[unsafe(export_name = "hypatia_xsave_optimized")]
+pub static OPTIMIZED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the host value of XCR0, or the empty set if `init`
+/// has not enabled XSAVE.
+pub fn host() -> Components {
+    Components::from_bits_truncate(HOST.load(Ordering::Relaxed))
+}
+
+/// Reads XCR0.
+///
+/// # Safety
+/// XSAVE must be enabled in %cr4.
+pub unsafe fn xcr0() -> Components {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("xgetbv",
+            in("ecx") 0,
+            out("eax") lo,
+            out("edx") hi,
+            options(att_syntax, nomem, nostack, preserves_flags));
+    }
+    Components::from_bits_retain(u64::from(hi) << 32 | u64::from(lo))
+}
+
+/// Loads XCR0.
+///
+/// # Safety
+/// XSAVE must be enabled in %cr4, and the components must be a
+/// valid combination supported by the CPU.
+pub unsafe fn set_xcr0(components: Components) {
+    let xcr0 = components.bits();
+    unsafe {
+        asm!("xsetbv",
+            in("ecx") 0,
+            in("eax") xcr0 as u32,
+            in("edx") (xcr0 >> 32) as u32,
+            options(att_syntax, nomem, nostack, preserves_flags));
+    }
+}
+
+/// Enables XSAVE and SSE in %cr4, and loads XCR0 with the host
+/// value.  Must be called on each CPU.  Returns the host value,
+/// which is empty if the CPU lacks XSAVE, in which case nothing
+/// is enabled.
+pub fn init() -> Components {
+    if !cpuid::has(Features::XSAVE) {
+        return Components::empty();
+    }
+    let xcr0 = Components::host(Components::supported(cpuid::cpuid));
+    assert!(area_size(cpuid::cpuid, xcr0) <= AREA_SIZE, "XSAVE area too large");
+    let cr4 = unsafe { x86::controlregs::cr4() };
+    let cr4 = cr4 | Cr4::CR4_ENABLE_SSE | Cr4::CR4_UNMASKED_SSE | Cr4::CR4_ENABLE_OS_XSAVE;
+    unsafe {
+        x86::controlregs::cr4_write(cr4);
+        set_xcr0(xcr0);
+    }
+    let optimized = cpuid::cpuid(0xD, 1).eax & 1 != 0;
+    OPTIMIZED.store(optimized, Ordering::Relaxed);
+    HOST.store(xcr0.bits(), Ordering::Relaxed);
+    xcr0
+}
+
+/// The extended state of a guest, and the value of XCR0 it has
+/// set.
+pub struct Guest {
+    area: Area,
+    xcr0: Components,
+}
+
+impl Guest {
+    /// Returns the state of a guest at reset, with only x87 state
+    /// enabled.
+    pub const fn new() -> Guest {
+        Guest { area: Area::new(), xcr0: Components::X87 }
+    }
+
+    /// Returns the guest's value of XCR0.
+    pub fn xcr0(&self) -> Components {
+        self.xcr0
+    }
+
+    /// Sets the guest's value of XCR0, as on an exit for XSETBV.
+    /// The value must be valid, and enable only components that
+    /// the host also enables; otherwise, the guest should be
+    /// given a #GP.  The saved state of components the guest
+    /// disables is discarded.
+    pub fn set_xcr0(&mut self, value: u64) -> Result<()> {
+        let xcr0 = Components::from_bits(value).ok_or("unknown XCR0 component")?;
+        if !xcr0.is_valid_xcr0() {
+            return Err("invalid XCR0 combination");
+        }
+        if !host().contains(xcr0) {
+            return Err("XCR0 component not enabled by host");
+        }
+        self.area.reset(self.xcr0.difference(xcr0));
+        self.xcr0 = xcr0;
+        Ok(())
+    }
+
+    /// Saves the host's state into the given area, and loads the
+    /// guest's state and XCR0, immediately before VM entry.  The
+    /// guest's state is restored under the host's XCR0, so that
+    /// components the guest has not enabled are put into their
+    /// initial configuration rather than leaking host values.
+    ///
+    /// # Safety
+    /// `init` must have been called on this CPU, and nothing
+    /// may use extended state until the matching `exit`.
+    pub unsafe fn enter(&self, host: &mut Area) {
+        let components = self::host();
+        unsafe {
+            host.save(components);
+            self.area.restore(components);
+            set_xcr0(self.xcr0);
+        }
+    }
+
+    /// Saves the guest's state, and restores the host's XCR0 and
+    /// the state saved in the given area, immediately after VM
+    /// exit.
+    ///
+    /// # Safety
+    /// Must follow a call to `enter` with the same host area.
+    pub unsafe fn exit(&mut self, host: &Area) {
+        let components = self::host();
+        unsafe {
+            self.area.save(self.xcr0);
+            set_xcr0(components);
+            host.restore(components);
+        }
+    }
+}
+
+impl Default for Guest {
+    fn default() -> Guest {
+        Guest::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpuid::canned;
+
+    // A CPU with AVX-512 and PKRU, with the standard offsets.
+    fn avx512() -> impl Fn(u32, u32) -> Registers {
+        canned(&[
+            ((0, 0), [0x16, 0, 0, 0]),
+            ((0xD, 0), [0x2e7, 0x2b00, 0x2b08, 0]),
+            ((0xD, 1), [0xf, 0, 0, 0]),
+            ((0xD, 2), [256, 576, 0, 0]),
+            ((0xD, 5), [64, 1088, 0, 0]),
+            ((0xD, 6), [512, 1152, 0, 0]),
+            ((0xD, 7), [1024, 1664, 0, 0]),
+            ((0xD, 9), [8, 2688, 0, 0]),
+        ])
+    }
+
+    #[test]
+    fn supported() {
+        let supported = Components::supported(avx512());
+        let expected = Components::MANAGED | Components::PKRU;
+        assert_eq!(supported, expected);
+        assert_eq!(Components::host(supported), Components::MANAGED);
+        let old = canned(&[((0, 0), [0xB, 0, 0, 0])]);
+        assert_eq!(Components::supported(old), Components::empty());
+    }
+
+    #[test]
+    fn host() {
+        let avx = Components::X87 | Components::SSE | Components::AVX;
+        assert_eq!(Components::host(avx | Components::MPX), avx);
+        assert_eq!(Components::host(avx | Components::OPMASK), avx);
+        assert_eq!(Components::host(avx | Components::AMX), avx);
+        let sse = Components::X87 | Components::SSE;
+        assert_eq!(Components::host(sse), sse);
+    }
+
+    #[test]
+    fn valid_xcr0() {
+        let avx = Components::X87 | Components::SSE | Components::AVX;
+        assert!(Components::X87.is_valid_xcr0());
+        assert!(avx.is_valid_xcr0());
+        assert!((avx | Components::AVX512).is_valid_xcr0());
+        assert!(!Components::SSE.is_valid_xcr0());
+        assert!(!(Components::X87 | Components::AVX).is_valid_xcr0());
+        assert!(!(avx | Components::OPMASK).is_valid_xcr0());
+        assert!(!(Components::X87 | Components::SSE | Components::AVX512).is_valid_xcr0());
+        assert!(!(Components::X87 | Components::BNDREGS).is_valid_xcr0());
+        assert!(!(avx | Components::TILEDATA).is_valid_xcr0());
+    }
+
+    #[test]
+    fn sizes() {
+        let cpu = avx512();
+        assert_eq!(area_size(&cpu, Components::X87 | Components::SSE), 576);
+        let avx = Components::X87 | Components::SSE | Components::AVX;
+        assert_eq!(area_size(&cpu, avx), 832);
+        assert_eq!(area_size(&cpu, Components::MANAGED), 2688);
+        assert_eq!(area_size(&cpu, Components::MANAGED | Components::PKRU), 2696);
+        assert!(area_size(&cpu, Components::MANAGED) <= AREA_SIZE);
+    }
+
+    #[test]
+    fn area() {
+        let mut area = Area::new();
+        assert_eq!(core::mem::align_of::<Area>(), 64);
+        assert_eq!(area.components(), Components::empty());
+        assert_eq!(area.0[MXCSR_OFFSET..MXCSR_OFFSET + 4], [0x80, 0x1F, 0, 0]);
+        let bv = (Components::X87 | Components::SSE | Components::AVX).bits();
+        area.0[LEGACY_SIZE..LEGACY_SIZE + 8].copy_from_slice(&bv.to_le_bytes());
+        area.reset(Components::AVX);
+        assert_eq!(area.components(), Components::X87 | Components::SSE);
+    }
+
+    #[test]
+    fn guest_xcr0() {
+        let mut guest = Guest::new();
+        assert_eq!(guest.xcr0(), Components::X87);
+        assert!(guest.set_xcr0(0b111).is_err());
+        HOST.store(Components::MANAGED.bits(), Ordering::Relaxed);
+        assert!(guest.set_xcr0(0b10).is_err());
+        assert!(guest.set_xcr0(1 << 8).is_err());
+        assert!(guest.set_xcr0(Components::PKRU.bits() | 1).is_err());
+        assert_eq!(guest.xcr0(), Components::X87);
+        guest.set_xcr0(0b111).expect("enabled AVX");
+        assert_eq!(guest.xcr0(), Components::X87 | Components::SSE | Components::AVX);
+        let bv = 0b111u64.to_le_bytes();
+        guest.area.0[LEGACY_SIZE..LEGACY_SIZE + 8].copy_from_slice(&bv);
+        guest.set_xcr0(0b11).expect("disabled AVX");
+        assert_eq!(guest.area.components(), Components::X87 | Components::SSE);
+    }
+}
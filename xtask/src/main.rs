@@ -76,6 +76,9 @@ enum Command {
         smp: u32,
         #[arg(long, default_value_t = 2048)]
         ram: u32,
+        /// The QEMU CPU model, which must support VMX with EPT
+        #[arg(long, default_value = "host")]
+        cpu: String,
     },
     /// Expands macros
     Expand,
@@ -150,7 +153,9 @@ fn main() {
         Command::Archive { profile, locked } => archive(profile.into(), locked),
         Command::Test { profile, locked } => test(profile.into(), locked),
         Command::Lint { locked } => lint(locked),
-        Command::Run { profile, locked, smp, ram } => run(profile.into(), locked, smp, ram),
+        Command::Run { profile, locked, smp, ram, cpu } => {
+            run(profile.into(), locked, smp, ram, &cpu)
+        }
         Command::Expand => expand(),
         Command::Clean => clean(),
     } {
@@ -249,12 +254,12 @@ fn lint(locked: Locked) -> Result<()> {
     Ok(())
 }
 
-fn run(profile: Profile, locked: Locked, smp: u32, ram: u32) -> Result<()> {
+fn run(profile: Profile, locked: Locked, smp: u32, ram: u32, cpu: &str) -> Result<()> {
     archive(profile, locked)?;
     let args = format!(
         "-nographic \
             -accel kvm \
-            -cpu kvm64,+rdtscp,+pdpe1gb,+fsgsbase,+x2apic \
+            -cpu {cpu} \
             -machine q35 \
             -smp {smp} \
             -m {ram} \